@@ -0,0 +1,78 @@
+//! Resolution and planning for cotton, the package manager.
+//!
+//! A [`Graph`](resolve::Graph) maps each requirement to the version it resolves to. It is filled
+//! in by [`Graph::append`](resolve::Graph::append) from a [`Source`](resolve::Source) of package
+//! metadata, and laid out in `node_modules` by
+//! [`Graph::build_trees`](resolve::Graph::build_trees), which returns the trees of a
+//! [`Plan`](plan::Plan). A [`Packument`](npm::Packument) selects versions from registry metadata
+//! the same way as cotton. Downloading metadata and installing the packages is left to the caller.
+//!
+//! ```no_run
+//! use color_eyre::{eyre::eyre, Result};
+//! use compact_str::CompactString;
+//! use cotton_core::{
+//!     npm::{Packument, PrereleasePolicy},
+//!     package::PackageInfo,
+//!     platform::{Platform, Platforms},
+//!     resolve::{Graph, PlanOptions, ResolveOptions, Source, Strategy},
+//!     spec::PackageSpecifier,
+//! };
+//! use node_semver::Version;
+//! use std::{
+//!     collections::BTreeMap,
+//!     future::{ready, Future},
+//!     sync::Arc,
+//! };
+//!
+//! /// Packuments downloaded ahead of time, by package name
+//! struct Registry(BTreeMap<CompactString, Packument<PackageInfo>>);
+//!
+//! impl Source for Registry {
+//!     fn fetch(
+//!         &self,
+//!         req: PackageSpecifier,
+//!         strategy: Strategy,
+//!     ) -> impl Future<Output = Result<(Version, Arc<PackageInfo>)>> + Send {
+//!         let policy = PrereleasePolicy::default();
+//!         let resolved = self
+//!             .0
+//!             .get(&req.name)
+//!             .and_then(|x| x.resolve_range(&req.version, policy, strategy, None))
+//!             .map(|(version, package)| (version.clone(), Arc::new(package.clone())))
+//!             .ok_or_else(|| eyre!("No version of {} satisfies {}", req.name, req.version));
+//!         ready(resolved)
+//!     }
+//! }
+//!
+//! async fn plan(registry: Registry, roots: Vec<PackageSpecifier>) -> Result<()> {
+//!     let platforms = Platforms::new(Platform {
+//!         os: "linux".into(),
+//!         cpu: "x64".into(),
+//!         libc: Some("glibc".into()),
+//!     });
+//!
+//!     let mut graph = Graph::default();
+//!     let options = ResolveOptions::new(roots.clone(), platforms.clone());
+//!     graph.append(options, registry).await?;
+//!
+//!     let rules = BTreeMap::new();
+//!     let options = PlanOptions {
+//!         rules: &rules,
+//!         platforms: &platforms,
+//!         events: &(),
+//!     };
+//!     for tree in graph.build_trees(&roots, &options)? {
+//!         println!("node_modules/{}", tree.root.name);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+pub mod npm;
+pub mod overrides;
+pub mod package;
+pub mod plan;
+pub mod platform;
+pub mod resolve;
+pub mod spec;
@@ -0,0 +1,178 @@
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use compact_str::CompactString;
+use indexmap::IndexMap;
+use node_semver::Version;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr, time::SystemTime};
+
+use crate::{resolve::Strategy, spec::VersionSpecifier};
+
+/// Every version of a package, as served by a registry, where `M` is the metadata of a version
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Packument<M> {
+    #[serde(rename = "dist-tags", default)]
+    pub dist_tags: FxHashMap<CompactString, CompactString>,
+    pub versions: IndexMap<Version, M>,
+    /// When each version was published
+    #[serde(default)]
+    pub time: FxHashMap<CompactString, CompactString>,
+}
+
+/// A time after which published versions are ignored, written as a date, a timestamp or a duration
+/// before now, such as `2024-01-01`, `2024-01-01T12:00:00Z` or `7d`
+#[derive(Clone, Copy, Debug)]
+pub struct Cutoff(pub SystemTime);
+
+impl FromStr for Cutoff {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(duration) = humantime::parse_duration(s) {
+            return Ok(Cutoff(SystemTime::now() - duration));
+        }
+
+        let timestamp = if s.len() == "2024-01-01".len() {
+            format!("{s}T00:00:00Z")
+        } else {
+            s.to_string()
+        };
+        humantime::parse_rfc3339_weak(&timestamp)
+            .map(Cutoff)
+            .map_err(|_| format!("`{s}` is not a date, timestamp or duration"))
+    }
+}
+
+impl Display for Cutoff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", humantime::format_rfc3339_seconds(self.0))
+    }
+}
+
+/// Which of the versions admitted by a range to use, when some of them are prereleases
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "snake_case")]
+pub enum PrereleasePolicy {
+    /// Use a prerelease only if no stable version satisfies the range
+    #[default]
+    PreferStable,
+    /// Never use a prerelease
+    Never,
+    /// Use the highest version, even if it is a prerelease
+    Highest,
+}
+
+/// Version to use when a registry does not specify a `latest` tag
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LatestFallback {
+    #[default]
+    HighestStable,
+    Highest,
+    Error,
+}
+
+impl<M> Packument<M> {
+    /// Whether `version` was published before `before`, counting versions without a publish
+    /// time as published, as npm does
+    pub fn published_in_time(&self, version: &Version, before: Option<Cutoff>) -> bool {
+        let Some(Cutoff(before)) = before else {
+            return true;
+        };
+        self.time
+            .get(version.to_string().as_str())
+            .and_then(|x| humantime::parse_rfc3339_weak(x).ok())
+            .is_none_or(|published| published <= before)
+    }
+
+    /// The version of a tag, or if it was published after `before`, the highest older version
+    /// below it
+    pub fn tagged_in_time(
+        &self,
+        name: &str,
+        tagged: Version,
+        before: Option<Cutoff>,
+    ) -> Result<Version> {
+        if self.published_in_time(&tagged, before) {
+            return Ok(tagged);
+        }
+
+        self.versions
+            .keys()
+            .filter(|v| **v <= tagged && self.published_in_time(v, before))
+            .filter(|v| tagged.is_prerelease() || !v.is_prerelease())
+            .max()
+            .cloned()
+            .wrap_err_with(|| {
+                eyre!(
+                    "No version of {name} up to {tagged} was published before {}",
+                    before.unwrap()
+                )
+            })
+    }
+
+    /// The version that a range resolves to, among the versions published before `before`
+    pub fn resolve_range(
+        &self,
+        range: &VersionSpecifier,
+        policy: PrereleasePolicy,
+        strategy: Strategy,
+        before: Option<Cutoff>,
+    ) -> Option<(&Version, &M)> {
+        let candidates = self.versions.iter().filter(|(v, _)| {
+            range.satisfies(v)
+                && self.published_in_time(v, before)
+                && (policy != PrereleasePolicy::Never || !v.is_prerelease())
+        });
+        let stable_first = policy == PrereleasePolicy::PreferStable;
+        match strategy {
+            Strategy::Highest => {
+                candidates.max_by_key(|(v, _)| (stable_first && !v.is_prerelease(), *v))
+            }
+            Strategy::Lowest => {
+                candidates.min_by_key(|(v, _)| (stable_first && v.is_prerelease(), *v))
+            }
+        }
+    }
+
+    /// Whether `latest` uses a tag, instead of falling back to the published versions
+    pub fn is_tagged(&self, default_tag: Option<&str>) -> bool {
+        default_tag.is_some_and(|x| self.dist_tags.contains_key(x))
+            || self.dist_tags.contains_key("latest")
+    }
+
+    /// The version of the default tag of the package's scope, if the package has that tag, and
+    /// otherwise of `latest`
+    pub fn latest(
+        &self,
+        name: &str,
+        default_tag: Option<&str>,
+        fallback: LatestFallback,
+        before: Option<Cutoff>,
+    ) -> Result<Version> {
+        if let Some(tag) = default_tag.and_then(|x| self.dist_tags.get(x)) {
+            return self.tagged_in_time(name, Version::parse(tag)?, before);
+        }
+        if let Some(tag) = self.dist_tags.get("latest") {
+            return self.tagged_in_time(name, Version::parse(tag)?, before);
+        }
+
+        let version = match fallback {
+            LatestFallback::HighestStable => self
+                .versions
+                .keys()
+                .filter(|v| !v.is_prerelease() && self.published_in_time(v, before))
+                .max(),
+            LatestFallback::Highest => self
+                .versions
+                .keys()
+                .filter(|v| self.published_in_time(v, before))
+                .max(),
+            LatestFallback::Error => None,
+        }
+        .wrap_err_with(|| eyre!("Package `{name}` does not specify a `latest` tag"))?;
+
+        Ok(version.clone())
+    }
+}
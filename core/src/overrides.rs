@@ -0,0 +1,88 @@
+use compact_str::CompactString;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{package::PackageInfo, spec::VersionSpecifier};
+
+/// An entry of npm's `overrides`, either a version or overrides for the dependencies of a package
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum Override {
+    Version(VersionSpecifier),
+    Nested(BTreeMap<CompactString, Override>),
+}
+
+/// Versions forced by the root package for transitive dependencies
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    /// Applies to the dependency wherever it appears
+    global: FxHashMap<CompactString, VersionSpecifier>,
+    /// Applies to the dependency only when required by the parent package
+    scoped: FxHashMap<(CompactString, CompactString), VersionSpecifier>,
+}
+
+impl Overrides {
+    /// Force the version of a dependency, either everywhere or only where `parent` requires it
+    pub fn insert(&mut self, parent: Option<&str>, name: &str, version: VersionSpecifier) {
+        match parent {
+            Some(parent) => self.scoped.insert((parent.into(), name.into()), version),
+            None => self.global.insert(name.into(), version),
+        };
+    }
+
+    /// Every override, with the parent it is limited to
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&str>, &str, &VersionSpecifier)> {
+        self.global
+            .iter()
+            .map(|(name, version)| (None, name.as_str(), version))
+            .chain(
+                self.scoped.iter().map(|((parent, name), version)| {
+                    (Some(parent.as_str()), name.as_str(), version)
+                }),
+            )
+    }
+
+    fn get(&self, parent: &str, name: &str) -> Option<&VersionSpecifier> {
+        self.scoped
+            .get(&(parent.into(), name.into()))
+            .or_else(|| self.global.get(name))
+    }
+
+    /// Replace the requested versions of overridden dependencies, remembering the original versions
+    /// so that the package can be restored when the overrides change
+    pub fn apply(&self, package: &mut Arc<PackageInfo>) {
+        if self.global.is_empty() && self.scoped.is_empty() && package.overridden.is_empty() {
+            return;
+        }
+
+        let mut updated = (**package).clone();
+
+        for (name, version) in std::mem::take(&mut updated.overridden) {
+            if let Some(x) = updated.optional_dependencies.get_mut(&name) {
+                *x = version;
+            } else {
+                updated.dependencies.insert(name, version);
+            }
+        }
+
+        for deps in [
+            &mut updated.dependencies,
+            &mut updated.optional_dependencies,
+        ] {
+            for (name, version) in deps.iter_mut() {
+                if let Some(forced) = self.get(&updated.name, name) {
+                    if forced != version {
+                        updated
+                            .overridden
+                            .insert(name.clone(), std::mem::replace(version, forced.clone()));
+                    }
+                }
+            }
+        }
+
+        if updated != **package {
+            *package = Arc::new(updated);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use compact_str::{CompactString, ToCompactString};
+use node_semver::Version;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    plan::Dependency,
+    platform::{PlatformMap, Platforms},
+    spec::{PackageSpecifier, VersionSpecifier},
+};
+
+/// Metadata of a single version of a package, as locked
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default, Hash)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct PackageInfo {
+    pub name: CompactString,
+    pub dist: Dist,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub optional_dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_dependencies_meta: BTreeMap<CompactString, PeerDependencyMeta>,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub os: PlatformMap,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub cpu: PlatformMap,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub libc: PlatformMap,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bin: Option<Bin>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub scripts: BTreeMap<CompactString, CompactString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<CompactString>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub engines: BTreeMap<CompactString, CompactString>,
+    /// Original versions of dependencies replaced by overrides of the root package
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub overridden: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<CompactString>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default, Hash)]
+#[serde(default)]
+pub struct PeerDependencyMeta {
+    pub optional: bool,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Deserialize)]
+pub struct VersionedPackageInfo {
+    pub package: Arc<PackageInfo>,
+    pub version: Version,
+}
+
+impl VersionedPackageInfo {
+    pub fn dependency(&self) -> Dependency {
+        Dependency {
+            name: self.package.name.to_compact_string(),
+            version: self.version.clone(),
+            dist: self.package.dist.clone(),
+            bins: self.package.bins().into_iter().collect(),
+            scripts: self.package.scripts.clone(),
+        }
+    }
+}
+
+impl PackageInfo {
+    pub fn bins(&self) -> BTreeMap<CompactString, CompactString> {
+        match &self.bin {
+            Some(Bin::Multi(x)) => x.clone().into_iter().collect(),
+            Some(Bin::Single(x)) => [(self.name.to_compact_string(), x.to_compact_string())]
+                .into_iter()
+                .collect(),
+            None => [].into_iter().collect(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = PackageSpecifier> + '_ {
+        self.dependencies
+            .iter()
+            .chain(self.optional_dependencies.iter())
+            .map(|(n, v)| PackageSpecifier {
+                name: n.to_compact_string(),
+                version: v.to_owned(),
+                optional: self.optional_dependencies.contains_key(n),
+            })
+    }
+
+    /// Whether the package is installed for any of the supported platforms
+    pub fn supported(&self, platforms: &Platforms) -> bool {
+        platforms.os.iter().any(|x| self.os.is_supported(x))
+            && platforms.cpu.iter().any(|x| self.cpu.is_supported(x))
+            && platforms
+                .libc
+                .as_ref()
+                .is_none_or(|list| list.iter().any(|x| self.libc.is_supported(x)))
+    }
+
+    /// Whether the package runs on the target platform
+    pub fn native(&self, platforms: &Platforms) -> bool {
+        let target = &platforms.target;
+        self.os.is_supported(&target.os)
+            && self.cpu.is_supported(&target.cpu)
+            && target
+                .libc
+                .as_ref()
+                .is_none_or(|libc| self.libc.is_supported(libc))
+    }
+
+    /// Whether a peer dependency is marked optional in `peerDependenciesMeta`
+    pub fn is_optional_peer(&self, name: &str) -> bool {
+        self.peer_dependencies_meta
+            .get(name)
+            .is_some_and(|x| x.optional)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Hash)]
+#[serde(untagged)]
+pub enum Bin {
+    Single(CompactString),
+    Multi(BTreeMap<CompactString, CompactString>),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default, PartialOrd, Ord)]
+pub struct Dist {
+    pub tarball: CompactString,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<CompactString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shasum: Option<CompactString>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<Signature>,
+    /// Size of the extracted package in bytes, as reported by the registry
+    #[serde(
+        rename = "unpackedSize",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub unpacked_size: Option<u64>,
+    /// Attestations that the registry publishes for this version, which are only checked during
+    /// resolution and not locked
+    #[serde(default, skip_serializing)]
+    pub attestations: Option<Attestations>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Attestations {
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Provenance {
+    #[serde(rename = "predicateType")]
+    pub predicate_type: CompactString,
+}
+
+/// Hashes of a tarball that are computed while it is read, limited to the ones that a `Dist` records
+#[derive(Clone, Default)]
+pub struct TarballDigest {
+    sha512: Option<Sha512>,
+    sha256: Option<Sha256>,
+    sha1: Option<Sha1>,
+}
+
+impl TarballDigest {
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.sha512 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.sha256 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.sha1 {
+            hasher.update(data);
+        }
+    }
+}
+
+/// Hash algorithms that an `integrity` may list, from weakest to strongest
+const ALGORITHMS: [&str; 3] = ["sha1", "sha256", "sha512"];
+
+impl Dist {
+    /// The hashes of the strongest algorithm that `integrity` lists, which are the only ones
+    /// checked, as ssri does
+    fn strongest(&self) -> Option<(&str, Vec<&str>)> {
+        let hashes = self
+            .integrity
+            .as_deref()?
+            .split_whitespace()
+            .filter_map(|x| x.split_once('-'))
+            .filter(|(algorithm, _)| ALGORITHMS.contains(algorithm));
+        let algorithm = hashes
+            .clone()
+            .map(|(algorithm, _)| algorithm)
+            .max_by_key(|x| ALGORITHMS.iter().position(|y| y == x))?;
+
+        Some((
+            algorithm,
+            hashes
+                .filter(|(x, _)| *x == algorithm)
+                .map(|(_, hash)| hash)
+                .collect(),
+        ))
+    }
+
+    /// A digest that computes the hash needed to verify a tarball against this `Dist`
+    pub fn digest(&self) -> TarballDigest {
+        let mut digest = TarballDigest::default();
+        match self.strongest() {
+            Some(("sha512", _)) => digest.sha512 = Some(Sha512::new()),
+            Some(("sha256", _)) => digest.sha256 = Some(Sha256::new()),
+            Some(_) => digest.sha1 = Some(Sha1::new()),
+            None if self.shasum.is_some() => digest.sha1 = Some(Sha1::new()),
+            None => {}
+        }
+        digest
+    }
+
+    /// Whether an integrity or shasum is recorded to verify tarballs against
+    pub fn has_integrity(&self) -> bool {
+        self.strongest().is_some() || self.shasum.is_some()
+    }
+
+    /// Whether the hash in `digest` matches the strongest recorded integrity, or the shasum if
+    /// there is no integrity. Without either, nothing matches.
+    pub fn matches(&self, digest: TarballDigest) -> bool {
+        if let Some((algorithm, expected)) = self.strongest() {
+            let actual = match algorithm {
+                "sha512" => digest.sha512.map(|x| STANDARD.encode(x.finalize())),
+                "sha256" => digest.sha256.map(|x| STANDARD.encode(x.finalize())),
+                _ => digest.sha1.map(|x| STANDARD.encode(x.finalize())),
+            };
+            return actual.is_some_and(|x| expected.contains(&x.as_str()));
+        }
+
+        match &self.shasum {
+            Some(shasum) => digest
+                .sha1
+                .is_some_and(|x| format!("{:x}", x.finalize()) == *shasum),
+            None => false,
+        }
+    }
+
+    /// Whether `data` matches the recorded integrity or shasum
+    pub fn verify(&self, data: &[u8]) -> bool {
+        let mut digest = self.digest();
+        digest.update(data);
+        self.matches(digest)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default, PartialOrd, Ord)]
+pub struct Signature {
+    pub keyid: CompactString,
+    pub sig: CompactString,
+}
+
+/// Exceptions for a single dependency, wherever it appears in the graph
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageRule {
+    /// Never run the install scripts of the package
+    pub ignore_scripts: bool,
+    /// Skip checking the `engines` of the package against the active node version
+    pub ignore_engines: bool,
+    /// Install the package even if its `os`, `cpu` or `libc` exclude the target platform
+    pub ignore_platform: bool,
+    /// Where the package is installed in `node_modules`, instead of wherever hoisting puts it
+    pub placement: Option<Placement>,
+}
+
+/// Where a package is installed, for tools that expect it at a fixed path
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Placement {
+    /// A single copy at the root of `node_modules`, which every dependent must share
+    Root,
+    /// Next to each dependent, and only at the root if the project depends on it directly
+    Nested,
+}
+
+impl PackageRule {
+    /// The rule of packages that are not listed
+    pub const NONE: PackageRule = PackageRule {
+        ignore_scripts: false,
+        ignore_engines: false,
+        ignore_platform: false,
+        placement: None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"tarball";
+
+    fn integrity(algorithm: &str, data: &[u8]) -> String {
+        let hash = match algorithm {
+            "sha512" => STANDARD.encode(Sha512::digest(data)),
+            "sha256" => STANDARD.encode(Sha256::digest(data)),
+            _ => STANDARD.encode(Sha1::digest(data)),
+        };
+        format!("{algorithm}-{hash}")
+    }
+
+    fn dist(integrity: Option<String>, shasum: Option<String>) -> Dist {
+        Dist {
+            tarball: "https://registry.npmjs.org/a/-/a-1.0.0.tgz".into(),
+            integrity: integrity.map(Into::into),
+            shasum: shasum.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_integrity() {
+        assert!(dist(Some(integrity("sha512", DATA)), None).verify(DATA));
+        assert!(dist(Some(integrity("sha256", DATA)), None).verify(DATA));
+    }
+
+    #[test]
+    fn rejects_mismatch() {
+        assert!(!dist(Some(integrity("sha512", b"other")), None).verify(DATA));
+        assert!(!dist(None, Some(format!("{:x}", Sha1::digest(b"other")))).verify(DATA));
+    }
+
+    #[test]
+    fn matches_shasum_without_integrity() {
+        assert!(dist(None, Some(format!("{:x}", Sha1::digest(DATA)))).verify(DATA));
+    }
+
+    #[test]
+    fn rejects_missing_hash() {
+        let dist = dist(None, None);
+        assert!(!dist.has_integrity());
+        assert!(!dist.verify(DATA));
+
+        let unknown = Dist {
+            integrity: Some("md5-abc".into()),
+            ..dist
+        };
+        assert!(!unknown.has_integrity());
+        assert!(!unknown.verify(DATA));
+    }
+
+    #[test]
+    fn checks_only_the_strongest_algorithm() {
+        let weak_match = format!(
+            "{} {}",
+            integrity("sha1", DATA),
+            integrity("sha512", b"other")
+        );
+        assert!(!dist(Some(weak_match), None).verify(DATA));
+
+        let strong_match = format!(
+            "{} {}",
+            integrity("sha1", b"other"),
+            integrity("sha512", DATA)
+        );
+        assert!(dist(Some(strong_match), None).verify(DATA));
+    }
+}
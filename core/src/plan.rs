@@ -0,0 +1,100 @@
+use compact_str::CompactString;
+use node_semver::Version;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Display, path::MAIN_SEPARATOR};
+
+use crate::package::Dist;
+
+/// A package to be installed
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize, Deserialize)]
+pub struct Dependency {
+    pub name: CompactString,
+    pub version: Version,
+    pub dist: Dist,
+    pub bins: BTreeMap<CompactString, CompactString>,
+    pub scripts: BTreeMap<CompactString, CompactString>,
+}
+
+impl Dependency {
+    pub fn id(&self) -> String {
+        format!("{}@{}", self.name, self.version).replace(MAIN_SEPARATOR, "!")
+    }
+}
+
+/// A package with the dependencies that are nested in its own `node_modules`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DependencyTree {
+    #[serde(flatten)]
+    pub root: Dependency,
+    pub children: FxHashMap<CompactString, DependencyTree>,
+    /// Whether the package is only reached through `optionalDependencies`, so that it may fail
+    /// to install
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+impl DependencyTree {
+    pub fn filter(&self, exclude: &FxHashSet<Dependency>) -> Self {
+        Self {
+            root: self.root.clone(),
+            children: self
+                .children
+                .iter()
+                .filter_map(|(name, tree)| {
+                    if !exclude.contains(&tree.root) {
+                        Some((name.clone(), tree.filter(exclude)))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            optional: self.optional,
+        }
+    }
+}
+
+/// Which dependencies of the root are installed, also exported to scripts as `NODE_ENV`
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Development,
+    /// Leave out `devDependencies`
+    Production,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Development => write!(f, "development"),
+            Mode::Production => write!(f, "production"),
+        }
+    }
+}
+
+/// The layout of `node_modules`, by the name of each package at its root
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Plan {
+    /// Named so that it cannot be mistaken for a package, which never starts with `_`
+    #[serde(rename = "_mode", default)]
+    pub mode: Mode,
+    #[serde(flatten)]
+    pub trees: FxHashMap<CompactString, DependencyTree>,
+}
+
+impl Plan {
+    pub fn new(trees: FxHashMap<CompactString, DependencyTree>, mode: Mode) -> Self {
+        Self { mode, trees }
+    }
+}
+
+/// Number of packages in the trees, counting every nested copy
+pub fn tree_size(trees: &FxHashMap<CompactString, DependencyTree>) -> usize {
+    trees.len()
+        + trees
+            .values()
+            .map(|x| tree_size(&x.children))
+            .sum::<usize>()
+}
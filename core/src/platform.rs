@@ -0,0 +1,102 @@
+use compact_str::CompactString;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The `os`, `cpu` or `libc` field of a package, where entries starting with `!` are excluded
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct PlatformMap(BTreeSet<CompactString>);
+
+impl PlatformMap {
+    pub fn allowed(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(|x| !x.starts_with('!'))
+            .map(|x| x.as_str())
+    }
+
+    pub fn blocked(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|x| x.strip_prefix('!'))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn is_supported(&self, platform: &str) -> bool {
+        if self.is_empty() {
+            true
+        } else {
+            self.allowed().any(|o| o == platform) && !self.blocked().any(|o| o == platform)
+        }
+    }
+}
+
+/// A platform, as named by node
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Platform {
+    pub os: String,
+    pub cpu: String,
+    /// Unknown when installing for another machine
+    pub libc: Option<String>,
+}
+
+/// Every platform that packages are installed for
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Platforms {
+    pub os: Vec<String>,
+    pub cpu: Vec<String>,
+    /// Packages are not filtered by libc when it is unknown
+    pub libc: Option<Vec<String>>,
+    /// The platform that install scripts run on
+    pub target: Platform,
+}
+
+impl Platforms {
+    /// Only the given platform
+    pub fn new(target: Platform) -> Self {
+        Self {
+            os: vec![target.os.clone()],
+            cpu: vec![target.cpu.clone()],
+            libc: target.libc.clone().map(|x| vec![x]),
+            target,
+        }
+    }
+
+    /// Whether a scoped package name ending in `<os>-<cpu>`, optionally followed by a libc, such
+    /// as `@esbuild/darwin-arm64` or `@rollup/rollup-linux-x64-musl`, names an os, cpu or libc
+    /// that packages are not installed for
+    pub fn is_foreign(&self, name: &str) -> bool {
+        let Some((_, name)) = name.strip_prefix('@').and_then(|x| x.split_once('/')) else {
+            return false;
+        };
+        let parts = name.split('-').collect_vec();
+        let (os, cpu, libc) = match parts.as_slice() {
+            [.., os, cpu] if NODE_OS.contains(os) && NODE_CPU.contains(cpu) => (*os, *cpu, None),
+            [.., os, cpu, libc] if NODE_OS.contains(os) && NODE_CPU.contains(cpu) => {
+                (*os, *cpu, Some(*libc))
+            }
+            _ => return false,
+        };
+
+        let unsupported = |list: &[String], x: &str| !list.iter().any(|y| y == x);
+        let unsupported_libc =
+            |x: &str| self.libc.as_ref().is_some_and(|list| unsupported(list, x));
+
+        unsupported(&self.os, os)
+            || unsupported(&self.cpu, cpu)
+            || match libc {
+                Some("musl") => unsupported_libc("musl"),
+                Some("gnu" | "glibc") => unsupported_libc("glibc"),
+                _ => false,
+            }
+    }
+}
+
+const NODE_OS: [&str; 9] = [
+    "aix", "android", "darwin", "freebsd", "linux", "netbsd", "openbsd", "sunos", "win32",
+];
+
+const NODE_CPU: [&str; 11] = [
+    "arm", "arm64", "ia32", "loong64", "mips", "mipsel", "ppc", "ppc64", "riscv64", "s390x", "x64",
+];
@@ -0,0 +1,590 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Report, Section,
+};
+use compact_str::{CompactString, ToCompactString};
+use dashmap::{DashMap, DashSet};
+use itertools::Itertools;
+use node_semver::Version;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    mem::take,
+    sync::Arc,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    overrides::Overrides,
+    package::{PackageInfo, PackageRule, Placement, VersionedPackageInfo},
+    plan::{Dependency, DependencyTree},
+    platform::Platforms,
+    spec::PackageSpecifier,
+};
+
+/// Requirements and the versions they are resolved to, which is what a lockfile records
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Graph {
+    #[serde(flatten)]
+    pub relations: FxHashMap<PackageSpecifier, VersionedPackageInfo>,
+}
+
+/// Whether a range resolves to the highest or the lowest version that satisfies it
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Strategy {
+    #[default]
+    Highest,
+    Lowest,
+}
+
+/// Where the metadata of packages comes from, such as a registry
+pub trait Source: Send + Sync + 'static {
+    /// The version that a requirement resolves to, along with its metadata
+    fn fetch(
+        &self,
+        req: PackageSpecifier,
+        strategy: Strategy,
+    ) -> impl Future<Output = Result<(Version, Arc<PackageInfo>)>> + Send;
+
+    /// Called with each newly resolved package that can be installed, such as to start
+    /// downloading it before the graph is complete
+    fn resolved(&self, _dependency: Dependency) {}
+
+    /// Called when an optional dependency fails to resolve, and is left out of the graph
+    fn skipped(&self, _req: &PackageSpecifier, _error: &Report) {}
+}
+
+/// Receives what is left out while a graph is pruned and laid out, such as to print it
+pub trait Events: Sync {
+    /// Called with the number of requirements pruned because nothing reaches them
+    fn pruned(&self, _count: usize) {}
+
+    /// Called when a package is left out of a tree, since it depends on itself through `stack`
+    fn cyclic(&self, _stack: &[VersionedPackageInfo], _package: &VersionedPackageInfo) {}
+
+    /// Called when an optional package is left out of a tree, since its dependency `missing` is
+    /// not in the graph
+    fn incomplete(&self, _package: &VersionedPackageInfo, _missing: &PackageSpecifier) {}
+}
+
+/// Ignores every event
+impl Events for () {}
+
+/// A requirement that is missing from the graph, such as when a lockfile is out of date
+#[derive(Debug)]
+pub struct MissingRequirement(pub PackageSpecifier);
+
+impl Display for MissingRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A dependency could not be found")
+    }
+}
+
+impl std::error::Error for MissingRequirement {}
+
+/// How the requirements of a root package are resolved
+#[derive(Debug, Clone)]
+pub struct ResolveOptions {
+    roots: Vec<PackageSpecifier>,
+    /// Requirements of the root, which `direct_strategy` applies to
+    direct: FxHashSet<PackageSpecifier>,
+    /// Names of the root dependencies that are visible to every package as peers, which are all
+    /// of the roots unless set otherwise
+    pub provided: FxHashSet<CompactString>,
+    /// Strategy for the requirements of the root, while the rest resolve to the highest version
+    pub direct_strategy: Strategy,
+    /// Leave out optional dependencies named after platforms that are not installed, such as
+    /// `@esbuild/win32-x64`, without fetching them
+    pub skip_foreign: bool,
+    /// Resolve required peers that the root does not provide as regular dependencies
+    pub auto_install_peers: bool,
+    pub overrides: Overrides,
+    pub rules: BTreeMap<CompactString, PackageRule>,
+    pub platforms: Platforms,
+}
+
+impl ResolveOptions {
+    pub fn new(roots: Vec<PackageSpecifier>, platforms: Platforms) -> Self {
+        Self {
+            provided: roots.iter().map(|x| x.name.clone()).collect(),
+            direct: roots.iter().cloned().collect(),
+            roots,
+            direct_strategy: Strategy::Highest,
+            skip_foreign: false,
+            auto_install_peers: false,
+            overrides: Overrides::default(),
+            rules: BTreeMap::new(),
+            platforms,
+        }
+    }
+
+    /// Requirements of the root package
+    pub fn roots(&self) -> &[PackageSpecifier] {
+        &self.roots
+    }
+
+    fn ignores_platform(&self, name: &str) -> bool {
+        self.rules.get(name).is_some_and(|x| x.ignore_platform)
+    }
+
+    /// Resolve required peers that the root does not provide as regular dependencies of the package
+    fn add_unmet_peers(&self, package: &mut Arc<PackageInfo>) {
+        if !self.auto_install_peers {
+            return;
+        }
+
+        let unmet = package
+            .peer_dependencies
+            .iter()
+            .filter(|(name, _)| {
+                !package.is_optional_peer(name)
+                    && !self.provided.contains(*name)
+                    && !package.dependencies.contains_key(*name)
+                    && !package.optional_dependencies.contains_key(*name)
+            })
+            .map(|(name, range)| (name.clone(), range.clone()))
+            .collect_vec();
+
+        if !unmet.is_empty() {
+            Arc::make_mut(package).dependencies.extend(unmet);
+        }
+    }
+}
+
+impl Graph {
+    /// Resolve the requirements of the root that are not in the graph yet, and drop the ones that
+    /// are no longer reached
+    pub async fn append<S: Source>(&mut self, options: ResolveOptions, source: S) -> Result<()> {
+        /// `optional` is set for requirements only reached through `optionalDependencies`, which
+        /// are skipped if they fail to resolve
+        fn queue_resolve<S: Source>(
+            send: flume::Sender<JoinHandle<Result<()>>>,
+            req: PackageSpecifier,
+            relations: Arc<DashMap<PackageSpecifier, VersionedPackageInfo>>,
+            seen: Arc<DashSet<PackageSpecifier>>,
+            options: Arc<ResolveOptions>,
+            source: Arc<S>,
+            optional: bool,
+        ) -> Result<()> {
+            if !seen.insert(req.clone()) {
+                return Ok(());
+            }
+
+            let cached = relations.get_mut(&req).map(|mut subpackage| {
+                options.add_unmet_peers(&mut subpackage.package);
+                options.overrides.apply(&mut subpackage.package);
+                subpackage.package.iter().collect_vec()
+            });
+
+            if let Some(children) = cached {
+                for child_req in children {
+                    let child_optional = optional || child_req.optional;
+                    queue_resolve(
+                        send.clone(),
+                        child_req,
+                        relations.clone(),
+                        seen.clone(),
+                        options.clone(),
+                        source.clone(),
+                        child_optional,
+                    )?;
+                }
+
+                return Ok(());
+            }
+
+            send.clone().send(tokio::spawn(async move {
+                let strategy = if options.direct.contains(&req) {
+                    options.direct_strategy
+                } else {
+                    Strategy::Highest
+                };
+                let (version, mut subpackage) = match source.fetch(req.clone(), strategy).await {
+                    Ok(x) => x,
+                    Err(e) if optional => {
+                        source.skipped(&req, &e);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                // Platform variants are never installed elsewhere, so avoid fetching their metadata
+                if options.skip_foreign {
+                    Arc::make_mut(&mut subpackage)
+                        .optional_dependencies
+                        .retain(|name, _| {
+                            !options.platforms.is_foreign(name) || options.ignores_platform(name)
+                        });
+                }
+
+                options.add_unmet_peers(&mut subpackage);
+                options.overrides.apply(&mut subpackage);
+
+                if subpackage.supported(&options.platforms) || options.ignores_platform(&req.name) {
+                    source.resolved(Dependency {
+                        name: req.name.to_compact_string(),
+                        version: version.clone(),
+                        dist: subpackage.dist.clone(),
+                        bins: subpackage.bins().into_iter().collect(),
+                        scripts: subpackage.scripts.clone(),
+                    });
+                }
+
+                relations.insert(
+                    req.clone(),
+                    VersionedPackageInfo {
+                        package: subpackage.clone(),
+                        version,
+                    },
+                );
+
+                for child_req in subpackage.iter() {
+                    let child_optional = optional || child_req.optional;
+                    queue_resolve(
+                        send.clone(),
+                        child_req,
+                        relations.clone(),
+                        seen.clone(),
+                        options.clone(),
+                        source.clone(),
+                        child_optional,
+                    )?;
+                }
+
+                Ok(()) as Result<_>
+            }))?;
+
+            Ok(())
+        }
+
+        let relations: Arc<DashMap<_, _>> =
+            Arc::new(take(&mut self.relations).into_iter().collect());
+
+        let (send, recv) = flume::unbounded();
+
+        let seen = Arc::new(DashSet::new());
+
+        let options = Arc::new(options);
+        let source = Arc::new(source);
+
+        for req in options.roots.iter().cloned() {
+            let optional = req.optional;
+            queue_resolve(
+                send.clone(),
+                req,
+                relations.clone(),
+                seen.clone(),
+                options.clone(),
+                source.clone(),
+                optional,
+            )?;
+        }
+
+        drop(send);
+
+        while let Ok(f) = recv.recv_async().await {
+            f.await??;
+        }
+
+        // Requirements that were not reached again are left over from removed dependencies
+        self.relations = relations
+            .iter()
+            .filter(|x| seen.contains(x.key()))
+            .map(|x| (x.key().clone(), x.value().clone()))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Requirements of the root that are missing from the graph, and locked requirements that are
+    /// no longer reachable, found the same way as `append` but without resolving anything. Missing
+    /// optional requirements are not reported, since they are left out when they fail to resolve.
+    pub fn check_coverage(
+        &self,
+        options: &ResolveOptions,
+    ) -> (Vec<PackageSpecifier>, Vec<PackageSpecifier>) {
+        let mut missing = vec![];
+        let mut seen = FxHashSet::default();
+        let mut queue: VecDeque<(PackageSpecifier, bool)> = options
+            .roots
+            .iter()
+            .map(|x| (x.clone(), x.optional))
+            .collect();
+
+        while let Some((req, optional)) = queue.pop_front() {
+            if !seen.insert(req.clone()) {
+                continue;
+            }
+            let Some(locked) = self.relations.get(&req) else {
+                if !optional {
+                    missing.push(req);
+                }
+                continue;
+            };
+
+            let mut package = locked.package.clone();
+            options.add_unmet_peers(&mut package);
+            options.overrides.apply(&mut package);
+            queue.extend(package.iter().map(|x| {
+                let optional = optional || x.optional;
+                (x, optional)
+            }));
+        }
+
+        let unreachable = self
+            .relations
+            .keys()
+            .filter(|x| !seen.contains(*x))
+            .cloned()
+            .collect();
+
+        (missing, unreachable)
+    }
+
+    /// The package that a requirement resolves to, failing with `MissingRequirement`
+    pub fn resolve_req(&self, req: &PackageSpecifier) -> Result<VersionedPackageInfo> {
+        Ok(self
+            .relations
+            .get(req)
+            .ok_or_else(|| {
+                Report::new(MissingRequirement(req.clone()))
+                    .with_note(|| format!("Attempted to find {req:?}"))
+            })?
+            .clone())
+    }
+
+    /// The part of the graph reachable from `roots`, without requirements left behind by
+    /// removed or updated dependencies
+    pub fn pruned(&self, roots: &[PackageSpecifier], events: &impl Events) -> Graph {
+        let mut relations = FxHashMap::default();
+
+        let mut queue: VecDeque<PackageSpecifier> = roots.iter().cloned().collect();
+        while let Some(req) = queue.pop_front() {
+            if relations.contains_key(&req) {
+                continue;
+            }
+            // Missing requirements are reported when the pruned graph is used
+            let Some(package) = self.relations.get(&req) else {
+                continue;
+            };
+            queue.extend(package.package.iter());
+            relations.insert(req, package.clone());
+        }
+
+        events.pruned(self.relations.len() - relations.len());
+
+        Graph { relations }
+    }
+
+    fn build_tree(
+        &self,
+        package: &VersionedPackageInfo,
+        options: &PlanOptions,
+        stack: &mut Vec<VersionedPackageInfo>,
+        scopes: &mut Vec<FxHashMap<CompactString, VersionedPackageInfo>>,
+        optional: bool,
+    ) -> Result<Option<DependencyTree>> {
+        // A package is only nested below itself when another version shadows the copy further up,
+        // so it is installed once more here, where it is visible. Its own dependencies are then
+        // visible from the outer copy, which ends the cycle; a further repeat is left out.
+        let repeats = stack.iter().filter(|x| package == *x).count();
+        if repeats > 1 {
+            options.events.cyclic(stack, package);
+
+            return Ok(None);
+        }
+
+        let mut root = package.dependency();
+        let rule = options.rule(&package.package.name);
+
+        if !rule.ignore_platform && !package.package.supported(options.platforms) {
+            if optional {
+                return Ok(None);
+            } else {
+                return Err(
+                    Report::msg("Required dependency is not supported").note(format!(
+                        "Package {}@{} is not supported on this platform.",
+                        package.package.name, package.version
+                    )),
+                );
+            }
+        }
+
+        // Packages for other platforms from `supported_architectures` cannot be built here
+        if rule.ignore_scripts
+            || (!rule.ignore_platform && !package.package.native(options.platforms))
+        {
+            root.scripts.clear();
+        }
+
+        // Node uses the closest `node_modules` that contains a name, so a dependency is nested here
+        // unless the version visible from here is already the one it resolves to
+        let mut nested: BTreeMap<CompactString, (VersionedPackageInfo, bool)> = BTreeMap::new();
+        for dep in package.package.iter() {
+            let package2 = match self.resolve_req(&dep) {
+                Ok(x) => x,
+                // Optional dependencies that failed to resolve were left out of the graph
+                Err(_) if dep.optional => continue,
+                Err(_) if optional => {
+                    options.events.incomplete(package, &dep);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+            let visible = scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(&package2.package.name));
+            if visible != Some(&package2) {
+                if options.rule(&package2.package.name).placement == Some(Placement::Root) {
+                    return Err(eyre!(
+                        "{}@{} requires {}@{}, which cannot share the copy at the root of node_modules",
+                        package.package.name,
+                        package.version,
+                        package2.package.name,
+                        package2.version
+                    )
+                    .with_note(|| {
+                        format!(
+                            "{} is placed at the root by `cotton.packageRules`",
+                            package2.package.name
+                        )
+                    })
+                    .suggestion(format!(
+                        "Add an override so that every package uses the same version of {}",
+                        package2.package.name
+                    )));
+                }
+                nested.insert(package2.package.name.clone(), (package2, dep.optional));
+            }
+        }
+
+        // Nested versions shadow the ones in outer scopes for everything below this package
+        scopes.push(
+            nested
+                .iter()
+                .map(|(name, (package2, _))| (name.clone(), package2.clone()))
+                .collect(),
+        );
+        stack.push(package.clone());
+
+        let mut deps = vec![];
+        for (package2, optional) in nested.into_values() {
+            if let Some(tree) = self.build_tree(&package2, options, stack, scopes, optional)? {
+                deps.push(tree);
+            }
+        }
+
+        stack.pop().unwrap();
+        scopes.pop().unwrap();
+
+        let tree = DependencyTree {
+            children: deps
+                .into_iter()
+                .map(|x| (x.root.name.to_compact_string(), x))
+                .collect(),
+            root,
+            optional,
+        };
+
+        Ok(Some(tree))
+    }
+
+    /// Lay out the packages reached from `root_reqs` in `node_modules`, hoisting the highest
+    /// version of each package to the root
+    pub fn build_trees(
+        &self,
+        root_reqs: &[PackageSpecifier],
+        options: &PlanOptions,
+    ) -> Result<Vec<DependencyTree>> {
+        let mut is_optional = FxHashMap::default();
+
+        let mut reqs = FxHashMap::default();
+
+        for req in root_reqs {
+            let pkg = match self.resolve_req(req) {
+                Ok(x) => x,
+                Err(_) if req.optional => continue,
+                Err(e) => return Err(e),
+            };
+            reqs.insert(req.name.clone(), pkg.clone());
+            is_optional.insert(pkg, req.optional);
+        }
+
+        let mut flat_deps = FxHashSet::default();
+        let mut edge = VecDeque::new();
+        edge.extend(reqs.values().cloned());
+
+        while let Some(next) = edge.pop_front() {
+            if !flat_deps.contains(&next) {
+                let parent_optional = is_optional[&next];
+                for req in next.package.iter() {
+                    let pkg = match self.resolve_req(&req) {
+                        Ok(x) => x,
+                        Err(_) if req.optional || parent_optional => continue,
+                        Err(e) => return Err(e),
+                    };
+                    // A package is only optional if every requirement that reaches it is
+                    let optional = parent_optional || req.optional;
+                    is_optional
+                        .entry(pkg.clone())
+                        .and_modify(|x| *x &= optional)
+                        .or_insert(optional);
+                    edge.push_back(pkg);
+                }
+                flat_deps.insert(next);
+            }
+        }
+
+        let mut hoisted: FxHashMap<_, VersionedPackageInfo> = FxHashMap::default();
+        for dep in flat_deps {
+            if let Some(prev) = hoisted.get(&dep.package.name) {
+                if dep.version > prev.version {
+                    hoisted.insert(dep.package.name.clone(), dep.clone());
+                }
+            } else {
+                hoisted.insert(dep.package.name.clone(), dep.clone());
+            }
+        }
+
+        for (name, pkg) in &reqs {
+            hoisted.insert(name.clone(), pkg.clone());
+        }
+
+        // Packages placed next to their dependents are only at the root if the root depends on them
+        hoisted.retain(|name, _| {
+            reqs.contains_key(name) || options.rule(name).placement != Some(Placement::Nested)
+        });
+
+        for (name, pkg) in hoisted.iter() {
+            reqs.insert(name.clone(), pkg.clone());
+        }
+
+        let mut scopes = vec![hoisted];
+
+        let mut v = vec![];
+        for pkg in reqs.values() {
+            v.push(self.build_tree(pkg, options, &mut vec![], &mut scopes, is_optional[pkg])?);
+        }
+
+        let v = v.into_iter().flatten().collect();
+        Ok(v)
+    }
+}
+
+/// How the packages of a graph are laid out in `node_modules`
+#[derive(Clone, Copy)]
+pub struct PlanOptions<'a> {
+    pub rules: &'a BTreeMap<CompactString, PackageRule>,
+    pub platforms: &'a Platforms,
+    pub events: &'a dyn Events,
+}
+
+impl PlanOptions<'_> {
+    fn rule(&self, name: &str) -> &PackageRule {
+        self.rules.get(name).unwrap_or(&PackageRule::NONE)
+    }
+}
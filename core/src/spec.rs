@@ -0,0 +1,138 @@
+use compact_str::{CompactString, ToCompactString};
+use node_semver::{Range, Version};
+use serde::{de, Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Display;
+use url::Url;
+
+/// A specifier with a protocol such as `npm:`, `file:` or `workspace:`
+#[derive(Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct VersionSpecifierPrefixed {
+    pub prefix: CompactString,
+    pub rest: CompactString,
+}
+
+impl Display for VersionSpecifierPrefixed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.prefix, self.rest)
+    }
+}
+
+impl Serialize for VersionSpecifierPrefixed {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionSpecifierPrefixed {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let (prefix, rest) = s
+            .split_once(':')
+            .ok_or_else(|| de::Error::custom("missing :"))?;
+        if prefix.starts_with("http") {
+            return Err(de::Error::custom("http not allowed"));
+        }
+        Ok(Self {
+            prefix: prefix.to_compact_string(),
+            rest: rest.to_compact_string(),
+        })
+    }
+}
+
+/// The version of a dependency, as written in package.json
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum VersionSpecifier {
+    Range(Range),
+    Prefixed(VersionSpecifierPrefixed),
+    DirectUrl(Url),
+    Other(CompactString),
+}
+
+impl VersionSpecifier {
+    pub fn satisfies(&self, v: &Version) -> bool {
+        match self {
+            VersionSpecifier::Range(r) => r.satisfies(v),
+            VersionSpecifier::Prefixed(_) => true,
+            VersionSpecifier::DirectUrl(_) => true,
+            VersionSpecifier::Other(_) => false,
+        }
+    }
+}
+
+impl Display for VersionSpecifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionSpecifier::Range(a) => a.fmt(f),
+            VersionSpecifier::Prefixed(a) => a.fmt(f),
+            VersionSpecifier::DirectUrl(a) => a.fmt(f),
+            VersionSpecifier::Other(a) => a.fmt(f),
+        }
+    }
+}
+
+/// A requirement on a package, which is the key of a dependency graph
+#[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
+pub struct PackageSpecifier {
+    pub name: CompactString,
+    pub version: VersionSpecifier,
+    pub optional: bool,
+}
+
+impl Display for PackageSpecifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}!{}{}",
+            self.name,
+            self.version,
+            if self.optional { "?" } else { "" }
+        )
+    }
+}
+
+impl Serialize for PackageSpecifier {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PackageSpecifier {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let (name, rest) = s
+            .split_once('!')
+            .ok_or_else(|| de::Error::custom("Failed to parse version"))?;
+        let optional = rest.ends_with('?');
+        let version = rest.trim_end_matches('?');
+        Ok(Self {
+            name: name.to_compact_string(),
+            version: serde_json::from_value(Value::String(version.to_string()))
+                .map_err(de::Error::custom)?,
+            optional,
+        })
+    }
+}
+
+impl std::fmt::Debug for PackageSpecifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.version)?;
+        if self.optional {
+            write!(f, " (optional)")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+use color_eyre::{
+    eyre::{eyre, ContextCompat, Result},
+    Section,
+};
+use compact_str::CompactString;
+use itertools::Itertools;
+use node_semver::Version;
+use owo_colors::OwoColorize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{stdin, IsTerminal},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs::{read_to_string, remove_file, write};
+
+use crate::{
+    local::is_local,
+    npm::fetch_package,
+    package::{PackageMetadata, VersionedPackageInfo},
+    progress::{log_progress, PROGRESS_BAR},
+    registry::split_spec,
+    resolve::{resolve_graph, Graph, VerboseEvents},
+    util::{lockfile_path, manifest_path, read_package, save_lockfile, save_package},
+};
+
+/// Versions of each package installed by the previous package manager
+type Installed = BTreeMap<CompactString, BTreeSet<Version>>;
+
+/// Resolution passes before giving up on matching the previous versions
+const MAX_PASSES: usize = 8;
+
+/// Range prefixes in yarn lockfiles that do not come from a registry
+const NON_REGISTRY: [&str; 9] = [
+    "workspace:",
+    "link:",
+    "portal:",
+    "file:",
+    "patch:",
+    "exec:",
+    "github:",
+    "git",
+    "http",
+];
+
+#[derive(Clone, Copy)]
+enum Source {
+    Npm,
+    Yarn,
+    Pnpm,
+}
+
+impl Source {
+    const ALL: [(Source, &'static str); 4] = [
+        (Source::Npm, "package-lock.json"),
+        (Source::Npm, "npm-shrinkwrap.json"),
+        (Source::Yarn, "yarn.lock"),
+        (Source::Pnpm, "pnpm-lock.yaml"),
+    ];
+
+    fn detect() -> Option<(Source, &'static str)> {
+        Self::ALL
+            .into_iter()
+            .find(|(_, path)| Path::new(path).exists())
+    }
+
+    fn parse(self, text: &str) -> Result<Installed> {
+        Ok(match self {
+            Source::Npm => npm_versions(text)?,
+            Source::Yarn => yarn_versions(text),
+            Source::Pnpm => pnpm_versions(text),
+        })
+    }
+}
+
+fn insert(installed: &mut Installed, name: &str, version: &str) {
+    if let Ok(version) = Version::parse(version) {
+        installed.entry(name.into()).or_default().insert(version);
+    }
+}
+
+fn npm_versions(text: &str) -> Result<Installed> {
+    fn walk(dependencies: &Value, installed: &mut Installed) {
+        for (name, entry) in dependencies.as_object().into_iter().flatten() {
+            insert(
+                installed,
+                name,
+                entry["version"].as_str().unwrap_or_default(),
+            );
+            walk(&entry["dependencies"], installed);
+        }
+    }
+
+    let lockfile: Value = serde_json::from_str(text)?;
+    let mut installed = Installed::default();
+
+    match lockfile["packages"].as_object() {
+        Some(packages) => {
+            for (path, entry) in packages {
+                // The root and workspace directories are not installed packages
+                let Some((_, name)) = path.rsplit_once("node_modules/") else {
+                    continue;
+                };
+                if entry["link"].as_bool() == Some(true) {
+                    continue;
+                }
+                let name = entry["name"].as_str().unwrap_or(name);
+                insert(
+                    &mut installed,
+                    name,
+                    entry["version"].as_str().unwrap_or_default(),
+                );
+            }
+        }
+        // Lockfiles from npm 6 and older only have a nested tree
+        None => walk(&lockfile["dependencies"], &mut installed),
+    }
+
+    Ok(installed)
+}
+
+/// Read both the classic and the YAML-based format of `yarn.lock`
+fn yarn_versions(text: &str) -> Installed {
+    let mut installed = Installed::default();
+    let mut names = vec![];
+
+    for line in text.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        if !line.starts_with(' ') {
+            // A header lists the requirements that resolved to the entry, such as `"a@^1.0.0", "a@^1.1.0":`
+            names = line
+                .trim_end_matches(':')
+                .split(", ")
+                .filter_map(|descriptor| {
+                    let (name, range) = split_spec(descriptor.trim_matches('"'))?;
+                    let range = range.strip_prefix("npm:").unwrap_or(range);
+                    let registry =
+                        !range.contains('@') && !NON_REGISTRY.iter().any(|x| range.starts_with(x));
+                    registry.then(|| name.to_string())
+                })
+                .unique()
+                .collect_vec();
+        } else if line.starts_with("  ") && !line.starts_with("   ") {
+            if let Some(version) = line.trim().strip_prefix("version") {
+                let version = version.trim_start_matches(':').trim().trim_matches('"');
+                for name in &names {
+                    insert(&mut installed, name, version);
+                }
+            }
+        }
+    }
+
+    installed
+}
+
+/// Read the package keys of `pnpm-lock.yaml`, such as `/a/1.0.0`, `/a@1.0.0(b@2.0.0)` or `a@1.0.0`
+/// depending on the format
+fn pnpm_versions(text: &str) -> Installed {
+    let mut installed = Installed::default();
+    let legacy = text
+        .lines()
+        .find_map(|x| x.strip_prefix("lockfileVersion:"))
+        .is_some_and(|x| {
+            x.trim()
+                .trim_matches(|c| c == '\'' || c == '"')
+                .starts_with('5')
+        });
+
+    let mut in_packages = false;
+    for line in text.lines() {
+        if !line.starts_with(' ') && !line.trim().is_empty() {
+            in_packages = matches!(line.trim_end(), "packages:" | "snapshots:");
+            continue;
+        }
+        if !in_packages || !line.starts_with("  ") || line.starts_with("   ") {
+            continue;
+        }
+
+        let key = line
+            .trim()
+            .trim_end_matches(':')
+            .trim_matches(|c| c == '\'' || c == '"');
+        let key = key.strip_prefix('/').unwrap_or(key);
+
+        if legacy {
+            // Peers are appended after `_`, and never contain `/`
+            if let Some((name, version)) = key.rsplit_once('/') {
+                insert(&mut installed, name, version.split('_').next().unwrap());
+            }
+        } else if let Some((name, version)) = split_spec(key.split('(').next().unwrap()) {
+            insert(&mut installed, name, version);
+        }
+    }
+
+    installed
+}
+
+/// A token read from an environment variable, for `${VAR}` in `.npmrc`
+fn env_token(value: &str) -> Option<toml::Value> {
+    let var = value.strip_prefix("${")?.strip_suffix('}')?;
+    Some(toml::Value::Table(toml::Table::from_iter([(
+        "from_env".to_string(),
+        toml::Value::String(var.into()),
+    )])))
+}
+
+fn registry_entry(url: &str, scope: Option<&str>) -> toml::Value {
+    let mut entry = toml::Table::new();
+    entry.insert(
+        "url".into(),
+        toml::Value::String(url.trim_end_matches('/').into()),
+    );
+    if let Some(scope) = scope {
+        entry.insert("scope".into(), toml::Value::String(scope.into()));
+    }
+    toml::Value::Table(entry)
+}
+
+/// Translate the registries and settings of `.npmrc` and yarn's config files into `cotton.toml`,
+/// returning a description of each translated or skipped setting
+fn translate_config(table: &mut toml::Table) -> Result<Vec<String>> {
+    let mut notes = vec![];
+    let mut registries = vec![];
+    let mut tokens = vec![];
+
+    if let Ok(npmrc) = std::fs::read_to_string(".npmrc") {
+        for line in npmrc.lines().map(|x| x.trim()) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            if key == "registry" {
+                registries.push(registry_entry(value, None));
+            } else if let Some(scope) = key.strip_suffix(":registry") {
+                registries.push(registry_entry(value, Some(scope)));
+            } else if let Some(host) = key.strip_suffix(":_authToken") {
+                if let Some(token) = env_token(value) {
+                    tokens.push((host.trim_end_matches('/').to_string(), token));
+                } else {
+                    notes.push(format!(
+                        "Skipped the inline token for {host} in .npmrc; move it to an environment variable and set `auth.token.from_env`"
+                    ));
+                }
+            } else if key == "engine-strict" && value == "true" {
+                table.insert("engine_strict".into(), toml::Value::Boolean(true));
+                notes.push("Translated engine-strict from .npmrc".into());
+            } else if key == "auto-install-peers" && value == "true" {
+                table.insert("auto_install_peers".into(), toml::Value::Boolean(true));
+                notes.push("Translated auto-install-peers from .npmrc".into());
+            } else {
+                notes.push(format!("Skipped unsupported setting `{key}` in .npmrc"));
+            }
+        }
+    }
+
+    if let Ok(yarnrc) = std::fs::read_to_string(".yarnrc.yml") {
+        let value = |x: &str| x.trim().trim_matches(|c| c == '\'' || c == '"').to_string();
+        let mut in_scopes = false;
+        let mut scope = None;
+        for line in yarnrc.lines() {
+            if !line.starts_with(' ') {
+                in_scopes = line.trim_end() == "npmScopes:";
+                if let Some(url) = line.strip_prefix("npmRegistryServer:") {
+                    registries.push(registry_entry(&value(url), None));
+                }
+            } else if in_scopes && !line.starts_with("   ") {
+                scope = Some(value(line.trim_end().trim_end_matches(':')));
+            } else if let (true, Some(scope), Some(url)) = (
+                in_scopes,
+                &scope,
+                line.trim().strip_prefix("npmRegistryServer:"),
+            ) {
+                registries.push(registry_entry(&value(url), Some(&format!("@{scope}"))));
+            }
+        }
+    } else if let Ok(yarnrc) = std::fs::read_to_string(".yarnrc") {
+        for line in yarnrc.lines() {
+            if let Some(url) = line.strip_prefix("registry ") {
+                registries.push(registry_entry(url.trim().trim_matches('"'), None));
+            }
+        }
+    }
+
+    // Tokens in .npmrc are keyed by the registry URL without its scheme
+    for registry in &mut registries {
+        let url = registry["url"].as_str().unwrap_or_default().to_string();
+        let host = url.split_once("//").map(|x| x.1).unwrap_or(&url);
+        if let Some((_, token)) = tokens
+            .iter()
+            .find(|(x, _)| x.trim_start_matches('/') == host)
+        {
+            registry.as_table_mut().unwrap().insert(
+                "auth".into(),
+                toml::Value::Table(toml::Table::from_iter([("token".into(), token.clone())])),
+            );
+        }
+    }
+
+    // Registries that cotton.toml already lists are kept as they are
+    let existing = table
+        .entry("registry")
+        .or_insert_with(|| toml::Value::Array(vec![]))
+        .as_array_mut()
+        .wrap_err("`registry` in cotton.toml must be an array")?;
+    for registry in registries {
+        if !existing.iter().any(|x| x.get("url") == registry.get("url")) {
+            notes.push(format!(
+                "Translated registry {}",
+                registry["url"].as_str().unwrap_or_default()
+            ));
+            existing.push(registry);
+        }
+    }
+    if existing.is_empty() {
+        table.remove("registry");
+    }
+
+    Ok(notes)
+}
+
+/// Workspace patterns from `pnpm-workspace.yaml`, which cotton reads from package.json instead
+fn pnpm_workspaces() -> Option<Vec<Value>> {
+    let text = std::fs::read_to_string("pnpm-workspace.yaml").ok()?;
+    let mut in_packages = false;
+    let mut patterns = vec![];
+    for line in text.lines() {
+        if !line.starts_with(' ') && !line.trim().is_empty() {
+            in_packages = line.trim_end() == "packages:";
+        } else if let Some(pattern) = line.trim().strip_prefix("- ").filter(|_| in_packages) {
+            let pattern = pattern.trim().trim_matches(|c| c == '\'' || c == '"');
+            patterns.push(Value::String(pattern.into()));
+        }
+    }
+    Some(patterns)
+}
+
+/// Resolve the packages of the root, preferring the highest previously installed version that
+/// satisfies each requirement
+async fn resolve_like(package: &PackageMetadata, previous: &Installed) -> Result<Graph> {
+    let mut graph = Graph::default();
+
+    for _ in 0..MAX_PASSES {
+        resolve_graph(&mut graph, package, false).await?;
+
+        let mut changed = false;
+        for (req, locked) in graph.relations.iter_mut() {
+            let Some(version) = previous
+                .get(&req.name)
+                .and_then(|versions| versions.iter().rfind(|v| req.version.satisfies(v)))
+            else {
+                continue;
+            };
+            if *version == locked.version || is_local(&locked.package.dist) {
+                continue;
+            }
+
+            let metadata = fetch_package(&req.name).await?;
+            let Some(package) = metadata.versions.get(version) else {
+                continue;
+            };
+            *locked = VersionedPackageInfo {
+                package: Arc::new(package.clone().info()),
+                version: version.clone(),
+            };
+            changed = true;
+        }
+
+        // Dependencies of the swapped versions are resolved in the next pass
+        if !changed {
+            break;
+        }
+    }
+
+    Ok(graph)
+}
+
+/// Print the differences between the previous and the new versions, returning how many
+/// packages match
+fn print_differences(previous: &Installed, graph: &Graph, roots: &PackageMetadata) -> usize {
+    let graph = graph.pruned(&roots.iter_all().collect_vec(), &VerboseEvents);
+    let mut resolved = Installed::default();
+    for x in graph.relations.values() {
+        if !is_local(&x.package.dist) {
+            resolved
+                .entry(x.package.name.clone())
+                .or_default()
+                .insert(x.version.clone());
+        }
+    }
+
+    let mut matching = 0;
+    PROGRESS_BAR.suspend(|| {
+        for name in previous.keys().chain(resolved.keys()).sorted().dedup() {
+            let before = previous.get(name).cloned().unwrap_or_default();
+            let after = resolved.get(name).cloned().unwrap_or_default();
+            matching += before.intersection(&after).count();
+
+            for version in before.difference(&after) {
+                println!("{} {}@{version}", " REMOVED ".on_red(), name.bright_blue());
+            }
+            for version in after.difference(&before) {
+                println!("{} {}@{version}", " ADDED ".on_green(), name.bright_blue());
+            }
+        }
+    });
+
+    matching
+}
+
+fn confirm() -> Result<bool> {
+    PROGRESS_BAR.suspend(|| {
+        println!("Switch this project to cotton? [y/N]");
+
+        let mut answer = String::new();
+        stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    })
+}
+
+/// Contents of a file before `adopt` changed it, to put back if the switch is cancelled
+struct Backup {
+    path: PathBuf,
+    contents: Option<String>,
+}
+
+impl Backup {
+    async fn new(path: PathBuf) -> Self {
+        let contents = read_to_string(&path).await.ok();
+        Self { path, contents }
+    }
+
+    async fn restore(self) -> Result<()> {
+        match self.contents {
+            Some(contents) => write(&self.path, contents).await?,
+            None => {
+                let _ = remove_file(&self.path).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn switch(source: Source, path: &str, yes: bool) -> Result<bool> {
+    let previous = source
+        .parse(&read_to_string(path).await?)
+        .map_err(|e| eyre!("Failed to read {path}: {e}"))?;
+
+    let mut table: toml::Table = match read_to_string("cotton.toml").await {
+        Ok(x) => toml::from_str(&x)?,
+        Err(_) => Default::default(),
+    };
+    let mut notes = translate_config(&mut table)?;
+    if !table.is_empty() {
+        write("cotton.toml", toml::to_string(&table)?).await?;
+    }
+
+    let mut manifest: Value = serde_json::from_str(&read_to_string(manifest_path()).await?)?;
+    let manifest_object = manifest
+        .as_object_mut()
+        .wrap_err("`package.json` is invalid")?;
+    if !manifest_object.contains_key("workspaces") {
+        if let Some(patterns) = pnpm_workspaces() {
+            notes.push("Translated workspaces from pnpm-workspace.yaml".into());
+            manifest_object.insert("workspaces".into(), Value::Array(patterns));
+        }
+    }
+    if manifest_object.contains_key("packageManager") {
+        notes.push("Replaced packageManager in package.json".into());
+        manifest_object.insert(
+            "packageManager".into(),
+            Value::String(format!("cotton@{}", env!("CARGO_PKG_VERSION"))),
+        );
+    }
+    save_package(&manifest).await?;
+
+    PROGRESS_BAR.suspend(|| {
+        for note in &notes {
+            println!("{} {note}", " CONFIG ".on_blue());
+        }
+    });
+
+    log_progress(&format!("Resolving the versions from {path}"));
+    let package = read_package().await?;
+    let graph = resolve_like(&package, &previous).await?;
+
+    let matching = print_differences(&previous, &graph, &package);
+    let total = previous.values().map(|x| x.len()).sum::<usize>();
+    PROGRESS_BAR.suspend(|| {
+        println!(
+            "{} of {} packages from {path} are resolved to the same version",
+            matching.yellow(),
+            total.yellow()
+        )
+    });
+
+    let confirmed = if yes {
+        true
+    } else if stdin().is_terminal() {
+        confirm()?
+    } else {
+        return Err(eyre!("Switching to cotton requires confirmation")
+            .suggestion("Pass --yes to switch without confirmation"));
+    };
+    if !confirmed {
+        return Ok(false);
+    }
+
+    save_lockfile(&graph).await?;
+
+    Ok(true)
+}
+
+/// Switch a project from npm, yarn or pnpm to cotton, keeping the versions of its lockfile
+pub async fn adopt(yes: bool) -> Result<()> {
+    if lockfile_path().exists() {
+        return Err(eyre!("This project already has a cotton lockfile")
+            .suggestion("Remove cotton.lock to adopt the lockfile of another package manager"));
+    }
+
+    let (source, path) = Source::detect()
+        .wrap_err("No package-lock.json, yarn.lock or pnpm-lock.yaml was found")
+        .suggestion("Run `cotton install` to start using cotton")?;
+
+    let backups = [
+        Backup::new("cotton.toml".into()).await,
+        Backup::new(manifest_path()).await,
+    ];
+
+    let switched = switch(source, path, yes).await;
+    if !matches!(switched, Ok(true)) {
+        for backup in backups {
+            backup.restore().await?;
+        }
+    }
+
+    PROGRESS_BAR.finish_and_clear();
+    if switched? {
+        println!("Switched to cotton. Remove {path} and node_modules, then run `cotton install`.");
+    } else {
+        println!("Cancelled, no files were changed.");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use compact_str::CompactString;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use rustc_hash::FxHashSet;
+use std::{
+    env,
+    io::{stdin, IsTerminal},
+};
+use tokio::fs::{read_to_string, write};
+use toml_edit::{table, value, Array, DocumentMut};
+
+use crate::{
+    config::Config, package::PackageMetadata, progress::PROGRESS_BAR, resolve::Graph,
+    util::supported_platforms, ARGS,
+};
+
+pub const INSTALL_SCRIPTS: [&str; 3] = ["preinstall", "install", "postinstall"];
+
+/// A lifecycle script run during installation, identified by package name and command
+pub type InstallScript = (CompactString, CompactString);
+
+/// Install scripts of every package in the graph that can run on the target platform, except for
+/// packages whose scripts are ignored by the root
+pub fn install_scripts(root: &PackageMetadata, graph: &Graph) -> FxHashSet<InstallScript> {
+    graph
+        .relations
+        .values()
+        .filter(|x| {
+            let rule = root.rule(&x.package.name);
+            !rule.ignore_scripts
+                && (rule.ignore_platform || x.package.native(supported_platforms()))
+        })
+        .flat_map(|x| {
+            INSTALL_SCRIPTS
+                .iter()
+                .filter_map(|stage| x.package.scripts.get(*stage))
+                .map(|script| (x.package.name.clone(), script.clone()))
+        })
+        .collect()
+}
+
+/// Record approved scripts in `cotton.toml`, keyed by package name, keeping its comments and
+/// formatting
+async fn save_approvals(scripts: &[InstallScript]) -> Result<()> {
+    let mut doc: DocumentMut = match read_to_string("cotton.toml").await {
+        Ok(x) => x.parse()?,
+        Err(_) => Default::default(),
+    };
+
+    let approved = doc
+        .entry("approved_scripts")
+        .or_insert_with(table)
+        .as_table_like_mut()
+        .ok_or_else(|| eyre!("`approved_scripts` in cotton.toml must be a table"))?;
+
+    for (name, script) in scripts {
+        let commands = approved
+            .entry(name.as_str())
+            .or_insert(value(Array::new()))
+            .as_array_mut()
+            .ok_or_else(|| eyre!("`approved_scripts.{name}` in cotton.toml must be a list"))?;
+        commands.push(script.as_str());
+    }
+
+    write("cotton.toml", doc.to_string()).await?;
+
+    Ok(())
+}
+
+fn confirm(scripts: &[InstallScript]) -> Result<bool> {
+    PROGRESS_BAR.suspend(|| {
+        println!("New install scripts will be executed:");
+        for (name, script) in scripts {
+            println!("  {}: {}", name.bright_blue(), script);
+        }
+        println!("Allow these scripts? [y/N]");
+
+        let mut answer = String::new();
+        stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    })
+}
+
+/// Require approval for install scripts that are neither in `committed`, from the lockfile as
+/// committed to git, nor in `approved_scripts`. With `--immutable`, nothing is asked or saved.
+pub async fn approve_new_scripts(
+    root: &PackageMetadata,
+    committed: &FxHashSet<InstallScript>,
+    graph: &Graph,
+    config: &Config,
+) -> Result<()> {
+    let new = install_scripts(root, graph)
+        .into_iter()
+        .filter(|x| !committed.contains(x))
+        .filter(|(name, script)| {
+            !config
+                .approved_scripts
+                .get(name.as_str())
+                .is_some_and(|x| x.iter().any(|x| x == script))
+        })
+        .sorted()
+        .collect_vec();
+
+    if new.is_empty() {
+        return Ok(());
+    }
+
+    if !ARGS.immutable && stdin().is_terminal() && env::var_os("CI").is_none() {
+        if confirm(&new)? {
+            return save_approvals(&new).await;
+        }
+        return Err(eyre!("Install scripts were not approved"));
+    }
+
+    Err(eyre!(
+        "{} new install scripts require approval: {}",
+        new.len(),
+        new.iter()
+            .map(|(name, script)| format!("{name} ({script})"))
+            .join(", ")
+    )
+    .suggestion(
+        "Run `cotton install` interactively, or add them to `approved_scripts` in cotton.toml",
+    ))
+}
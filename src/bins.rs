@@ -0,0 +1,95 @@
+use color_eyre::eyre::Result;
+use compact_str::CompactString;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{
+    fs::{exists, read_link, rename, set_permissions, Permissions},
+    os::unix::{fs::symlink, prelude::PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    npm::{Dependency, DependencyTree},
+    progress::log_warning,
+};
+
+/// Normalize bins the way npm does: commands are reduced to their file name, and
+/// paths are resolved relative to the package root without being able to leave it
+pub fn normalize_bins(dep: &Dependency) -> Vec<(CompactString, PathBuf)> {
+    dep.bins
+        .iter()
+        .filter_map(|(cmd, path)| {
+            let cmd = Path::new(cmd.as_str()).file_name()?.to_str()?;
+            let path = normalize_bin_path(path)?;
+            Some((cmd.into(), path))
+        })
+        .collect()
+}
+
+fn normalize_bin_path(path: &str) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(x) => normalized.push(x),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    (!normalized.as_os_str().is_empty()).then_some(normalized)
+}
+
+/// Location of a bin inside `node_modules/.bin`
+pub fn bin_link_path(cmd: &str) -> PathBuf {
+    PathBuf::from("node_modules/.bin").join(cmd)
+}
+
+/// Link the bins of a top-level package of the project at `root` whose files exist, returning
+/// the bins that are missing
+///
+/// Bins may be created by the package's install scripts, so missing ones are linked later.
+pub fn link_bins(root: &Path, dep: &Dependency) -> Result<Vec<(CompactString, PathBuf)>> {
+    let mut missing = vec![];
+
+    for (cmd, path) in normalize_bins(dep) {
+        let file = root.join("node_modules").join(&*dep.name).join(&path);
+        if !exists(&file)? {
+            missing.push((cmd, path));
+            continue;
+        }
+
+        let target = Path::new("..").join(&*dep.name).join(&path);
+        let link = root.join(bin_link_path(&cmd));
+        if read_link(&link).ok().as_deref() != Some(target.as_path()) {
+            // Renaming a new link over the old one also replaces regular files and links created
+            // by other packages in the meantime, which `symlink` fails on
+            let suffix: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+            let temp = link.with_file_name(format!(".{cmd}.{suffix}"));
+            symlink(&target, &temp)?;
+            rename(&temp, &link)?;
+        }
+        set_permissions(&file, Permissions::from_mode(0o755))?;
+    }
+
+    Ok(missing)
+}
+
+/// Link bins that were not available during installation, such as those generated by install scripts,
+/// and warn about bins that still point to missing files
+pub fn link_deferred_bins<'a>(trees: impl Iterator<Item = &'a DependencyTree>) -> Result<()> {
+    for tree in trees {
+        for (cmd, path) in link_bins(Path::new(""), &tree.root)? {
+            log_warning(&format!(
+                "Bin `{cmd}` of {} was not linked, since {} does not exist",
+                tree.root.name,
+                path.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
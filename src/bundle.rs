@@ -0,0 +1,58 @@
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use color_eyre::eyre::Result;
+use futures::future::try_join_all;
+use std::path::Path;
+use tokio::{
+    fs::{create_dir_all, File},
+    io::{AsyncWriteExt, BufReader},
+};
+use tokio_tar::{Archive, Builder};
+
+use crate::{
+    local::is_local,
+    plan::{download_package_shared, store_dir},
+    progress::log_progress,
+    resolve::Graph,
+    util::supported_platforms,
+};
+
+/// Pack the store entries of every package in the graph into a single archive
+pub async fn export_store(graph: &Graph, path: &Path) -> Result<usize> {
+    let deps = graph
+        .relations
+        .values()
+        .filter(|x| x.package.supported(supported_platforms()) && !is_local(&x.package.dist))
+        .map(|x| x.dependency())
+        .collect::<Vec<_>>();
+
+    try_join_all(deps.iter().cloned().map(download_package_shared)).await?;
+
+    let store = store_dir();
+    let mut builder = Builder::new(GzipEncoder::new(File::create(path).await?));
+
+    let mut ids = deps.iter().map(|x| x.id()).collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+
+    for id in &ids {
+        log_progress(&format!("Packing {id}"));
+        builder.append_dir_all(id, store.join(id)).await?;
+    }
+
+    let mut encoder = builder.into_inner().await?;
+    encoder.shutdown().await?;
+
+    Ok(ids.len())
+}
+
+/// Restore store entries from an archive created by `export_store`
+pub async fn import_store(path: &Path) -> Result<()> {
+    let store = store_dir();
+    create_dir_all(&store).await?;
+
+    let reader = BufReader::new(File::open(path).await?);
+    let mut archive = Archive::new(GzipDecoder::new(reader));
+    archive.unpack(&store).await?;
+
+    Ok(())
+}
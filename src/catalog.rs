@@ -0,0 +1,147 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use compact_str::CompactString;
+use itertools::Itertools;
+use serde_json::Value;
+use std::{collections::BTreeMap, env};
+
+use crate::{
+    config::read_config,
+    package::PackageMetadata,
+    publish::DEPENDENCY_FIELDS,
+    util::{read_json, VersionSpecifier},
+};
+
+type Catalog = BTreeMap<CompactString, VersionSpecifier>;
+
+/// Versions shared by the packages of a monorepo, which `catalog:` and `catalog:<name>`
+/// specifiers refer to
+#[derive(Default, Debug)]
+pub struct Catalogs {
+    default: Catalog,
+    named: BTreeMap<CompactString, Catalog>,
+}
+
+/// The catalog of a `catalog:` specifier, where an empty name refers to the default catalog
+pub fn catalog_name(spec: &VersionSpecifier) -> Option<&str> {
+    match spec {
+        VersionSpecifier::Prefixed(prefixed) if prefixed.prefix == "catalog" => {
+            Some(&prefixed.rest)
+        }
+        _ => None,
+    }
+}
+
+impl Catalogs {
+    /// Catalogs from cotton.toml and the `cotton` field of the root package, where the root
+    /// package takes precedence
+    pub async fn new(root: &PackageMetadata) -> Result<Self> {
+        let config = read_config().await?;
+
+        let mut catalogs = Self {
+            default: config.catalog,
+            named: config.catalogs,
+        };
+        catalogs.default.extend(root.cotton.catalog.clone());
+        for (name, catalog) in &root.cotton.catalogs {
+            catalogs
+                .named
+                .entry(name.clone())
+                .or_default()
+                .extend(catalog.clone());
+        }
+
+        Ok(catalogs)
+    }
+
+    /// Catalogs of the workspace root that encloses the current directory, or of `package` if
+    /// it is not in a workspace
+    pub async fn enclosing(package: &PackageMetadata) -> Result<Self> {
+        for ancestor in env::current_dir()?.ancestors() {
+            if let Ok(root) = read_json::<PackageMetadata>(ancestor.join("package.json")).await {
+                if root.workspaces.is_some() {
+                    return Self::new(&root).await;
+                }
+            }
+        }
+
+        Self::new(package).await
+    }
+
+    fn lookup(&self, catalog: &str, name: &str) -> Result<VersionSpecifier> {
+        let (label, entries) = match catalog {
+            "" | "default" => ("the default catalog", &self.default),
+            catalog => (
+                catalog,
+                self.named.get(catalog).ok_or_else(|| {
+                    let report = eyre!("Catalog `{catalog}` does not exist")
+                        .suggestion(format!("Define it under `catalogs.{catalog}`"));
+                    if self.named.is_empty() {
+                        return report;
+                    }
+                    report.with_note(|| format!("Catalogs: {}", self.named.keys().join(", ")))
+                })?,
+            ),
+        };
+
+        entries.get(name).cloned().ok_or_else(|| {
+            eyre!("`{name}` is not in {label}")
+                .suggestion("Add its version to `catalog` in cotton.toml or package.json")
+        })
+    }
+
+    /// Replace the `catalog:` specifiers of a package with the versions they refer to
+    pub fn apply(&self, package: &mut PackageMetadata) -> Result<()> {
+        let groups = package
+            .cotton
+            .groups
+            .values_mut()
+            .flat_map(|x| x.iter_mut());
+        for (name, spec) in package
+            .dependencies
+            .iter_mut()
+            .chain(package.dev_dependencies.iter_mut())
+            .chain(package.optional_dependencies.iter_mut())
+            .chain(package.peer_dependencies.iter_mut())
+            .chain(groups)
+        {
+            if let Some(catalog) = catalog_name(spec) {
+                *spec = self.lookup(catalog, name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the `catalog:` specifiers of a manifest that is about to be packed
+    pub fn rewrite_manifest(&self, manifest: &mut Value) -> Result<()> {
+        for field in DEPENDENCY_FIELDS {
+            let Some(deps) = manifest.get_mut(field).and_then(|x| x.as_object_mut()) else {
+                continue;
+            };
+
+            for (name, spec) in deps.iter_mut() {
+                let Some(catalog) = spec.as_str().and_then(|x| x.strip_prefix("catalog:")) else {
+                    continue;
+                };
+                *spec = Value::String(self.lookup(catalog, name)?.to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether any dependency of a package refers to a catalog
+pub fn uses_catalogs(package: &PackageMetadata) -> bool {
+    package
+        .dependencies
+        .values()
+        .chain(package.dev_dependencies.values())
+        .chain(package.optional_dependencies.values())
+        .chain(package.peer_dependencies.values())
+        .chain(package.cotton.groups.values().flat_map(|x| x.values()))
+        .any(|x| catalog_name(x).is_some())
+}
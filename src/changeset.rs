@@ -0,0 +1,342 @@
+use clap::ValueEnum;
+use color_eyre::{
+    eyre::{eyre, ContextCompat, Result},
+    Help,
+};
+use compact_str::CompactString;
+use itertools::Itertools;
+use node_semver::{Range, Version};
+use rand::{distributions::Alphanumeric, Rng};
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, write},
+    process::Command,
+};
+
+use crate::{
+    diff::write_manifest,
+    progress::{log_progress, log_warning},
+    publish::DEPENDENCY_FIELDS,
+    util::read_json,
+    workspace::{sort_workspaces, Workspace},
+    ARGS,
+};
+
+const CHANGESET_DIR: &str = ".changeset";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, ValueEnum)]
+pub enum Bump {
+    Patch,
+    Minor,
+    Major,
+}
+
+impl Bump {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "patch" => Some(Bump::Patch),
+            "minor" => Some(Bump::Minor),
+            "major" => Some(Bump::Major),
+            _ => None,
+        }
+    }
+
+    fn apply(self, v: &Version) -> Version {
+        let (major, minor, patch) = match self {
+            Bump::Major => (v.major + 1, 0, 0),
+            Bump::Minor => (v.major, v.minor + 1, 0),
+            Bump::Patch => (v.major, v.minor, v.patch + 1),
+        };
+
+        Version {
+            major,
+            minor,
+            patch,
+            build: vec![],
+            pre_release: vec![],
+        }
+    }
+
+    fn heading(self) -> &'static str {
+        match self {
+            Bump::Major => "Major Changes",
+            Bump::Minor => "Minor Changes",
+            Bump::Patch => "Patch Changes",
+        }
+    }
+}
+
+impl Display for Bump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bump::Patch => write!(f, "patch"),
+            Bump::Minor => write!(f, "minor"),
+            Bump::Major => write!(f, "major"),
+        }
+    }
+}
+
+/// A pending release intent, stored as a markdown file with the bumps in its front matter
+struct Changeset {
+    path: PathBuf,
+    bumps: Vec<(CompactString, Bump)>,
+    summary: String,
+}
+
+fn parse_changeset(path: PathBuf, content: &str) -> Result<Changeset> {
+    let invalid = || eyre!("Invalid changeset {}", path.display());
+
+    let (front, summary) = content
+        .strip_prefix("---\n")
+        .and_then(|x| x.split_once("\n---"))
+        .wrap_err_with(invalid)?;
+
+    let mut bumps = vec![];
+    for line in front.lines().filter(|x| !x.trim().is_empty()) {
+        let (name, bump) = line.rsplit_once(':').wrap_err_with(invalid)?;
+        let name = name.trim().trim_matches(|c| c == '"' || c == '\'');
+        let bump = Bump::parse(bump.trim()).wrap_err_with(invalid)?;
+        bumps.push((name.into(), bump));
+    }
+
+    Ok(Changeset {
+        summary: summary.trim().to_string(),
+        path,
+        bumps,
+    })
+}
+
+async fn read_changesets() -> Result<Vec<Changeset>> {
+    let mut changesets = vec![];
+
+    let Ok(mut dir) = read_dir(CHANGESET_DIR).await else {
+        return Ok(changesets);
+    };
+
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|x| x == "md") && entry.file_name() != "README.md" {
+            let content = read_to_string(&path).await?;
+            changesets.push(parse_changeset(path, &content)?);
+        }
+    }
+
+    changesets.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changesets)
+}
+
+fn check_packages<'a>(
+    workspaces: &[Workspace],
+    names: impl IntoIterator<Item = &'a CompactString>,
+) -> Result<()> {
+    for name in names {
+        if !workspaces.iter().any(|x| x.name == *name) {
+            return Err(
+                eyre!("`{name}` is not a workspace package").suggestion(format!(
+                    "Workspace packages are: {}",
+                    workspaces.iter().map(|x| &x.name).sorted().join(", ")
+                )),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Record a changeset bumping `packages`, returning its path
+pub async fn add(
+    workspaces: &[Workspace],
+    bump: Bump,
+    packages: &[CompactString],
+    message: &str,
+) -> Result<PathBuf> {
+    check_packages(workspaces, packages)?;
+
+    create_dir_all(CHANGESET_DIR).await?;
+
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    let path = Path::new(CHANGESET_DIR).join(format!("{}.md", id.to_lowercase()));
+
+    let front = packages
+        .iter()
+        .map(|name| format!("\"{name}\": {bump}"))
+        .join("\n");
+    write(&path, format!("---\n{front}\n---\n\n{}\n", message.trim())).await?;
+
+    Ok(path)
+}
+
+fn changelog_entry(version: &Version, notes: &[(Bump, &str)], updated: &[String]) -> String {
+    let mut entry = format!("## {version}\n");
+
+    for bump in [Bump::Major, Bump::Minor, Bump::Patch] {
+        let mut lines = notes
+            .iter()
+            .filter(|(x, _)| *x == bump)
+            .map(|(_, summary)| format!("- {}", summary.replace('\n', "\n  ")))
+            .collect_vec();
+
+        if bump == Bump::Patch && !updated.is_empty() {
+            lines.push(format!(
+                "- Updated dependencies\n{}",
+                updated.iter().map(|x| format!("  - {x}")).join("\n")
+            ));
+        }
+
+        if !lines.is_empty() {
+            entry += &format!("\n### {}\n\n{}\n", bump.heading(), lines.join("\n"));
+        }
+    }
+
+    entry
+}
+
+async fn prepend_changelog(dir: &Path, name: &str, entry: &str) -> Result<()> {
+    let path = dir.join("CHANGELOG.md");
+    let existing = read_to_string(&path).await.unwrap_or_default();
+
+    let header = format!("# {name}\n");
+    let rest = existing.strip_prefix(&header).unwrap_or(&existing).trim();
+
+    write(
+        path,
+        format!("{header}\n{entry}\n{rest}").trim_end().to_string() + "\n",
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Apply all pending changesets, bumping versions, updating dependents and writing changelogs
+pub async fn version(workspaces: Vec<Workspace>) -> Result<Vec<(CompactString, Version)>> {
+    let changesets = read_changesets().await?;
+
+    if changesets.is_empty() {
+        return Err(eyre!("No changesets found")
+            .suggestion("Record one with `cotton changeset add --bump <BUMP> <PACKAGES>...`"));
+    }
+
+    check_packages(
+        &workspaces,
+        changesets
+            .iter()
+            .flat_map(|x| x.bumps.iter().map(|(name, _)| name)),
+    )?;
+
+    let mut bumps: FxHashMap<&str, Bump> = FxHashMap::default();
+    let mut notes: FxHashMap<&str, Vec<(Bump, &str)>> = FxHashMap::default();
+    for changeset in &changesets {
+        for (name, bump) in &changeset.bumps {
+            let max = bumps.entry(name).or_insert(*bump);
+            *max = (*max).max(*bump);
+            notes
+                .entry(name)
+                .or_default()
+                .push((*bump, &changeset.summary));
+        }
+    }
+
+    let mut released: Vec<(CompactString, Version)> = vec![];
+
+    // Dependencies come first, so their new versions are known when their dependents are updated
+    for workspace in sort_workspaces(workspaces) {
+        let manifest_path = workspace.dir.join("package.json");
+        let mut manifest: Value = read_json(&manifest_path).await?;
+        let mut changed = false;
+        let mut updated = vec![];
+
+        for field in DEPENDENCY_FIELDS {
+            let Some(deps) = manifest.get_mut(field).and_then(|x| x.as_object_mut()) else {
+                continue;
+            };
+
+            for (dep, spec) in deps.iter_mut() {
+                let Some((_, version)) = released.iter().find(|(name, _)| name == dep) else {
+                    continue;
+                };
+                let Some(current) = spec.as_str() else {
+                    continue;
+                };
+                if current.starts_with("workspace:")
+                    || Range::parse(current).is_ok_and(|x| x.satisfies(version))
+                {
+                    continue;
+                }
+
+                let prefix = match current.chars().next() {
+                    Some(c @ ('^' | '~')) => c.to_string(),
+                    _ => String::new(),
+                };
+                *spec = Value::String(format!("{prefix}{version}"));
+                changed = true;
+
+                if field != "devDependencies" {
+                    updated.push(format!("{dep}@{version}"));
+                }
+            }
+        }
+
+        let bump = bumps
+            .get(workspace.name.as_str())
+            .copied()
+            .or((!updated.is_empty()).then_some(Bump::Patch));
+
+        if let Some(bump) = bump {
+            let current =
+                workspace.package.version.as_ref().wrap_err_with(|| {
+                    eyre!("Workspace package {} has no version", workspace.name)
+                })?;
+            let next = bump.apply(current);
+
+            manifest["version"] = Value::String(next.to_string());
+            changed = true;
+
+            let notes = notes.remove(workspace.name.as_str()).unwrap_or_default();
+            let entry = changelog_entry(&next, &notes, &updated);
+            if !ARGS.no_write {
+                prepend_changelog(&workspace.dir, &workspace.name, &entry).await?;
+            }
+
+            released.push((workspace.name.clone(), next));
+        }
+
+        if changed {
+            write_manifest(&manifest_path, &manifest).await?;
+        }
+    }
+
+    // Changesets are kept until their versions are actually written
+    if !ARGS.no_write {
+        for changeset in changesets {
+            remove_file(&changeset.path).await?;
+        }
+    }
+
+    Ok(released)
+}
+
+/// Create a git tag for every published release
+pub async fn tag_releases(releases: &[(CompactString, Version)]) -> Result<()> {
+    for (name, version) in releases {
+        let tag = format!("{name}@{version}");
+        let status = Command::new("git").arg("tag").arg(&tag).status().await?;
+
+        if status.success() {
+            log_progress(&format!("Tagged {tag}"));
+        } else {
+            log_warning(&format!("Failed to create git tag {tag}"));
+        }
+    }
+
+    Ok(())
+}
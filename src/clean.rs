@@ -0,0 +1,251 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use indicatif::HumanBytes;
+use owo_colors::OwoColorize;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    fs::{read_dir, remove_dir_all, remove_file, symlink_metadata},
+    io::{stdin, ErrorKind, IsTerminal},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    git::GitSource, plan::store_dir, progress::log_verbose, projects,
+    util::load_graph_from_lockfile,
+};
+
+/// Measures the space freed by removing files, which only happens once every hardlink to a file is removed
+#[derive(Default)]
+struct Usage {
+    links: FxHashMap<(u64, u64), u64>,
+}
+
+impl Usage {
+    fn measure(&mut self, path: &Path) -> u64 {
+        let Ok(metadata) = symlink_metadata(path) else {
+            return 0;
+        };
+
+        if metadata.is_dir() {
+            return read_dir(path)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|x| self.measure(&x.path()))
+                .sum();
+        }
+
+        let links = self
+            .links
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert(metadata.nlink());
+        *links = links.saturating_sub(1);
+
+        if *links == 0 {
+            metadata.len()
+        } else {
+            0
+        }
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let result = if path.is_dir() && !path.is_symlink() {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn report(path: &Path, size: u64, dry_run: bool) {
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    println!("{verb} {} ({})", path.display(), HumanBytes(size).yellow());
+}
+
+/// Directories of a project that `clean` removes
+fn project_dirs(root: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    ["node_modules", ".cotton"]
+        .into_iter()
+        .map(|x| root.join(x))
+        .filter(|x| x.exists())
+}
+
+/// Remove `node_modules` and `.cotton`, reporting the space reclaimed by each
+pub fn clean_all(dry_run: bool) -> Result<u64> {
+    let mut usage = Usage::default();
+    let mut total = 0;
+
+    for dir in project_dirs(Path::new("")) {
+        let size = usage.measure(&dir);
+        if !dry_run {
+            remove(&dir)?;
+        }
+        report(&dir, size, dry_run);
+        total += size;
+    }
+
+    Ok(total)
+}
+
+fn confirm(count: usize) -> Result<bool> {
+    println!("Remove node_modules and .cotton from {count} projects? [y/N]");
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn describe_age(last_install: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    match now.saturating_sub(last_install) / (24 * 60 * 60) {
+        0 => "today".into(),
+        1 => "yesterday".into(),
+        days => format!("{days} days ago"),
+    }
+}
+
+/// Remove `node_modules` and `.cotton` from every project in the registry, after listing them
+pub fn clean_all_projects(dry_run: bool, yes: bool) -> Result<u64> {
+    let registry = projects::load()?;
+
+    let (existing, missing): (Vec<_>, Vec<_>) = registry
+        .projects
+        .iter()
+        .map(|(path, record)| (path.clone(), record.last_install))
+        .partition(|(path, _)| path.join("package.json").exists());
+    if !missing.is_empty() && !dry_run {
+        projects::update(|registry| {
+            for (path, _) in &missing {
+                registry.projects.remove(path);
+            }
+        })?;
+        log_verbose(&format!(
+            "Forgot {} projects that no longer exist",
+            missing.len()
+        ));
+    }
+
+    let mut usage = Usage::default();
+    let mut found = vec![];
+    let mut count = 0;
+    for (root, last_install) in existing {
+        let dirs: Vec<_> = project_dirs(&root)
+            .map(|dir| {
+                let size = usage.measure(&dir);
+                (dir, size)
+            })
+            .collect();
+        if dirs.is_empty() {
+            continue;
+        }
+
+        let size: u64 = dirs.iter().map(|(_, size)| size).sum();
+        println!(
+            "{} ({}, last installed {})",
+            root.display(),
+            HumanBytes(size).yellow(),
+            describe_age(last_install)
+        );
+        found.extend(dirs);
+        count += 1;
+    }
+
+    let total = found.iter().map(|(_, size)| size).sum();
+    if found.is_empty() || dry_run {
+        return Ok(total);
+    }
+
+    let confirmed = if yes {
+        true
+    } else if stdin().is_terminal() {
+        confirm(count)?
+    } else {
+        return Err(
+            eyre!("Cleaning every project requires confirmation").suggestion(
+                "Pass --yes to clean without confirmation, or --dry-run to list the projects",
+            ),
+        );
+    };
+    if !confirmed {
+        return Ok(0);
+    }
+
+    for (dir, size) in &found {
+        remove(dir)?;
+        report(dir, *size, false);
+    }
+
+    Ok(total)
+}
+
+/// Store entries used by the current lockfile, which are never considered stale
+async fn current_entries() -> Result<FxHashSet<String>> {
+    let mut entries = FxHashSet::default();
+
+    for dep in load_graph_from_lockfile()
+        .await?
+        .relations
+        .values()
+        .map(|x| x.dependency())
+    {
+        if let Some((_, commit, _)) = GitSource::parse_locked(&dep.dist.tarball) {
+            entries.insert(format!("git/{commit}"));
+            entries.insert(format!("git/{commit}.complete"));
+        }
+        entries.insert(dep.id());
+    }
+
+    Ok(entries)
+}
+
+/// Remove store entries that are not used by the lockfile and were last modified before `older_than`
+pub async fn clean_stale(older_than: Duration, dry_run: bool) -> Result<u64> {
+    let store = store_dir();
+    let current = current_entries().await?;
+    let cutoff = SystemTime::now() - older_than;
+
+    let mut candidates = vec![];
+    for entry in read_dir(&store).into_iter().flatten().flatten() {
+        if entry.file_name() == "git" {
+            for entry in read_dir(entry.path()).into_iter().flatten().flatten() {
+                let name = format!("git/{}", entry.file_name().to_string_lossy());
+                candidates.push((name, entry.path()));
+            }
+        } else {
+            let name = entry.file_name().to_string_lossy().to_string();
+            candidates.push((name, entry.path()));
+        }
+    }
+    candidates.sort();
+
+    let stale = candidates.into_iter().filter(|(name, path)| {
+        let modified = symlink_metadata(path).and_then(|x| x.modified());
+        !current.contains(name) && matches!(modified, Ok(x) if x < cutoff)
+    });
+
+    let mut usage = Usage::default();
+    let mut total = 0;
+    for (_, path) in stale {
+        let size = usage.measure(&path);
+        if !dry_run {
+            remove(&path)?;
+        }
+        report(&path, size, dry_run);
+        total += size;
+    }
+
+    Ok(total)
+}
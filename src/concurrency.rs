@@ -0,0 +1,151 @@
+use once_cell::sync::Lazy;
+use reqwest::{Response, Url};
+use rustc_hash::FxHashMap;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+use crate::progress::log_verbose;
+
+const INITIAL_LIMIT: f64 = 16.0;
+const MIN_LIMIT: f64 = 1.0;
+const MAX_LIMIT: f64 = 256.0;
+
+/// Responses slower than this multiple of the fastest observed response count as queueing
+const LATENCY_TOLERANCE: u32 = 3;
+
+struct LimitState {
+    limit: f64,
+    in_flight: usize,
+    /// Fastest observed response, slowly forgotten so that the baseline can adapt
+    baseline: Option<Duration>,
+    last_decrease: Option<Instant>,
+}
+
+/// Concurrency limit of requests to one host, adjusted with additive increase and
+/// multiplicative decrease
+struct HostLimit {
+    host: String,
+    state: Mutex<LimitState>,
+    released: Notify,
+}
+
+impl HostLimit {
+    fn release(&self, outcome: Option<(Outcome, Duration)>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+
+        match outcome {
+            Some((Outcome::Success, latency)) => {
+                let baseline = match state.baseline {
+                    Some(x) => x.min(latency).mul_f64(0.95) + latency.mul_f64(0.05),
+                    None => latency,
+                };
+                state.baseline = Some(baseline);
+
+                if latency <= baseline * LATENCY_TOLERANCE {
+                    state.limit = (state.limit + 1.0 / state.limit).min(MAX_LIMIT);
+                }
+            }
+            Some((Outcome::Overloaded, _)) => {
+                // Requests that were already in flight fail together, so back off once per round trip
+                let window = state.baseline.unwrap_or(Duration::from_millis(100));
+                if state.last_decrease.is_none_or(|x| x.elapsed() > window) {
+                    state.limit = (state.limit / 2.0).max(MIN_LIMIT);
+                    state.last_decrease = Some(Instant::now());
+                    log_verbose(&format!(
+                        "Reducing concurrency for {} to {}",
+                        self.host, state.limit as usize
+                    ));
+                }
+            }
+            None => {}
+        }
+
+        drop(state);
+        self.released.notify_waiters();
+    }
+}
+
+static HOSTS: Lazy<Mutex<FxHashMap<String, Arc<HostLimit>>>> = Lazy::new(Default::default);
+
+enum Outcome {
+    Success,
+    Overloaded,
+}
+
+/// Permission to send one request, which adjusts the limit of the host when finished
+pub struct Permit {
+    host: Arc<HostLimit>,
+    start: Instant,
+    outcome: Option<(Outcome, Duration)>,
+}
+
+impl Permit {
+    /// Record whether the host handled the request, or signalled that it is overloaded
+    ///
+    /// The permit may be kept while reading the body, which does not count towards the latency.
+    pub fn finish(&mut self, res: &reqwest::Result<Response>) {
+        let outcome = match res {
+            Ok(res) if res.status().as_u16() == 429 || res.status().is_server_error() => {
+                Outcome::Overloaded
+            }
+            Ok(_) => Outcome::Success,
+            Err(e) if e.is_timeout() || e.is_connect() => Outcome::Overloaded,
+            Err(_) => return,
+        };
+        self.outcome = Some((outcome, self.start.elapsed()));
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.host.release(self.outcome.take());
+    }
+}
+
+/// Wait until another request may be sent to the host of `url`
+pub async fn acquire(url: &str) -> Permit {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|x| x.host_str().map(|x| x.to_string()))
+        .unwrap_or_default();
+
+    let host = HOSTS
+        .lock()
+        .unwrap()
+        .entry(host.clone())
+        .or_insert_with(|| {
+            Arc::new(HostLimit {
+                host,
+                state: Mutex::new(LimitState {
+                    limit: INITIAL_LIMIT,
+                    in_flight: 0,
+                    baseline: None,
+                    last_decrease: None,
+                }),
+                released: Notify::new(),
+            })
+        })
+        .clone();
+
+    loop {
+        let released = host.released.notified();
+        {
+            let mut state = host.state.lock().unwrap();
+            if state.in_flight < state.limit as usize {
+                state.in_flight += 1;
+                break;
+            }
+        }
+        released.await;
+    }
+
+    Permit {
+        host,
+        start: Instant::now(),
+        outcome: None,
+    }
+}
@@ -1,9 +1,17 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, WrapErr};
+use compact_str::CompactString;
 use reqwest::RequestBuilder;
-use serde::{Deserialize, Serialize};
-use std::env;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::BTreeMap, env, fmt::Display, io::ErrorKind, path::Path, time::Duration};
 use tokio::fs::read_to_string;
 
+use crate::{
+    global::cotton_home, metrics::MetricsConfig, pin::ScopePin, transform::TransformConfig,
+    util::VersionSpecifier,
+};
+
+pub use cotton_core::npm::{LatestFallback, PrereleasePolicy};
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -11,6 +19,135 @@ pub struct Config {
     pub registry: Vec<Registry>,
     #[serde(default)]
     pub allow_install_scripts: bool,
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub latest_fallback: LatestFallback,
+    #[serde(default)]
+    pub alias: BTreeMap<String, String>,
+    /// Where `cotton self-update` looks for releases, in the format of the GitHub releases API,
+    /// which is only read from the user's config so that a project cannot choose the executable
+    #[serde(default)]
+    pub self_update_url: Option<String>,
+    /// How long `cotton update` reuses the previous resolution of a range, such as `1h`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub resolution_ttl: Option<Duration>,
+    #[serde(default)]
+    pub transform: Option<TransformConfig>,
+    /// Poll watched files at this interval, such as `2s`, instead of using native file watches
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub watch_poll_interval: Option<Duration>,
+    /// Skip optional dependencies whose name targets another platform, such as `@esbuild/darwin-arm64`
+    #[serde(default)]
+    pub skip_foreign_optional: bool,
+    /// Install required peer dependencies that are not provided by the root, as npm 7+ does
+    #[serde(default)]
+    pub auto_install_peers: bool,
+    /// Run install scripts when their package is first used by `run` or `exec`, instead of during installation
+    #[serde(default)]
+    pub defer_scripts: bool,
+    /// Fail instead of warning when packages do not support the active node version
+    #[serde(default)]
+    pub engine_strict: bool,
+    /// Install packages for this os instead of the current one, such as `linux`
+    #[serde(default)]
+    pub target_os: Option<String>,
+    /// Install packages for this cpu instead of the current one, such as `x64`
+    #[serde(default)]
+    pub target_cpu: Option<String>,
+    /// Split the lockfile into a shard for each workspace, with requirements shared between
+    /// workspaces kept in `cotton.lock`
+    #[serde(default)]
+    pub shard_lockfile: bool,
+    /// Read local and workspace packages again on every install, so that dependencies removed
+    /// from them are pruned from the lockfile
+    #[serde(default)]
+    pub prune_lockfile: bool,
+    /// Additional platforms to install optional platform-specific packages for
+    #[serde(default)]
+    pub supported_architectures: SupportedArchitectures,
+    /// Install scripts approved to run, as commands by package name
+    #[serde(default)]
+    pub approved_scripts: BTreeMap<String, Vec<String>>,
+    /// Registries that packages under a scope must come from
+    #[serde(default)]
+    pub pin: Vec<ScopePin>,
+    /// Whether ranges resolve to prerelease versions that they admit
+    #[serde(default)]
+    pub prerelease: PrereleasePolicy,
+    /// Packages whose ranges resolve to the highest version they admit, even if it is a prerelease
+    #[serde(default)]
+    pub allow_prerelease: Vec<String>,
+    /// Dist-tag that `latest` refers to for packages under a scope, such as `"@mycorp" = "stable"`
+    #[serde(default)]
+    pub default_tag: BTreeMap<String, String>,
+    /// Range operator saved before versions added without one, such as `~`, or `""` for exact
+    /// versions
+    #[serde(default)]
+    pub save_prefix: SavePrefix,
+    /// Save exact versions, as if `--pin` was always passed
+    #[serde(default)]
+    pub save_exact: bool,
+    /// Versions that `catalog:` specifiers in workspace packages refer to, by package name
+    #[serde(default)]
+    pub catalog: BTreeMap<CompactString, VersionSpecifier>,
+    /// Catalogs that `catalog:<name>` specifiers refer to
+    #[serde(default)]
+    pub catalogs: BTreeMap<CompactString, BTreeMap<CompactString, VersionSpecifier>>,
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|x| humantime::parse_duration(&x).map_err(D::Error::custom))
+        .transpose()
+}
+
+fn serialize_duration<S: Serializer>(x: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    x.map(|x| humantime::format_duration(x).to_string())
+        .serialize(s)
+}
+
+/// Platforms to install packages for, where `current` refers to the target platform and an
+/// empty list only includes it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SupportedArchitectures {
+    #[serde(default)]
+    pub os: Vec<String>,
+    #[serde(default)]
+    pub cpu: Vec<String>,
+    #[serde(default)]
+    pub libc: Vec<String>,
+}
+
+/// Range operator saved before resolved versions by `add` and `upgrade`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum SavePrefix {
+    #[default]
+    #[serde(rename = "^")]
+    Caret,
+    #[serde(rename = "~")]
+    Tilde,
+    #[serde(rename = "")]
+    Exact,
+}
+
+impl Display for SavePrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SavePrefix::Caret => write!(f, "^"),
+            SavePrefix::Tilde => write!(f, "~"),
+            SavePrefix::Exact => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
@@ -36,6 +173,25 @@ pub enum RegistryAuth {
     },
 }
 
+impl Registry {
+    pub fn npm() -> Self {
+        Self {
+            url: "https://registry.npmjs.org".into(),
+            scope: None,
+            auth: None,
+        }
+    }
+}
+
+impl Display for RegistryAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryAuth::Token { token } => write!(f, "token {token}"),
+            RegistryAuth::Basic { username, .. } => write!(f, "basic {username}"),
+        }
+    }
+}
+
 pub fn client_auth(req: RequestBuilder, auth: Option<&RegistryAuth>) -> Result<RequestBuilder> {
     Ok(match auth {
         Some(RegistryAuth::Token { token }) => {
@@ -60,6 +216,15 @@ pub enum AuthSource {
     FromEnv { from_env: String },
 }
 
+impl Display for AuthSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthSource::Inline(_) => write!(f, "(inline)"),
+            AuthSource::FromEnv { from_env } => write!(f, "from ${from_env}"),
+        }
+    }
+}
+
 impl AuthSource {
     #[tracing::instrument]
     pub fn read_token(&self) -> Result<String> {
@@ -71,7 +236,34 @@ impl AuthSource {
 }
 
 pub async fn read_config() -> Result<Config> {
-    let config = read_to_string("cotton.toml").await;
+    read_config_in(Path::new("")).await
+}
+
+/// The config of the project in `dir`
+pub async fn read_config_in(dir: &Path) -> Result<Config> {
+    let config = read_to_string(dir.join("cotton.toml")).await;
+    if let Ok(config) = config {
+        Ok(toml::from_str(&config)?)
+    } else {
+        Ok(Config::default())
+    }
+}
+
+/// The config in cotton's home directory, for settings that a project must not control
+pub async fn read_user_config() -> Result<Config> {
+    let path = cotton_home()?.join("cotton.toml");
+    match read_to_string(&path).await {
+        Ok(config) => {
+            toml::from_str(&config).wrap_err_with(|| format!("Failed to read {}", path.display()))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the config before the async runtime is available, such as while parsing arguments
+pub fn read_config_sync() -> Result<Config> {
+    let config = std::fs::read_to_string("cotton.toml");
     if let Ok(config) = config {
         Ok(toml::from_str(&config)?)
     } else {
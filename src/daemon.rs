@@ -0,0 +1,83 @@
+use color_eyre::eyre::Result;
+use futures::future::try_join_all;
+use owo_colors::OwoColorize;
+use std::path::Path;
+
+use crate::{
+    init_storage,
+    npm::{Dependency, DependencyTree},
+    plan::download_package_shared,
+    plan_graph, prepare_graph,
+    progress::{log_warning, PROGRESS_BAR},
+    util::{hash_hex, lockfile_contents, lockfile_paths, manifest_path, read_input, read_package},
+    watch::async_watch,
+};
+
+fn collect_deps(tree: &DependencyTree, deps: &mut Vec<Dependency>) {
+    deps.push(tree.root.clone());
+    for child in tree.children.values() {
+        collect_deps(child, deps);
+    }
+}
+
+/// Resolve the project and download every package in its plan to the store
+async fn prefetch() -> Result<usize> {
+    let package = read_package().await?;
+
+    init_storage().await?;
+
+    let graph = prepare_graph(&package).await?;
+    let plan = plan_graph(&package, &graph)?;
+
+    let mut deps = vec![];
+    for tree in plan.trees.values() {
+        collect_deps(tree, &mut deps);
+    }
+
+    try_join_all(deps.iter().cloned().map(download_package_shared)).await?;
+
+    Ok(deps.len())
+}
+
+async fn input_hashes() -> (String, String) {
+    (
+        hash_hex(read_input(&manifest_path()).await.unwrap_or_default()),
+        hash_hex(lockfile_contents().await),
+    )
+}
+
+/// Watch `package.json` and the lockfile, preparing the store whenever they change
+pub async fn daemon() -> Result<()> {
+    let mut last = None;
+
+    loop {
+        let current = input_hashes().await;
+
+        // Changes to the lockfile made by `prefetch` itself are skipped here
+        if last.as_ref() != Some(&current) {
+            match prefetch().await {
+                Ok(count) => PROGRESS_BAR.suspend(|| {
+                    println!(
+                        "{} Prepared {} packages",
+                        " DAEMON ".on_blue(),
+                        count.yellow()
+                    )
+                }),
+                Err(e) => log_warning(&format!("Failed to prepare packages: {e}")),
+            }
+            PROGRESS_BAR.finish_and_clear();
+
+            last = Some(input_hashes().await);
+        }
+
+        let mut paths = lockfile_paths().await;
+        paths.push(manifest_path());
+        async_watch(
+            paths
+                .iter()
+                .map(|x| x.as_path())
+                .filter(|x| x.exists() && *x != Path::new("-")),
+        )
+        .await?;
+    }
+}
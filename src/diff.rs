@@ -0,0 +1,86 @@
+use color_eyre::eyre::Result;
+use owo_colors::OwoColorize;
+use serde_json::Value;
+use std::path::Path;
+use tokio::fs::read_to_string;
+
+use crate::{progress::PROGRESS_BAR, util::write_json, ARGS};
+
+enum Line<'a> {
+    Same,
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Changed lines between two texts, from their longest common subsequence of lines
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lengths[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines
+}
+
+/// Print the lines of a file that a change would modify
+pub fn print_diff(path: &Path, old: &str, new: &str) {
+    let lines = diff_lines(old, new);
+    if lines.iter().all(|x| matches!(x, Line::Same)) {
+        return;
+    }
+
+    PROGRESS_BAR.suspend(|| {
+        println!("{}", path.display().bold());
+        for line in lines {
+            match line {
+                Line::Same => {}
+                Line::Removed(x) => println!("{}", format!("-{x}").red()),
+                Line::Added(x) => println!("{}", format!("+{x}").green()),
+            }
+        }
+    });
+}
+
+/// Write a modified manifest, printing the changed lines. With `--no-write`, the changes are only
+/// printed.
+pub async fn write_manifest(path: &Path, manifest: &Value) -> Result<()> {
+    // Manifests that are created or were empty have nothing to compare against
+    if let Ok(old) = read_to_string(path).await {
+        if serde_json::from_str::<Value>(&old).is_ok_and(|x| x != Value::Object(Default::default()))
+        {
+            print_diff(path, &old, &serde_json::to_string_pretty(manifest)?);
+        }
+    }
+
+    if ARGS.no_write {
+        return Ok(());
+    }
+
+    write_json(path, manifest).await
+}
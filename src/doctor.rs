@@ -0,0 +1,142 @@
+use color_eyre::eyre::Result;
+use compact_str::CompactString;
+use nix::errno::Errno;
+use owo_colors::OwoColorize;
+use std::{
+    fmt::Display,
+    fs::{metadata, read_dir, read_link},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    global::dangling_shims,
+    npm::DependencyTree,
+    plan::{install_path, Plan},
+    scoped_path::scoped_join,
+};
+
+enum PathProblem {
+    Loop,
+    Dangling(PathBuf),
+    Unresolvable(String),
+}
+
+impl Display for PathProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathProblem::Loop => write!(f, "symlink loop"),
+            PathProblem::Dangling(target) => {
+                write!(f, "symlink to missing {}", target.display())
+            }
+            PathProblem::Unresolvable(e) => {
+                write!(f, "cannot be resolved inside node_modules: {e}")
+            }
+        }
+    }
+}
+
+fn check_link(path: &Path) -> Option<PathProblem> {
+    if let Err(e) = metadata(path) {
+        if e.raw_os_error() == Some(Errno::ELOOP as i32) {
+            return Some(PathProblem::Loop);
+        }
+        if e.kind() == ErrorKind::NotFound {
+            return Some(PathProblem::Dangling(read_link(path).unwrap_or_default()));
+        }
+    }
+
+    let relative = path.strip_prefix("node_modules").ok()?;
+    scoped_join("node_modules", relative)
+        .err()
+        .map(|e| PathProblem::Unresolvable(e.to_string()))
+}
+
+fn walk(dir: &Path, found: &mut Vec<(PathBuf, PathProblem)>) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        if ty.is_symlink() {
+            if let Some(problem) = check_link(&entry.path()) {
+                found.push((entry.path(), problem));
+            }
+        } else if ty.is_dir() {
+            walk(&entry.path(), found)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_planned(
+    tree: &DependencyTree,
+    prefix: &mut Vec<CompactString>,
+    found: &mut Vec<(PathBuf, PathProblem)>,
+) {
+    if let Err(e) = install_path(prefix, &tree.root) {
+        let path = prefix
+            .iter()
+            .chain([&tree.root.name])
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>()
+            .join("/node_modules/");
+        found.push((
+            Path::new("node_modules").join(path),
+            PathProblem::Unresolvable(e.root_cause().to_string()),
+        ));
+    }
+
+    prefix.push(tree.root.name.clone());
+    for child in tree.children.values() {
+        check_planned(child, prefix, found);
+    }
+    prefix.pop();
+}
+
+/// Report links in the global bin directory to bins that were removed, or that belong to a deleted
+/// global directory, returning the number of problems
+pub fn check_global() -> Result<usize> {
+    let found = dangling_shims()?;
+
+    for shim in &found {
+        let origin = if shim.managed {
+            ""
+        } else {
+            ", which was not linked by cotton"
+        };
+        println!(
+            "{} {}: link to missing {}{origin}",
+            " GLOBAL ".on_red(),
+            shim.path.display(),
+            shim.target.display()
+        );
+    }
+    if found.iter().any(|x| x.managed) {
+        println!("Leftover links are removed by the next `cotton add --global` or `cotton remove --global`");
+    }
+
+    Ok(found.len())
+}
+
+/// Report symlinks in `node_modules` that are broken, loop, or cannot be resolved when installing,
+/// returning the number of problems
+pub fn check_paths(plan: Option<&Plan>) -> Result<usize> {
+    let mut found = vec![];
+
+    if Path::new("node_modules").exists() {
+        walk(Path::new("node_modules"), &mut found)?;
+    }
+    if let Some(plan) = plan {
+        for tree in plan.trees.values() {
+            check_planned(tree, &mut vec![], &mut found);
+        }
+    }
+
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    found.dedup_by(|a, b| a.0 == b.0);
+    for (path, problem) in &found {
+        println!("{} {}: {}", " PATH ".on_red(), path.display(), problem);
+    }
+
+    Ok(found.len())
+}
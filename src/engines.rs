@@ -0,0 +1,77 @@
+use compact_str::CompactString;
+use node_semver::{Range, Version};
+use std::fmt::Display;
+use tokio::process::Command;
+
+use crate::{package::PackageMetadata, resolve::Graph, util::supported_platforms};
+
+/// A package whose `engines.node` range does not include the active node version
+pub struct EngineProblem {
+    pub package: CompactString,
+    pub range: CompactString,
+    pub node: Version,
+}
+
+impl Display for EngineProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requires node {}, but {} is active",
+            self.package, self.range, self.node
+        )
+    }
+}
+
+/// Version of the `node` found in `PATH`
+async fn node_version() -> Option<Version> {
+    let output = Command::new("node").arg("--version").output().await.ok()?;
+    let version = String::from_utf8(output.stdout).ok()?;
+    Version::parse(version.trim().trim_start_matches('v')).ok()
+}
+
+/// Find the root package and resolved dependencies that do not support the active node version
+pub async fn check_engines(root: &PackageMetadata, graph: &Graph) -> Vec<EngineProblem> {
+    let mut required: Vec<(CompactString, CompactString)> = graph
+        .relations
+        .values()
+        .filter(|x| {
+            let rule = root.rule(&x.package.name);
+            !rule.ignore_engines
+                && (rule.ignore_platform || x.package.supported(supported_platforms()))
+        })
+        .filter_map(|x| {
+            let range = x.package.engines.get("node")?;
+            Some((
+                format!("{}@{}", x.package.name, x.version).into(),
+                range.clone(),
+            ))
+        })
+        .collect();
+    if let Some(range) = root.engines.get("node").and_then(|x| x.as_str()) {
+        required.push(("package.json".into(), range.into()));
+    }
+
+    if required.is_empty() {
+        return vec![];
+    }
+
+    let Some(node) = node_version().await else {
+        return vec![];
+    };
+
+    let mut problems: Vec<_> = required
+        .into_iter()
+        .filter(|(_, range)| {
+            // Ranges that cannot be parsed are ignored, as npm does
+            Range::parse(range).is_ok_and(|x| !x.satisfies(&node))
+        })
+        .map(|(package, range)| EngineProblem {
+            package,
+            range,
+            node: node.clone(),
+        })
+        .collect();
+    problems.sort_by(|a, b| a.package.cmp(&b.package));
+    problems.dedup_by(|a, b| a.package == b.package);
+    problems
+}
@@ -0,0 +1,202 @@
+use compact_str::CompactString;
+use node_semver::Version;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::{
+    npm::{DependencyTree, PlatformMap},
+    package::{PackageInfo, PackageMetadata, PackageSpecifier},
+    plan::Plan,
+    resolve::Graph,
+    util::VersionSpecifier,
+};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmLockfile {
+    pub name: CompactString,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    pub lockfile_version: u32,
+    pub requires: bool,
+    pub packages: BTreeMap<String, NpmLockEntry>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmLockEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<CompactString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<CompactString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<CompactString>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dev: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dev_optional: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub peer: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dev_dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub optional_dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub bin: BTreeMap<CompactString, CompactString>,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub os: PlatformMap,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub cpu: PlatformMap,
+    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
+    pub libc: PlatformMap,
+}
+
+/// How a package is reached from the root, which decides npm's `dev`, `optional`, `devOptional`
+/// and `peer` flags
+#[derive(Default, Clone, Copy)]
+struct Reach {
+    /// Reached without going through `devDependencies`
+    prod: bool,
+    /// Reached without going through `optionalDependencies`
+    required: bool,
+    /// Reached through neither
+    prod_required: bool,
+    /// Required by some package other than as a peer
+    non_peer: bool,
+}
+
+impl Reach {
+    fn apply(self, entry: &mut NpmLockEntry) {
+        entry.dev = !self.prod;
+        entry.optional = !self.required;
+        entry.dev_optional = self.prod && self.required && !self.prod_required;
+        entry.peer = !self.non_peer;
+    }
+}
+
+fn reach(package: &PackageMetadata, graph: &Graph) -> FxHashMap<(CompactString, Version), Reach> {
+    let mut reach = FxHashMap::<_, Reach>::default();
+    let mut seen = FxHashSet::default();
+
+    let mut queue = package
+        .iter_all()
+        .map(|req| {
+            let dev = !package.dependencies.contains_key(&req.name) && !req.optional;
+            let optional = req.optional;
+            (req, dev, optional, false)
+        })
+        .collect::<VecDeque<(PackageSpecifier, bool, bool, bool)>>();
+
+    while let Some((req, dev, optional, peer)) = queue.pop_front() {
+        let Ok(resolved) = graph.resolve_req(&req) else {
+            continue;
+        };
+        let key = (resolved.package.name.clone(), resolved.version);
+
+        let entry = reach.entry(key.clone()).or_default();
+        entry.prod |= !dev;
+        entry.required |= !optional;
+        entry.prod_required |= !dev && !optional;
+        entry.non_peer |= !peer;
+
+        if !seen.insert((key, dev, optional)) {
+            continue;
+        }
+
+        for child in resolved.package.iter() {
+            let peer = resolved.package.peer_dependencies.contains_key(&child.name);
+            let optional = optional || child.optional;
+            queue.push_back((child, dev, optional, peer));
+        }
+    }
+
+    reach
+}
+
+fn add_tree(
+    packages: &mut BTreeMap<String, NpmLockEntry>,
+    infos: &FxHashMap<(CompactString, Version), Arc<PackageInfo>>,
+    reach: &FxHashMap<(CompactString, Version), Reach>,
+    tree: &DependencyTree,
+    prefix: &str,
+) {
+    let path = format!("{prefix}node_modules/{}", tree.root.name);
+
+    let mut entry = NpmLockEntry {
+        version: Some(tree.root.version.clone()),
+        resolved: Some(tree.root.dist.tarball.clone()),
+        integrity: tree.root.dist.integrity.clone(),
+        bin: tree.root.bins.clone(),
+        ..Default::default()
+    };
+
+    let key = (tree.root.name.clone(), tree.root.version.clone());
+
+    if let Some(reach) = reach.get(&key) {
+        reach.apply(&mut entry);
+    }
+
+    if let Some(info) = infos.get(&key) {
+        entry.dependencies = info.dependencies.clone();
+        entry.optional_dependencies = info.optional_dependencies.clone();
+        entry.os = info.os.clone();
+        entry.cpu = info.cpu.clone();
+        entry.libc = info.libc.clone();
+    }
+
+    packages.insert(path.clone(), entry);
+
+    for child in tree.children.values() {
+        add_tree(packages, infos, reach, child, &format!("{path}/"));
+    }
+}
+
+pub fn npm_lockfile(package: &PackageMetadata, graph: &Graph, plan: &Plan) -> NpmLockfile {
+    let infos = graph
+        .relations
+        .values()
+        .map(|x| {
+            (
+                (x.package.name.clone(), x.version.clone()),
+                x.package.clone(),
+            )
+        })
+        .collect();
+
+    let reach = reach(package, graph);
+    let mut packages = BTreeMap::new();
+
+    packages.insert(
+        String::new(),
+        NpmLockEntry {
+            name: Some(package.name.clone()),
+            version: package.version.clone(),
+            dependencies: package.dependencies.clone(),
+            dev_dependencies: package.dev_dependencies.clone().into_iter().collect(),
+            optional_dependencies: package.optional_dependencies.clone(),
+            ..Default::default()
+        },
+    );
+
+    for tree in plan.trees.values() {
+        add_tree(&mut packages, &infos, &reach, tree, "");
+    }
+
+    NpmLockfile {
+        name: package.name.clone(),
+        version: package.version.clone(),
+        lockfile_version: 3,
+        requires: true,
+        packages,
+    }
+}
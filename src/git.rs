@@ -0,0 +1,431 @@
+use color_eyre::eyre::{eyre, Result};
+use compact_str::ToCompactString;
+use cotton_core::spec::VersionSpecifierPrefixed;
+use node_semver::{Identifier, Range, Version};
+use reqwest::Url;
+use rustc_hash::FxHashMap;
+use std::{
+    env::current_exe,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, write},
+    process::Command,
+};
+
+use crate::{
+    config::read_config,
+    local::{is_tarball, join_lexically},
+    npm::fetch_tarball_manifest,
+    package::{PackageInfo, PackageMetadata},
+    plan::store_dir,
+    progress::{log_progress, log_verbose, log_warning},
+    scoped_path::scoped_join,
+    util::{check_network, read_json, VersionSpecifier},
+};
+
+/// Written to the completion marker of a checkout whose `prepare` script was not run
+const UNPREPARED: &str = "unprepared";
+
+/// A git repository, an optional branch, tag or commit, and an optional subdirectory containing
+/// the package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSource {
+    pub url: String,
+    pub committish: Option<String>,
+    pub path: Option<String>,
+}
+
+impl GitSource {
+    /// Recognize `git+https://`, `git+ssh://` and `git://` specifiers, optionally followed by
+    /// `#<committish>` and `::path:<subdirectory>` as with npm
+    pub fn parse(spec: &VersionSpecifier) -> Option<Self> {
+        let VersionSpecifier::Prefixed(prefixed) = spec else {
+            return None;
+        };
+
+        let protocol = match prefixed.prefix.as_str() {
+            "git" => "git",
+            x => x.strip_prefix("git+")?,
+        };
+        let url = format!("{protocol}:{}", prefixed.rest);
+        let (url, fragment) = url.split_once('#').unwrap_or((url.as_str(), ""));
+        let (committish, path) = split_path(fragment);
+
+        Some(Self {
+            url: url.to_string(),
+            committish: Some(committish.to_string()).filter(|x| !x.is_empty()),
+            path: path.map(|x| x.to_string()),
+        })
+    }
+
+    /// Recognize a locked tarball URL created by `locked_url`, returning the repository, commit
+    /// and subdirectory
+    pub fn parse_locked(tarball: &str) -> Option<(&str, &str, Option<&str>)> {
+        let (url, fragment) = tarball.strip_prefix("git+")?.rsplit_once('#')?;
+        let (commit, path) = split_path(fragment);
+        Some((url, commit, path))
+    }
+
+    fn locked_url(&self, commit: &str) -> String {
+        format!("git+{}#{}", self.url, self.fragment(commit))
+    }
+
+    fn fragment(&self, commit: &str) -> String {
+        match &self.path {
+            Some(path) => format!("{commit}::path:{path}"),
+            None => commit.to_string(),
+        }
+    }
+
+    /// Rewrite `spec` to refer to this repository at `commit`
+    fn write_spec(&self, commit: &str, spec: &mut VersionSpecifierPrefixed) {
+        let (protocol, rest) = self
+            .url
+            .split_once(':')
+            .unwrap_or(("git", self.url.as_str()));
+        spec.prefix = match protocol {
+            "git" => "git".to_compact_string(),
+            x => format!("git+{x}").to_compact_string(),
+        };
+        spec.rest = format!("{rest}#{}", self.fragment(commit)).to_compact_string();
+    }
+}
+
+/// Split `<committish>::path:<subdirectory>` into its parts
+fn split_path(fragment: &str) -> (&str, Option<&str>) {
+    match fragment.split_once("::path:") {
+        Some((committish, path)) => (committish, Some(path)),
+        None => (fragment, None),
+    }
+}
+
+/// The directory of the package inside a checkout
+pub fn package_dir(checkout: &Path, path: Option<&str>) -> Result<PathBuf> {
+    match path {
+        Some(path) => Ok(scoped_join(checkout, path)?),
+        None => Ok(checkout.to_path_buf()),
+    }
+}
+
+/// Rewrite the relative `file:` and `link:` dependencies of a package from `source` into
+/// subdirectories of the same commit, so that they are checked out again on another machine
+fn rebase_git_dependencies(
+    package: &mut PackageMetadata,
+    source: &GitSource,
+    commit: &str,
+) -> Result<()> {
+    let base = Path::new(source.path.as_deref().unwrap_or(""));
+
+    for (name, spec) in package
+        .dependencies
+        .iter_mut()
+        .chain(package.optional_dependencies.iter_mut())
+    {
+        let VersionSpecifier::Prefixed(prefixed) = spec else {
+            continue;
+        };
+        if !matches!(prefixed.prefix.as_str(), "file" | "link") {
+            continue;
+        }
+
+        let path = join_lexically(base, &prefixed.rest);
+        if is_tarball(&prefixed.rest)
+            || !path
+                .components()
+                .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(eyre!(
+                "Dependency {name} of {} refers to {}, which is not a directory inside the repository",
+                package.name,
+                prefixed
+            ));
+        }
+
+        let nested = GitSource {
+            url: source.url.clone(),
+            committish: Some(commit.to_string()),
+            path: Some(path.to_string_lossy().to_string()).filter(|x| x != "."),
+        };
+        nested.write_spec(commit, prefixed);
+    }
+
+    Ok(())
+}
+
+fn is_commit(x: &str) -> bool {
+    x.len() == 40 && x.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Reject repositories that git would read as an option, such as `--upload-pack=<command>`
+fn check_url(url: &str) -> Result<()> {
+    if url.starts_with('-') {
+        return Err(eyre!("Invalid git repository URL: {url}"));
+    }
+    Ok(())
+}
+
+pub async fn git(args: &[&str], dir: Option<&Path>) -> Result<String> {
+    if matches!(args.first(), Some(&"ls-remote" | &"clone" | &"fetch")) {
+        check_network(&format!("`git {}`", args.join(" ")))?;
+    }
+
+    let mut command = Command::new("git");
+    command.args(args);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    let output = command.output().await?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Find the commit that a branch or tag refers to
+async fn resolve_commit(source: &GitSource) -> Result<String> {
+    let committish = source.committish.as_deref().unwrap_or("HEAD");
+
+    if is_commit(committish) {
+        return Ok(committish.to_string());
+    }
+
+    check_url(&source.url)?;
+    let refs = git(&["ls-remote", "--", &source.url, committish], None).await?;
+    let refs: Vec<_> = refs
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect();
+
+    // An annotated tag is listed as the tag object, followed by the commit as a peeled `^{}` entry
+    let candidates = [
+        format!("refs/tags/{committish}^{{}}"),
+        format!("refs/tags/{committish}"),
+        format!("refs/heads/{committish}"),
+        committish.to_string(),
+    ];
+
+    match candidates
+        .iter()
+        .find_map(|candidate| refs.iter().find(|(_, name)| *name == candidate.as_str()))
+    {
+        Some((commit, _)) => Ok(commit.to_string()),
+        None if committish.chars().all(|c| c.is_ascii_hexdigit()) => Err(eyre!(
+            "{} has no ref named `{committish}`; abbreviated commits are not supported, use the full commit hash",
+            source.url
+        )),
+        None => Err(eyre!("{} has no ref named `{committish}`", source.url)),
+    }
+}
+
+async fn run_prepare(dir: &Path) -> Result<()> {
+    let exe = current_exe()?;
+
+    for args in [&["install"][..], &["run", "prepare"]] {
+        let status = Command::new(&exe)
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(eyre!(
+                "`cotton {}` failed in {} ({status})",
+                args.join(" "),
+                dir.display()
+            ));
+        }
+    }
+
+    // Only the prepared files belong to the package, not what was installed to prepare it
+    remove_dir_all(dir.join("node_modules")).await?;
+    let _ = remove_dir_all(dir.join(".cotton")).await;
+    let _ = remove_file(dir.join("cotton.lock")).await;
+
+    Ok(())
+}
+
+/// Check out a commit into the store, running its `prepare` script if present and
+/// `allow_scripts` is set
+pub async fn checkout(url: &str, commit: &str, allow_scripts: bool) -> Result<PathBuf> {
+    // Both come from the lockfile, so they may not escape the store or be read as options
+    if !is_commit(commit) {
+        return Err(eyre!("Invalid git commit for {url}: {commit}"));
+    }
+    check_url(url)?;
+
+    let root = store_dir().join("git");
+    create_dir_all(&root).await?;
+    let dir = scoped_join(&root, commit)?;
+    let complete = scoped_join(&root, format!("{commit}.complete"))?;
+
+    match read_to_string(&complete).await {
+        Ok(marker) if marker != UNPREPARED || !allow_scripts => {
+            log_verbose(&format!("Using cached checkout of {url}#{commit}"));
+            return Ok(dir);
+        }
+        _ => {}
+    }
+
+    let _ = remove_dir_all(&dir).await;
+
+    let path = dir.to_string_lossy();
+    git(&["clone", "--quiet", "--", url, &path], None).await?;
+    git(&["checkout", "--quiet", commit], Some(&dir)).await?;
+    remove_dir_all(dir.join(".git")).await?;
+
+    let package: PackageMetadata = read_json(dir.join("package.json")).await?;
+    let mut marker = "";
+    if package.scripts.contains_key("prepare") {
+        if allow_scripts {
+            log_progress(&format!("Preparing {url}#{commit}"));
+            run_prepare(&dir).await?;
+        } else {
+            log_warning(&format!(
+                "Not running the prepare script of {url}#{commit}, since install scripts are not allowed"
+            ));
+            marker = UNPREPARED;
+        }
+    }
+
+    write(&complete, marker).await?;
+
+    Ok(dir)
+}
+
+/// Resolve a git dependency to a commit, locking it in the tarball URL
+pub async fn fetch_git_package(source: &GitSource) -> Result<(Version, Arc<PackageInfo>)> {
+    let commit = resolve_commit(source).await?;
+    let allow_scripts = read_config().await?.allow_install_scripts;
+    let checkout = checkout(&source.url, &commit, allow_scripts).await?;
+    let dir = package_dir(&checkout, source.path.as_deref())?;
+
+    let mut package: PackageMetadata = read_json(dir.join("package.json")).await?;
+    rebase_git_dependencies(&mut package, source, &commit)?;
+    let mut version = package
+        .version
+        .clone()
+        .ok_or_else(|| eyre!("Package from {} does not specify a version", source.url))?;
+
+    // Different commits may share a version, so keep them apart in the store
+    version.build = vec![Identifier::AlphaNumeric(format!("git{}", &commit[..12]))];
+
+    package.dist.tarball = source.locked_url(&commit).to_compact_string();
+
+    Ok((version, Arc::new(package.info())))
+}
+
+/// A GitHub repository, downloaded as a tarball from codeload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitHubSource {
+    pub owner: String,
+    pub repo: String,
+    pub committish: Option<String>,
+}
+
+impl GitHubSource {
+    /// Recognize `owner/repo`, `owner/repo#ref` and `github:owner/repo#semver:<range>` specifiers
+    pub fn parse(spec: &VersionSpecifier) -> Option<Self> {
+        let spec = match spec {
+            VersionSpecifier::Prefixed(prefixed) if prefixed.prefix == "github" => &prefixed.rest,
+            VersionSpecifier::Other(other) => other,
+            _ => return None,
+        };
+
+        let (repo, committish) = match spec.split_once('#') {
+            Some((repo, committish)) => (repo, Some(committish.to_string())),
+            None => (spec.as_str(), None),
+        };
+        let (owner, repo) = repo.split_once('/')?;
+        if owner.is_empty() || repo.is_empty() || repo.contains('/') || owner.contains(':') {
+            return None;
+        }
+
+        Some(Self {
+            owner: owner.to_string(),
+            repo: repo.trim_end_matches(".git").to_string(),
+            committish,
+        })
+    }
+
+    fn git(&self) -> GitSource {
+        GitSource {
+            url: format!("https://github.com/{}/{}.git", self.owner, self.repo),
+            committish: self.committish.clone(),
+            path: None,
+        }
+    }
+
+    pub fn tarball_url(&self, commit: &str) -> String {
+        format!(
+            "https://codeload.github.com/{}/{}/tar.gz/{commit}",
+            self.owner, self.repo
+        )
+    }
+}
+
+/// Find the commit of the highest tag satisfying `range`
+async fn resolve_semver_tag(url: &str, range: &Range) -> Result<String> {
+    check_url(url)?;
+    let refs = git(&["ls-remote", "--tags", "--", url], None).await?;
+
+    // Annotated tags are listed twice, and the peeled `^{}` entry refers to the commit
+    let mut tags: FxHashMap<Version, &str> = FxHashMap::default();
+    for (commit, name) in refs.lines().filter_map(|line| line.split_once('\t')) {
+        let Some(tag) = name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        let peeled = tag.ends_with("^{}");
+        let Ok(version) = Version::parse(tag.trim_end_matches("^{}").trim_start_matches('v'))
+        else {
+            continue;
+        };
+        if peeled || !tags.contains_key(&version) {
+            tags.insert(version, commit);
+        }
+    }
+
+    tags.into_iter()
+        .filter(|(version, _)| range.satisfies(version))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, commit)| commit.to_string())
+        .ok_or_else(|| eyre!("No tag of {url} satisfies {range}"))
+}
+
+/// Find the commit that a GitHub branch, tag, commit or `semver:` range refers to
+pub async fn resolve_github_commit(source: &GitHubSource) -> Result<String> {
+    let git = source.git();
+
+    match source
+        .committish
+        .as_deref()
+        .and_then(|x| x.strip_prefix("semver:"))
+    {
+        Some(range) => resolve_semver_tag(&git.url, &Range::parse(range)?).await,
+        None => resolve_commit(&git).await,
+    }
+}
+
+/// Resolve a GitHub dependency to a commit, locking it in a codeload tarball URL
+pub async fn fetch_github_package(source: &GitHubSource) -> Result<(Version, Arc<PackageInfo>)> {
+    let commit = resolve_github_commit(source).await?;
+
+    let url = Url::parse(&source.tarball_url(&commit))?;
+    let mut package = fetch_tarball_manifest(&url).await?;
+    let mut version = package
+        .version
+        .clone()
+        .ok_or_else(|| eyre!("Package from {url} does not specify a version"))?;
+
+    version.build = vec![Identifier::AlphaNumeric(format!("git{}", &commit[..12]))];
+
+    package.dist.tarball = url.to_compact_string();
+
+    Ok((version, Arc::new(package.info())))
+}
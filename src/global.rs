@@ -0,0 +1,136 @@
+use color_eyre::eyre::{ContextCompat, Result};
+use compact_str::CompactString;
+use serde_json::{Map, Value};
+use std::{
+    env::{self, set_current_dir},
+    fs::{read_dir, read_link, remove_file},
+    io::ErrorKind,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+use tokio::fs::{create_dir_all, metadata};
+
+use crate::{bins::normalize_bins, progress::log_verbose, read_plan, util::save_package};
+
+/// Root of per-user state, `$COTTON_HOME` or `~/.cotton`
+pub fn cotton_home() -> Result<PathBuf> {
+    if let Some(home) = env::var_os("COTTON_HOME") {
+        return Ok(home.into());
+    }
+
+    Ok(PathBuf::from(env::var_os("HOME").wrap_err("HOME is not set")?).join(".cotton"))
+}
+
+pub fn global_dir() -> Result<PathBuf> {
+    Ok(cotton_home()?.join("global"))
+}
+
+pub fn global_bin_dir() -> Result<PathBuf> {
+    Ok(cotton_home()?.join("bin"))
+}
+
+/// Switch into the global prefix, which is managed like a regular project
+pub async fn enter_global() -> Result<PathBuf> {
+    let dir = global_dir()?;
+    create_dir_all(&dir).await?;
+    set_current_dir(&dir)?;
+    log_verbose(&format!("Now in {dir:?}"));
+
+    if metadata("package.json").await.is_err() {
+        save_package(&Value::Object(Map::new())).await?;
+    }
+
+    Ok(dir)
+}
+
+/// A link in the global bin directory whose target no longer exists
+pub struct DanglingShim {
+    pub path: PathBuf,
+    pub target: PathBuf,
+    /// Whether the link was created by cotton, possibly for a global directory that has since been deleted
+    pub managed: bool,
+}
+
+/// Whether a link target is a bin of a cotton global directory
+fn is_managed(target: &Path, global_dir: &Path) -> bool {
+    target.starts_with(global_dir)
+        || target
+            .parent()
+            .is_some_and(|x| x.ends_with("node_modules/.bin"))
+}
+
+/// Find links in the global bin directory that point to missing bins
+pub fn dangling_shims() -> Result<Vec<DanglingShim>> {
+    let global_dir = global_dir()?;
+    let bin_dir = global_bin_dir()?;
+
+    let entries = match read_dir(&bin_dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut found = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if let Ok(target) = read_link(&path) {
+            if !path.exists() {
+                found.push(DanglingShim {
+                    managed: is_managed(&target, &global_dir),
+                    path,
+                    target,
+                });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(found)
+}
+
+/// Remove the global bin links of packages that are being uninstalled, before their files are gone
+pub async fn unlink_global_bins(names: &[CompactString]) -> Result<()> {
+    let Ok(plan) = read_plan("node_modules/.cotton/plan.json").await else {
+        return Ok(());
+    };
+    let global_dir = global_dir()?;
+    let bin_dir = global_bin_dir()?;
+
+    for tree in names.iter().filter_map(|x| plan.trees.get(x)) {
+        for (cmd, _) in normalize_bins(&tree.root) {
+            let link = bin_dir.join(cmd.as_str());
+            if read_link(&link).is_ok_and(|x| x.starts_with(&global_dir)) {
+                log_verbose(&format!("Removing bin {link:?} of {}", tree.root.name));
+                remove_file(&link)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Link bins of globally installed packages into the global bin directory,
+/// removing links to bins that no longer exist
+pub async fn link_global_bins() -> Result<()> {
+    let global_dir = global_dir()?;
+    let bin_dir = global_bin_dir()?;
+    create_dir_all(&bin_dir).await?;
+
+    for shim in dangling_shims()?.into_iter().filter(|x| x.managed) {
+        log_verbose(&format!("Removing dangling bin {:?}", shim.path));
+        remove_file(&shim.path)?;
+    }
+
+    let installed_bins = global_dir.join("node_modules/.bin");
+    for entry in read_dir(&installed_bins)? {
+        let entry = entry?;
+        let link = bin_dir.join(entry.file_name());
+        match symlink(entry.path(), &link) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            r => r?,
+        }
+    }
+
+    Ok(())
+}
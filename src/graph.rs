@@ -0,0 +1,82 @@
+use color_eyre::eyre::Result;
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeSet;
+
+use crate::{package::PackageMetadata, resolve::Graph};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// Edges between `name@version` nodes, starting from the root package
+fn edges(package: &PackageMetadata, graph: &Graph) -> Result<BTreeSet<(String, String)>> {
+    let root = if package.name.is_empty() {
+        "root".to_string()
+    } else {
+        package.name.to_string()
+    };
+
+    let mut edges = BTreeSet::new();
+
+    for req in package.iter_all() {
+        let dep = graph.resolve_req(&req)?;
+        edges.insert((
+            root.clone(),
+            format!("{}@{}", dep.package.name, dep.version),
+        ));
+    }
+
+    for pkg in graph.relations.values() {
+        let from = format!("{}@{}", pkg.package.name, pkg.version);
+        for req in pkg.package.iter() {
+            let dep = graph.resolve_req(&req)?;
+            edges.insert((
+                from.clone(),
+                format!("{}@{}", dep.package.name, dep.version),
+            ));
+        }
+    }
+
+    Ok(edges)
+}
+
+pub fn render_graph(
+    package: &PackageMetadata,
+    graph: &Graph,
+    format: GraphFormat,
+) -> Result<String> {
+    let edges = edges(package, graph)?;
+
+    Ok(match format {
+        GraphFormat::Dot => format!(
+            "digraph dependencies {{\n{}\n}}\n",
+            edges
+                .iter()
+                .map(|(from, to)| format!("  {from:?} -> {to:?};"))
+                .join("\n")
+        ),
+        GraphFormat::Mermaid => {
+            // Mermaid node ids cannot contain `@` or `/`, so nodes are numbered and labelled
+            let mut ids = FxHashMap::default();
+            let mut nodes = vec![];
+            for name in edges.iter().flat_map(|(from, to)| [from, to]) {
+                ids.entry(name).or_insert_with(|| {
+                    nodes.push(format!("  n{}[\"{name}\"]", nodes.len()));
+                    nodes.len() - 1
+                });
+            }
+
+            format!(
+                "graph LR\n{}\n{}\n",
+                nodes.join("\n"),
+                edges
+                    .iter()
+                    .map(|(from, to)| format!("  n{} --> n{}", ids[from], ids[to]))
+                    .join("\n")
+            )
+        }
+    })
+}
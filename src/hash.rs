@@ -0,0 +1,64 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use itertools::Itertools;
+
+use crate::{
+    package::{PackageMetadata, PackageSpecifier},
+    resolve::{Graph, VerboseEvents},
+    util::hash_hex,
+    workspace::workspace_range,
+};
+
+/// Hash of the resolved dependencies of the project, or of one of its workspaces, which stays the
+/// same as long as every package in the subtree resolves to the same contents
+pub fn dependency_hash(
+    root: &PackageMetadata,
+    graph: &Graph,
+    workspace: Option<&str>,
+) -> Result<String> {
+    let roots: Vec<PackageSpecifier> = match workspace {
+        Some(name) => {
+            // Only workspaces depended on through `workspace:` are resolved into the graph
+            let resolved = graph
+                .relations
+                .iter()
+                .filter(|(req, _)| req.name == name && workspace_range(&req.version).is_some())
+                .collect_vec();
+            if resolved.is_empty() {
+                return Err(eyre!("Workspace {name} is not in the lockfile").suggestion(
+                    "Depend on it with a `workspace:` specifier, and run `cotton install`",
+                ));
+            }
+            resolved
+                .into_iter()
+                .flat_map(|(_, x)| x.package.iter())
+                .collect()
+        }
+        None => root.iter_all().collect(),
+    };
+
+    if let Some(missing) = roots.iter().find(|x| !graph.relations.contains_key(x)) {
+        return Err(eyre!("{missing} is not in the lockfile")
+            .suggestion("Run `cotton install` to update the lockfile"));
+    }
+
+    let lines = graph
+        .pruned(&roots, &VerboseEvents)
+        .relations
+        .into_iter()
+        .map(|(req, x)| {
+            let dist = &x.package.dist;
+            format!(
+                "{req} {}@{} {}",
+                x.package.name,
+                x.version,
+                dist.integrity.as_deref().unwrap_or(&dist.tarball)
+            )
+        })
+        .sorted()
+        .join("\n");
+
+    Ok(hash_hex(lines))
+}
@@ -0,0 +1,110 @@
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use compact_str::CompactString;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tokio::fs::read_to_string;
+
+use crate::{
+    local::{linked_path, local_path},
+    npm::Dependency,
+    package::PackageMetadata,
+    plan::Plan,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMapFormat {
+    /// A browser import map, with `imports` for each package and its subpaths
+    Importmap,
+    /// An object of package directories, for the `alias` option of bundlers
+    Alias,
+}
+
+/// Conditions of `exports` that apply to browser modules, in order of preference
+const CONDITIONS: &[&str] = &["browser", "import", "module", "default"];
+
+fn resolve_export(target: &Value) -> Option<&str> {
+    match target {
+        Value::String(x) => Some(x),
+        Value::Array(x) => x.iter().find_map(resolve_export),
+        Value::Object(x) => CONDITIONS
+            .iter()
+            .find_map(|c| x.get(*c).and_then(resolve_export)),
+        _ => None,
+    }
+}
+
+/// Module that a bare import of the package loads in the browser
+fn entry_point(manifest: &Value) -> &str {
+    let exports = &manifest["exports"];
+    // Either a map of subpaths, or the conditions of the package itself
+    let root = exports.get(".").unwrap_or(exports);
+
+    resolve_export(root)
+        .or_else(|| manifest["module"].as_str())
+        .or_else(|| manifest["browser"].as_str())
+        .or_else(|| manifest["main"].as_str())
+        .unwrap_or("index.js")
+        .trim_start_matches("./")
+}
+
+/// Directory of a package on a CDN, unless it is a local package that is never published
+fn cdn_url(dep: &Dependency, cdn: Option<&str>) -> Option<String> {
+    if local_path(&dep.dist).is_some() || linked_path(&dep.dist).is_some() {
+        return None;
+    }
+    let cdn = cdn?.trim_end_matches('/');
+    Some(format!("{cdn}/{}@{}/", dep.name, dep.version))
+}
+
+/// Import map or bundler aliases for the installed direct dependencies of a package
+pub async fn import_map(
+    package: &PackageMetadata,
+    plan: &Plan,
+    format: ImportMapFormat,
+    base: &str,
+    cdn: Option<&str>,
+    dev: bool,
+) -> Result<Value> {
+    let names = package
+        .dependencies
+        .keys()
+        .chain(package.optional_dependencies.keys())
+        .chain(package.dev_dependencies.keys().filter(|_| dev));
+
+    let mut imports = BTreeMap::<CompactString, String>::new();
+    for name in names {
+        // Optional dependencies may not be installed on this platform
+        let Some(tree) = plan.trees.get(name) else {
+            continue;
+        };
+        let cdn_url = cdn_url(&tree.root, cdn);
+        let url = cdn_url
+            .clone()
+            .unwrap_or_else(|| format!("{}/{name}/", base.trim_end_matches('/')));
+
+        if format == ImportMapFormat::Alias {
+            imports.insert(name.clone(), url.trim_end_matches('/').to_string());
+            continue;
+        }
+
+        let entry = if cdn_url.is_some() {
+            // CDNs resolve the entry point of the package themselves
+            url.trim_end_matches('/').to_string()
+        } else {
+            let manifest = read_to_string(format!("node_modules/{name}/package.json"))
+                .await
+                .ok()
+                .and_then(|x| serde_json::from_str(&x).ok())
+                .unwrap_or_default();
+            format!("{url}{}", entry_point(&manifest))
+        };
+        imports.insert(name.clone(), entry);
+        imports.insert(format!("{name}/").into(), url);
+    }
+
+    Ok(match format {
+        ImportMapFormat::Importmap => json!({ "imports": imports }),
+        ImportMapFormat::Alias => json!(imports),
+    })
+}
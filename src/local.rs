@@ -0,0 +1,221 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use compact_str::ToCompactString;
+use node_semver::{Identifier, Version};
+use sha2::{Digest, Sha512};
+use std::{
+    fs::{canonicalize, create_dir_all, hard_link, read, read_dir, read_link},
+    io::Cursor,
+    os::unix::fs::symlink,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    npm::read_tarball_manifest,
+    package::{Dist, PackageInfo, PackageMetadata},
+    util::{hash_hex, read_json, VersionSpecifier},
+};
+
+/// Directories that are never linked from a local package
+const SKIPPED: [&str; 2] = ["node_modules", ".git"];
+
+pub fn is_tarball(path: &str) -> bool {
+    path.ends_with(".tgz") || path.ends_with(".tar.gz")
+}
+
+fn file_path(spec: &VersionSpecifier) -> Option<&str> {
+    match spec {
+        VersionSpecifier::Prefixed(prefixed) if prefixed.prefix == "file" => Some(&prefixed.rest),
+        _ => None,
+    }
+}
+
+/// The directory of a `file:` specifier, relative to the project
+pub fn local_dir(spec: &VersionSpecifier) -> Option<&str> {
+    file_path(spec).filter(|x| !is_tarball(x))
+}
+
+/// The tarball of a `file:` specifier, relative to the project
+pub fn local_tarball(spec: &VersionSpecifier) -> Option<&str> {
+    file_path(spec).filter(|x| is_tarball(x))
+}
+
+/// The directory of a `link:` specifier, relative to the project
+pub fn linked_dir(spec: &VersionSpecifier) -> Option<&str> {
+    match spec {
+        VersionSpecifier::Prefixed(prefixed) if prefixed.prefix == "link" => Some(&prefixed.rest),
+        _ => None,
+    }
+}
+
+/// The directory of a locked local package
+pub fn local_path(dist: &Dist) -> Option<&str> {
+    dist.tarball
+        .strip_prefix("file:")
+        .filter(|x| !is_tarball(x))
+}
+
+/// The tarball of a locked local tarball package, which is extracted into the store
+pub fn local_tarball_path(dist: &Dist) -> Option<&str> {
+    dist.tarball.strip_prefix("file:").filter(|x| is_tarball(x))
+}
+
+/// The directory of a locked linked package
+pub fn linked_path(dist: &Dist) -> Option<&str> {
+    dist.tarball.strip_prefix("link:")
+}
+
+/// Whether a locked package is installed from a local directory instead of the store
+pub fn is_local(dist: &Dist) -> bool {
+    local_path(dist).is_some() || linked_path(dist).is_some()
+}
+
+/// Join `path` onto `base`, resolving `.` and `..` without touching the filesystem
+pub fn join_lexically(base: &Path, path: &str) -> PathBuf {
+    let mut joined = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(joined.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                joined.pop();
+            }
+            x => joined.push(x),
+        }
+    }
+    if joined.as_os_str().is_empty() {
+        joined.push(".");
+    }
+    joined
+}
+
+/// Rewrite the relative `file:` and `link:` dependencies of a package located in `base`, which
+/// refer to paths relative to the package itself, into paths relative to the project
+pub fn rebase_local_dependencies(package: &mut PackageMetadata, base: &Path) -> Result<()> {
+    for spec in package
+        .dependencies
+        .values_mut()
+        .chain(package.optional_dependencies.values_mut())
+    {
+        let VersionSpecifier::Prefixed(prefixed) = spec else {
+            continue;
+        };
+        if !matches!(prefixed.prefix.as_str(), "file" | "link")
+            || Path::new(prefixed.rest.as_str()).is_absolute()
+        {
+            continue;
+        }
+
+        let path = join_lexically(base, &prefixed.rest);
+        prefixed.rest = path.to_string_lossy().to_compact_string();
+    }
+
+    Ok(())
+}
+
+/// Reject relative `file:` and `link:` dependencies of a package downloaded from `source`,
+/// since there is no directory they could be relative to
+pub fn check_remote_dependencies(package: &PackageMetadata, source: &str) -> Result<()> {
+    let local = package
+        .dependencies
+        .iter()
+        .chain(package.optional_dependencies.iter())
+        .find(|(_, spec)| {
+            (local_dir(spec).or(local_tarball(spec)).or(linked_dir(spec)))
+                .is_some_and(|x| Path::new(x).is_relative())
+        });
+
+    match local {
+        Some((name, spec)) => Err(eyre!(
+            "Dependency {name} of {} refers to the local path {spec}, which cannot be resolved for a package from {source}",
+            package.name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Read the metadata of a local package, locking its path instead of a version
+pub async fn fetch_local_package(protocol: &str, dir: &str) -> Result<(Version, Arc<PackageInfo>)> {
+    let mut package: PackageMetadata = read_json(Path::new(dir).join("package.json"))
+        .await
+        .map_err(|e| eyre!("Failed to read local package {dir}: {e}"))?;
+    rebase_local_dependencies(&mut package, Path::new(dir))?;
+
+    package.dist = Dist {
+        tarball: format!("{protocol}:{dir}").to_compact_string(),
+        ..Default::default()
+    };
+
+    // The contents are linked from the directory on every install, so the version is meaningless
+    Ok((Version::from((0, 0, 0)), Arc::new(package.info())))
+}
+
+/// Read the metadata of a local tarball, keying it by the hash of its contents
+pub async fn fetch_local_tarball(path: &str) -> Result<(Version, Arc<PackageInfo>)> {
+    let data = read(path).map_err(|e| eyre!("Failed to read local tarball {path}: {e}"))?;
+
+    let mut package = read_tarball_manifest(Cursor::new(&data), path).await?;
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    rebase_local_dependencies(&mut package, base)?;
+    let mut version = package
+        .version
+        .clone()
+        .ok_or_else(|| eyre!("Package from {path} does not specify a version"))?;
+
+    // The tarball may change without its version changing, so keep different contents apart in the store
+    let hash = hash_hex(&data);
+    version.build = vec![Identifier::AlphaNumeric(format!("sha256{}", &hash[..16]))];
+
+    package.dist = Dist {
+        tarball: format!("file:{path}").to_compact_string(),
+        integrity: Some(format!("sha512-{}", STANDARD.encode(Sha512::digest(&data))).into()),
+        ..Default::default()
+    };
+
+    Ok((version, Arc::new(package.info())))
+}
+
+pub fn hardlink_local_dir(src: &Path, dst: &Path) -> Result<()> {
+    create_dir_all(dst)?;
+    for entry in read_dir(src)? {
+        let entry = entry?;
+        if SKIPPED.iter().any(|x| entry.file_name() == *x) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            hardlink_local_dir(&entry.path(), &dst.join(entry.file_name()))?;
+        } else {
+            hard_link(entry.path(), dst.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `target` is a symlink that resolves to `dir`
+pub fn is_linked(target: &Path, dir: impl AsRef<Path>) -> bool {
+    read_link(target).is_ok() && canonicalize(target).ok() == canonicalize(dir).ok()
+}
+
+/// Symlink `target` inside `node_modules` of the project at `root` to the project-relative
+/// `dir`, where `target` is relative to `root`
+pub fn link_dir(root: &Path, dir: &str, target: &Path) -> Result<()> {
+    let parent = target.parent().wrap_err("Invalid install path")?;
+    create_dir_all(root.join(parent))?;
+
+    // Keep the link relative, so that the project can be moved
+    let mut source = PathBuf::new();
+    if Path::new(dir).is_relative() {
+        for component in parent.components() {
+            if let Component::Normal(_) = component {
+                source.push("..");
+            }
+        }
+    }
+    source.push(dir);
+
+    symlink(source, root.join(target))?;
+    Ok(())
+}
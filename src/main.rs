@@ -1,61 +1,142 @@
+mod adopt;
+mod approval;
+mod bins;
+mod bundle;
 mod cache;
+mod catalog;
+mod changeset;
+mod clean;
+mod concurrency;
 mod config;
+mod daemon;
+mod diff;
+mod doctor;
+mod engines;
+mod export;
+mod git;
+mod global;
+mod graph;
+mod hash;
+mod importmap;
+mod local;
+mod memo;
+mod metrics;
 mod npm;
+mod overrides;
 mod package;
+mod package_manager;
+mod peer;
+mod pin;
 mod plan;
+mod preflight;
 mod progress;
+mod projects;
+mod publish;
+mod registry;
 mod resolve;
+mod resolve_check;
+mod result;
+mod sbom;
 mod scoped_path;
+mod scripts;
+mod self_update;
+mod session;
+mod shard;
+mod stats;
+mod task_cache;
+mod template;
+mod transform;
+mod trust;
 mod util;
+mod verify;
 mod watch;
-
-use async_recursion::async_recursion;
-use clap::Parser;
-use color_eyre::eyre::{eyre, ContextCompat, Result};
+mod why;
+mod workspace;
+
+use approval::{approve_new_scripts, install_scripts};
+use bins::link_deferred_bins;
+use changeset::Bump;
+use clap::{CommandFactory, Parser};
+use clean::{clean_all, clean_all_projects, clean_stale};
+use color_eyre::eyre::{eyre, ContextCompat, Result, WrapErr};
 use color_eyre::owo_colors::OwoColorize;
-use color_eyre::Help;
+use color_eyre::{Help, Report};
 use compact_str::{CompactString, ToCompactString};
-use config::read_config;
+use config::{
+    read_config, read_config_sync, read_user_config, Config, LatestFallback, PrereleasePolicy,
+    Registry, SavePrefix,
+};
+use cotton_core::resolve::{MissingRequirement, PlanOptions};
+use doctor::{check_global, check_paths};
+use engines::check_engines;
+use export::npm_lockfile;
 use futures::future::try_join_all;
 use futures::lock::Mutex;
 use futures_lite::future::race;
+use global::{enter_global, global_bin_dir, link_global_bins, unlink_global_bins};
+use graph::{render_graph, GraphFormat};
+use importmap::{import_map, ImportMapFormat};
+use indicatif::HumanBytes;
 use itertools::Itertools;
-use multimap::MultiMap;
+use metrics::{report_install, InstallMetrics};
 use nix::sys::signal::{self, Signal};
 use nix::unistd::{execvp, Pid};
-use node_semver::Version;
-use npm::{fetch_package, Dependency};
+use node_semver::{Range, Version};
+use npm::{
+    fetch_dist_tags, fetch_latest, fetch_package, prerelease_policy, report_deprecated, Cutoff,
+    ResolutionMode,
+};
 use once_cell::sync::Lazy;
-use package::{PackageMetadata, PackageSpecifier};
+use package::{Mode, PackageMetadata};
+use package_manager::{check_package_manager, user_agent};
+use peer::check_peers;
 use plan::tree_size;
-use progress::{log_progress, log_verbose};
+use progress::{log_progress, log_verbose, log_warning};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use resolve::{Graph, Lockfile};
-use rustc_hash::FxHashSet;
+use registry::split_spec;
+use resolve::{check_coverage, forget_local, resolve_graph, Graph, VerboseEvents};
+use result::{error_class, exit_with, write_result, PACKAGES, RESULT_FILE, START};
+use sbom::{sbom, SbomFormat};
+use scripts::{
+    defer_install_scripts, install_script_tasks, run_deferred_scripts, run_install_scripts,
+    run_lifecycle_script, set_lifecycle_env,
+};
+use self_update::UpdateStatus;
 use serde_json::{Map, Value};
-use std::collections::VecDeque;
+use session::{load_session, save_session};
 use std::env::{current_dir, current_exe, set_current_dir, set_var, temp_dir};
 use std::ffi::{CString, OsStr, OsString};
 use std::fs::remove_dir_all;
 use std::io::ErrorKind;
 use std::os::unix::fs::symlink;
 use std::os::unix::prelude::OsStrExt;
-use std::{env, path::PathBuf, process::exit, time::Instant};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Instant,
+};
+use task_cache::{restore_outputs, save_outputs, task_key};
+use template::{git_author, init_from_template, TemplateVars};
 use tokio::fs::{create_dir, create_dir_all, metadata};
 use tokio::{fs::read_to_string, process::Command};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use util::{read_package, read_package_or_default, save_package, write_json};
+use util::{
+    install_mode, is_cross_platform, load_committed_graph, lockfile_path, read_package,
+    read_package_or_default, save_lockfile, save_package, supported_platforms, write_json,
+    VersionSpecifier, NO_NETWORK,
+};
 use watch::async_watch;
 use which::which;
+use workspace::{find_workspaces, Task, Workspace};
 
-use crate::npm::DependencyTree;
-use crate::scoped_path::scoped_join;
 use crate::util::load_graph_from_lockfile;
 use crate::{
-    plan::{execute_plan, Plan},
+    plan::{execute_plan, plan_satisfies, store_dir, InstallMarker, Plan, STORE_DIR},
+    preflight::{check_permissions, check_plan},
     progress::PROGRESS_BAR,
 };
 
@@ -71,17 +152,78 @@ pub struct Args {
     /// Prevent any modifications to the lockfile
     #[clap(long, global = true)]
     immutable: bool,
+    /// Fail instead of accessing the network, such as for commands that only read the lockfile
+    #[clap(long, global = true)]
+    no_network: bool,
+    /// Check that the files of installed packages exist, reinstalling packages that are missing any
+    #[clap(long, global = true)]
+    check_files: bool,
+    /// Print the changes to package.json instead of saving them
+    #[clap(long, global = true)]
+    no_write: bool,
     /// Run in a custom working directory
     #[clap(long, global = true, alias = "cwd")]
     working_dir: Option<PathBuf>,
+    /// Read `package.json` from a custom path (`-` for stdin)
+    #[clap(long, global = true)]
+    manifest: Option<PathBuf>,
+    /// Read and write `cotton.lock` at a custom path (`-` for stdin)
+    #[clap(long, global = true)]
+    lockfile: Option<PathBuf>,
+    /// Fail instead of warning when peer dependencies are not satisfied
+    #[clap(long, global = true)]
+    strict_peer_deps: bool,
+    /// Install packages for another os, such as `linux`
+    #[clap(long, global = true)]
+    target_os: Option<String>,
+    /// Install packages for another cpu, such as `x64`
+    #[clap(long, global = true)]
+    target_cpu: Option<String>,
+    /// Continue even if package.json declares a different package manager
+    #[clap(long, global = true)]
+    force: bool,
+    /// Save all registry responses used in this run to an archive, for reproducing bugs
+    #[clap(long, global = true)]
+    record_session: Option<PathBuf>,
+    /// Use registry responses from an archive saved with `--record-session` instead of the network
+    #[clap(long, global = true, conflicts_with = "record_session")]
+    replay: Option<PathBuf>,
+    /// Install `devDependencies` only in development, and set NODE_ENV for scripts [default: NODE_ENV]
+    #[clap(long, global = true, value_enum)]
+    mode: Option<Mode>,
+    /// Ignore versions published after this date, timestamp or duration ago, such as `2024-01-01` or `7d`
+    #[clap(long, global = true)]
+    before: Option<Cutoff>,
+    /// Whether ranges resolve to prereleases, overriding `prerelease` in cotton.toml
+    #[clap(long, global = true, value_enum)]
+    prerelease: Option<PrereleasePolicy>,
+    /// Resolve the dependencies in package.json to their lowest versions, to check their ranges
+    #[clap(long, global = true, value_enum, default_value_t)]
+    resolution_mode: ResolutionMode,
+    /// Also install the dependencies in these groups of `cotton.groups` in package.json
+    #[clap(long, global = true, value_delimiter = ',')]
+    with: Vec<CompactString>,
     #[clap(subcommand)]
     cmd: Subcommand,
 }
 
 #[derive(Parser, Debug, Clone)]
 pub enum Subcommand {
+    /// Create package.json in the current directory, optionally from a template
+    Init {
+        /// Download a template from `github:owner/repo`, substituting `{{name}}` and `{{author}}`
+        #[clap(long)]
+        from: Option<String>,
+        /// Name of the project, instead of the directory name
+        #[clap(long)]
+        name: Option<String>,
+    },
     /// Install packages defined in package.json
-    Install,
+    Install {
+        /// Only check that the lockfile is up to date, exiting with code 3 if it is not
+        #[clap(long)]
+        check: bool,
+    },
     /// Prepare and save a newly planned lockfile
     Update,
     /// Add package to package.json
@@ -93,15 +235,54 @@ pub enum Subcommand {
         /// Pin dependencies to a specific version
         #[clap(long, alias = "exact")]
         pin: bool,
+        /// Install globally and link bins into `~/.cotton/bin`
+        #[clap(short, long)]
+        global: bool,
+        /// Add runtime dependencies to a private workspace root
+        #[clap(long)]
+        root: bool,
     },
     /// Run a script defined in package.json
     Run {
         name: CompactString,
-        #[clap(long)]
-        watch: Vec<PathBuf>,
+        #[clap(flatten)]
+        options: RunOptions,
+    },
+    /// Run the `test` script
+    Test {
+        #[clap(flatten)]
+        options: RunOptions,
+    },
+    /// Run the `start` script, or `node server.js` if it is not defined
+    Start {
+        #[clap(flatten)]
+        options: RunOptions,
+    },
+    /// Run the `stop` script
+    Stop {
+        #[clap(flatten)]
+        options: RunOptions,
+    },
+    /// Run the `build` script
+    Build {
+        #[clap(flatten)]
+        options: RunOptions,
     },
     /// Clean packages installed in `node_modules` and remove cache
-    Clean,
+    Clean {
+        /// Only report what would be removed
+        #[clap(long)]
+        dry_run: bool,
+        /// Only remove store entries unused by the lockfile and older than this (e.g. `30d`)
+        #[clap(long, conflicts_with = "all_projects")]
+        older_than: Option<humantime::Duration>,
+        /// Clean every project that cotton has installed packages for on this machine
+        #[clap(long)]
+        all_projects: bool,
+        /// Clean every project without asking for confirmation
+        #[clap(long, requires = "all_projects")]
+        yes: bool,
+    },
     /// Update packages specified in package.json to the latest available version
     Upgrade {
         /// Pin dependencies to a specific version
@@ -109,39 +290,362 @@ pub enum Subcommand {
         pin: bool,
     },
     /// Execute a command that is not specified as a script
-    Exec { exe: OsString, args: Vec<OsString> },
+    Exec {
+        exe: OsString,
+        args: Vec<OsString>,
+        /// Execute in every workspace
+        #[clap(short, long)]
+        recursive: bool,
+        /// Execute in all workspaces at once, instead of in dependency order
+        #[clap(long, requires = "recursive")]
+        parallel: bool,
+    },
     /// Remove package from package.json
     Remove {
         names: Vec<CompactString>,
         /// Remove from `devDependencies` instead of `dependencies`
         #[clap(short = 'D', long)]
         dev: bool,
+        /// Remove a globally installed package
+        #[clap(short, long)]
+        global: bool,
+    },
+    /// List dependencies and their installed versions
+    List {
+        /// List globally installed packages
+        #[clap(short, long)]
+        global: bool,
     },
     /// Find all uses of a given package
     Why {
         name: CompactString,
         version: Option<Version>,
+        /// Show every chain of dependents up to `package.json` as a tree
+        #[clap(long)]
+        tree: bool,
+        /// Print the chains of dependents as JSON
+        #[clap(long, conflicts_with = "tree")]
+        json: bool,
     },
     /// Create new projects from a `create-` starter kit
     Create { name: CompactString },
     /// Download (if needed) and execute a command
     #[clap(name = "x")]
     DownloadAndExec { name: OsString, args: Vec<OsString> },
+    /// Export the lockfile as an npm-compatible `package-lock.json`
+    Export,
+    /// Generate a software bill of materials for the resolved dependencies
+    Sbom {
+        #[clap(long, value_enum, default_value_t = SbomFormat::Cyclonedx)]
+        format: SbomFormat,
+        /// Write to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Export the resolved dependency graph, as Graphviz by default
+    Graph {
+        /// Output Graphviz DOT
+        #[clap(long, conflicts_with = "mermaid")]
+        dot: bool,
+        /// Output a Mermaid flowchart
+        #[clap(long)]
+        mermaid: bool,
+        /// Write to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate an import map for the installed direct dependencies, for buildless web projects
+    EmitImportmap {
+        #[clap(long, value_enum, default_value_t = ImportMapFormat::Importmap)]
+        format: ImportMapFormat,
+        /// Load packages from this CDN, such as `https://esm.sh`, instead of `node_modules`
+        #[clap(long)]
+        cdn: Option<String>,
+        /// Path that `node_modules` is served from [default: /node_modules, or ./node_modules for
+        /// aliases]
+        #[clap(long)]
+        base: Option<String>,
+        /// Include devDependencies
+        #[clap(long)]
+        dev: bool,
+        /// Write to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the environment used to run scripts
+    Env,
+    /// Manage dist-tags of a package on the registry
+    Tag {
+        #[clap(subcommand)]
+        cmd: TagCommand,
+    },
+    /// Pack the package into a tarball, as it would be published
+    Pack,
+    /// Publish the package to the registry
+    Publish {
+        /// Publish every workspace package whose version is not yet on the registry
+        #[clap(short, long)]
+        recursive: bool,
+        /// Show what would be published without uploading anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Dist-tag to publish under
+        #[clap(long, default_value = "latest")]
+        tag: String,
+    },
+    /// Manage pending releases of workspace packages
+    Changeset {
+        #[clap(subcommand)]
+        cmd: ChangesetCommand,
+    },
+    /// Deprecate versions of a published package, given as `name` or `name@range`
+    Deprecate {
+        spec: CompactString,
+        /// Message shown to users; an empty message removes the deprecation
+        message: String,
+    },
+    /// Review and accept packages whose content or signer changed since first install
+    Trust {
+        /// Accept the changes
+        #[clap(long)]
+        accept: bool,
+        /// Only accept changes to these packages, given as `name@version`
+        packages: Vec<String>,
+    },
+    /// Replace cotton with the latest release
+    SelfUpdate {
+        /// Only check whether a newer release is available
+        #[clap(long)]
+        check: bool,
+        /// Release to check, in the format of the GitHub releases API
+        #[clap(long)]
+        url: Option<String>,
+    },
+    /// Force versions of transitive dependencies through the `overrides` of package.json
+    Override {
+        #[clap(subcommand)]
+        cmd: OverrideCommand,
+    },
+    /// Manage the package store
+    Store {
+        #[clap(subcommand)]
+        cmd: StoreCommand,
+    },
+    /// Watch `package.json` and `cotton.lock`, downloading changed packages in the background
+    Daemon,
+    /// Verify that installed packages match the plan
+    Verify {
+        /// Reinstall broken packages
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Check that installed packages can resolve their dependencies' entry points
+    ResolveCheck,
+    /// Print a hash of the resolved dependencies, for the cache keys of task runners
+    Hash {
+        /// Hash the dependencies of this workspace package instead of the whole project
+        #[clap(long)]
+        workspace: Option<String>,
+    },
+    /// Print an overview of the dependency graph, such as duplicated and deprecated packages
+    Stats,
+    /// Switch from npm, yarn or pnpm, keeping the versions of their lockfile
+    Adopt {
+        /// Switch without asking for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Check that the configured registries are reachable and accept their credentials
+    Ping {
+        /// Only check the registry with this url or scope
+        registry: Option<String>,
+    },
+    /// Diagnose problems with the installation (runs every check if none are selected)
+    Doctor {
+        /// Check for broken or looping symlinks in `node_modules`
+        #[clap(long)]
+        paths: bool,
+        /// Check for leftover links in the global bin directory
+        #[clap(long)]
+        global: bool,
+    },
+    /// List completion candidates for shell completion scripts
+    #[clap(name = "__complete", hide = true)]
+    Complete { kind: CompletionKind },
+    /// Install multiple projects in one process, sharing metadata and the store of the first project
+    Ci {
+        /// Directories of the projects to install
+        #[clap(long, value_delimiter = ',', required = true)]
+        projects: Vec<PathBuf>,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum CompletionKind {
+    /// Scripts defined in package.json
+    Scripts,
+    /// Packages installed at the top level of `node_modules`
+    Packages,
+    /// Subcommands, including aliases
+    Commands,
+    /// Names of workspace packages
+    Workspaces,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum TagCommand {
+    /// Point a tag at a version, given as `name@version`
+    Add {
+        spec: CompactString,
+        tag: CompactString,
+    },
+    /// Remove a tag
+    Rm {
+        name: CompactString,
+        tag: CompactString,
+    },
+    /// List tags
+    Ls { name: CompactString },
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum ChangesetCommand {
+    /// Record a pending release of workspace packages
+    Add {
+        #[clap(long, value_enum)]
+        bump: Bump,
+        /// Summary to include in the changelog
+        #[clap(short, long)]
+        message: String,
+        #[clap(required = true)]
+        packages: Vec<CompactString>,
+    },
+    /// Apply pending changesets, bumping versions and writing changelogs
+    Version,
+    /// Publish new versions and tag them in git
+    Publish {
+        /// Show what would be published without uploading anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Dist-tag to publish under
+        #[clap(long, default_value = "latest")]
+        tag: String,
+    },
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum OverrideCommand {
+    /// Force a version of a package, such as to apply a security fix to a transitive dependency
+    Add {
+        name: String,
+        version: String,
+        /// Only override the package where this package depends on it
+        #[clap(long)]
+        parent: Option<String>,
+    },
+    /// Remove an override, from either `overrides` or `resolutions`
+    Remove {
+        name: String,
+        #[clap(long)]
+        parent: Option<String>,
+    },
+    /// List the overrides that are applied when resolving
+    List,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum StoreCommand {
+    /// Pack all packages referenced by the lockfile into an archive
+    Export { file: PathBuf },
+    /// Restore packages into the store from an archive
+    Import { file: PathBuf },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct RunOptions {
+    /// Restart the script when files in these paths are modified
+    #[clap(long)]
+    watch: Vec<PathBuf>,
+    /// Exit successfully if the script is not defined
+    #[clap(long)]
+    if_present: bool,
+    /// Run the script in every workspace that defines it
+    #[clap(short, long, conflicts_with = "watch")]
+    recursive: bool,
+    /// Run in all workspaces at once, instead of in dependency order
+    #[clap(long, requires = "recursive")]
+    parallel: bool,
+    /// Restore the outputs of the script instead of running it if its inputs are unchanged
+    #[clap(long, conflicts_with_all = ["watch", "recursive"])]
+    cached: bool,
 }
 
-async fn prepare_plan(package: &PackageMetadata) -> Result<Plan> {
+async fn prepare_graph(package: &PackageMetadata) -> Result<Graph> {
     log_progress("Preparing");
 
-    let mut graph = load_graph_from_lockfile().await;
+    let mut graph = load_graph_from_lockfile().await?;
+    let config = read_config().await?;
+
+    if !ARGS.immutable {
+        if config.prune_lockfile {
+            forget_local(&mut graph);
+        }
+
+        resolve_graph(&mut graph, package, true).await?;
+    }
+
+    // The lockfile on disk may come from an unreviewed change, so only the committed one counts
+    // as approval, and the check also runs when the lockfile is used as is
+    if config.allow_install_scripts {
+        let committed_scripts = install_scripts(package, &load_committed_graph().await);
+        approve_new_scripts(package, &committed_scripts, &graph, &config).await?;
+    }
 
     if !ARGS.immutable {
-        graph.append(package.iter_all(), true).await?;
-        write_json("cotton.lock", Lockfile::new(graph.clone())).await?;
+        save_lockfile(&graph).await?;
     }
 
+    pin::check_graph(&graph).await?;
+    trust::observe_graph(&graph)?;
+
     log_progress("Retrieved dependency graph");
 
-    let trees = graph.build_trees(&package.iter_all().collect_vec())?;
+    Ok(graph)
+}
+
+fn plan_graph(package: &PackageMetadata, graph: &Graph) -> Result<Plan> {
+    let mode = install_mode();
+    for group in &ARGS.with {
+        if !package.cotton.groups.contains_key(group) {
+            return Err(
+                eyre!("Group `{group}` is not defined in package.json").with_note(|| {
+                    format!(
+                        "Available groups: {}",
+                        package.cotton.groups.keys().join(", ")
+                    )
+                }),
+            );
+        }
+    }
+
+    let roots = package.iter_installed(mode, &ARGS.with).collect_vec();
+    let options = PlanOptions {
+        rules: &package.cotton.package_rules,
+        platforms: supported_platforms(),
+        events: &VerboseEvents,
+    };
+    let trees = graph
+        .pruned(&roots, &VerboseEvents)
+        .build_trees(&roots, &options)
+        .map_err(|e| {
+            if e.downcast_ref::<MissingRequirement>().is_none() {
+                e
+            } else if ARGS.immutable {
+                e.suggestion("Make sure that the lockfile is up-to-date. Passing --immutable prevents any changes to the lockfile.")
+            } else {
+                e.suggestion("Make sure that the lockfile is consistent. Automatic resolution of merge conflicts can lead to inconsistency.")
+            }
+        })?;
     log_progress(&format!("Fetched {} root deps", trees.len().yellow()));
 
     let plan = Plan::new(
@@ -149,6 +653,7 @@ async fn prepare_plan(package: &PackageMetadata) -> Result<Plan> {
             .iter()
             .map(|x| (x.root.name.to_compact_string(), x.clone()))
             .collect(),
+        mode,
     );
 
     log_progress(&format!(
@@ -165,93 +670,205 @@ async fn read_plan(path: &str) -> Result<Plan> {
 }
 
 pub async fn verify_installation(package: &PackageMetadata, plan: &Plan) -> Result<bool> {
+    let marker = InstallMarker::new(plan).await?;
+    if marker.is_current().await {
+        return Ok(true);
+    }
+
     let installed = read_plan("node_modules/.cotton/plan.json").await?;
 
     if &installed != plan {
         return Ok(false);
     }
 
-    Ok(installed.satisfies(package))
+    let satisfied = plan_satisfies(&installed, package);
+    if satisfied {
+        marker.save().await?;
+    }
+
+    Ok(satisfied)
 }
 
-async fn exec_install_script(root: &Dependency, stack: &[CompactString]) -> Result<()> {
-    let path = stack.join("/node_modules/");
+/// Exit code of `install --check` when the lockfile is out of date, apart from other failures
+const OUTDATED_LOCKFILE_EXIT_CODE: i32 = 3;
 
-    let dir = scoped_join("node_modules", path)?;
+/// Check that the lockfile covers package.json and nothing else, without writing anything
+async fn check_lockfile() -> Result<()> {
+    let package = read_package().await?;
+    let graph = load_graph_from_lockfile().await?;
+    let (missing, unreachable) = check_coverage(&graph, &package).await?;
+    PROGRESS_BAR.finish_and_clear();
 
-    for script_name in ["preinstall", "install", "postinstall"] {
-        if let Some(script) = root.scripts.get(script_name) {
-            PROGRESS_BAR.suspend(|| {
-                println!("Executing {script_name} script for {}", stack.join(" > "));
-            });
+    for req in &missing {
+        println!("{} {}@{}", " MISSING ".on_red(), req.name, req.version);
+    }
+    for req in &unreachable {
+        println!("{} {}@{}", " UNUSED ".on_yellow(), req.name, req.version);
+    }
 
-            let mut child = Command::new(shell().await?)
-                .arg("-c")
-                .arg(script)
-                .current_dir(&dir)
-                .env("PATH", new_path()?)
-                .spawn()?;
+    if missing.is_empty() && unreachable.is_empty() {
+        println!("{} is up to date", lockfile_path().display());
+        return Ok(());
+    }
 
-            if !child.wait().await?.success() {
-                return Err(eyre!("Install script unsuccessful"));
-            }
-        }
+    println!(
+        "{} is out of date; run `cotton install` to update it",
+        lockfile_path().display()
+    );
+    exit_with(OUTDATED_LOCKFILE_EXIT_CODE);
+}
+
+async fn install() -> Result<()> {
+    let start = Instant::now();
+
+    let result = install_packages(start).await;
+    if let Ok(packages) = result {
+        *PACKAGES.lock().unwrap() = Some(packages);
     }
 
-    Ok(())
+    if let Some(metrics) = read_config().await?.metrics {
+        report_install(
+            &metrics,
+            &InstallMetrics {
+                duration: start.elapsed(),
+                packages: *result.as_ref().unwrap_or(&0),
+                failed: result.is_err(),
+            },
+        )
+        .await;
+    }
+
+    result.map(|_| ())
 }
 
-#[async_recursion]
-async fn exec_install_scripts(tree: &DependencyTree, stack: &mut Vec<CompactString>) -> Result<()> {
-    exec_install_script(&tree.root, stack).await?;
+async fn install_packages(start: Instant) -> Result<usize> {
+    let size = match plan_install().await? {
+        PlannedInstall::Installed(size) => size,
+        PlannedInstall::Pending { config, plan } => {
+            let plan = execute_plan(Path::new(""), plan).await?;
+            finish_install(start, &config, plan).await?
+        }
+    };
 
-    stack.push(tree.root.name.clone());
-    for tree in tree.children.values() {
-        exec_install_scripts(tree, stack).await?;
+    if let Err(e) = projects::register() {
+        log_verbose(&format!("Failed to record project: {e}"));
     }
-    stack.pop().unwrap();
 
-    Ok(())
+    report_deprecated();
+
+    PROGRESS_BAR.finish_and_clear();
+
+    Ok(size)
 }
 
-async fn install() -> Result<()> {
+/// The project in the current directory, after resolving and checking its dependencies
+enum PlannedInstall {
+    /// Every package is already installed, with the number of packages
+    Installed(usize),
+    /// Packages still need to be linked into `node_modules` with `execute_plan`
+    Pending { config: Config, plan: Plan },
+}
+
+async fn plan_install() -> Result<PlannedInstall> {
     let package = read_package().await?;
+    check_package_manager(&package)?;
 
     init_storage().await?;
     let config = read_config().await?;
 
-    let start = Instant::now();
-
-    let plan = prepare_plan(&package).await?;
-    let size = tree_size(&plan.trees);
-
-    if matches!(verify_installation(&package, &plan).await, Ok(true)) {
-        log_verbose("Packages already installed")
-    } else {
-        execute_plan(plan.clone()).await?;
+    let graph = prepare_graph(&package).await?;
+    let plan = plan_graph(&package, &graph)?;
 
+    let peer_problems = check_peers(&graph, &plan);
+    if ARGS.strict_peer_deps && !peer_problems.is_empty() {
         PROGRESS_BAR.suspend(|| {
-            if size > 0 {
-                println!(
-                    "Installed {} packages in {}ms",
-                    size.yellow(),
-                    start.elapsed().as_millis().yellow()
-                )
+            for problem in &peer_problems {
+                println!("{} {problem}", " PEER ".on_red());
             }
         });
+        return Err(eyre!(
+            "{} peer dependencies are not satisfied",
+            peer_problems.len()
+        )
+        .suggestion("Add the missing peers to package.json, or remove --strict-peer-deps"));
+    }
 
-        if config.allow_install_scripts {
-            for (name, tree) in plan.trees.iter() {
-                exec_install_scripts(tree, &mut vec![name.clone()]).await?;
+    let engine_problems = check_engines(&package, &graph).await;
+    if config.engine_strict && !engine_problems.is_empty() {
+        PROGRESS_BAR.suspend(|| {
+            for problem in &engine_problems {
+                println!("{} {problem}", " ENGINE ".on_red());
             }
+        });
+        return Err(eyre!(
+            "{} packages do not support the active node version",
+            engine_problems.len()
+        )
+        .suggestion(
+            "Switch to a supported node version, or remove `engine_strict` from cotton.toml",
+        ));
+    }
+
+    if matches!(verify_installation(&package, &plan).await, Ok(true)) {
+        log_verbose("Packages already installed");
+
+        // Markers only record what was installed, and not whether files were deleted since
+        if ARGS.check_files {
+            verify::repair_missing_files(&plan).await?;
         }
 
-        write_json("node_modules/.cotton/plan.json", &plan).await?;
+        return Ok(PlannedInstall::Installed(tree_size(&plan.trees)));
     }
 
-    PROGRESS_BAR.finish_and_clear();
+    for problem in &peer_problems {
+        log_warning(&problem.to_string());
+    }
+    for problem in &engine_problems {
+        log_warning(&problem.to_string());
+    }
 
-    Ok(())
+    check_plan(&plan)?;
+
+    Ok(PlannedInstall::Pending { config, plan })
+}
+
+/// Run install scripts and record the installation, after the packages of the project in the
+/// current directory were linked
+async fn finish_install(start: Instant, config: &Config, plan: Plan) -> Result<usize> {
+    let size = tree_size(&plan.trees);
+
+    if ARGS.check_files {
+        verify::repair_missing_files(&plan).await?;
+    }
+
+    PROGRESS_BAR.suspend(|| {
+        if size > 0 {
+            println!(
+                "Installed {} packages in {}ms",
+                size.yellow(),
+                start.elapsed().as_millis().yellow()
+            )
+        }
+    });
+
+    // Scripts would build native code for this machine instead of the target
+    if config.allow_install_scripts && is_cross_platform() {
+        log_warning("Install scripts are not run when installing for another platform");
+    } else if config.allow_install_scripts {
+        let tasks = install_script_tasks(&plan);
+        if config.defer_scripts {
+            defer_install_scripts(&tasks).await?;
+        } else {
+            run_install_scripts(&tasks).await?;
+        }
+    }
+
+    link_deferred_bins(plan.trees.values())?;
+
+    write_json("node_modules/.cotton/plan.json", &plan).await?;
+    InstallMarker::new(&plan).await?.save().await?;
+
+    Ok(size)
 }
 
 fn new_path() -> Result<OsString> {
@@ -272,13 +889,107 @@ fn join_paths() -> Result<()> {
 }
 
 pub async fn init_storage() -> Result<()> {
-    create_dir_all(".cotton/store").await?;
+    check_permissions()?;
+
+    create_dir_all(store_dir()).await?;
     create_dir_all("node_modules/.cotton").await?;
     create_dir_all("node_modules/.bin").await?;
 
     Ok(())
 }
 
+/// The specifier to save for `name@requested`, where `requested` is a dist-tag, version or range
+async fn added_version(
+    name: &str,
+    requested: &str,
+    pin: bool,
+    prefix: SavePrefix,
+    fallback: LatestFallback,
+) -> Result<String> {
+    let version = if requested == "latest" {
+        fetch_latest(name, fallback).await?
+    } else if let Ok(range) = Range::parse(requested) {
+        let res = fetch_package(name).await?;
+        let (version, _) = res
+            .resolve_range(
+                &VersionSpecifier::Range(range),
+                prerelease_policy(name).await?,
+                ARGS.resolution_mode.direct(),
+                ARGS.before,
+            )
+            .wrap_err_with(|| eyre!("No version of {name} satisfies {requested}"))?;
+
+        // Ranges and versions are saved as written, unless the version should be pinned
+        if !pin {
+            return Ok(requested.to_string());
+        }
+        version.clone()
+    } else {
+        let tags = fetch_dist_tags(name).await?;
+        let tag = tags.get(requested).wrap_err_with(|| {
+            eyre!("Package {name} does not have a `{requested}` tag")
+                .with_note(|| format!("Available tags: {}", tags.keys().sorted().join(", ")))
+        })?;
+        let version = Version::parse(tag)?;
+        if ARGS.before.is_some() {
+            fetch_package(name)
+                .await?
+                .tagged_in_time(name, version, ARGS.before)?
+        } else {
+            version
+        }
+    };
+
+    Ok(if pin {
+        version.to_string()
+    } else {
+        format!("{prefix}{version}")
+    })
+}
+
+/// Refuse to add runtime dependencies to a private workspace root, since they belong in one of
+/// its members
+async fn check_workspace_root() -> Result<()> {
+    let package: Value = read_package_or_default().await?;
+    if package["private"].as_bool() != Some(true) || package["workspaces"].is_null() {
+        return Ok(());
+    }
+
+    let members = find_workspaces(&read_package().await?)
+        .await?
+        .into_iter()
+        .map(|x| x.dir.display().to_string())
+        .collect_vec();
+    Err(eyre!("Refusing to add dependencies to the workspace root")
+        .with_note(|| format!("Workspaces: {}", members.join(", ")))
+        .suggestion("Run `cotton add` in the workspace that uses the packages, add them with -D for tooling, or pass --root"))
+}
+
+/// Error for removing a package that is not a dependency, pointing to the workspaces that do
+/// depend on it
+async fn not_specified(name: &str) -> Report {
+    let error = eyre!("Package `{name}` is not specified in `package.json`");
+
+    let Ok(root) = read_package().await else {
+        return error;
+    };
+    let members = find_workspaces(&root)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|x| x.package.iter_all().any(|x| x.name == name))
+        .map(|x| x.dir.display().to_string())
+        .collect_vec();
+    if members.is_empty() {
+        return error;
+    }
+
+    error.suggestion(format!(
+        "It is a dependency of {}; run `cotton remove` there",
+        members.join(", ")
+    ))
+}
+
 async fn add_packages(names: &[CompactString], dev: bool, pin: bool) -> Result<()> {
     let mut package: Value = read_package_or_default().await?;
     let dependencies = package
@@ -293,28 +1004,29 @@ async fn add_packages(names: &[CompactString], dev: bool, pin: bool) -> Result<(
         .as_object_mut()
         .wrap_err("`package.json` contains non-object dependencies field")?;
 
+    let config = read_config().await?;
+    let pin = pin || config.save_exact;
+
     log_progress("Resolving packages");
 
-    for (name, res) in try_join_all(names.iter().map(|name| async move {
+    for (name, version) in try_join_all(names.iter().map(|spec| async move {
         PROGRESS_BAR.inc_length(1);
-        let x = fetch_package(name).await.map(|res| (name, res));
+        let (name, requested) = split_spec(spec).unwrap_or((spec, "latest"));
+        let x = added_version(
+            name,
+            requested,
+            pin,
+            config.save_prefix,
+            config.latest_fallback,
+        )
+        .await
+        .map(|version| (name, version));
         PROGRESS_BAR.inc(1);
         log_progress(&format!("Resolved {name}"));
         x
     }))
     .await?
     {
-        let latest = res
-            .dist_tags
-            .get("latest")
-            .wrap_err("Package `latest` tag not specified")?;
-
-        let version = if pin {
-            latest.to_string()
-        } else {
-            format!("^{latest}")
-        };
-
         dependencies.insert(name.to_string(), Value::String(version.to_string()));
 
         PROGRESS_BAR.suspend(|| println!("Added {} {}", name.yellow(), version.yellow()));
@@ -325,55 +1037,259 @@ async fn add_packages(names: &[CompactString], dev: bool, pin: bool) -> Result<(
     Ok(())
 }
 
-pub async fn shell() -> Result<String> {
-    for candidate in [
-        "/bin/zsh",
-        "/usr/bin/zsh",
-        "/bin/bash",
-        "/usr/bin/bash",
-        "/bin/sh",
-        "/usr/bin/sh",
-    ] {
-        if metadata(candidate).await.is_ok() {
-            return Ok(candidate.to_string());
+async fn remove_packages(names: &[CompactString], dev: bool) -> Result<()> {
+    let mut package: Value = read_package_or_default().await?;
+    let dependencies = package
+        .as_object_mut()
+        .wrap_err("`package.json` is invalid")?
+        .entry(if dev {
+            "devDependencies"
+        } else {
+            "dependencies"
+        })
+        .or_insert(Value::Object(Default::default()))
+        .as_object_mut()
+        .wrap_err("`package.json` contains non-object dependencies field")?;
+
+    for name in names {
+        if dependencies.remove(&name.to_string()).is_none() {
+            return Err(not_specified(name).await);
         }
     }
-    Err(eyre!("No shell found"))
+
+    log_progress(&format!("Removed {} dependencies", names.len()));
+
+    save_package(&package).await?;
+
+    Ok(())
 }
 
-fn build_map(graph: &Graph) -> Result<MultiMap<(CompactString, Version), PackageSpecifier>> {
-    let mut map = MultiMap::new();
+/// Global packages are installed right away, so their changes cannot only be printed
+fn check_global_write() -> Result<()> {
+    if ARGS.no_write {
+        return Err(eyre!("--no-write cannot be used with --global"));
+    }
+    Ok(())
+}
 
-    for (from, to) in graph.relations.iter() {
-        for child_req in to.package.iter() {
-            let child_dep = graph.resolve_req(&child_req)?;
-            map.insert(
-                (child_dep.package.name.clone(), child_dep.version),
-                from.clone(),
-            );
-        }
+async fn list_packages() -> Result<()> {
+    let package = read_package().await?;
+    let installed = read_plan("node_modules/.cotton/plan.json").await.ok();
+
+    for req in package.iter_all().sorted_by(|a, b| a.name.cmp(&b.name)) {
+        let version = installed
+            .as_ref()
+            .and_then(|x| x.trees.get(&req.name))
+            .map(|x| x.root.version.to_string());
+
+        println!(
+            "{} {} {}",
+            req.name.yellow(),
+            req.version,
+            version
+                .map(|x| format!("(installed {x})").green().to_string())
+                .unwrap_or_else(|| "(not installed)".red().to_string())
+        );
     }
 
-    Ok(map)
+    Ok(())
 }
 
-#[tracing::instrument]
-fn exec_with_args(exe: &OsStr, args: &[OsString]) -> Result<()> {
-    let exe = CString::new(exe.as_bytes().to_vec()).map_err(|_| eyre!("invalid path"))?;
+async fn require_workspaces() -> Result<Vec<Workspace>> {
+    let package = read_package().await?;
+    let workspaces = find_workspaces(&package).await?;
 
-    let mut args = args
-        .iter()
-        .map(|x| CString::new(x.as_bytes().to_vec()).map_err(|_| eyre!("invalid arguments")))
-        .collect::<Result<Vec<_>>>()?;
+    if workspaces.is_empty() {
+        return Err(eyre!("No workspaces found").suggestion(
+            "Declare workspace directories in the `workspaces` field of package.json",
+        ));
+    }
 
-    args.insert(0, exe.clone());
-    execvp(&exe, &args)?;
+    Ok(workspaces)
+}
 
-    Ok(())
+async fn run_in_workspaces(task: Task<'_>, parallel: bool) -> Result<()> {
+    let workspaces = require_workspaces().await?;
+
+    workspace::run_in_workspaces(workspaces, &shell().await?, &current_dir()?, task, parallel).await
 }
 
-async fn install_bin_temp(package_name: &str) -> Result<()> {
-    let orig_dir = current_dir()?;
+/// The command npm runs for a script that is not defined in package.json
+fn default_script(name: &str) -> Option<&'static str> {
+    match name {
+        "start" if Path::new("server.js").exists() => Some("node server.js"),
+        _ => None,
+    }
+}
+
+/// Run a script declared as a task, or restore its outputs from a previous run with the same inputs
+async fn run_cached(name: &str) -> Result<()> {
+    let package = read_package().await?;
+
+    let script = package
+        .scripts
+        .get(name)
+        .wrap_err(format!("Script `{name}` is not defined"))?
+        .as_str()
+        .wrap_err(format!("Script `{name}` is not a string"))?;
+    let task = package.cotton.tasks.get(name).ok_or_else(|| {
+        eyre!("Script `{name}` is not declared as a task").suggestion(format!(
+            "Declare its inputs and outputs in package.json under `cotton.tasks.{name}`"
+        ))
+    })?;
+
+    install().await?;
+    run_deferred_scripts(script).await?;
+
+    let graph = load_graph_from_lockfile().await?;
+    let key = task_key(&package, &graph, script, task)?;
+    log_verbose(&format!("Task key of `{name}` is {key}"));
+
+    if restore_outputs(&key, task)? {
+        PROGRESS_BAR.suspend(|| println!("{} Restored outputs of `{name}`", " CACHED ".on_green()));
+        return Ok(());
+    }
+
+    let mut command = Command::new(shell().await?);
+    command.arg("-c").arg(script);
+    set_lifecycle_env(
+        &mut command,
+        name,
+        script,
+        Path::new("."),
+        &package.name,
+        package.version.as_ref().map(|x| x.to_string()).as_deref(),
+    )?;
+    let status = command.spawn()?.wait().await?;
+
+    if !status.success() {
+        exit_with(status.code().unwrap_or(1));
+    }
+
+    save_outputs(&key, task)
+}
+
+async fn run_script(name: &str, options: &RunOptions) -> Result<()> {
+    if options.recursive {
+        install().await?;
+        return run_in_workspaces(Task::Script(name), options.parallel).await;
+    }
+
+    if options.if_present
+        && !read_package().await?.scripts.contains_key(name)
+        && default_script(name).is_none()
+    {
+        log_verbose(&format!("Script `{name}` is not defined, skipping"));
+        return Ok(());
+    }
+
+    join_paths()?;
+
+    if options.cached {
+        return run_cached(name).await;
+    }
+
+    loop {
+        let child_mutex = Mutex::new(None);
+
+        race(
+            async {
+                let event = async_watch(options.watch.iter().map(|x| x.as_ref())).await?;
+                PROGRESS_BAR.suspend(|| {
+                    println!(
+                        "{} File modified: {}",
+                        " WATCH ".on_purple(),
+                        event.paths[0].to_string_lossy()
+                    )
+                });
+                PROGRESS_BAR.finish_and_clear();
+
+                Ok(())
+            },
+            async {
+                let package = read_package().await?;
+
+                let script = match package.scripts.get(name) {
+                    Some(script) => script
+                        .as_str()
+                        .wrap_err(format!("Script `{name}` is not a string"))?,
+                    None => {
+                        default_script(name).wrap_err(format!("Script `{name}` is not defined"))?
+                    }
+                };
+
+                install().await?;
+                run_deferred_scripts(script).await?;
+
+                let mut command = Command::new(shell().await?);
+                command.arg("-c").arg(script);
+                set_lifecycle_env(
+                    &mut command,
+                    name,
+                    script,
+                    Path::new("."),
+                    &package.name,
+                    package.version.as_ref().map(|x| x.to_string()).as_deref(),
+                )?;
+                let child = command.spawn()?;
+
+                let mut child_mutex = child_mutex.lock().await;
+                *child_mutex = Some(child);
+
+                let exit_code = child_mutex.as_mut().unwrap().wait().await?.code();
+
+                if let Some(exit_code) = exit_code {
+                    exit_with(exit_code);
+                }
+
+                Ok(()) as Result<_>
+            },
+        )
+        .await?;
+
+        let mut child = child_mutex.lock().await;
+        if let Some(child) = child.as_mut() {
+            if let Some(pid) = child.id() {
+                signal::kill(Pid::from_raw(pid as _), Signal::SIGINT)?;
+                child.wait().await?;
+            }
+        }
+    }
+}
+
+pub async fn shell() -> Result<String> {
+    for candidate in [
+        "/bin/zsh",
+        "/usr/bin/zsh",
+        "/bin/bash",
+        "/usr/bin/bash",
+        "/bin/sh",
+        "/usr/bin/sh",
+    ] {
+        if metadata(candidate).await.is_ok() {
+            return Ok(candidate.to_string());
+        }
+    }
+    Err(eyre!("No shell found"))
+}
+
+#[tracing::instrument]
+fn exec_with_args(exe: &OsStr, args: &[OsString]) -> Result<()> {
+    let exe = CString::new(exe.as_bytes().to_vec()).map_err(|_| eyre!("invalid path"))?;
+
+    let mut args = args
+        .iter()
+        .map(|x| CString::new(x.as_bytes().to_vec()).map_err(|_| eyre!("invalid arguments")))
+        .collect::<Result<Vec<_>>>()?;
+
+    args.insert(0, exe.clone());
+    execvp(&exe, &args)?;
+
+    Ok(())
+}
+
+async fn install_bin_temp(package_name: &str) -> Result<()> {
+    let orig_dir = current_dir()?;
 
     let dir_name: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -390,10 +1306,7 @@ async fn install_bin_temp(package_name: &str) -> Result<()> {
     save_package(&Value::Object(Map::new())).await?;
     add_packages(&[package_name.to_compact_string()], false, false).await?;
     install().await?;
-    set_var(
-        "npm_config_user_agent",
-        "yarn/1.22.19 npm/none cotton/0.0.0",
-    );
+    set_var("npm_config_user_agent", USER_AGENT);
     symlink(current_exe()?, "node_modules/.bin/yarn")?;
     join_paths()?;
 
@@ -403,7 +1316,85 @@ async fn install_bin_temp(package_name: &str) -> Result<()> {
     Ok(())
 }
 
-pub static ARGS: Lazy<Args> = Lazy::new(Args::parse);
+const USER_AGENT: &str = "yarn/1.22.19 npm/none cotton/0.0.0";
+
+async fn print_env() -> Result<()> {
+    let config = read_config().await?;
+
+    println!("{}", "Environment".bold());
+    println!("PATH={}", new_path()?.to_string_lossy());
+    println!("npm_config_user_agent={USER_AGENT}");
+    println!();
+
+    println!("{}", "Shell".bold());
+    println!("{}", shell().await?);
+    println!();
+
+    println!("{}", "Registries".bold());
+    let registries = if config.registry.is_empty() {
+        vec![Registry::npm()]
+    } else {
+        config.registry
+    };
+    for registry in registries {
+        println!(
+            "{} (scope: {}, auth: {})",
+            registry.url.yellow(),
+            registry.scope.as_deref().unwrap_or("*"),
+            registry
+                .auth
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "none".into())
+        );
+    }
+
+    Ok(())
+}
+
+/// Expand the first subcommand if it matches an alias defined in `cotton.toml`
+fn expand_aliases(mut args: Vec<OsString>) -> Vec<OsString> {
+    let aliases = match read_config_sync() {
+        Ok(config) => config.alias,
+        Err(_) => return args,
+    };
+    if aliases.is_empty() {
+        return args;
+    }
+
+    let command = Args::command();
+    let takes_value: Vec<_> = command
+        .get_arguments()
+        .filter(|x| x.get_action().takes_values())
+        .flat_map(|x| {
+            x.get_long()
+                .into_iter()
+                .chain(x.get_all_aliases().unwrap_or_default())
+        })
+        .map(|x| format!("--{x}"))
+        .collect();
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].to_string_lossy().to_string();
+        if takes_value.contains(&arg) {
+            i += 2;
+        } else if arg.starts_with('-') {
+            i += 1;
+        } else {
+            if command.find_subcommand(&arg).is_none() {
+                if let Some(expansion) = aliases.get(&arg) {
+                    args.splice(i..=i, expansion.split_whitespace().map(OsString::from));
+                }
+            }
+            break;
+        }
+    }
+
+    args
+}
+
+pub static ARGS: Lazy<Args> =
+    Lazy::new(|| Args::parse_from(expand_aliases(env::args_os().collect())));
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -413,13 +1404,80 @@ async fn main() -> Result<()> {
 
     color_eyre::install()?;
 
+    Lazy::force(&START);
+    Lazy::force(&RESULT_FILE);
+
+    // Nested invocations keep the directory that the outermost one was started in
+    if env::var_os("INIT_CWD").is_none() {
+        set_var("INIT_CWD", current_dir()?);
+    }
+
     if let Some(cwd) = &ARGS.working_dir {
         set_current_dir(cwd)?;
     }
 
+    set_var("npm_config_user_agent", user_agent());
+    if let Some(mode) = ARGS.mode {
+        set_var("NODE_ENV", mode.to_string());
+    }
+    if let Ok(config) = read_config().await {
+        let registry = config
+            .registry
+            .into_iter()
+            .find(|x| x.scope.is_none())
+            .unwrap_or_else(Registry::npm);
+        set_var("npm_config_registry", registry.url);
+    }
+
+    if let Some(path) = &ARGS.replay {
+        load_session(path)
+            .await
+            .wrap_err_with(|| format!("Failed to load session {}", path.display()))?;
+    }
+
+    // Failed runs are recorded as well, since they are usually the ones worth reproducing
+    let result = run().await.map_err(|e| {
+        // Requests blocked by `--no-network` only report their url
+        if ARGS.no_network && error_class(&e) == "network" {
+            e.note(NO_NETWORK)
+        } else {
+            e
+        }
+    });
+
+    if let Some(path) = &ARGS.record_session {
+        let count = save_session(path).await?;
+        PROGRESS_BAR.suspend(|| {
+            println!(
+                "Recorded {} registry responses to {}",
+                count.yellow(),
+                path.display()
+            )
+        });
+    }
+
+    if let Err(e) = write_result(if result.is_ok() { 0 } else { 1 }, result.as_ref().err()) {
+        log_warning(&format!("Failed to write result file: {e}"));
+    }
+
+    result?;
+
+    PROGRESS_BAR.finish_and_clear();
+
+    exit(0);
+}
+
+async fn run() -> Result<()> {
     match &ARGS.cmd {
-        Subcommand::Install => {
+        Subcommand::Install { check: true } => {
+            check_lockfile().await?;
+        }
+        Subcommand::Install { check: false } => {
             install().await?;
+
+            if read_config().await?.allow_install_scripts {
+                run_lifecycle_script(Path::new("."), &read_package().await?, "prepare").await?;
+            }
         }
         Subcommand::Update => {
             if ARGS.immutable {
@@ -429,14 +1487,35 @@ async fn main() -> Result<()> {
             }
 
             let package = read_package().await?;
+            check_package_manager(&package)?;
 
             init_storage().await?;
 
             let start = Instant::now();
 
-            let mut graph = Graph::default();
-            graph.append(package.iter_all(), false).await?;
-            write_json("cotton.lock", Lockfile::new(graph.clone())).await?;
+            // Resolutions of another mode must not be reused, nor recorded for later reuse
+            let ttl = read_config()
+                .await?
+                .resolution_ttl
+                .filter(|_| ARGS.resolution_mode == ResolutionMode::Highest);
+
+            let mut graph = match ttl {
+                Some(ttl) => {
+                    memo::fresh_graph(load_graph_from_lockfile().await.unwrap_or_default(), ttl)
+                        .await?
+                }
+                None => Graph::default(),
+            };
+            // Local packages may have changed on disk since they were resolved
+            forget_local(&mut graph);
+            let reused = graph.relations.keys().cloned().collect();
+
+            resolve_graph(&mut graph, &package, false).await?;
+            save_lockfile(&graph).await?;
+
+            if ttl.is_some() {
+                memo::record(&graph, &reused).await?;
+            }
 
             PROGRESS_BAR.suspend(|| {
                 println!(
@@ -446,78 +1525,114 @@ async fn main() -> Result<()> {
                 )
             });
         }
-        Subcommand::Add { names, dev, pin } => {
+        Subcommand::Add {
+            names,
+            dev,
+            pin,
+            global,
+            root,
+        } => {
             if names.is_empty() {
                 PROGRESS_BAR.suspend(|| println!("Note: no packages specified"));
             }
 
-            add_packages(names, *dev, *pin).await?;
+            if *global {
+                check_global_write()?;
+                enter_global().await?;
+                add_packages(names, *dev, *pin).await?;
+                install().await?;
+                link_global_bins().await?;
+
+                let bin_dir = global_bin_dir()?;
+                let path = env::var_os("PATH").unwrap_or_default();
+                if !env::split_paths(&path).any(|x| x == bin_dir) {
+                    PROGRESS_BAR.suspend(|| {
+                        println!(
+                            "Note: add {} to PATH to use globally installed bins",
+                            bin_dir.to_string_lossy().yellow()
+                        )
+                    });
+                }
+            } else {
+                if !*dev && !*root {
+                    check_workspace_root().await?;
+                }
+                add_packages(names, *dev, *pin).await?;
+            }
         }
-        Subcommand::Run { name, watch } => {
-            join_paths()?;
-
-            loop {
-                let child_mutex = Mutex::new(None);
-
-                race(
-                    async {
-                        let event = async_watch(watch.iter().map(|x| x.as_ref())).await?;
-                        PROGRESS_BAR.suspend(|| {
-                            println!(
-                                "{} File modified: {}",
-                                " WATCH ".on_purple(),
-                                event.paths[0].to_string_lossy()
-                            )
-                        });
-                        PROGRESS_BAR.finish_and_clear();
-
-                        Ok(())
-                    },
-                    async {
-                        let package = read_package().await?;
-
-                        let script = package
-                            .scripts
-                            .get(name)
-                            .wrap_err(format!("Script `{name}` is not defined"))?
-                            .as_str()
-                            .wrap_err(format!("Script `{name}` is not a string"))?;
-
-                        install().await?;
-
-                        let child = Command::new(shell().await?).arg("-c").arg(script).spawn()?;
-
-                        let mut child_mutex = child_mutex.lock().await;
-                        *child_mutex = Some(child);
-
-                        let exit_code = child_mutex.as_mut().unwrap().wait().await?.code();
-
-                        if let Some(exit_code) = exit_code {
-                            exit(exit_code);
-                        }
-
-                        Ok(()) as Result<_>
-                    },
-                )
-                .await?;
+        Subcommand::Run { name, options } => {
+            run_script(name, options).await?;
+        }
+        Subcommand::Test { options } => {
+            run_script("test", options).await?;
+        }
+        Subcommand::Start { options } => {
+            run_script("start", options).await?;
+        }
+        Subcommand::Stop { options } => {
+            run_script("stop", options).await?;
+        }
+        Subcommand::Build { options } => {
+            run_script("build", options).await?;
+        }
+        Subcommand::Clean {
+            dry_run,
+            older_than,
+            all_projects,
+            yes,
+        } => {
+            let total = match older_than {
+                Some(older_than) => clean_stale((*older_than).into(), *dry_run).await?,
+                None if *all_projects => clean_all_projects(*dry_run, *yes)?,
+                None => clean_all(*dry_run)?,
+            };
+
+            let verb = if *dry_run {
+                "Would reclaim"
+            } else {
+                "Reclaimed"
+            };
+            println!("{verb} {} in total", HumanBytes(total).yellow());
+        }
+        Subcommand::Init { from, name } => {
+            if metadata("package.json").await.is_ok() {
+                return Err(eyre!("package.json already exists"));
+            }
 
-                let mut child = child_mutex.lock().await;
-                if let Some(child) = child.as_mut() {
-                    if let Some(pid) = child.id() {
-                        signal::kill(Pid::from_raw(pid as _), Signal::SIGINT)?;
-                        child.wait().await?;
+            let name = match name {
+                Some(name) => name.clone(),
+                None => current_dir()?
+                    .file_name()
+                    .wrap_err("Cannot infer a project name from the current directory")?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let vars = TemplateVars {
+                name,
+                author: git_author().await,
+            };
+
+            match from {
+                Some(from) => init_from_template(from, Path::new("."), &vars).await?,
+                None => {
+                    let mut package = Map::new();
+                    package.insert("name".into(), vars.name.clone().into());
+                    package.insert("version".into(), "1.0.0".into());
+                    if !vars.author.is_empty() {
+                        package.insert("author".into(), vars.author.clone().into());
                     }
+                    write_json("package.json", package).await?;
                 }
             }
-        }
-        Subcommand::Clean => {
-            for dir in ["node_modules", ".cotton"] {
-                match remove_dir_all(dir) {
-                    Ok(()) => {}
-                    Err(e) if e.kind() == ErrorKind::NotFound => {}
-                    r => r?,
-                }
+
+            if metadata("package.json").await.is_ok() {
+                install().await?;
+            }
+            if metadata(".git").await.is_err() {
+                git::git(&["init", "--quiet"], None).await?;
             }
+
+            PROGRESS_BAR.suspend(|| println!("Created {}", vars.name.yellow()));
         }
         Subcommand::Upgrade { pin } => {
             let package = read_package().await?;
@@ -534,114 +1649,588 @@ async fn main() -> Result<()> {
             )
             .await?;
         }
-        Subcommand::Exec { exe, args } => {
+        Subcommand::Exec {
+            exe,
+            args,
+            recursive,
+            parallel,
+        } => {
             install().await?;
-            join_paths()?;
 
-            exec_with_args(exe, args)?;
+            if *recursive {
+                run_in_workspaces(Task::Exec(exe, args), *parallel).await?;
+            } else {
+                run_deferred_scripts(&exe.to_string_lossy()).await?;
+                join_paths()?;
+
+                exec_with_args(exe, args)?;
+            }
         }
-        Subcommand::Remove { names, dev } => {
+        Subcommand::Remove { names, dev, global } => {
             if names.is_empty() {
                 PROGRESS_BAR.suspend(|| println!("Note: no packages specified"));
             }
 
-            let mut package: Value = read_package_or_default().await?;
-            let dependencies = package
-                .as_object_mut()
-                .wrap_err("`package.json` is invalid")?
-                .entry(if *dev {
-                    "devDependencies"
-                } else {
-                    "dependencies"
-                })
-                .or_insert(Value::Object(Default::default()))
-                .as_object_mut()
-                .wrap_err("`package.json` contains non-object dependencies field")?;
+            if *global {
+                check_global_write()?;
+                enter_global().await?;
+                unlink_global_bins(names).await?;
+                remove_packages(names, *dev).await?;
+
+                // Reinstall from scratch so that removed packages and their bins are gone
+                match remove_dir_all("node_modules") {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    r => r?,
+                }
+                install().await?;
+                link_global_bins().await?;
+            } else {
+                remove_packages(names, *dev).await?;
+            }
+        }
+        Subcommand::List { global } => {
+            if *global {
+                enter_global().await?;
+            }
 
-            for name in names {
-                dependencies
-                    .remove(&name.to_string())
-                    .wrap_err(eyre!("Package `{name}` is not specified in `package.json`"))?;
+            list_packages().await?;
+        }
+        Subcommand::Why {
+            name,
+            version,
+            tree,
+            json,
+        } => {
+            let package = read_package().await?;
+
+            let graph = load_graph_from_lockfile()
+                .await?
+                .pruned(&package.iter_all().collect_vec(), &VerboseEvents);
+            let map = why::load_map(&graph).await?;
+
+            if *tree || *json {
+                why::print_tree(&package, &graph, &map, name, version.as_ref(), *json)?;
+            } else {
+                why::print_flat(&package, &graph, &map, name, version.as_ref())?;
             }
+        }
+        Subcommand::Create { name } => {
+            let name = format!("create-{name}");
+            install_bin_temp(&name).await?;
+            exec_with_args(OsStr::new(&name), &[])?;
+        }
+        Subcommand::DownloadAndExec { name, args } => {
+            if let Err(e) = which(name) {
+                log_verbose(&e.to_string());
+                install_bin_temp(name.to_str().wrap_err("package name invalid")?).await?;
+            }
+            exec_with_args(name, args)?;
+        }
+        Subcommand::Export => {
+            let package = read_package().await?;
+
+            init_storage().await?;
+
+            let graph = prepare_graph(&package).await?;
+            let plan = plan_graph(&package, &graph)?;
+            let lockfile = npm_lockfile(&package, &graph, &plan);
 
-            log_progress(&format!("Removed {} dependencies", names.len()));
+            write_json("package-lock.json", &lockfile).await?;
 
-            save_package(&package).await?;
+            PROGRESS_BAR.suspend(|| {
+                println!(
+                    "Exported {} packages to package-lock.json",
+                    (lockfile.packages.len() - 1).yellow()
+                )
+            });
         }
-        Subcommand::Why { name, version } => {
+        Subcommand::Sbom { format, output } => {
             let package = read_package().await?;
 
-            let graph = load_graph_from_lockfile().await;
+            init_storage().await?;
 
-            let map = build_map(&graph)?;
+            let graph = prepare_graph(&package).await?;
+            let sbom = sbom(&package, &graph, *format)?;
 
-            let mut seen = FxHashSet::default();
-            let mut queue = VecDeque::new();
+            PROGRESS_BAR.finish_and_clear();
 
-            if let Some(version) = version {
-                queue.push_back((name.clone(), version.clone()));
+            if let Some(output) = output {
+                write_json(output, &sbom).await?;
             } else {
-                for (req, resolved) in graph.relations.iter() {
-                    if name == req.name {
-                        queue.push_back((name.clone(), resolved.version.clone()));
-                    }
+                println!("{}", serde_json::to_string_pretty(&sbom)?);
+            }
+        }
+        Subcommand::Graph {
+            dot: _,
+            mermaid,
+            output,
+        } => {
+            let package = read_package().await?;
+
+            init_storage().await?;
+
+            let graph = prepare_graph(&package).await?;
+            let format = if *mermaid {
+                GraphFormat::Mermaid
+            } else {
+                GraphFormat::Dot
+            };
+            let rendered = render_graph(&package, &graph, format)?;
+
+            PROGRESS_BAR.finish_and_clear();
+
+            if let Some(output) = output {
+                tokio::fs::write(output, rendered).await?;
+            } else {
+                print!("{rendered}");
+            }
+        }
+        Subcommand::EmitImportmap {
+            format,
+            cdn,
+            base,
+            dev,
+            output,
+        } => {
+            let package = read_package().await?;
+            let plan = read_plan("node_modules/.cotton/plan.json")
+                .await
+                .wrap_err("Packages are not installed")
+                .suggestion("Run `cotton install` first")?;
+
+            let base = base.as_deref().unwrap_or(match format {
+                ImportMapFormat::Importmap => "/node_modules",
+                ImportMapFormat::Alias => "./node_modules",
+            });
+            let map = import_map(&package, &plan, *format, base, cdn.as_deref(), *dev).await?;
+
+            if let Some(output) = output {
+                write_json(output, &map).await?;
+            } else {
+                println!("{}", serde_json::to_string_pretty(&map)?);
+            }
+        }
+        Subcommand::Env => {
+            print_env().await?;
+        }
+        Subcommand::Tag { cmd } => match cmd {
+            TagCommand::Add { spec, tag } => {
+                let (name, version) = registry::split_spec(spec)
+                    .wrap_err("Expected a package in the form `name@version`")?;
+                let version = Version::parse(version)?;
+
+                registry::add_tag(name, &version, tag).await?;
+
+                println!("Tagged {}@{} as {}", name.yellow(), version, tag.yellow());
+            }
+            TagCommand::Rm { name, tag } => {
+                registry::remove_tag(name, tag).await?;
+
+                println!("Removed tag {} from {}", tag.yellow(), name.yellow());
+            }
+            TagCommand::Ls { name } => {
+                for (tag, version) in registry::list_tags(name).await?.into_iter().sorted() {
+                    println!("{}: {}", tag.yellow(), version);
+                }
+            }
+        },
+        Subcommand::Pack => {
+            let file = publish::pack_to_file(Path::new(".")).await?;
+            println!("Packed {}", file.display().yellow());
+        }
+        Subcommand::Publish {
+            recursive,
+            dry_run,
+            tag,
+        } => {
+            let package = read_package().await?;
+
+            let workspaces = if *recursive {
+                find_workspaces(&package).await?
+            } else {
+                vec![Workspace {
+                    name: package.name.clone(),
+                    dir: PathBuf::from("."),
+                    package,
+                }]
+            };
+
+            let published = publish::publish(workspaces, tag, *dry_run).await?;
+
+            if published.is_empty() {
+                println!("Nothing to publish");
+            } else if !*dry_run {
+                for (name, version) in published {
+                    println!("Published {}@{}", name.yellow(), version);
+                }
+            }
+        }
+        Subcommand::Changeset { cmd } => match cmd {
+            ChangesetCommand::Add {
+                bump,
+                message,
+                packages,
+            } => {
+                let path =
+                    changeset::add(&require_workspaces().await?, *bump, packages, message).await?;
+
+                println!("Created {}", path.display().yellow());
+            }
+            ChangesetCommand::Version => {
+                for (name, version) in changeset::version(require_workspaces().await?).await? {
+                    println!("Bumped {} to {}", name.yellow(), version);
                 }
             }
+            ChangesetCommand::Publish { dry_run, tag } => {
+                let published =
+                    publish::publish(require_workspaces().await?, tag, *dry_run).await?;
+
+                if published.is_empty() {
+                    println!("Nothing to publish");
+                } else if !*dry_run {
+                    changeset::tag_releases(&published).await?;
+                }
+            }
+        },
+        Subcommand::Deprecate { spec, message } => {
+            let (name, range) = match registry::split_spec(spec) {
+                Some((name, range)) => (name, range.parse()?),
+                None => (spec.as_str(), "*".parse()?),
+            };
+
+            let versions = registry::deprecate(name, &range, message).await?;
+
+            println!(
+                "{} {} versions of {}",
+                if message.is_empty() {
+                    "Undeprecated"
+                } else {
+                    "Deprecated"
+                },
+                versions.len().yellow(),
+                name.yellow()
+            );
+        }
+        Subcommand::Trust { accept, packages } => {
+            {
+                let mut store = trust::store()?;
+                let keys = store
+                    .pending
+                    .keys()
+                    .filter(|x| packages.is_empty() || packages.contains(x))
+                    .cloned()
+                    .collect_vec();
+
+                if keys.is_empty() {
+                    println!("No changed packages");
+                }
 
-            if queue.is_empty() {
-                return Err(eyre!("Package {} is not used", name));
-            }
-
-            while let Some((name, version)) = queue.pop_front() {
-                if seen.insert((name.clone(), version.clone())) {
-                    if let Some(required_by) = map.get_vec(&(name.clone(), version.clone())) {
-                        let required_by: FxHashSet<_> = required_by
-                            .iter()
-                            .map(|x| graph.resolve_req(x))
-                            .try_collect()?;
-                        if !required_by.is_empty() {
-                            println!(
-                                "{}",
-                                format!("{}@{} is used by:", name.yellow(), version).bold()
-                            );
-                            for dep in required_by {
-                                queue.push_back((dep.package.name.clone(), dep.version.clone()));
-                                println!(" - {}@{}", dep.package.name, dep.version);
-                            }
-                            println!();
+                for key in keys {
+                    if *accept {
+                        let record = store.pending.remove(&key).unwrap();
+                        store.trusted.insert(key.clone(), record);
+                        println!("Accepted {}", key.yellow());
+                    } else {
+                        println!("{}", key.yellow().bold());
+                        match store.trusted.get(&key) {
+                            Some(trusted) => println!("  trusted: {trusted}"),
+                            None => println!("  trusted: none, new package"),
                         }
-                    } else if package
-                        .iter_all()
-                        .any(|x| x.name == name && x.version.satisfies(&version))
-                    {
+                        println!("  now:     {}", store.pending[&key]);
+                    }
+                }
+            }
+
+            trust::save()?;
+        }
+        Subcommand::SelfUpdate { check, url } => {
+            // The executable is replaced with whatever the endpoint serves, so a project's
+            // cotton.toml is never consulted
+            if url.is_none() && read_config().await?.self_update_url.is_some() {
+                log_warning("Ignoring `self_update_url` in the project's cotton.toml");
+            }
+            let url = match url {
+                Some(url) => url.clone(),
+                None => read_user_config().await?.self_update_url.ok_or_else(|| {
+                    eyre!("No release endpoint is configured")
+                        .suggestion("Set `self_update_url` in ~/.cotton/cotton.toml, or pass --url")
+                        .note(format!(
+                            "Builds of cotton are published without releases at {}",
+                            self_update::BUILDS_URL
+                        ))
+                })?,
+            };
+
+            match self_update::self_update(&url, *check).await? {
+                UpdateStatus::UpToDate(version) => {
+                    println!("cotton {} is up to date", version.yellow())
+                }
+                UpdateStatus::Available(version) => {
+                    println!("cotton {} is available", version.yellow())
+                }
+                UpdateStatus::Updated(version) => {
+                    println!("Updated cotton to {}", version.yellow())
+                }
+            }
+        }
+        Subcommand::Override { cmd } => match cmd {
+            OverrideCommand::Add {
+                name,
+                version,
+                parent,
+            } => overrides::add_override(name, version, parent.as_deref()).await?,
+            OverrideCommand::Remove { name, parent } => {
+                overrides::remove_override(name, parent.as_deref()).await?
+            }
+            OverrideCommand::List => overrides::print_overrides().await?,
+        },
+        Subcommand::Store { cmd } => {
+            init_storage().await?;
+
+            match cmd {
+                StoreCommand::Export { file } => {
+                    let graph = load_graph_from_lockfile().await?;
+                    let count = bundle::export_store(&graph, file).await?;
+
+                    PROGRESS_BAR.suspend(|| {
+                        println!(
+                            "Exported {} packages to {}",
+                            count.yellow(),
+                            file.to_string_lossy()
+                        )
+                    });
+                }
+                StoreCommand::Import { file } => {
+                    bundle::import_store(file).await?;
+
+                    PROGRESS_BAR
+                        .suspend(|| println!("Imported packages from {}", file.to_string_lossy()));
+                }
+            }
+        }
+        Subcommand::Daemon => {
+            daemon::daemon().await?;
+        }
+        Subcommand::Verify { repair } => {
+            let plan = read_plan("node_modules/.cotton/plan.json")
+                .await
+                .wrap_err("Packages are not installed")
+                .suggestion("Run `cotton install` first")?;
+
+            init_storage().await?;
+            verify::verify(&plan, *repair).await?;
+        }
+        Subcommand::Doctor { paths, global } => {
+            let all = !paths && !global;
+            let mut problems = 0;
+
+            if *paths || all {
+                let plan = read_plan("node_modules/.cotton/plan.json").await.ok();
+                problems += check_paths(plan.as_ref())?;
+            }
+
+            if *global || all {
+                problems += check_global()?;
+            }
+
+            if problems > 0 {
+                return Err(eyre!("Found {problems} problems"));
+            }
+            println!("No problems found");
+        }
+        Subcommand::Stats => {
+            let package = read_package().await?;
+
+            // Only the lockfile is read, so that stats never resolve or download anything
+            let graph = load_graph_from_lockfile().await?;
+            PROGRESS_BAR.finish_and_clear();
+
+            stats::print_stats(&package, &graph)?;
+        }
+        Subcommand::Adopt { yes } => {
+            adopt::adopt(*yes).await?;
+        }
+        Subcommand::Ping { registry } => {
+            let mut registries = read_config().await?.registry;
+            // Packages outside of the configured scopes are fetched from npm
+            if registries.iter().all(|x| x.scope.is_some()) {
+                registries.push(Registry::npm());
+            }
+
+            if let Some(filter) = registry {
+                let filter = filter.trim_end_matches('/');
+                registries.retain(|x| {
+                    x.url.trim_end_matches('/') == filter || x.scope.as_deref() == Some(filter)
+                });
+                if registries.is_empty() {
+                    if !filter.starts_with("http://") && !filter.starts_with("https://") {
+                        return Err(eyre!("No registry is configured for {filter}")
+                            .suggestion("Pass the url or scope of a registry in cotton.toml"));
+                    }
+                    registries.push(Registry {
+                        url: filter.to_string(),
+                        scope: None,
+                        auth: None,
+                    });
+                }
+            }
+
+            let mut failed = 0;
+            for registry in &registries {
+                let name = match &registry.scope {
+                    Some(scope) => format!("{} ({scope})", registry.url),
+                    None => registry.url.clone(),
+                };
+                let via = registry::proxy_for(&registry.url)
+                    .map(|x| format!(" via proxy {x}"))
+                    .unwrap_or_default();
+
+                match registry::ping(registry).await {
+                    Ok(ping) => {
+                        let user = ping
+                            .username
+                            .map(|x| format!(" as {}", x.bright_blue()))
+                            .unwrap_or_default();
                         println!(
-                            "{}",
-                            format!("{}@{} is used by package.json", name.yellow(), version).bold()
+                            "{} {name}: responded in {}ms{user}{via}",
+                            " OK ".on_green(),
+                            ping.latency.as_millis().yellow()
                         );
-                        println!();
-                    } else {
-                        return Err(eyre!("Package {}@{} is not used", name, version));
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        println!("{} {name}: {e}{via}", " FAILED ".on_red());
                     }
                 }
             }
 
-            println!("Analyzed {} packages", seen.len().yellow());
+            if failed > 0 {
+                return Err(eyre!("{failed} registries failed the check"));
+            }
+        }
+        Subcommand::Hash { workspace } => {
+            let package = read_package().await?;
+            let graph = load_graph_from_lockfile().await?;
+
+            println!(
+                "{}",
+                hash::dependency_hash(&package, &graph, workspace.as_deref())?
+            );
         }
-        Subcommand::Create { name } => {
-            let name = format!("create-{name}");
-            install_bin_temp(&name).await?;
-            exec_with_args(OsStr::new(&name), &[])?;
+        Subcommand::ResolveCheck => {
+            let plan = read_plan("node_modules/.cotton/plan.json")
+                .await
+                .wrap_err("Packages are not installed")
+                .suggestion("Run `cotton install` first")?;
+
+            resolve_check::resolve_check(&plan)?;
         }
-        Subcommand::DownloadAndExec { name, args } => {
-            if let Err(e) = which(name) {
-                log_verbose(&e.to_string());
-                install_bin_temp(name.to_str().wrap_err("package name invalid")?).await?;
+        Subcommand::Complete { kind } => {
+            // Completion must stay fast, so only local files are read
+            let candidates = match kind {
+                CompletionKind::Scripts => read_package()
+                    .await
+                    .map(|x| x.scripts.into_keys().map(|x| x.to_string()).collect())
+                    .unwrap_or_default(),
+                CompletionKind::Packages => read_plan("node_modules/.cotton/plan.json")
+                    .await
+                    .map(|x| x.trees.into_keys().map(|x| x.to_string()).collect())
+                    .unwrap_or_default(),
+                CompletionKind::Workspaces => match read_package().await {
+                    Ok(package) => find_workspaces(&package)
+                        .await?
+                        .into_iter()
+                        .map(|x| x.name.to_string())
+                        .collect(),
+                    Err(_) => vec![],
+                },
+                CompletionKind::Commands => Args::command()
+                    .get_subcommands()
+                    .filter(|x| !x.is_hide_set())
+                    .map(|x| x.get_name().to_string())
+                    .chain(read_config().await?.alias.into_keys())
+                    .collect_vec(),
+            };
+
+            for candidate in candidates.into_iter().sorted() {
+                println!("{candidate}");
             }
-            exec_with_args(name, args)?;
         }
-    }
+        Subcommand::Ci { projects } => {
+            let projects: Vec<_> = projects.iter().map(|x| x.canonicalize()).try_collect()?;
 
-    PROGRESS_BAR.finish_and_clear();
+            // All projects are installed from the store of the first project, so that every
+            // package is downloaded once and hardlinked into each project
+            let store = projects[0].join(".cotton/store");
+            create_dir_all(&store).await?;
+            STORE_DIR
+                .set(store)
+                .map_err(|_| eyre!("Store directory is already set"))?;
 
-    exit(0);
+            let start = Instant::now();
+
+            // Resolving reads the project from the current directory, so projects are resolved
+            // one at a time
+            let mut pending = vec![];
+            for project in &projects {
+                set_current_dir(project)?;
+                PROGRESS_BAR.suspend(|| {
+                    println!("{} {}", " PROJECT ".on_blue(), project.to_string_lossy())
+                });
+
+                if let PlannedInstall::Pending { config, plan } = plan_install().await? {
+                    pending.push((project, config, plan));
+                }
+            }
+
+            // Linking only writes to the project's `node_modules` and the shared store, so all
+            // projects are linked at once
+            let linked = try_join_all(pending.into_iter().map(
+                |(project, config, plan)| async move {
+                    let plan = execute_plan(project, plan).await?;
+                    Result::<_>::Ok((project, config, plan))
+                },
+            ))
+            .await?;
+
+            // Install scripts run in the project directory, so projects are finished one at a time
+            for (project, config, plan) in linked {
+                set_current_dir(project)?;
+                let size = finish_install(start, &config, plan).await?;
+
+                if let Some(metrics) = &config.metrics {
+                    report_install(
+                        metrics,
+                        &InstallMetrics {
+                            duration: start.elapsed(),
+                            packages: size,
+                            failed: false,
+                        },
+                    )
+                    .await;
+                }
+            }
+
+            for project in &projects {
+                set_current_dir(project)?;
+                if let Err(e) = projects::register() {
+                    log_verbose(&format!("Failed to record project: {e}"));
+                }
+            }
+
+            report_deprecated();
+            PROGRESS_BAR.finish_and_clear();
+
+            PROGRESS_BAR.suspend(|| {
+                println!(
+                    "Installed {} projects in {}ms",
+                    projects.len().yellow(),
+                    start.elapsed().as_millis().yellow()
+                )
+            });
+        }
+    }
+
+    Ok(())
 }
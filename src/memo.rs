@@ -0,0 +1,82 @@
+use color_eyre::eyre::Result;
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs::create_dir_all;
+
+use crate::{
+    npm::select_registry,
+    package::PackageSpecifier,
+    progress::log_verbose,
+    resolve::Graph,
+    util::{read_json, write_json},
+};
+
+const MEMO_PATH: &str = ".cotton/resolution-memo.json";
+
+/// When and against which registry a range was resolved
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Resolution {
+    registry: String,
+    resolved_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ResolutionMemo(BTreeMap<PackageSpecifier, Resolution>);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Keep the locked resolutions that were made against the same registry within `ttl`
+pub async fn fresh_graph(locked: Graph, ttl: Duration) -> Result<Graph> {
+    let memo: ResolutionMemo = read_json(MEMO_PATH).await.unwrap_or_default();
+    let now = now();
+
+    let mut graph = Graph::default();
+    for (req, pkg) in locked.relations {
+        let Some(resolution) = memo.0.get(&req) else {
+            continue;
+        };
+        if now.saturating_sub(resolution.resolved_at) > ttl.as_secs()
+            || select_registry(&req.name).await?.url != resolution.registry
+        {
+            continue;
+        }
+        graph.relations.insert(req, pkg);
+    }
+
+    log_verbose(&format!(
+        "Reusing {} resolutions from previous updates",
+        graph.relations.len()
+    ));
+
+    Ok(graph)
+}
+
+/// Record when each range in the graph was resolved, keeping the times of reused resolutions
+pub async fn record(graph: &Graph, reused: &FxHashSet<PackageSpecifier>) -> Result<()> {
+    let old: ResolutionMemo = read_json(MEMO_PATH).await.unwrap_or_default();
+    let now = now();
+
+    let mut memo = ResolutionMemo::default();
+    for req in graph.relations.keys() {
+        let resolution = match old.0.get(req) {
+            Some(x) if reused.contains(req) => x.clone(),
+            _ => Resolution {
+                registry: select_registry(&req.name).await?.url,
+                resolved_at: now,
+            },
+        };
+        memo.0.insert(req.clone(), resolution);
+    }
+
+    create_dir_all(".cotton").await?;
+    write_json(MEMO_PATH, memo).await
+}
@@ -0,0 +1,136 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{rename, write},
+    net::UdpSocket,
+};
+
+use crate::{progress::log_warning, util::check_network};
+
+pub static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+pub static CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of a statsd server, such as `127.0.0.1:8125`
+    pub statsd: Option<String>,
+    /// Path of a Prometheus textfile collector file
+    pub textfile: Option<PathBuf>,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    "cotton".into()
+}
+
+pub struct InstallMetrics {
+    pub duration: Duration,
+    pub packages: usize,
+    pub failed: bool,
+}
+
+impl InstallMetrics {
+    fn values(&self) -> [(&'static str, &'static str, f64); 5] {
+        [
+            (
+                "install_duration_seconds",
+                "Duration of the last install",
+                self.duration.as_secs_f64(),
+            ),
+            (
+                "install_packages",
+                "Number of packages in the last install",
+                self.packages as f64,
+            ),
+            (
+                "install_downloads",
+                "Number of packages downloaded during the last install",
+                DOWNLOADS.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "install_cache_hits",
+                "Number of packages already present in the store during the last install",
+                CACHE_HITS.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "install_failed",
+                "Whether the last install failed",
+                if self.failed { 1. } else { 0. },
+            ),
+        ]
+    }
+}
+
+async fn send_statsd(addr: &str, prefix: &str, metrics: &InstallMetrics) -> Result<()> {
+    let mut lines = vec![
+        format!(
+            "{prefix}.install.duration:{}|ms",
+            metrics.duration.as_millis()
+        ),
+        format!("{prefix}.install.packages:{}|g", metrics.packages),
+        format!(
+            "{prefix}.install.downloads:{}|c",
+            DOWNLOADS.load(Ordering::Relaxed)
+        ),
+        format!(
+            "{prefix}.install.cache_hits:{}|c",
+            CACHE_HITS.load(Ordering::Relaxed)
+        ),
+    ];
+
+    if metrics.failed {
+        lines.push(format!("{prefix}.install.failures:1|c"));
+    }
+
+    check_network(&format!("statsd at {addr}"))?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.send_to(lines.join("\n").as_bytes(), addr).await?;
+
+    Ok(())
+}
+
+async fn write_textfile(path: &PathBuf, prefix: &str, metrics: &InstallMetrics) -> Result<()> {
+    let mut out = String::new();
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut values = metrics.values().to_vec();
+    values.push((
+        "install_last_run_timestamp_seconds",
+        "Time of the last install",
+        timestamp as f64,
+    ));
+
+    for (name, help, value) in values {
+        out += &format!("# HELP {prefix}_{name} {help}\n");
+        out += &format!("# TYPE {prefix}_{name} gauge\n");
+        out += &format!("{prefix}_{name} {value}\n");
+    }
+
+    // Write to a temporary file first, so that the collector never reads a partial file
+    let tmp = path.with_extension("prom.tmp");
+    write(&tmp, out).await?;
+    rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+pub async fn report_install(config: &MetricsConfig, metrics: &InstallMetrics) {
+    if let Some(addr) = &config.statsd {
+        if let Err(e) = send_statsd(addr, &config.prefix, metrics).await {
+            log_warning(&format!("Failed to send metrics to statsd: {e}"));
+        }
+    }
+
+    if let Some(path) = &config.textfile {
+        if let Err(e) = write_textfile(path, &config.prefix, metrics).await {
+            log_warning(&format!("Failed to write metrics textfile: {e}"));
+        }
+    }
+}
@@ -3,72 +3,115 @@ use async_recursion::async_recursion;
 use cached::proc_macro::cached;
 use color_eyre::{
     eyre::{eyre, ContextCompat, Result},
-    Report,
+    Report, Section,
 };
 use compact_str::{CompactString, ToCompactString};
 use futures::TryStreamExt;
-use indexmap::IndexMap;
 use itertools::Itertools;
 use node_semver::Version;
 use once_cell::sync::Lazy;
 use owo_colors::OwoColorize;
-use rustc_hash::{FxHashMap, FxHashSet};
-use serde::{Deserialize, Serialize};
+use reqwest::Url;
+use rustc_hash::FxHashMap;
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    path::MAIN_SEPARATOR,
-    sync::Arc,
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
 };
-use std::{fmt::Debug, io};
 use tap::Pipe;
-use tokio::{io::AsyncReadExt, sync::Semaphore};
+use tokio::io::{AsyncBufRead, AsyncReadExt};
 use tokio_tar::Archive;
 use tokio_util::io::StreamReader;
 
 use crate::{
     cache::Cache,
-    config::{client_auth, read_config, Registry},
-    package::{Dist, PackageInfo, PackageMetadata, PackageSpecifier},
-    progress::{log_progress, log_verbose},
-    util::{decode_json, retry, ArcResult, VersionSpecifier, CLIENT, CLIENT_LIMIT, CLIENT_Z},
+    concurrency,
+    config::{client_auth, read_config, LatestFallback, PrereleasePolicy, Registry},
+    git::{fetch_git_package, fetch_github_package, GitHubSource, GitSource},
+    local::{
+        check_remote_dependencies, fetch_local_package, fetch_local_tarball, linked_dir, local_dir,
+        local_tarball,
+    },
+    package::{PackageInfo, PackageMetadata, PackageSpecifier},
+    pin::check_resolved,
+    progress::{log_progress, log_verbose, log_warning, PROGRESS_BAR},
+    session::registry_response,
+    util::{decode_json, retry, ArcResult, VersionSpecifier, CLIENT, CLIENT_Z},
+    workspace::{fetch_workspace_package, workspace_range},
+    ARGS,
 };
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
-pub struct RegistryResponse {
-    #[serde(rename = "dist-tags")]
-    pub dist_tags: FxHashMap<CompactString, CompactString>,
-    pub versions: IndexMap<Version, PackageMetadata>,
-}
+pub use cotton_core::{
+    npm::{Cutoff, Packument},
+    plan::{Dependency, DependencyTree},
+    platform::PlatformMap,
+    resolve::Strategy,
+};
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
-pub struct PlatformMap(BTreeSet<CompactString>);
+/// A packument, with the full metadata of each version
+pub type RegistryResponse = Packument<PackageMetadata>;
+
+/// Which versions `install` and `update` resolve requirements to
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ResolutionMode {
+    /// The highest version that satisfies each requirement
+    #[default]
+    Highest,
+    /// The lowest version for the dependencies in package.json, to check their ranges, and the
+    /// highest for the rest
+    LowestDirect,
+}
 
-impl PlatformMap {
-    pub fn allowed(&self) -> impl Iterator<Item = &str> {
-        self.0
-            .iter()
-            .filter(|x| !x.starts_with('!'))
-            .map(|x| x.as_str())
+impl ResolutionMode {
+    pub fn direct(self) -> Strategy {
+        match self {
+            ResolutionMode::Highest => Strategy::Highest,
+            ResolutionMode::LowestDirect => Strategy::Lowest,
+        }
     }
+}
 
-    pub fn blocked(&self) -> impl Iterator<Item = &str> {
-        self.0.iter().filter_map(|x| x.strip_prefix('!'))
+/// The version of the default tag of the package's scope or of `latest`, warning when the package
+/// has neither
+fn latest_version(
+    res: &RegistryResponse,
+    name: &str,
+    default_tag: Option<&str>,
+    fallback: LatestFallback,
+) -> Result<Version> {
+    let version = res.latest(name, default_tag, fallback, ARGS.before)?;
+
+    if !res.is_tagged(default_tag) {
+        log_warning(&format!(
+            "Package {} does not specify a `latest` tag, using {}",
+            name.bright_blue(),
+            version.yellow()
+        ));
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
-    }
+    Ok(version)
+}
 
-    pub fn is_supported(&self, platform: &str) -> bool {
-        if self.is_empty() {
-            true
-        } else {
-            self.allowed().any(|o| o == platform) && !self.blocked().any(|o| o == platform)
-        }
+/// How ranges of a package treat prereleases, where packages in `allow_prerelease` take precedence
+/// over `--prerelease`
+pub async fn prerelease_policy(name: &str) -> Result<PrereleasePolicy> {
+    let config = read_config().await?;
+    if config.allow_prerelease.iter().any(|x| x == name) {
+        return Ok(PrereleasePolicy::Highest);
     }
+    Ok(ARGS.prerelease.unwrap_or(config.prerelease))
+}
+
+/// The dist-tag to use instead of `latest` for a package, from `default_tag` in cotton.toml
+pub async fn default_tag(name: &str) -> Result<Option<String>> {
+    let config = read_config().await?;
+    Ok(name
+        .split_once('/')
+        .and_then(|(scope, _)| config.default_tag.get(scope))
+        .cloned())
 }
 
-async fn select_registry(name: &str) -> Result<Registry> {
+pub async fn select_registry(name: &str) -> Result<Registry> {
     for registry in read_config().await?.registry {
         if let Some(scope) = &registry.scope {
             if name.starts_with(scope) {
@@ -79,34 +122,33 @@ async fn select_registry(name: &str) -> Result<Registry> {
         }
     }
 
-    Ok(Registry {
-        url: "https://registry.npmjs.org".into(),
-        scope: None,
-        auth: None,
-    })
+    Ok(Registry::npm())
 }
 
 #[tracing::instrument]
 pub async fn fetch_package(name: &str) -> Result<Arc<RegistryResponse>> {
     #[tracing::instrument]
     async fn fetch_package(name: &str) -> Result<RegistryResponse> {
-        static S: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(CLIENT_LIMIT));
-        let _permit = S.acquire().await.unwrap();
-
         let selected_registry = select_registry(name).await?;
 
         retry(|| async {
-            decode_json(
-                &CLIENT_Z
-                    .get(format!("{}/{name}", selected_registry.url))
-                    .pipe(|x| client_auth(x, selected_registry.auth.as_ref()))?
-                    .send()
-                    .await?
-                    .error_for_status()?
-                    .bytes()
-                    .await?,
-            )
-            .map_err(|e| eyre!("[{name}] {e}"))
+            let bytes = registry_response(format!("packuments/{name}"), || async {
+                let url = format!("{}/{name}", selected_registry.url);
+                let request = CLIENT_Z
+                    .get(&url)
+                    .pipe(|x| client_auth(x, selected_registry.auth.as_ref()))?;
+
+                let mut permit = concurrency::acquire(&url).await;
+                let res = request.send().await;
+                permit.finish(&res);
+
+                let bytes = res?.error_for_status()?.bytes().await?.to_vec();
+                drop(permit);
+                Ok(bytes)
+            })
+            .await?;
+
+            decode_json(&bytes).map_err(|e| eyre!("[{name}] {e}"))
         })
         .await
     }
@@ -123,20 +165,198 @@ pub async fn fetch_package(name: &str) -> Result<Arc<RegistryResponse>> {
         .map_err(Report::msg)
 }
 
+/// Fetch only the dist-tags of a package, which is much smaller than the full packument
+#[tracing::instrument]
+pub async fn fetch_dist_tags(name: &str) -> Result<FxHashMap<CompactString, CompactString>> {
+    let selected_registry = select_registry(name).await?;
+
+    let bytes = registry_response(format!("dist-tags/{name}"), || async {
+        Ok(CLIENT_Z
+            .get(format!(
+                "{}/-/package/{}/dist-tags",
+                selected_registry.url,
+                name.replace('/', "%2f")
+            ))
+            .pipe(|x| client_auth(x, selected_registry.auth.as_ref()))?
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec())
+    })
+    .await?;
+
+    Ok(decode_json(&bytes)?)
+}
+
+/// Find the latest version of a package, only fetching the full packument if needed
+pub async fn fetch_latest(name: &str, fallback: LatestFallback) -> Result<Version> {
+    let default_tag = default_tag(name).await?;
+
+    // Publish times are only in the packument
+    if ARGS.before.is_some() {
+        return latest_version(
+            &fetch_package(name).await?,
+            name,
+            default_tag.as_deref(),
+            fallback,
+        );
+    }
+
+    match fetch_dist_tags(name).await {
+        Ok(tags) => {
+            let tag = default_tag.as_deref().and_then(|x| tags.get(x));
+            if let Some(latest) = tag.or_else(|| tags.get("latest")) {
+                return Ok(Version::parse(latest)?);
+            }
+        }
+        Err(e) => log_verbose(&format!("Failed to fetch dist-tags for {name}: {e}")),
+    }
+
+    latest_version(
+        &fetch_package(name).await?,
+        name,
+        default_tag.as_deref(),
+        fallback,
+    )
+}
+
+/// Read `package.json` from the top-level directory of a tarball
+pub async fn fetch_tarball_manifest(url: &Url) -> Result<PackageMetadata> {
+    let res = CLIENT
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes_stream()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+    let package = read_tarball_manifest(StreamReader::new(res), url.as_str()).await?;
+    check_remote_dependencies(&package, url.as_str())?;
+    Ok(package)
+}
+
+/// Read `package.json` from a gzipped package tarball
+pub async fn read_tarball_manifest(
+    reader: impl AsyncBufRead + Unpin + Send,
+    source: &str,
+) -> Result<PackageMetadata> {
+    let reader = GzipDecoder::new(reader);
+
+    let mut archive = Archive::new(reader);
+    let mut entries = archive.entries()?;
+
+    while let Some(mut entry) = entries.try_next().await? {
+        let path = entry.path()?;
+        if path.components().count() == 2 && path.ends_with("package.json") {
+            let mut buf = String::new();
+            entry.read_to_string(&mut buf).await?;
+
+            return Ok(serde_json::from_str(&buf)?);
+        }
+    }
+
+    Err(eyre!("Package from {source} does not contain package.json"))
+}
+
+/// Deprecation messages of the package versions resolved during this run, keyed by id
+static DEPRECATED: Lazy<Mutex<BTreeMap<String, String>>> = Lazy::new(Default::default);
+
+/// Warn about a deprecated version the first time it is resolved
+fn note_deprecated(name: &str, version: &Version, package: &PackageMetadata) {
+    let Some(message) = package.deprecation() else {
+        return;
+    };
+
+    let id = format!("{name}@{version}");
+    let new = DEPRECATED
+        .lock()
+        .unwrap()
+        .insert(id.clone(), message.to_string())
+        .is_none();
+    if new {
+        log_warning(&format!("{} is deprecated: {message}", id.bright_blue()));
+    }
+}
+
+/// Summarize the deprecated versions resolved during this run, which are easily missed among the
+/// other output when they are transitive dependencies
+pub fn report_deprecated() {
+    let deprecated = DEPRECATED.lock().unwrap();
+    if deprecated.is_empty() {
+        return;
+    }
+
+    PROGRESS_BAR.suspend(|| {
+        println!(
+            "{} {} deprecated packages: {}",
+            " DEPRECATED ".on_yellow(),
+            deprecated.len().yellow(),
+            deprecated.keys().join(", ")
+        )
+    });
+}
+
 #[tracing::instrument]
 #[cached(result)]
 #[async_recursion]
-pub async fn fetch_versioned_package(d: PackageSpecifier) -> Result<(Version, Arc<PackageInfo>)> {
+pub async fn fetch_versioned_package(
+    d: PackageSpecifier,
+    strategy: Strategy,
+) -> Result<(Version, Arc<PackageInfo>)> {
     log_progress(&format!("Fetched {}", d.name.bright_blue()));
 
+    if let Some(dir) = local_dir(&d.version) {
+        let (version, mut package) = fetch_local_package("file", dir).await?;
+        Arc::make_mut(&mut package).name = d.name;
+        return Ok((version, package));
+    }
+
+    if let Some(path) = local_tarball(&d.version) {
+        let (version, mut package) = fetch_local_tarball(path).await?;
+        Arc::make_mut(&mut package).name = d.name;
+        return Ok((version, package));
+    }
+
+    if let Some(dir) = linked_dir(&d.version) {
+        let (version, mut package) = fetch_local_package("link", dir).await?;
+        Arc::make_mut(&mut package).name = d.name;
+        return Ok((version, package));
+    }
+
+    if let Some(range) = workspace_range(&d.version) {
+        return fetch_workspace_package(&d.name, range).await;
+    }
+
+    if let Some(source) = GitHubSource::parse(&d.version) {
+        let (version, mut package) = fetch_github_package(&source).await?;
+        Arc::make_mut(&mut package).name = d.name;
+        return Ok((version, package));
+    }
+
+    if let Some(source) = GitSource::parse(&d.version) {
+        let (version, mut package) = fetch_git_package(&source).await?;
+        Arc::make_mut(&mut package).name = d.name;
+        return Ok((version, package));
+    }
+
     match &d.version {
         VersionSpecifier::Other(tag) => {
             let res = fetch_package(&d.name).await?;
-            let tag = res
-                .dist_tags
-                .get(tag)
-                .wrap_err_with(|| eyre!("Version cannot be satisfied: {} {}", d.name, d.version))?;
-            let version = Version::parse(tag)?;
+            let version = if tag == "latest" {
+                latest_version(
+                    &res,
+                    &d.name,
+                    default_tag(&d.name).await?.as_deref(),
+                    read_config().await?.latest_fallback,
+                )?
+            } else {
+                let tag = res.dist_tags.get(tag).wrap_err_with(|| {
+                    eyre!("Version cannot be satisfied: {} {}", d.name, d.version)
+                })?;
+                res.tagged_in_time(&d.name, Version::parse(tag)?, ARGS.before)?
+            };
             let package = res.versions.get(&version).wrap_err_with(|| {
                 eyre!(
                     "Tag refers to a version that does not exist: {} - {} refers to {}",
@@ -145,23 +365,36 @@ pub async fn fetch_versioned_package(d: PackageSpecifier) -> Result<(Version, Ar
                     version
                 )
             })?;
+            note_deprecated(&d.name, &version, package);
+            check_resolved(&d.name, &version, package).await?;
 
             Ok((version, Arc::new(package.clone().info())))
         }
         VersionSpecifier::Range(_) => {
             let res = fetch_package(&d.name).await?;
+            let policy = prerelease_policy(&d.name).await?;
             let (version, package) = res
-                .versions
-                .iter()
-                .sorted_by_key(|(v, _)| !v.is_prerelease())
-                .rfind(|(v, _)| d.version.satisfies(v))
-                .wrap_err_with(|| {
-                    eyre!(
+                .resolve_range(&d.version, policy, strategy, ARGS.before)
+                .ok_or_else(|| {
+                    let mut report = eyre!(
                         "Version cannot be satisfied: expected {} {}",
                         d.name,
                         d.version
-                    )
+                    );
+                    if let Some(before) = ARGS.before {
+                        report = report
+                            .note(format!("Only versions published before {before} are used"));
+                    }
+                    if policy == PrereleasePolicy::Never {
+                        report = report.note("Prereleases are not used").suggestion(format!(
+                            "Add {} to `allow_prerelease` in cotton.toml to use its prereleases",
+                            d.name
+                        ));
+                    }
+                    report
                 })?;
+            note_deprecated(&d.name, version, package);
+            check_resolved(&d.name, version, package).await?;
 
             Ok((version.clone(), Arc::new(package.clone().info())))
         }
@@ -171,37 +404,15 @@ pub async fn fetch_versioned_package(d: PackageSpecifier) -> Result<(Version, Ar
                 d.name, d.version
             ));
 
-            let res = CLIENT
-                .get(url.clone())
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes_stream()
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
-
-            let reader = StreamReader::new(res);
-            let reader = GzipDecoder::new(reader);
-
-            let mut archive = Archive::new(reader);
-            let mut entries = archive.entries()?;
-
-            while let Some(mut entry) = entries.try_next().await? {
-                if entry.path()?.to_str() == Some("package/package.json") {
-                    let mut buf = String::new();
-                    entry.read_to_string(&mut buf).await?;
-
-                    let mut package: PackageMetadata = serde_json::from_str(&buf)?;
-                    let version = package.version.clone().wrap_err_with(|| {
-                        format!("Package from {url} does not specify a version")
-                    })?;
+            let mut package = fetch_tarball_manifest(url).await?;
+            let version = package
+                .version
+                .clone()
+                .wrap_err_with(|| format!("Package from {url} does not specify a version"))?;
 
-                    package.dist.tarball = url.to_compact_string();
+            package.dist.tarball = url.to_compact_string();
 
-                    return Ok((version, Arc::new(package.info())));
-                }
-            }
-
-            Err(eyre!("Package from {url} does not contain package.json"))
+            Ok((version, Arc::new(package.info())))
         }
         VersionSpecifier::Prefixed(prefixed) => match prefixed.prefix.as_str() {
             "npm" => {
@@ -218,7 +429,8 @@ pub async fn fetch_versioned_package(d: PackageSpecifier) -> Result<(Version, Ar
                     optional: d.optional,
                 };
 
-                let (inner_version, mut inner_pkg) = fetch_versioned_package(inner_req).await?;
+                let (inner_version, mut inner_pkg) =
+                    fetch_versioned_package(inner_req, strategy).await?;
                 Arc::make_mut(&mut inner_pkg).name = d.name;
 
                 Ok((inner_version, inner_pkg))
@@ -227,44 +439,3 @@ pub async fn fetch_versioned_package(d: PackageSpecifier) -> Result<(Version, Ar
         },
     }
 }
-
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
-pub struct DependencyTree {
-    #[serde(flatten)]
-    pub root: Dependency,
-    pub children: FxHashMap<CompactString, DependencyTree>,
-}
-
-impl DependencyTree {
-    pub fn filter(&self, exclude: &FxHashSet<Dependency>) -> Self {
-        Self {
-            root: self.root.clone(),
-            children: self
-                .children
-                .iter()
-                .filter_map(|(name, tree)| {
-                    if !exclude.contains(&tree.root) {
-                        Some((name.clone(), tree.filter(exclude)))
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-        }
-    }
-}
-
-#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize, Deserialize)]
-pub struct Dependency {
-    pub name: CompactString,
-    pub version: Version,
-    pub dist: Dist,
-    pub bins: BTreeMap<CompactString, CompactString>,
-    pub scripts: BTreeMap<CompactString, CompactString>,
-}
-
-impl Dependency {
-    pub fn id(&self) -> String {
-        format!("{}@{}", self.name, self.version).replace(MAIN_SEPARATOR, "!")
-    }
-}
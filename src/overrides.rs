@@ -0,0 +1,233 @@
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use owo_colors::OwoColorize;
+use serde_json::{Map, Value};
+
+use crate::{
+    package::PackageMetadata,
+    progress::{log_warning, PROGRESS_BAR},
+    util::{read_package, read_package_or_default, save_package, VersionSpecifier},
+};
+
+pub use cotton_core::overrides::{Override, Overrides};
+
+/// Collect npm `overrides` and yarn `resolutions` of the root package
+pub fn collect_overrides(root: &PackageMetadata) -> Overrides {
+    let mut overrides = Overrides::default();
+
+    for (name, value) in &root.overrides {
+        match value {
+            Override::Version(version) => insert(&mut overrides, None, name, version, root),
+            Override::Nested(children) => {
+                for (child, value) in children {
+                    match (child.as_str(), value) {
+                        (".", Override::Version(version)) => {
+                            insert(&mut overrides, None, name, version, root)
+                        }
+                        (_, Override::Version(version)) => {
+                            insert(&mut overrides, Some(name), child, version, root)
+                        }
+                        (_, Override::Nested(_)) => log_warning(&format!(
+                            "Override of {name} > {child} is nested too deeply, and is ignored"
+                        )),
+                    }
+                }
+            }
+        }
+    }
+
+    for (key, version) in &root.resolutions {
+        let (parent, name) = split_resolution(key);
+        insert(&mut overrides, parent, name, version, root);
+    }
+
+    overrides
+}
+
+fn insert(
+    overrides: &mut Overrides,
+    parent: Option<&str>,
+    name: &str,
+    version: &VersionSpecifier,
+    root: &PackageMetadata,
+) {
+    // `$name` refers to the version of a direct dependency of the root package
+    let version = match version.to_string().strip_prefix('$') {
+        Some(reference) => match root
+            .dependencies
+            .get(reference)
+            .or_else(|| root.dev_dependencies.get(reference))
+            .or_else(|| root.optional_dependencies.get(reference))
+        {
+            Some(version) => version.clone(),
+            None => {
+                log_warning(&format!(
+                    "Override of {name} refers to ${reference}, which is not a dependency"
+                ));
+                return;
+            }
+        },
+        None => version.clone(),
+    };
+
+    overrides.insert(parent, name, version);
+}
+
+/// Split a yarn resolution such as `**/name` or `parent/name` into the parent and the dependency
+fn split_resolution(key: &str) -> (Option<&str>, &str) {
+    let key = key.strip_prefix("**/").unwrap_or(key);
+
+    // Scoped package names contain a slash themselves
+    let parent_len = match key.strip_prefix('@') {
+        Some(rest) => rest
+            .find('/')
+            .and_then(|scope| rest[scope + 1..].find('/').map(|name| 1 + scope + 1 + name)),
+        None => key.find('/'),
+    };
+
+    match parent_len {
+        Some(len) => (Some(&key[..len]), &key[len + 1..]),
+        None => (None, key),
+    }
+}
+
+fn describe(name: &str, parent: Option<&str>) -> String {
+    match parent {
+        Some(parent) => format!("{parent} > {name}"),
+        None => name.to_string(),
+    }
+}
+
+fn overrides_mut(package: &mut Value) -> Result<&mut Map<String, Value>> {
+    package
+        .as_object_mut()
+        .wrap_err("`package.json` is invalid")?
+        .entry("overrides")
+        .or_insert(Value::Object(Default::default()))
+        .as_object_mut()
+        .wrap_err("`package.json` contains non-object overrides field")
+}
+
+/// Force the version of a dependency in the `overrides` of package.json, either everywhere or
+/// only where `parent` requires it
+pub async fn add_override(name: &str, version: &str, parent: Option<&str>) -> Result<()> {
+    let mut package: Value = read_package_or_default().await?;
+    let overrides = overrides_mut(&mut package)?;
+
+    match parent {
+        Some(parent) => {
+            let entry = overrides
+                .entry(parent)
+                .or_insert(Value::Object(Default::default()));
+            // A version for the parent itself is kept as `.`
+            if let Value::String(own) = entry {
+                *entry = Value::Object(Map::from_iter([(".".into(), own.clone().into())]));
+            }
+            entry
+                .as_object_mut()
+                .wrap_err_with(|| eyre!("Override of {parent} is invalid"))?
+                .insert(name.into(), version.into());
+        }
+        None => match overrides.get_mut(name) {
+            // Overrides of its dependencies are kept
+            Some(Value::Object(nested)) => {
+                nested.insert(".".into(), version.into());
+            }
+            _ => {
+                overrides.insert(name.into(), version.into());
+            }
+        },
+    }
+
+    save_package(&package).await?;
+
+    PROGRESS_BAR.suspend(|| {
+        println!(
+            "Overrode {} with {}",
+            describe(name, parent).yellow(),
+            version.yellow()
+        )
+    });
+
+    Ok(())
+}
+
+/// Remove an override from package.json, whether it is in npm `overrides` or yarn `resolutions`
+pub async fn remove_override(name: &str, parent: Option<&str>) -> Result<()> {
+    let mut package: Value = read_package_or_default().await?;
+    let object = package
+        .as_object_mut()
+        .wrap_err("`package.json` is invalid")?;
+
+    let mut removed = false;
+
+    if let Some(overrides) = object.get_mut("overrides").and_then(|x| x.as_object_mut()) {
+        match parent {
+            Some(parent) => {
+                if let Some(nested) = overrides.get_mut(parent).and_then(|x| x.as_object_mut()) {
+                    removed = nested.remove(name).is_some();
+                    if nested.is_empty() {
+                        overrides.remove(parent);
+                    }
+                }
+            }
+            None => match overrides.get_mut(name) {
+                Some(Value::Object(nested)) => {
+                    removed = nested.remove(".").is_some();
+                    if nested.is_empty() {
+                        overrides.remove(name);
+                    }
+                }
+                Some(_) => removed = overrides.remove(name).is_some(),
+                None => {}
+            },
+        }
+        if overrides.is_empty() {
+            object.remove("overrides");
+        }
+    }
+
+    if let Some(resolutions) = object
+        .get_mut("resolutions")
+        .and_then(|x| x.as_object_mut())
+    {
+        let before = resolutions.len();
+        resolutions.retain(|key, _| split_resolution(key) != (parent, name));
+        removed |= resolutions.len() != before;
+        if resolutions.is_empty() {
+            object.remove("resolutions");
+        }
+    }
+
+    if !removed {
+        return Err(eyre!("{} is not overridden", describe(name, parent)));
+    }
+
+    save_package(&package).await?;
+
+    PROGRESS_BAR.suspend(|| println!("Removed override of {}", describe(name, parent).yellow()));
+
+    Ok(())
+}
+
+/// Print the overrides of the root package, as the resolver applies them
+pub async fn print_overrides() -> Result<()> {
+    let root = read_package().await?;
+    let overrides = collect_overrides(&root);
+
+    let mut lines = overrides
+        .iter()
+        .map(|(parent, name, version)| (describe(name, parent), version))
+        .collect::<Vec<_>>();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    PROGRESS_BAR.suspend(|| {
+        if lines.is_empty() {
+            println!("No overrides");
+        }
+        for (target, version) in lines {
+            println!("{} {}", target.yellow(), version);
+        }
+    });
+
+    Ok(())
+}
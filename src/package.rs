@@ -1,23 +1,25 @@
-use std::{
-    collections::BTreeMap,
-    fmt::{Debug, Display},
-    sync::Arc,
-};
+use std::collections::BTreeMap;
 
 use crate::{
-    npm::PlatformMap,
-    util::{get_node_cpu, get_node_os, VersionSpecifier},
+    overrides::Override, task_cache::TaskConfig, util::VersionSpecifier, workspace::Workspaces,
 };
-use color_eyre::eyre::Result;
+use color_eyre::{eyre::eyre, Section};
 use compact_str::{CompactString, ToCompactString};
+use cotton_core::{
+    package::{Bin, PeerDependencyMeta},
+    platform::PlatformMap,
+};
 use node_semver::Version;
 use rustc_hash::FxHashMap;
-use serde::{
-    de::{self},
-    Deserialize, Serialize,
-};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+pub use cotton_core::{
+    package::{Dist, PackageInfo, PackageRule, VersionedPackageInfo},
+    plan::Mode,
+    spec::PackageSpecifier,
+};
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
@@ -29,163 +31,171 @@ pub struct PackageMetadata {
     pub dependencies: BTreeMap<CompactString, VersionSpecifier>,
     pub optional_dependencies: BTreeMap<CompactString, VersionSpecifier>,
     pub dev_dependencies: FxHashMap<CompactString, VersionSpecifier>,
+    pub peer_dependencies: BTreeMap<CompactString, VersionSpecifier>,
+    pub peer_dependencies_meta: BTreeMap<CompactString, PeerDependencyMeta>,
     pub os: PlatformMap,
     pub cpu: PlatformMap,
+    pub libc: PlatformMap,
     pub scripts: FxHashMap<CompactString, Value>,
+    pub license: Option<Value>,
+    pub workspaces: Option<Workspaces>,
+    pub engines: Value,
+    pub package_manager: Option<CompactString>,
+    pub overrides: BTreeMap<CompactString, Override>,
+    pub resolutions: BTreeMap<CompactString, VersionSpecifier>,
+    pub cotton: CottonField,
+    pub deprecated: Option<Value>,
+    /// User that published this version, as recorded by the registry
+    #[serde(rename = "_npmUser", skip_serializing)]
+    pub npm_user: Option<NpmUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(default)]
+pub struct NpmUser {
+    pub name: CompactString,
+}
+
+/// The `cotton` field of package.json
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct CottonField {
+    pub tasks: BTreeMap<CompactString, TaskConfig>,
+    pub package_rules: BTreeMap<CompactString, PackageRule>,
+    /// Named sets of dependencies that are locked with the others, but only installed with `--with`
+    pub groups: BTreeMap<CompactString, BTreeMap<CompactString, VersionSpecifier>>,
+    /// Versions that `catalog:` specifiers refer to, by package name
+    pub catalog: BTreeMap<CompactString, VersionSpecifier>,
+    /// Catalogs that `catalog:<name>` specifiers refer to
+    pub catalogs: BTreeMap<CompactString, BTreeMap<CompactString, VersionSpecifier>>,
 }
 
 impl PackageMetadata {
+    /// The rule for a dependency from `cotton.packageRules`
+    pub fn rule(&self, name: &str) -> &PackageRule {
+        self.cotton
+            .package_rules
+            .get(name)
+            .unwrap_or(&PackageRule::NONE)
+    }
+
+    /// The message of the `deprecated` field, if this version is deprecated
+    pub fn deprecation(&self) -> Option<&str> {
+        match &self.deprecated {
+            Some(Value::String(x)) if !x.is_empty() => Some(x),
+            Some(Value::Bool(true)) => Some("no reason given"),
+            _ => None,
+        }
+    }
+
     pub fn info(self) -> PackageInfo {
+        let deprecated = self.deprecation().map(|x| x.into());
         PackageInfo {
+            license: match &self.license {
+                Some(Value::String(x)) => Some(x.to_compact_string()),
+                Some(Value::Object(x)) => x
+                    .get("type")
+                    .and_then(|x| x.as_str())
+                    .map(|x| x.to_compact_string()),
+                _ => None,
+            },
             name: self.name,
             dist: self.dist,
             dependencies: self.dependencies,
             optional_dependencies: self.optional_dependencies,
+            peer_dependencies: self.peer_dependencies,
+            peer_dependencies_meta: self.peer_dependencies_meta,
             os: self.os,
             cpu: self.cpu,
+            libc: self.libc,
             bin: self.bin,
             scripts: self
                 .scripts
                 .iter()
                 .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_compact_string())))
                 .collect(),
+            engines: match &self.engines {
+                Value::Object(x) => x
+                    .iter()
+                    .filter_map(|(k, v)| Some((k.to_compact_string(), v.as_str()?.into())))
+                    .collect(),
+                _ => Default::default(),
+            },
+            overridden: Default::default(),
+            deprecated,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default, Hash)]
-#[serde(rename_all = "camelCase")]
-#[serde(default)]
-pub struct PackageInfo {
-    pub name: CompactString,
-    pub dist: Dist,
-    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
-    pub dependencies: BTreeMap<CompactString, VersionSpecifier>,
-    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
-    pub optional_dependencies: BTreeMap<CompactString, VersionSpecifier>,
-    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
-    pub os: PlatformMap,
-    #[serde(skip_serializing_if = "PlatformMap::is_empty")]
-    pub cpu: PlatformMap,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub bin: Option<Bin>,
-    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
-    pub scripts: BTreeMap<CompactString, CompactString>,
-}
-
-#[derive(Debug, Hash, PartialEq, Eq, Clone, Deserialize)]
-pub struct VersionedPackageInfo {
-    pub package: Arc<PackageInfo>,
-    pub version: Version,
-}
-
-impl PackageInfo {
-    pub fn bins(&self) -> BTreeMap<CompactString, CompactString> {
-        match &self.bin {
-            Some(Bin::Multi(x)) => x.clone().into_iter().collect(),
-            Some(Bin::Single(x)) => [(self.name.to_compact_string(), x.to_compact_string())]
-                .into_iter()
-                .collect(),
-            None => [].into_iter().collect(),
-        }
+impl PackageMetadata {
+    pub fn iter_all(&self) -> impl Iterator<Item = PackageSpecifier> + '_ {
+        self.iter_declared().chain(
+            self.group_dependencies()
+                .map(move |(n, v)| self.specifier(n, v)),
+        )
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = PackageSpecifier> + '_ {
+    /// Dependencies in `dependencies`, `devDependencies` and `optionalDependencies`, leaving out
+    /// groups
+    pub fn iter_declared(&self) -> impl Iterator<Item = PackageSpecifier> + '_ {
         self.dependencies
             .iter()
+            .chain(self.dev_dependencies.iter())
             .chain(self.optional_dependencies.iter())
-            .map(|(n, v)| PackageSpecifier {
-                name: n.to_compact_string(),
-                version: v.to_owned(),
-                optional: self.optional_dependencies.contains_key(n),
-            })
+            .map(move |(n, v)| self.specifier(n, v))
     }
 
-    pub fn supported(&self) -> bool {
-        self.os.is_supported(get_node_os()) && self.cpu.is_supported(get_node_cpu())
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Hash)]
-#[serde(untagged)]
-pub enum Bin {
-    Single(CompactString),
-    Multi(BTreeMap<CompactString, CompactString>),
-}
-
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default, PartialOrd, Ord)]
-pub struct Dist {
-    pub tarball: CompactString,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
-pub struct PackageSpecifier {
-    pub name: CompactString,
-    pub version: VersionSpecifier,
-    pub optional: bool,
-}
-
-impl Display for PackageSpecifier {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}!{}{}",
-            self.name,
-            self.version,
-            if self.optional { "?" } else { "" }
-        )
-    }
-}
-
-impl Serialize for PackageSpecifier {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(&self.to_string())
+    fn specifier(&self, name: &CompactString, version: &VersionSpecifier) -> PackageSpecifier {
+        PackageSpecifier {
+            name: name.to_compact_string(),
+            version: version.to_owned(),
+            optional: self.optional_dependencies.contains_key(name),
+        }
     }
-}
 
-impl<'de> Deserialize<'de> for PackageSpecifier {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        let (name, rest) = s
-            .split_once('!')
-            .ok_or_else(|| de::Error::custom("Failed to parse version"))?;
-        let optional = rest.ends_with('?');
-        let version = rest.trim_end_matches('?');
-        Ok(Self {
-            name: name.to_compact_string(),
-            version: serde_json::from_value(Value::String(version.to_string()))
-                .map_err(de::Error::custom)?,
-            optional,
+    /// Dependencies in `cotton.groups`, where dependencies declared elsewhere are left out
+    fn group_dependencies(&self) -> impl Iterator<Item = (&CompactString, &VersionSpecifier)> {
+        self.cotton.groups.values().flatten().filter(|(n, _)| {
+            !self.dependencies.contains_key(*n)
+                && !self.dev_dependencies.contains_key(*n)
+                && !self.optional_dependencies.contains_key(*n)
         })
     }
-}
 
-impl Debug for PackageSpecifier {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.name, self.version)?;
-        if self.optional {
-            write!(f, " (optional)")?;
+    /// Fail if a dependency is in more than one group, since it is unclear which range applies
+    pub fn check_groups(&self) -> color_eyre::Result<()> {
+        let mut seen = BTreeMap::new();
+        for (group, deps) in &self.cotton.groups {
+            for name in deps.keys() {
+                if let Some(other) = seen.insert(name, group) {
+                    return Err(
+                        eyre!("`{name}` is in both group `{other}` and group `{group}`")
+                            .suggestion("Keep each dependency in only one group"),
+                    );
+                }
+            }
         }
+
         Ok(())
     }
-}
 
-impl PackageMetadata {
-    pub fn iter_all(&self) -> impl Iterator<Item = PackageSpecifier> + '_ {
-        self.dependencies
-            .iter()
-            .chain(self.dev_dependencies.iter())
-            .chain(self.optional_dependencies.iter())
-            .map(|(n, v)| PackageSpecifier {
-                name: n.to_compact_string(),
-                version: v.to_owned(),
-                optional: self.optional_dependencies.contains_key(n),
-            })
+    /// Dependencies of the root that are installed in `mode`, along with those in `groups`
+    pub fn iter_installed<'a>(
+        &'a self,
+        mode: Mode,
+        groups: &'a [CompactString],
+    ) -> impl Iterator<Item = PackageSpecifier> + 'a {
+        self.iter_all().filter(move |x| {
+            self.dependencies.contains_key(&x.name)
+                || self.optional_dependencies.contains_key(&x.name)
+                || (mode == Mode::Development && self.dev_dependencies.contains_key(&x.name))
+                || groups.iter().any(|group| {
+                    self.cotton
+                        .groups
+                        .get(group)
+                        .is_some_and(|deps| deps.contains_key(&x.name))
+                })
+        })
     }
 }
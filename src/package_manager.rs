@@ -0,0 +1,62 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use node_semver::Version;
+
+use crate::{
+    package::PackageMetadata,
+    progress::log_warning,
+    util::{host_node_cpu, host_node_os},
+    ARGS,
+};
+
+/// The user agent reported to registries and to scripts through `npm_config_user_agent`,
+/// in the format read by corepack and other tools
+pub fn user_agent() -> String {
+    format!(
+        "cotton/{} {} {}",
+        env!("CARGO_PKG_VERSION"),
+        host_node_os(),
+        host_node_cpu()
+    )
+}
+
+/// Check the `packageManager` field of package.json, which is written as `name@version`
+/// with an optional `+hash`
+pub fn check_package_manager(package: &PackageMetadata) -> Result<()> {
+    let Some(declared) = &package.package_manager else {
+        return Ok(());
+    };
+
+    let (name, version) = declared.rsplit_once('@').unwrap_or((declared, ""));
+    let version = version.split('+').next().unwrap_or_default();
+
+    if name != "cotton" {
+        if ARGS.force {
+            log_warning(&format!(
+                "This project is managed by {declared}, continuing because of --force"
+            ));
+            return Ok(());
+        }
+
+        return Err(
+            eyre!("This project is managed by {declared}").suggestion(format!(
+                "Use {name} instead, or pass --force to continue with cotton"
+            )),
+        );
+    }
+
+    let current = env!("CARGO_PKG_VERSION");
+    match Version::parse(version) {
+        Ok(version) if version.to_string() != current => log_warning(&format!(
+            "This project expects cotton {version}, but {current} is running"
+        )),
+        Ok(_) => {}
+        Err(_) => log_warning(&format!(
+            "Ignoring invalid packageManager version `{declared}`"
+        )),
+    }
+
+    Ok(())
+}
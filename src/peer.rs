@@ -0,0 +1,103 @@
+use compact_str::CompactString;
+use node_semver::Version;
+use rustc_hash::FxHashMap;
+use std::fmt::Display;
+
+use crate::{
+    npm::DependencyTree, package::PackageInfo, plan::Plan, resolve::Graph, util::VersionSpecifier,
+};
+
+/// A peer dependency that is missing or does not satisfy the requested range
+pub struct PeerProblem {
+    pub chain: Vec<CompactString>,
+    pub peer: CompactString,
+    pub range: VersionSpecifier,
+    pub found: Option<Version>,
+}
+
+impl Display for PeerProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} requires peer {}@{}",
+            self.chain.join(" > "),
+            self.peer,
+            self.range
+        )?;
+        match &self.found {
+            Some(version) => write!(f, ", but {version} is installed"),
+            None => write!(f, ", which is not installed"),
+        }
+    }
+}
+
+struct Checker<'a> {
+    packages: FxHashMap<(&'a str, &'a Version), &'a PackageInfo>,
+    problems: Vec<PeerProblem>,
+}
+
+impl<'a> Checker<'a> {
+    fn check_tree(
+        &mut self,
+        tree: &'a DependencyTree,
+        scopes: &mut Vec<&'a FxHashMap<CompactString, DependencyTree>>,
+        chain: &mut Vec<CompactString>,
+    ) {
+        chain.push(format!("{}@{}", tree.root.name, tree.root.version).into());
+
+        if let Some(package) = self
+            .packages
+            .get(&(tree.root.name.as_str(), &tree.root.version))
+        {
+            for (peer, range) in &package.peer_dependencies {
+                // Peers are found the same way Node finds any package, from the nearest `node_modules`
+                let found = [&tree.children]
+                    .into_iter()
+                    .chain(scopes.iter().rev().copied())
+                    .find_map(|x| x.get(peer))
+                    .map(|x| x.root.version.clone());
+
+                let unmet = match &found {
+                    Some(version) => !range.satisfies(version),
+                    None => !package.is_optional_peer(peer),
+                };
+                if unmet {
+                    self.problems.push(PeerProblem {
+                        chain: chain.clone(),
+                        peer: peer.clone(),
+                        range: range.clone(),
+                        found,
+                    });
+                }
+            }
+        }
+
+        scopes.push(&tree.children);
+        for child in tree.children.values() {
+            self.check_tree(child, scopes, chain);
+        }
+        scopes.pop();
+
+        chain.pop();
+    }
+}
+
+/// Find peer dependencies that are not satisfied by the packages in the plan
+pub fn check_peers(graph: &Graph, plan: &Plan) -> Vec<PeerProblem> {
+    let mut checker = Checker {
+        packages: graph
+            .relations
+            .values()
+            .map(|x| ((x.package.name.as_str(), &x.version), &*x.package))
+            .collect(),
+        problems: vec![],
+    };
+
+    let mut scopes = vec![&plan.trees];
+    for tree in plan.trees.values() {
+        checker.check_tree(tree, &mut scopes, &mut vec![]);
+    }
+
+    checker.problems.sort_by(|a, b| a.chain.cmp(&b.chain));
+    checker.problems
+}
@@ -0,0 +1,153 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use node_semver::Version;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::read_config,
+    git::{GitHubSource, GitSource},
+    local::{linked_dir, local_dir, local_tarball},
+    npm::select_registry,
+    package::PackageMetadata,
+    resolve::Graph,
+    util::VersionSpecifier,
+    workspace::workspace_range,
+};
+
+/// Where packages under a scope must come from, so that a public package cannot shadow an
+/// internal name
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScopePin {
+    /// Scope of the packages, such as `@mycorp`
+    pub scope: String,
+    /// URL of the registry that the packages are resolved and downloaded from
+    pub registry: String,
+    /// Users allowed to publish the packages, as recorded by the registry
+    #[serde(default)]
+    pub publishers: Vec<String>,
+    /// Require every version to have a provenance attestation
+    #[serde(default)]
+    pub provenance: bool,
+}
+
+impl ScopePin {
+    /// Whether a package is in the pinned scope
+    pub fn covers(&self, name: &str) -> bool {
+        name.split_once('/')
+            .is_some_and(|(scope, _)| scope == self.scope.trim_end_matches('/'))
+    }
+}
+
+/// Whether two URLs point to the same server
+pub fn same_host(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => {
+            a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default()
+        }
+        _ => false,
+    }
+}
+
+/// Whether a specifier is resolved from a registry, rather than from a directory, git or a URL
+fn from_registry(spec: &VersionSpecifier) -> bool {
+    local_dir(spec).is_none()
+        && local_tarball(spec).is_none()
+        && linked_dir(spec).is_none()
+        && workspace_range(spec).is_none()
+        && GitHubSource::parse(spec).is_none()
+        && GitSource::parse(spec).is_none()
+        && !matches!(spec, VersionSpecifier::DirectUrl(_))
+}
+
+fn check_tarball(pin: &ScopePin, id: &str, tarball: &str) -> Result<()> {
+    if same_host(tarball, &pin.registry) {
+        return Ok(());
+    }
+
+    Err(
+        eyre!("{id} is downloaded from {tarball}, outside of its pinned registry")
+            .with_note(|| format!("Packages under {} are pinned to {}", pin.scope, pin.registry))
+            .suggestion(
+                "This may be a dependency confusion attempt; check where the package was resolved from before removing it from cotton.lock",
+            ),
+    )
+}
+
+/// Check a version that was just resolved from the registry against the pin of its scope
+pub async fn check_resolved(
+    name: &str,
+    version: &Version,
+    package: &PackageMetadata,
+) -> Result<()> {
+    let config = read_config().await?;
+    let Some(pin) = config.pin.iter().find(|x| x.covers(name)) else {
+        return Ok(());
+    };
+    let id = format!("{name}@{version}");
+
+    let registry = select_registry(name).await?;
+    if registry.url.trim_end_matches('/') != pin.registry.trim_end_matches('/') {
+        return Err(eyre!(
+            "{name} is pinned to {}, but is resolved from {}",
+            pin.registry,
+            registry.url
+        )
+        .suggestion(format!(
+            "Add a registry with `scope = \"{}\"` to cotton.toml, before any registry without a scope",
+            pin.scope
+        )));
+    }
+
+    check_tarball(pin, &id, &package.dist.tarball)?;
+
+    if !pin.publishers.is_empty() {
+        let publisher = package.npm_user.as_ref().map(|x| x.name.as_str());
+        if !publisher.is_some_and(|x| pin.publishers.iter().any(|p| p == x)) {
+            return Err(eyre!(
+                "{id} was published by {}, who is not an allowed publisher",
+                publisher.unwrap_or("an unknown user")
+            )
+            .with_note(|| format!("Allowed publishers: {}", pin.publishers.join(", "))));
+        }
+    }
+
+    let attested = package
+        .dist
+        .attestations
+        .as_ref()
+        .is_some_and(|x| x.provenance.is_some());
+    if pin.provenance && !attested {
+        return Err(eyre!("{id} does not have a provenance attestation")
+            .with_note(|| format!("Packages under {} require provenance", pin.scope)));
+    }
+
+    Ok(())
+}
+
+/// Check that locked packages under pinned scopes are downloaded from their registries, which
+/// also covers lockfiles edited by hand
+pub async fn check_graph(graph: &Graph) -> Result<()> {
+    let pins = read_config().await?.pin;
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    for (spec, pkg) in &graph.relations {
+        if !from_registry(&spec.version) {
+            continue;
+        }
+        if let Some(pin) = pins.iter().find(|x| x.covers(&spec.name)) {
+            check_tarball(
+                pin,
+                &format!("{}@{}", spec.name, pkg.version),
+                &pkg.package.dist.tarball,
+            )?;
+        }
+    }
+
+    Ok(())
+}
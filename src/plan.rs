@@ -1,147 +1,557 @@
 use async_compression::tokio::bufread::GzipDecoder;
 use color_eyre::{
-    eyre::{eyre, Result},
-    Report,
+    eyre::{eyre, ContextCompat, Result},
+    Report, Section,
 };
 use compact_str::{CompactString, ToCompactString};
-use futures::TryStreamExt;
-use once_cell::sync::Lazy;
+use futures::{Future, TryStreamExt};
+use once_cell::sync::{Lazy, OnceCell};
 use owo_colors::OwoColorize;
-use rustc_hash::FxHashMap;
+use reqwest::{Response, StatusCode};
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
-use std::{
-    fs::Permissions,
-    io::{self, ErrorKind},
-    os::unix::prelude::PermissionsExt,
-    path::{Path, PathBuf},
-    sync::Arc,
+use std::fs::{
+    create_dir_all, exists, hard_link, read, read_dir, read_link, read_to_string, remove_dir_all,
+    write, File,
 };
 use std::{
-    fs::{create_dir_all, exists, metadata, read_dir, remove_dir_all, set_permissions, File},
+    env::current_dir,
+    hash::{Hash, Hasher},
+    io,
+    mem::take,
     os::unix::fs::symlink,
+    path::{Component, Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
 };
 use tap::Pipe;
-use tokio::{sync::Semaphore, task::JoinHandle};
-use tokio_tar::Archive;
-use tokio_util::io::StreamReader;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, BufReader},
+    task::JoinHandle,
+};
+use tokio_tar::{Archive, EntryType};
+use tokio_util::io::{InspectReader, StreamReader};
 
 use crate::{
+    bins::link_bins,
     cache::Cache,
-    config::{client_auth, read_config},
+    concurrency,
+    config::{client_auth, read_config_in, Config, Registry},
+    git::{self, GitSource},
+    local::{
+        hardlink_local_dir, is_linked, is_local, link_dir, linked_dir, linked_path, local_path,
+        local_tarball_path,
+    },
+    metrics::{CACHE_HITS, DOWNLOADS},
     npm::{Dependency, DependencyTree},
     package::PackageMetadata,
-    progress::{log_progress, log_verbose},
-    scoped_path::scoped_join,
-    util::{retry, VersionSpecifier, CLIENT, CLIENT_LIMIT},
+    pin::same_host,
+    progress::{log_progress, log_verbose, log_warning},
+    scoped_path::{scoped_join_for, PathOperation},
+    transform::apply_transform,
+    util::{
+        hash_hex, lockfile_contents, manifest_path, read_input, read_json, retry, write_json,
+        VersionSpecifier, CLIENT,
+    },
+    workspace::workspace_range,
+    ARGS,
 };
 
+pub use cotton_core::plan::{tree_size, Plan};
+
+/// Overrides the per-project store, allowing multiple projects to share one store
+pub static STORE_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+pub fn store_dir() -> PathBuf {
+    STORE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(".cotton/store"))
+}
+
+/// Whether every dependency of the root that `plan` would install is already installed with a
+/// matching version
+pub fn plan_satisfies(plan: &Plan, package: &PackageMetadata) -> bool {
+    let map: FxHashMap<_, _> = plan
+        .trees
+        .values()
+        .map(|x| (x.root.name.to_compact_string(), x.root.version.clone()))
+        .collect();
+    package.iter_installed(plan.mode, &ARGS.with).all(|req| {
+        if let Some(dir) = linked_dir(&req.version) {
+            return is_linked(&Path::new("node_modules").join(&*req.name), dir);
+        }
+        if workspace_range(&req.version).is_some() {
+            return read_link(Path::new("node_modules").join(&*req.name)).is_ok();
+        }
+        if let Some(version) = map.get(&req.name) {
+            if let VersionSpecifier::Range(range) = req.version {
+                return range.satisfies(version);
+            }
+        }
+        false
+    })
+}
+
+const MARKER_PATH: &str = "node_modules/.cotton/marker.json";
+
+/// Hashes of the inputs and result of the last install, used to skip comparing whole plans
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
-pub struct Plan {
-    #[serde(flatten)]
-    pub trees: FxHashMap<CompactString, DependencyTree>,
+pub struct InstallMarker {
+    plan: String,
+    package: String,
+    lockfile: String,
 }
 
-impl Plan {
-    pub fn new(trees: FxHashMap<CompactString, DependencyTree>) -> Self {
-        Self { trees }
+impl InstallMarker {
+    pub async fn new(plan: &Plan) -> Result<Self> {
+        Ok(Self {
+            plan: hash_hex(serde_json::to_vec(plan)?),
+            package: hash_hex(read_input(&manifest_path()).await.unwrap_or_default()),
+            lockfile: hash_hex(lockfile_contents().await),
+        })
     }
 
-    pub fn satisfies(&self, package: &PackageMetadata) -> bool {
-        let map: FxHashMap<_, _> = self
-            .trees
-            .values()
-            .map(|x| (x.root.name.to_compact_string(), x.root.version.clone()))
-            .collect();
-        package.iter_all().all(|req| {
-            if let Some(version) = map.get(&req.name) {
-                if let VersionSpecifier::Range(range) = req.version {
-                    return range.satisfies(version);
-                }
-            }
-            false
-        })
+    pub async fn is_current(&self) -> bool {
+        read_json::<Self>(MARKER_PATH).await.ok().as_ref() == Some(self)
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        write_json(MARKER_PATH, self).await
+    }
+}
+
+async fn fetch_tarball(url: &str, registries: &[Registry]) -> Result<Response> {
+    let auth = registries
+        .iter()
+        .find(|x| url.starts_with(&x.url))
+        .and_then(|x| x.auth.as_ref());
+
+    let request = CLIENT.get(url).pipe(|x| client_auth(x, auth))?;
+
+    let mut permit = concurrency::acquire(url).await;
+    let res = request.send().await;
+    permit.finish(&res);
+
+    Ok(res?)
+}
+
+/// Registries that may serve a tarball that is missing from where it was locked
+fn fallback_registries(dep: &Dependency, config: &Config) -> Vec<Registry> {
+    // Without a locked hash, any tarball published under the same name would be accepted
+    if !dep.dist.has_integrity() {
+        return vec![];
+    }
+
+    match config.pin.iter().find(|x| x.covers(&dep.name)) {
+        // A pinned scope is only served by its own registry, since anyone could claim its name
+        // on the others
+        Some(pin) => config
+            .registry
+            .iter()
+            .filter(|x| same_host(&x.url, &pin.registry))
+            .cloned()
+            .collect(),
+        None => config
+            .registry
+            .iter()
+            .cloned()
+            .chain([Registry::npm()])
+            .collect(),
+    }
+}
+
+/// Look for the same tarball on the fallback registries, in case it was removed from a mirror
+async fn fetch_fallback_tarball(
+    dep: &Dependency,
+    fallbacks: &[Registry],
+    registries: &[Registry],
+) -> Result<Option<(String, Vec<u8>)>> {
+    let basename = dep.name.rsplit('/').next().unwrap();
+
+    for registry in fallbacks {
+        if registry
+            .scope
+            .as_ref()
+            .is_some_and(|scope| !dep.name.starts_with(scope))
+        {
+            continue;
+        }
+
+        let url = format!(
+            "{}/{}/-/{basename}-{}.tgz",
+            registry.url, dep.name, dep.version
+        );
+        if url == dep.dist.tarball {
+            continue;
+        }
+
+        log_verbose(&format!("Trying {url}"));
+
+        let res = fetch_tarball(&url, registries).await?;
+        if !res.status().is_success() {
+            continue;
+        }
+
+        let data = res.bytes().await?.to_vec();
+        if !dep.dist.verify(&data) {
+            log_warning(&format!(
+                "{} from {url} does not match the locked integrity, skipping",
+                dep.id()
+            ));
+            continue;
+        }
+
+        return Ok(Some((url, data)));
     }
+
+    Ok(None)
+}
+
+/// Start downloading the tarball of a package, falling back to other registries if it is missing
+async fn fetch_remote_tarball(
+    dep: &Dependency,
+    config: &Config,
+) -> Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+    log_verbose(&format!("Downloading {}@{}", dep.name, dep.version));
+
+    // Registries whose credentials may be sent with a tarball request
+    let mut registries = config.registry.clone();
+    registries.push(Registry::npm());
+
+    let res = fetch_tarball(&dep.dist.tarball, &registries).await?;
+
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = if res.status() == StatusCode::NOT_FOUND {
+        let fallbacks = fallback_registries(dep, config);
+        let (url, data) = fetch_fallback_tarball(dep, &fallbacks, &registries)
+            .await?
+            .ok_or_else(|| {
+                let e = eyre!("{} was not found at {}", dep.id(), dep.dist.tarball);
+                if dep.dist.has_integrity() {
+                    e
+                } else {
+                    e.note("Other registries are not tried, since the lockfile records no integrity to check their tarballs against")
+                }
+            })?;
+
+        log_warning(&format!(
+            "{} was not found at {}, downloaded from {url} instead",
+            dep.id(),
+            dep.dist.tarball
+        ));
+
+        Box::new(io::Cursor::new(data))
+    } else {
+        Box::new(StreamReader::new(
+            res.error_for_status()?
+                .bytes_stream()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        ))
+    };
+
+    Ok(reader)
 }
 
-pub fn tree_size(trees: &FxHashMap<CompactString, DependencyTree>) -> usize {
-    trees.len()
-        + trees
-            .values()
-            .map(|x| tree_size(&x.children))
-            .sum::<usize>()
+/// Apply the configured transforms and mark a store entry as complete
+async fn complete_download(target_path: &Path, dep: &Dependency, config: &Config) -> Result<()> {
+    let mut marker = String::new();
+
+    if let Some(transform) = &config.transform {
+        apply_transform(&get_package_src(target_path)?, dep, transform).await?;
+        marker = transform.hash()?;
+    }
+
+    write(target_path.join("_complete"), marker)?;
+    DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+
+    Ok(())
 }
 
 #[tracing::instrument]
-async fn download_package(dep: &Dependency) -> Result<()> {
-    let target_path = scoped_join(".cotton/store", dep.id())?;
+/// Download a package into the store, using the config of the project at `root`
+async fn download_package(root: &Path, dep: &Dependency) -> Result<()> {
+    if is_local(&dep.dist) {
+        return Ok(());
+    }
+
+    let target_path = root.join(scoped_join_for(
+        store_dir(),
+        dep.id(),
+        PathOperation::Extract,
+        &dep.id(),
+    )?);
 
     create_dir_all(&target_path)?;
 
-    if metadata(target_path.join("_complete")).is_ok() {
-        log_verbose(&format!("Skipped downloading {}", dep.id()));
-        return Ok(());
+    let config = read_config_in(root).await?;
+
+    // The marker records the transforms that were applied to this entry
+    let transform = match &config.transform {
+        Some(transform) => transform.hash()?,
+        None => String::new(),
+    };
+    match read_to_string(target_path.join("_complete")) {
+        Ok(applied) if applied == transform => {
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            log_verbose(&format!("Skipped downloading {}", dep.id()));
+            return Ok(());
+        }
+        Ok(_) => {
+            log_verbose(&format!(
+                "Transforms changed, downloading {} again",
+                dep.id()
+            ));
+            remove_dir_all(&target_path)?;
+            create_dir_all(&target_path)?;
+        }
+        Err(_) => {}
     }
 
-    static S: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(CLIENT_LIMIT));
-    let permit = S.acquire().await.unwrap();
+    if let Some((url, commit, path)) = GitSource::parse_locked(&dep.dist.tarball) {
+        let checkout = git::checkout(url, commit, config.allow_install_scripts).await?;
+        hardlink_dir(
+            git::package_dir(&checkout, path)?,
+            target_path.join("package"),
+        )?;
 
-    log_verbose(&format!("Downloading {}@{}", dep.name, dep.version));
+        complete_download(&target_path, dep, &config).await?;
+
+        log_progress(&format!("Checked out {}", dep.id().bright_blue()));
+
+        return Ok(());
+    }
+
+    let mut digest = None;
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = match local_tarball_path(&dep.dist) {
+        Some(path) => {
+            let data = read(root.join(path))?;
+            if dep.dist.has_integrity() && !dep.dist.verify(&data) {
+                return Err(eyre!("{path} has changed since it was locked")
+                    .suggestion("Run `cotton update` to lock the new contents"));
+            }
+            Box::new(io::Cursor::new(data))
+        }
+        None if !dep.dist.has_integrity() => {
+            // Lockfiles written before integrity was recorded have nothing to verify against
+            if ARGS.immutable {
+                return Err(eyre!(
+                    "{} has no integrity in the lockfile to verify its tarball against",
+                    dep.id()
+                )
+                .suggestion("Run `cotton update` without --immutable to record it"));
+            }
+            log_warning(&format!(
+                "{} has no integrity in the lockfile, so its tarball is not verified",
+                dep.id()
+            ));
+            fetch_remote_tarball(dep, &config).await?
+        }
+        None => {
+            // Remote tarballs are hashed as they are streamed into the archive
+            let hashed = Arc::new(Mutex::new(dep.dist.digest()));
+            digest = Some(hashed.clone());
+            let reader = fetch_remote_tarball(dep, &config).await?;
+            Box::new(BufReader::new(InspectReader::new(reader, move |data| {
+                hashed.lock().unwrap().update(data)
+            })))
+        }
+    };
 
-    let registry_auth = read_config()
-        .await?
-        .registry
-        .into_iter()
-        .find(|x| dep.dist.tarball.starts_with(&x.url))
-        .and_then(|x| x.auth);
-
-    let res = CLIENT
-        .get(&*dep.dist.tarball)
-        .pipe(|x| client_auth(x, registry_auth.as_ref()))?
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes_stream()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
-
-    drop(permit);
-
-    let reader = StreamReader::new(res);
     let reader = GzipDecoder::new(reader);
 
     let mut archive = Archive::new(reader);
 
-    archive
-        .unpack(&target_path)
-        .await
-        .map_err(|e| eyre!("{e:?}"))?;
+    if let Err(e) = unpack_package(&mut archive, &target_path, dep).await {
+        remove_dir_all(&target_path)?;
+        return Err(e);
+    }
+
+    if let Some(digest) = digest {
+        // The archive ends before the padding and the gzip trailer, which are part of the hash
+        let drained = match archive.into_inner() {
+            Ok(decoder) => tokio::io::copy(&mut decoder.into_inner(), &mut tokio::io::sink())
+                .await
+                .map_err(|e| eyre!("Failed to read the end of the tarball of {}: {e}", dep.id())),
+            Err(_) => Err(eyre!(
+                "Failed to read the end of the tarball of {}, since it is still in use",
+                dep.id()
+            )),
+        };
+        if let Err(e) = drained {
+            remove_dir_all(&target_path)?;
+            return Err(e);
+        }
 
-    File::create(target_path.join("_complete"))?;
+        let digest = digest.lock().unwrap().clone();
+        if !dep.dist.matches(digest) {
+            remove_dir_all(&target_path)?;
+            return Err(eyre!(
+                "{} from {} does not match its recorded integrity",
+                dep.id(),
+                dep.dist.tarball
+            )
+            .suggestion("The tarball may have been tampered with; check the registry, or run `cotton update` if the package was republished"));
+        }
+    }
+
+    complete_download(&target_path, dep, &config).await?;
 
     log_progress(&format!("Downloaded {}", dep.id().bright_blue()));
 
     Ok(())
 }
 
-pub async fn download_package_shared(dep: Dependency) -> Result<()> {
-    static CACHE: Lazy<Cache<Dependency, Result<(), Arc<Report>>>> = Lazy::new(|| {
-        Cache::new(|key: Dependency| async move {
-            retry(|| download_package(&key)).await.map_err(Arc::new)
+/// A package to download, along with the project whose config it is downloaded with
+#[derive(Clone, Debug)]
+struct Download {
+    root: PathBuf,
+    dep: Dependency,
+}
+
+// Downloads are shared by package, since the store entry is the same for every project
+impl PartialEq for Download {
+    fn eq(&self, other: &Self) -> bool {
+        self.dep == other.dep
+    }
+}
+
+impl Eq for Download {}
+
+impl Hash for Download {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.dep.hash(state)
+    }
+}
+
+/// Download a package into the store once, however many projects and packages need it
+pub async fn download_package_in(root: PathBuf, dep: Dependency) -> Result<()> {
+    static CACHE: Lazy<Cache<Download, Result<(), Arc<Report>>>> = Lazy::new(|| {
+        Cache::new(|key: Download| async move {
+            retry(|| download_package(&key.root, &key.dep))
+                .await
+                .map_err(Arc::new)
         })
     });
 
-    CACHE.get(dep).await.map_err(Report::msg)
+    CACHE.get(Download { root, dep }).await.map_err(Report::msg)
+}
+
+/// Download a package for the project in the current directory
+pub fn download_package_shared(dep: Dependency) -> impl Future<Output = Result<()>> {
+    // The directory is read now, since it may change before a spawned download starts
+    let root = current_dir().unwrap_or_default();
+    download_package_in(root, dep)
+}
+
+/// Whether a symlink at `link`, relative to the package directory, resolves to `target` inside it
+fn stays_inside(link: &Path, target: &Path) -> bool {
+    let mut depth = link.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Whether `path` or one of its parents is a symlink in `links`, which extracting to it or
+/// linking to it would follow
+fn through_link(links: &FxHashSet<PathBuf>, path: &Path) -> bool {
+    path.ancestors().any(|x| links.contains(x))
+}
+
+/// A path inside a tarball without `.` components, which tarballs may start their paths with
+fn normalize_entry(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|x| *x != Component::CurDir)
+        .collect()
+}
+
+fn outside_link(link: &Path, target: &Path) -> Report {
+    eyre!(
+        "{} links to {}, which is outside of the package",
+        link.display(),
+        target.display()
+    )
+    .note("Links inside packages are only allowed to point to files of the same package")
+}
+
+/// Extract a package tarball, rejecting links that point outside of the package
+async fn unpack_package(
+    archive: &mut Archive<impl AsyncRead + Unpin + Send>,
+    dst: &Path,
+    dep: &Dependency,
+) -> Result<()> {
+    // Symlinks that were extracted so far, which later entries must not be extracted through,
+    // since the targets of links are only checked as text
+    let mut links = FxHashSet::default();
+
+    let mut entries = archive.entries()?;
+    while let Some(mut entry) = entries.try_next().await? {
+        let path = normalize_entry(&entry.path()?);
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if through_link(&links, parent) {
+            return Err(eyre!(
+                "{} is extracted through a symlink of the package",
+                Path::new(&dep.id())
+                    .join(path.iter().skip(1).collect::<PathBuf>())
+                    .display()
+            ));
+        }
+
+        if let Some(target) = entry.link_name()? {
+            // Tarballs contain a single directory, which the package is installed from
+            let inside = match entry.header().entry_type() {
+                EntryType::Symlink => {
+                    stays_inside(&path.iter().skip(1).collect::<PathBuf>(), &target)
+                }
+                // Hard links are relative to the root of the tarball instead of to the link
+                // A hard link to a symlink is a copy of the symlink, which resolves from elsewhere
+                EntryType::Link => {
+                    target.iter().next() == path.iter().next()
+                        && !through_link(&links, &normalize_entry(&target))
+                        && stays_inside(Path::new("."), &target.iter().skip(1).collect::<PathBuf>())
+                }
+                _ => true,
+            };
+            if !inside {
+                let link = Path::new(&dep.id()).join(path.iter().skip(1).collect::<PathBuf>());
+                return Err(outside_link(&link, &target));
+            }
+        }
+
+        if entry.header().entry_type() == EntryType::Symlink {
+            links.insert(path);
+        }
+        entry.unpack_in(dst).await.map_err(|e| eyre!("{e:?}"))?;
+    }
+
+    Ok(())
 }
 
 fn hardlink_dir(src: PathBuf, dst: PathBuf) -> Result<()> {
-    std::fs::create_dir_all(&dst)?;
-    let dir = std::fs::read_dir(src)?;
-    for entry in dir {
+    hardlink_tree(&src, &dst, Path::new(""))
+}
+
+fn hardlink_tree(src: &Path, dst: &Path, relative: &Path) -> Result<()> {
+    create_dir_all(dst)?;
+    for entry in read_dir(src)? {
         let entry = entry?;
         let ty = entry.file_type()?;
-        if ty.is_dir() {
-            hardlink_dir(entry.path(), dst.join(entry.file_name()))?;
+        let relative = relative.join(entry.file_name());
+        if ty.is_symlink() {
+            // Hard links to symlinks are resolved on some platforms, so the links are created again
+            let target = read_link(entry.path())?;
+            if !stays_inside(&relative, &target) {
+                return Err(outside_link(&entry.path(), &target));
+            }
+            symlink(target, dst.join(entry.file_name()))?;
+        } else if ty.is_dir() {
+            hardlink_tree(&entry.path(), &dst.join(entry.file_name()), &relative)?;
         } else {
-            std::fs::hard_link(entry.path(), dst.join(entry.file_name()))?;
+            hard_link(entry.path(), dst.join(entry.file_name()))?;
         }
     }
     Ok(())
@@ -158,10 +568,8 @@ fn get_package_src(src: &Path) -> Result<PathBuf> {
     Err(Report::msg("No package src found"))
 }
 
-#[tracing::instrument]
-pub async fn install_package(prefix: &[CompactString], dep: &Dependency) -> Result<()> {
-    download_package_shared(dep.clone()).await?;
-
+/// Location of a package inside `node_modules`, nested under the packages in `prefix`
+pub fn install_path(prefix: &[CompactString], dep: &Dependency) -> Result<PathBuf> {
     let mut target_path = PathBuf::new();
 
     for segment in prefix {
@@ -171,12 +579,54 @@ pub async fn install_package(prefix: &[CompactString], dep: &Dependency) -> Resu
 
     target_path.push(&*dep.name);
 
+    // The package itself may be a symlink to a linked directory, so only its parent is scoped
+    let name = target_path
+        .file_name()
+        .wrap_err_with(|| eyre!("Invalid package name {}", dep.name))?
+        .to_owned();
+    let parent = target_path.parent().unwrap_or(Path::new(""));
+
+    Ok(scoped_join_for("node_modules", parent, PathOperation::Install, &dep.id())?.join(name))
+}
+
+pub fn install_marker(target_path: &Path, dep: &Dependency) -> PathBuf {
+    target_path.join(format!(".installed!{}", dep.id()))
+}
+
+/// Location of the extracted package contents inside the store
+pub fn store_src_path(dep: &Dependency) -> Result<PathBuf> {
+    get_package_src(&scoped_join_for(
+        store_dir(),
+        dep.id(),
+        PathOperation::Install,
+        &dep.id(),
+    )?)
+}
+
+#[tracing::instrument]
+/// Install a package into `node_modules` of the project at `root`
+pub async fn install_package(
+    root: &Path,
+    prefix: &[CompactString],
+    dep: &Dependency,
+) -> Result<()> {
+    download_package_in(root.to_path_buf(), dep.clone()).await?;
+
+    let relative_path = install_path(prefix, dep)?;
+    let target_path = root.join(&relative_path);
+
     log_verbose(&format!("Installing {}", target_path.to_string_lossy()));
 
-    target_path = scoped_join("node_modules", target_path)?;
+    let install_marker = install_marker(&target_path, dep);
+    let local = local_path(&dep.dist);
+    let linked = linked_path(&dep.dist);
 
-    let install_marker = target_path.join(format!(".installed!{}", dep.id()));
-    if exists(&install_marker)? {
+    let installed = match linked {
+        Some(dir) => is_linked(&target_path, root.join(dir)),
+        // Local packages are linked again every time, since their contents may have changed
+        None => local.is_none() && exists(&install_marker)?,
+    };
+    if installed {
         log_verbose(&format!(
             "Skipping installation for {}",
             dep.id().bright_blue()
@@ -186,58 +636,82 @@ pub async fn install_package(prefix: &[CompactString], dep: &Dependency) -> Resu
 
     let _ = remove_dir_all(&target_path);
 
-    let src_path = scoped_join(".cotton/store", dep.id())?;
-
-    hardlink_dir(get_package_src(&src_path)?, target_path)?;
+    match (local, linked) {
+        (Some(dir), _) => hardlink_local_dir(&root.join(dir), &target_path)?,
+        (_, Some(dir)) => link_dir(root, dir, &relative_path)?,
+        _ => hardlink_dir(root.join(store_src_path(dep)?), target_path)?,
+    }
 
     if prefix.is_empty() {
-        for (cmd, path) in &dep.bins {
-            let path = path.to_compact_string();
-            let mut path = PathBuf::from("../").join(&*dep.name).join(&*path);
-            if !exists(PathBuf::from("node_modules/.bin").join(&path))? {
-                path.set_extension("js");
-            }
-            if !cmd.contains('/') {
-                let bin_path = PathBuf::from("node_modules/.bin").join(&**cmd);
-                if let Err(e) = symlink(&path, &bin_path) {
-                    if e.kind() != ErrorKind::AlreadyExists {
-                        return Err(e.into());
-                    }
-                }
-                set_permissions(&bin_path, Permissions::from_mode(0o755))?;
-            }
+        for (cmd, path) in link_bins(root, dep)? {
+            log_verbose(&format!(
+                "Deferring bin {cmd} of {}, since {} does not exist yet",
+                dep.id().bright_blue(),
+                path.display()
+            ));
         }
     }
 
-    File::create(&install_marker)?;
+    // A marker inside a linked package would end up in its source directory
+    if linked.is_none() {
+        File::create(&install_marker)?;
+    }
 
     log_progress(&format!("Installed {}", dep.id().bright_blue()));
 
     Ok(())
 }
 
-fn warmup_dep_tree(dep: &DependencyTree) {
-    tokio::spawn(download_package_shared(dep.root.clone()));
+fn warmup_dep_tree(root: &Path, dep: &DependencyTree) {
+    tokio::spawn(download_package_in(root.to_path_buf(), dep.root.clone()));
     for child in dep.children.values() {
-        warmup_dep_tree(child);
+        warmup_dep_tree(root, child);
     }
 }
 
-pub async fn execute_plan(plan: Plan) -> Result<()> {
+/// Install the packages of a plan into the project at `root`, returning the plan without the
+/// optional packages that failed
+pub async fn execute_plan(root: &Path, mut plan: Plan) -> Result<Plan> {
     let (send, recv) = flume::unbounded();
 
+    type Failed = Arc<Mutex<FxHashSet<Dependency>>>;
+
     fn queue_install(
         send: flume::Sender<JoinHandle<Result<()>>>,
+        root: PathBuf,
         tree: DependencyTree,
         prefix: Vec<CompactString>,
+        optional: bool,
+        failed: Failed,
     ) -> Result<()> {
         send.clone().send(tokio::spawn(async move {
-            install_package(&prefix, &tree.root).await?;
+            // Everything below an optional package is only needed by it
+            let optional = optional || tree.optional;
+
+            if let Err(e) = install_package(&root, &prefix, &tree.root).await {
+                if !optional {
+                    return Err(e);
+                }
+                log_warning(&format!(
+                    "Skipped optional dependency {}: {e}",
+                    tree.root.id().bright_blue()
+                ));
+                let _ = remove_dir_all(root.join(install_path(&prefix, &tree.root)?));
+                failed.lock().unwrap().insert(tree.root);
+                return Ok(());
+            }
 
             for (_, dep) in tree.children {
                 let mut prefix = prefix.clone();
                 prefix.push(tree.root.name.clone());
-                queue_install(send.clone(), dep, prefix)?;
+                queue_install(
+                    send.clone(),
+                    root.clone(),
+                    dep,
+                    prefix,
+                    optional,
+                    failed.clone(),
+                )?;
             }
 
             Result::Ok(())
@@ -246,9 +720,17 @@ pub async fn execute_plan(plan: Plan) -> Result<()> {
         Ok(())
     }
 
-    for (_, tree) in plan.trees.into_iter() {
-        warmup_dep_tree(&tree);
-        queue_install(send.clone(), tree, vec![])?;
+    let failed = Failed::default();
+    for tree in plan.trees.values() {
+        warmup_dep_tree(root, tree);
+        queue_install(
+            send.clone(),
+            root.to_path_buf(),
+            tree.clone(),
+            vec![],
+            false,
+            failed.clone(),
+        )?;
     }
 
     drop(send);
@@ -257,5 +739,66 @@ pub async fn execute_plan(plan: Plan) -> Result<()> {
         x.await??;
     }
 
-    Ok(())
+    // The failed packages are left out of the installed plan, so the next install tries them again
+    let failed = take(&mut *failed.lock().unwrap());
+    if !failed.is_empty() {
+        plan.trees = plan
+            .trees
+            .into_iter()
+            .filter(|(_, tree)| !failed.contains(&tree.root))
+            .map(|(name, tree)| (name, tree.filter(&failed)))
+            .collect();
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_absolute_targets() {
+        assert!(!stays_inside(Path::new("bin"), Path::new("/etc/passwd")));
+        assert!(!stays_inside(
+            Path::new("lib/bin"),
+            Path::new("/lib/index.js")
+        ));
+    }
+
+    #[test]
+    fn rejects_leaving_the_root() {
+        assert!(stays_inside(Path::new("lib/bin"), Path::new("../index.js")));
+        assert!(!stays_inside(Path::new("bin"), Path::new("../index.js")));
+        assert!(!stays_inside(
+            Path::new("lib/bin"),
+            Path::new("../../index.js")
+        ));
+        assert!(!stays_inside(
+            Path::new("lib/bin"),
+            Path::new("../lib/../../index.js")
+        ));
+        assert!(stays_inside(Path::new("lib/bin"), Path::new("./index.js")));
+    }
+
+    #[test]
+    fn rejects_chained_links() {
+        // `x/y -> .` keeps the next link at the root, while its text claims a depth of 2
+        assert!(stays_inside(Path::new("x/y"), Path::new(".")));
+        assert!(stays_inside(Path::new("x/y/z"), Path::new("../..")));
+
+        let links = FxHashSet::from_iter([PathBuf::from("package/x/y")]);
+        assert!(through_link(&links, Path::new("package/x/y")));
+        assert!(through_link(&links, Path::new("package/x/y/z")));
+        assert!(!through_link(&links, Path::new("package/x")));
+        assert!(!through_link(&links, Path::new("package/x/yz")));
+    }
+
+    #[test]
+    fn normalizes_entries() {
+        assert_eq!(
+            normalize_entry(Path::new("./package/./x/y")),
+            Path::new("package/x/y")
+        );
+    }
 }
@@ -0,0 +1,180 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use nix::{
+    errno::Errno,
+    sys::statvfs::statvfs,
+    unistd::{access, geteuid, AccessFlags},
+};
+use rustc_hash::FxHashSet;
+use std::{
+    fs::{metadata, read_dir, symlink_metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    local::is_local,
+    npm::{Dependency, DependencyTree},
+    plan::{store_dir, Plan},
+    scoped_path::{scoped_join_for, PathOperation},
+};
+
+/// Space assumed for a package when the registry does not report its unpacked size
+const DEFAULT_PACKAGE_SIZE: u64 = 1024 * 1024;
+
+/// Free space kept in reserve, since the estimate ignores filesystem overhead
+const SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{size:.1} {unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1} TiB")
+}
+
+/// The closest ancestor of `path` that exists, which is where it would be created
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .map(|x| {
+            if x.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                x
+            }
+        })
+        .find(|x| x.exists())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+fn check_writable(path: &Path) -> Result<()> {
+    let Err(errno) = access(path, AccessFlags::W_OK) else {
+        return Ok(());
+    };
+
+    let report = eyre!("Cannot write to {}: {}", path.display(), errno.desc());
+    if errno == Errno::EROFS {
+        return Err(report.suggestion(
+            "The directory is on a read-only filesystem; remount it as writable, or install from a writable copy of the project",
+        ));
+    }
+
+    let uid = geteuid().as_raw();
+    match metadata(path).map(|x| x.uid()) {
+        Ok(owner) if owner != uid => Err(report
+            .with_note(|| {
+                format!(
+                    "{} is owned by user {owner}, but cotton is running as user {uid}",
+                    path.display()
+                )
+            })
+            .suggestion(format!(
+                "If it was created by a container running as root, take ownership with `sudo chown -R $(id -u):$(id -g) {}`",
+                path.display()
+            ))),
+        _ => Err(report.suggestion(format!(
+            "Allow writing with `chmod -R u+w {}`",
+            path.display()
+        ))),
+    }
+}
+
+/// Check that the store and `node_modules` can be written to, before anything is created in them
+pub fn check_permissions() -> Result<()> {
+    for dir in [
+        store_dir(),
+        PathBuf::from("node_modules"),
+        PathBuf::from("node_modules/.cotton"),
+        PathBuf::from("node_modules/.bin"),
+    ] {
+        check_writable(&existing_ancestor(&dir))?;
+    }
+
+    // Packages are replaced by removing them, which needs them to be writable too
+    if let Ok(entries) = read_dir("node_modules") {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if symlink_metadata(&path).is_ok_and(|x| x.is_dir()) {
+                check_writable(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_missing(tree: &DependencyTree, missing: &mut FxHashSet<Dependency>) -> Result<()> {
+    let dep = &tree.root;
+    if !is_local(&dep.dist) && !missing.contains(dep) {
+        let path = scoped_join_for(store_dir(), dep.id(), PathOperation::Extract, &dep.id())?;
+        if !path.join("_complete").exists() {
+            missing.insert(dep.clone());
+        }
+    }
+    for child in tree.children.values() {
+        collect_missing(child, missing)?;
+    }
+    Ok(())
+}
+
+fn has_store_packages(tree: &DependencyTree) -> bool {
+    !is_local(&tree.root.dist) || tree.children.values().any(has_store_packages)
+}
+
+/// Check that the packages in `plan` fit in the store and can be linked into `node_modules`
+pub fn check_plan(plan: &Plan) -> Result<()> {
+    let store = existing_ancestor(&store_dir());
+
+    // Packages are hard linked from the store, which only works within one filesystem
+    if plan.trees.values().any(has_store_packages) {
+        if let (Ok(store_meta), Ok(modules_meta)) = (metadata(&store), metadata("node_modules")) {
+            if store_meta.dev() != modules_meta.dev() {
+                return Err(eyre!(
+                    "The store at {} is on a different filesystem than node_modules",
+                    store_dir().display()
+                )
+                .with_note(|| "Packages are hard linked from the store into node_modules")
+                .suggestion(
+                    "Keep the store and the project on the same filesystem, such as by mounting both into a container together",
+                ));
+            }
+        }
+    }
+
+    let mut missing = FxHashSet::default();
+    for tree in plan.trees.values() {
+        collect_missing(tree, &mut missing)?;
+    }
+    let required: u64 = missing
+        .iter()
+        .map(|x| x.dist.unpacked_size.unwrap_or(DEFAULT_PACKAGE_SIZE))
+        .sum();
+    if required == 0 {
+        return Ok(());
+    }
+
+    let Ok(stat) = statvfs(&store) else {
+        return Ok(());
+    };
+    let available = stat.blocks_available() * stat.fragment_size();
+    if required + SPACE_MARGIN > available {
+        return Err(eyre!(
+            "Downloading {} packages needs about {}, but only {} is free at {}",
+            missing.len(),
+            format_size(required),
+            format_size(available),
+            store.display()
+        )
+        .suggestion(
+            "Free up space, or remove unused store entries with `cotton clean --older-than 30d`",
+        ));
+    }
+
+    Ok(())
+}
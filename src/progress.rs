@@ -1,10 +1,10 @@
-use std::time::Duration;
+use std::{sync::atomic::Ordering, time::Duration};
 
 use indicatif::{ProgressBar, ProgressStyle};
 use once_cell::sync::Lazy;
 use owo_colors::OwoColorize;
 
-use crate::ARGS;
+use crate::{result::WARNINGS, ARGS};
 
 pub static PROGRESS_BAR: Lazy<ProgressBar> = Lazy::new(|| {
     let pb = ProgressBar::new(0).with_style(
@@ -24,6 +24,7 @@ pub fn log_verbose(text: &str) {
 }
 
 pub fn log_warning(text: &str) {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
     PROGRESS_BAR.suspend(|| println!("{} {}", " WARNING ".on_yellow(), text));
 }
 
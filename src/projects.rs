@@ -0,0 +1,73 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env::current_dir,
+    fs::{create_dir_all, read_to_string, rename, write, File},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::global::cotton_home;
+
+/// A project that cotton has installed packages for
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProjectRecord {
+    /// Seconds since the Unix epoch
+    pub last_install: u64,
+}
+
+/// Every project on this machine, keyed by its canonical path
+#[derive(Serialize, Deserialize, Default)]
+pub struct Projects {
+    #[serde(default)]
+    pub projects: BTreeMap<PathBuf, ProjectRecord>,
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(cotton_home()?.join("projects.json"))
+}
+
+pub fn load() -> Result<Projects> {
+    match read_to_string(path()?) {
+        Ok(s) => Ok(serde_json::from_str(&s)?),
+        Err(_) => Ok(Projects::default()),
+    }
+}
+
+fn save(projects: &Projects) -> Result<()> {
+    // Write to a temporary file first, so that the registry is never read while partially written
+    let tmp = path()?.with_extension("json.tmp");
+    write(&tmp, serde_json::to_string_pretty(projects)?)?;
+    rename(&tmp, path()?)?;
+    Ok(())
+}
+
+/// Change the registry while holding a lock on it, so that cotton running in several projects at
+/// once does not lose records
+pub fn update<T>(f: impl FnOnce(&mut Projects) -> T) -> Result<T> {
+    create_dir_all(cotton_home()?)?;
+    let lock = File::create(cotton_home()?.join("projects.lock"))?;
+    lock.lock()?;
+
+    let mut projects = load()?;
+    let result = f(&mut projects);
+    save(&projects)?;
+    Ok(result)
+}
+
+/// Record the current directory as a project, unless it is part of cotton's own state such as the global prefix
+pub fn register() -> Result<()> {
+    let dir = current_dir()?.canonicalize()?;
+    let home = cotton_home()?;
+    if dir.starts_with(home.canonicalize().unwrap_or(home)) {
+        return Ok(());
+    }
+
+    let record = ProjectRecord {
+        last_install: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    update(|projects| {
+        projects.projects.insert(dir, record);
+    })
+}
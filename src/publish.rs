@@ -0,0 +1,501 @@
+use async_compression::tokio::write::GzipEncoder;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use compact_str::CompactString;
+use futures::future::try_join_all;
+use glob::MatchOptions;
+use node_semver::Version;
+use owo_colors::OwoColorize;
+use reqwest::StatusCode;
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use std::{
+    fs::{read_dir, read_to_string, write},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    slice,
+};
+use tap::Pipe;
+use tokio::io::AsyncWriteExt;
+use tokio_tar::{Builder, Header};
+
+use crate::{
+    catalog::Catalogs,
+    config::{client_auth, read_config},
+    npm::select_registry,
+    package::PackageMetadata,
+    progress::{log_progress, PROGRESS_BAR},
+    scripts::run_lifecycle_script,
+    util::{read_json, read_package, CLIENT},
+    workspace::{enclosing_workspaces, sort_workspaces, Workspace},
+};
+
+pub const DEPENDENCY_FIELDS: [&str; 4] = [
+    "dependencies",
+    "devDependencies",
+    "optionalDependencies",
+    "peerDependencies",
+];
+
+/// Files that are always published, even if not listed in `files` or ignored
+const ALWAYS_INCLUDED: [&str; 4] = ["README", "LICENSE", "LICENCE", "CHANGELOG"];
+
+/// Files that are never published, as with npm, along with environment files that often hold
+/// secrets
+const EXCLUDED: [&str; 19] = [
+    ".git",
+    ".svn",
+    ".hg",
+    "CVS",
+    ".cotton",
+    "node_modules",
+    ".npmrc",
+    ".npmignore",
+    ".gitignore",
+    ".env",
+    ".env.*",
+    ".*.swp",
+    "._*",
+    ".DS_Store",
+    "npm-debug.log",
+    "package-lock.json",
+    "config.gypi",
+    "*.orig",
+    ".lock-wscript",
+];
+
+/// A line of `.npmignore` or `.gitignore`
+struct IgnoreRule {
+    pattern: glob::Pattern,
+    /// Lines starting with `!` include files that earlier lines ignored
+    negated: bool,
+    /// Patterns containing `/` match from the package root, while others match names at any depth
+    anchored: bool,
+    /// Patterns ending in `/` only match directories
+    dir_only: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(x) => (true, x),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(x) => (true, x),
+            None => (false, line),
+        };
+
+        Ok(Some(Self {
+            pattern: glob::Pattern::new(line.trim_start_matches('/'))?,
+            negated,
+            anchored: line.contains('/'),
+            dir_only,
+        }))
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        if self.anchored {
+            self.pattern.matches_path_with(path, options)
+        } else {
+            path.file_name()
+                .is_some_and(|x| self.pattern.matches_with(&x.to_string_lossy(), options))
+        }
+    }
+}
+
+/// Whether the last rule matching `path` ignores it
+fn is_ignored(rules: &[IgnoreRule], path: &Path, is_dir: bool) -> bool {
+    rules.iter().fold(false, |ignored, rule| {
+        if rule.matches(path, is_dir) {
+            !rule.negated
+        } else {
+            ignored
+        }
+    })
+}
+
+fn parse_rules<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Vec<IgnoreRule>> {
+    lines
+        .into_iter()
+        .filter_map(|x| IgnoreRule::parse(x).transpose())
+        .collect()
+}
+
+/// Rules from `.npmignore`, or from `.gitignore` if there is none, as npm reads them
+fn ignore_rules(dir: &Path) -> Result<Vec<IgnoreRule>> {
+    for name in [".npmignore", ".gitignore"] {
+        match read_to_string(dir.join(name)) {
+            Ok(text) => return parse_rules(text.lines()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(vec![])
+}
+
+fn is_always_included(file: &Path) -> bool {
+    let top_level = file.parent() == Some(Path::new(""));
+    let name = file.to_string_lossy().to_uppercase();
+
+    top_level && ALWAYS_INCLUDED.iter().any(|x| name.starts_with(x))
+}
+
+struct Release {
+    name: CompactString,
+    version: Version,
+    dir: PathBuf,
+    manifest: Value,
+}
+
+async fn is_published(name: &str, version: &Version) -> Result<bool> {
+    let registry = select_registry(name).await?;
+
+    let res = CLIENT
+        .get(format!("{}/{}", registry.url, name.replace('/', "%2f")))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .send()
+        .await?;
+
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    let packument: Value = res.error_for_status()?.json().await?;
+
+    Ok(packument["versions"].get(version.to_string()).is_some())
+}
+
+/// Replace `workspace:` specifiers with the versions being published
+fn rewrite_workspace_specifiers(
+    manifest: &mut Value,
+    versions: &FxHashMap<CompactString, Version>,
+) -> Result<()> {
+    for field in DEPENDENCY_FIELDS {
+        let Some(deps) = manifest.get_mut(field).and_then(|x| x.as_object_mut()) else {
+            continue;
+        };
+
+        for (name, spec) in deps.iter_mut() {
+            let Some(range) = spec.as_str().and_then(|x| x.strip_prefix("workspace:")) else {
+                continue;
+            };
+
+            let version = versions
+                .get(name.as_str())
+                .wrap_err_with(|| eyre!("`{name}` is not a workspace package"))?;
+
+            *spec = Value::String(match range {
+                "" | "*" => version.to_string(),
+                "^" => format!("^{version}"),
+                "~" => format!("~{version}"),
+                range => range.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Files in `dir` relative to the package root, leaving out excluded and ignored files
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    excluded: &[IgnoreRule],
+    ignored: &[IgnoreRule],
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        if is_ignored(excluded, &path, is_dir)
+            || (is_ignored(ignored, &path, is_dir) && !is_always_included(&path))
+        {
+            continue;
+        }
+
+        if is_dir {
+            collect_files(root, &path, excluded, ignored, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Files to include in the tarball, relative to `dir`, respecting the `files` field, or
+/// `.npmignore` and `.gitignore` when there is no `files` field
+fn package_files(dir: &Path, manifest: &Value) -> Result<Vec<PathBuf>> {
+    let excluded = parse_rules(EXCLUDED)?;
+    let patterns = manifest["files"].as_array();
+    // As with npm, `files` takes the place of the ignore files of the package root
+    let ignored = match patterns {
+        Some(_) => vec![],
+        None => ignore_rules(dir)?,
+    };
+
+    let mut files = vec![];
+    collect_files(dir, Path::new(""), &excluded, &ignored, &mut files)?;
+
+    if let Some(patterns) = patterns {
+        let patterns = patterns
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| glob::Pattern::new(x.trim_start_matches("./")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        files.retain(|file| {
+            is_always_included(file)
+                || file
+                    .ancestors()
+                    .any(|x| patterns.iter().any(|p| p.matches_path(x)))
+        });
+    }
+
+    files.retain(|x| x != Path::new("package.json"));
+    files.sort();
+
+    Ok(files)
+}
+
+/// The tarball of a release, along with the files in it
+async fn pack(release: &Release) -> Result<(Vec<u8>, Vec<PathBuf>)> {
+    let files = package_files(&release.dir, &release.manifest)?;
+    let mut builder = Builder::new(GzipEncoder::new(Vec::new()));
+
+    for file in &files {
+        builder
+            .append_path_with_name(release.dir.join(file), Path::new("package").join(file))
+            .await?;
+    }
+
+    let manifest = serde_json::to_vec_pretty(&release.manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "package/package.json", manifest.as_slice())
+        .await?;
+
+    let mut encoder = builder.into_inner().await?;
+    encoder.shutdown().await?;
+
+    let files = [PathBuf::from("package.json")]
+        .into_iter()
+        .chain(files)
+        .collect();
+    Ok((encoder.into_inner(), files))
+}
+
+async fn upload(release: &Release, tarball: &[u8], tag: &str) -> Result<()> {
+    let registry = select_registry(&release.name).await?;
+    let name = &release.name;
+    let version = release.version.to_string();
+
+    let basename = name.rsplit('/').next().unwrap();
+    let filename = format!("{basename}-{version}.tgz");
+
+    let mut manifest = release.manifest.clone();
+    manifest["_id"] = json!(format!("{name}@{version}"));
+    manifest["dist"] = json!({
+        "shasum": format!("{:x}", Sha1::digest(tarball)),
+        "integrity": format!("sha512-{}", STANDARD.encode(Sha512::digest(tarball))),
+        "tarball": format!("{}/{name}/-/{filename}", registry.url),
+    });
+
+    let body = json!({
+        "_id": name,
+        "name": name,
+        "description": manifest["description"],
+        "dist-tags": { tag: version },
+        "versions": { &version: manifest },
+        "_attachments": {
+            filename: {
+                "content_type": "application/octet-stream",
+                "data": STANDARD.encode(tarball),
+                "length": tarball.len(),
+            }
+        },
+    });
+
+    CLIENT
+        .put(format!("{}/{}", registry.url, name.replace('/', "%2f")))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| eyre!("Failed to publish {name}@{version}: {e}"))?;
+
+    Ok(())
+}
+
+/// Versions of the workspace packages that `workspace:` specifiers may refer to
+async fn workspace_versions(workspaces: &[Workspace]) -> Result<FxHashMap<CompactString, Version>> {
+    Ok(enclosing_workspaces()
+        .await?
+        .iter()
+        .chain(workspaces)
+        .filter_map(|x| Some((x.name.clone(), x.package.version.clone()?)))
+        .collect())
+}
+
+/// Pack the package in `dir` into `<name>-<version>.tgz` in the current directory, as it would be published
+pub async fn pack_to_file(dir: &Path) -> Result<PathBuf> {
+    let package: PackageMetadata = read_json(dir.join("package.json")).await?;
+    let version = package
+        .version
+        .clone()
+        .wrap_err("package.json does not specify a version")?;
+    let workspace = Workspace {
+        name: package.name.clone(),
+        dir: dir.to_path_buf(),
+        package,
+    };
+
+    let mut manifest: Value = read_json(dir.join("package.json")).await?;
+    Catalogs::enclosing(&workspace.package)
+        .await?
+        .rewrite_manifest(&mut manifest)?;
+    rewrite_workspace_specifiers(
+        &mut manifest,
+        &workspace_versions(slice::from_ref(&workspace)).await?,
+    )?;
+
+    let release = Release {
+        name: workspace.name,
+        version,
+        dir: workspace.dir,
+        manifest,
+    };
+    let (tarball, _) = pack(&release).await?;
+
+    let file = PathBuf::from(format!(
+        "{}-{}.tgz",
+        release.name.trim_start_matches('@').replace('/', "-"),
+        release.version
+    ));
+    write(&file, tarball)?;
+
+    Ok(file)
+}
+
+/// Publish every workspace whose version is not yet on the registry, dependencies first
+pub async fn publish(
+    workspaces: Vec<Workspace>,
+    tag: &str,
+    dry_run: bool,
+) -> Result<Vec<(CompactString, Version)>> {
+    let versions = workspace_versions(&workspaces).await?;
+    let catalogs = Catalogs::enclosing(&read_package().await?).await?;
+
+    let mut releases = vec![];
+    for workspace in sort_workspaces(workspaces) {
+        let mut manifest: Value = read_json(workspace.dir.join("package.json")).await?;
+        if manifest["private"].as_bool() == Some(true) {
+            continue;
+        }
+        let Some(version) = workspace.package.version.clone() else {
+            continue;
+        };
+
+        catalogs.rewrite_manifest(&mut manifest)?;
+        rewrite_workspace_specifiers(&mut manifest, &versions)?;
+
+        releases.push(Release {
+            name: workspace.name,
+            version,
+            dir: workspace.dir,
+            manifest,
+        });
+    }
+
+    let published =
+        try_join_all(releases.iter().map(|x| is_published(&x.name, &x.version))).await?;
+
+    let releases = releases
+        .into_iter()
+        .zip(published)
+        .filter(|(_, published)| !published)
+        .map(|(x, _)| x)
+        .collect::<Vec<_>>();
+
+    // Releases only need to wait for the releases they depend on, so group them into levels
+    let mut levels: FxHashMap<CompactString, usize> = FxHashMap::default();
+    let mut batches: Vec<Vec<&Release>> = vec![];
+    for release in &releases {
+        let level = DEPENDENCY_FIELDS
+            .iter()
+            .filter_map(|field| release.manifest[field].as_object())
+            .flat_map(|deps| deps.keys())
+            .filter_map(|dep| levels.get(dep.as_str()))
+            .map(|x| x + 1)
+            .max()
+            .unwrap_or(0);
+
+        levels.insert(release.name.clone(), level);
+        if batches.len() <= level {
+            batches.resize_with(level + 1, Vec::new);
+        }
+        batches[level].push(release);
+    }
+
+    let allow_scripts = read_config().await?.allow_install_scripts;
+
+    for batch in batches {
+        try_join_all(batch.into_iter().map(|release| async move {
+            if allow_scripts {
+                let package: PackageMetadata = read_json(release.dir.join("package.json")).await?;
+                run_lifecycle_script(&release.dir, &package, "prepublishOnly").await?;
+            }
+
+            let (tarball, files) = pack(release).await?;
+
+            if dry_run {
+                PROGRESS_BAR.suspend(|| {
+                    println!(
+                        "Would publish {}@{} ({} files, {} bytes)",
+                        release.name.yellow(),
+                        release.version,
+                        files.len(),
+                        tarball.len()
+                    );
+                    for file in &files {
+                        println!("  {}", file.display());
+                    }
+                });
+            } else {
+                upload(release, &tarball, tag).await?;
+                log_progress(&format!(
+                    "Published {}@{}",
+                    release.name.bright_blue(),
+                    release.version
+                ));
+            }
+
+            Result::<_>::Ok(())
+        }))
+        .await?;
+    }
+
+    Ok(releases.into_iter().map(|x| (x.name, x.version)).collect())
+}
@@ -0,0 +1,221 @@
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use compact_str::CompactString;
+use node_semver::{Range, Version};
+use reqwest::{StatusCode, Url};
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use tap::Pipe;
+
+use crate::{
+    config::{client_auth, Registry},
+    npm::{fetch_dist_tags, select_registry},
+    util::CLIENT,
+};
+
+fn dist_tag_url(registry: &Registry, name: &str, tag: &str) -> String {
+    format!(
+        "{}/-/package/{}/dist-tags/{tag}",
+        registry.url,
+        name.replace('/', "%2f")
+    )
+}
+
+/// Split `name@version`, taking care of the `@` in scoped package names
+pub fn split_spec(spec: &str) -> Option<(&str, &str)> {
+    let (name, version) = spec.rsplit_once('@')?;
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, version))
+    }
+}
+
+pub async fn list_tags(name: &str) -> Result<FxHashMap<CompactString, CompactString>> {
+    fetch_dist_tags(name).await
+}
+
+pub async fn add_tag(name: &str, version: &Version, tag: &str) -> Result<()> {
+    let registry = select_registry(name).await?;
+
+    CLIENT
+        .put(dist_tag_url(&registry, name, tag))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .json(&version.to_string())
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| eyre!("Failed to add tag `{tag}` to {name}@{version}: {e}"))?;
+
+    Ok(())
+}
+
+pub async fn remove_tag(name: &str, tag: &str) -> Result<()> {
+    let registry = select_registry(name).await?;
+
+    CLIENT
+        .delete(dist_tag_url(&registry, name, tag))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| eyre!("Failed to remove tag `{tag}` from {name}: {e}"))?;
+
+    Ok(())
+}
+
+/// Mark every version in `range` as deprecated, or undeprecate them if `message` is empty
+pub async fn deprecate(name: &str, range: &Range, message: &str) -> Result<Vec<Version>> {
+    let registry = select_registry(name).await?;
+    let url = format!("{}/{}", registry.url, name.replace('/', "%2f"));
+
+    let mut packument: Value = CLIENT
+        .get(format!("{url}?write=true"))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut deprecated = vec![];
+    for (version, metadata) in packument["versions"]
+        .as_object_mut()
+        .wrap_err_with(|| eyre!("Registry returned invalid metadata for {name}"))?
+    {
+        if let Ok(version) = Version::parse(version) {
+            if range.satisfies(&version) {
+                metadata["deprecated"] = Value::String(message.into());
+                deprecated.push(version);
+            }
+        }
+    }
+
+    if deprecated.is_empty() {
+        return Err(eyre!("No versions of {name} match {range}"));
+    }
+
+    CLIENT
+        .put(url)
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .json(&packument)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| eyre!("Failed to deprecate {name}: {e}"))?;
+
+    Ok(deprecated)
+}
+
+/// A registry that responded to a ping
+pub struct Ping {
+    pub latency: Duration,
+    /// The user that the configured credentials belong to, if the registry reports it
+    pub username: Option<String>,
+}
+
+fn proxy_var(name: &str) -> Option<String> {
+    env::var(name.to_lowercase())
+        .or_else(|_| env::var(name))
+        .ok()
+        .filter(|x| !x.is_empty())
+}
+
+/// The proxy that requests to `url` go through, read from the same variables as the HTTP client,
+/// without its credentials
+pub fn proxy_for(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+
+    let bypassed = proxy_var("NO_PROXY").is_some_and(|list| {
+        list.split(',').map(|x| x.trim()).any(|x| {
+            let domain = x.trim_start_matches('.');
+            x == "*"
+                || (!domain.is_empty() && (host == domain || host.ends_with(&format!(".{domain}"))))
+        })
+    });
+    if bypassed {
+        return None;
+    }
+
+    let proxy = match url.scheme() {
+        "https" => proxy_var("HTTPS_PROXY"),
+        _ => proxy_var("HTTP_PROXY"),
+    }
+    .or_else(|| proxy_var("ALL_PROXY"))?;
+
+    Some(match Url::parse(&proxy) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => proxy,
+    })
+}
+
+/// Check that a registry can be reached, and that it accepts the configured credentials
+pub async fn ping(registry: &Registry) -> Result<Ping> {
+    let start = Instant::now();
+    let response = CLIENT
+        .get(format!("{}/-/ping?write=true", registry.url))
+        .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+        .send()
+        .await
+        .map_err(|e| {
+            // The cause, such as a refused connection or a failing proxy, is only in the sources
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                let cause_message = cause.to_string();
+                if !message.ends_with(&cause_message) {
+                    message.push_str(&format!(": {cause_message}"));
+                }
+                source = cause.source();
+            }
+            eyre!("Could not be reached: {message}")
+        })?;
+    let latency = start.elapsed();
+
+    match response.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN if registry.auth.is_some() => {
+            return Err(eyre!("Credentials were rejected ({})", response.status()))
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            return Err(eyre!(
+                "Requires credentials ({}), but none are configured in cotton.toml",
+                response.status()
+            ))
+        }
+        // Not every registry implements the ping endpoint, but one that responds is reachable
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {}
+        status if !status.is_success() => return Err(eyre!("Responded with {status}")),
+        _ => {}
+    }
+
+    let mut username = None;
+    if registry.auth.is_some() {
+        let response = CLIENT
+            .get(format!("{}/-/whoami", registry.url))
+            .pipe(|x| client_auth(x, registry.auth.as_ref()))?
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                return Err(eyre!("Credentials were rejected ({})", response.status()))
+            }
+            status if status.is_success() => {
+                username = response
+                    .json::<Value>()
+                    .await
+                    .ok()
+                    .and_then(|x| x["username"].as_str().map(|x| x.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Ping { latency, username })
+}
@@ -1,288 +1,304 @@
-use crate::npm::{Dependency, DependencyTree};
-use crate::package::{PackageInfo, PackageSpecifier, VersionedPackageInfo};
+use crate::config::read_config;
+use crate::local::{linked_dir, local_dir};
+use crate::npm::{self, Dependency, Strategy};
+use crate::overrides::collect_overrides;
+use crate::package::{PackageInfo, PackageMetadata, PackageSpecifier, VersionedPackageInfo};
 use crate::plan::download_package_shared;
-use crate::progress::log_verbose;
-use crate::{npm, ARGS};
-use color_eyre::eyre::ContextCompat;
+use crate::progress::{log_verbose, log_warning};
+use crate::util::supported_platforms;
+use crate::workspace::{workspace_range, Workspace};
+use crate::ARGS;
+use color_eyre::eyre::{eyre, ContextCompat, WrapErr};
 use color_eyre::{Report, Section};
 use compact_str::{CompactString, ToCompactString};
-use dashmap::{DashMap, DashSet};
+use cotton_core::resolve::{Events, ResolveOptions, Source};
 use itertools::Itertools;
 use node_semver::Version;
 use owo_colors::OwoColorize;
-use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, VecDeque};
-use std::mem::take;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::task::JoinHandle;
 
-#[derive(Deserialize, Debug, Default, Clone)]
-pub struct Graph {
-    #[serde(flatten)]
-    pub relations: FxHashMap<PackageSpecifier, VersionedPackageInfo>,
-}
-
-impl Graph {
-    pub async fn append(
-        &mut self,
-        remaining: impl Iterator<Item = PackageSpecifier>,
-        download: bool,
-    ) -> color_eyre::Result<()> {
-        fn queue_resolve(
-            send: flume::Sender<JoinHandle<color_eyre::Result<()>>>,
-            req: PackageSpecifier,
-            relations: Arc<DashMap<PackageSpecifier, VersionedPackageInfo>>,
-            seen: Arc<DashSet<PackageSpecifier>>,
-            download: bool,
-        ) -> color_eyre::Result<()> {
-            if !seen.insert(req.clone()) {
-                return Ok(());
-            }
+pub use cotton_core::resolve::Graph;
 
-            if let Some(subpackage) = relations.get(&req) {
-                for child_req in subpackage.package.iter() {
-                    queue_resolve(
-                        send.clone(),
-                        child_req,
-                        relations.clone(),
-                        seen.clone(),
-                        download,
-                    )?;
-                }
+/// Resolves packages from the configured registries and other sources of dependencies
+struct Registries {
+    /// Download packages as soon as they are resolved
+    download: bool,
+}
 
-                return Ok(());
-            }
+impl Source for Registries {
+    fn fetch(
+        &self,
+        req: PackageSpecifier,
+        strategy: Strategy,
+    ) -> impl Future<Output = color_eyre::Result<(Version, Arc<PackageInfo>)>> + Send {
+        npm::fetch_versioned_package(req, strategy)
+    }
 
-            send.clone().send(tokio::spawn(async move {
-                let (version, subpackage) = npm::fetch_versioned_package(req.clone()).await?;
-
-                if download && subpackage.supported() {
-                    tokio::spawn(download_package_shared(Dependency {
-                        name: req.name.to_compact_string(),
-                        version: version.clone(),
-                        dist: subpackage.dist.clone(),
-                        bins: subpackage.bins().into_iter().collect(),
-                        scripts: subpackage.scripts.clone(),
-                    }));
-                }
+    fn resolved(&self, dependency: Dependency) {
+        if self.download {
+            tokio::spawn(download_package_shared(dependency));
+        }
+    }
 
-                relations.insert(
-                    req.clone(),
-                    VersionedPackageInfo {
-                        package: subpackage.clone(),
-                        version,
-                    },
-                );
-
-                for child_req in subpackage.iter() {
-                    queue_resolve(
-                        send.clone(),
-                        child_req,
-                        relations.clone(),
-                        seen.clone(),
-                        download,
-                    )?;
-                }
+    fn skipped(&self, req: &PackageSpecifier, error: &Report) {
+        log_warning(&format!(
+            "Skipped optional dependency {}: {error}",
+            req.name.bright_blue()
+        ));
+    }
+}
 
-                Ok(()) as color_eyre::Result<_>
-            }))?;
+/// Prints what is left out of the graph in verbose mode
+pub struct VerboseEvents;
 
-            Ok(())
-        }
+impl Events for VerboseEvents {
+    fn pruned(&self, count: usize) {
+        log_verbose(&format!(
+            "Pruned {count} unreachable requirements from the graph"
+        ));
+    }
 
-        let relations: Arc<DashMap<_, _>> =
-            Arc::new(take(&mut self.relations).into_iter().collect());
+    fn cyclic(&self, stack: &[VersionedPackageInfo], package: &VersionedPackageInfo) {
+        log_verbose(&format!(
+            "Detected cyclic dependencies: {} > {}@{}",
+            stack
+                .iter()
+                .map(|package| format!("{}@{}", package.package.name, package.version))
+                .join(" > "),
+            package.package.name,
+            package.version
+        ));
+    }
 
-        let (send, recv) = flume::unbounded();
+    fn incomplete(&self, package: &VersionedPackageInfo, missing: &PackageSpecifier) {
+        log_verbose(&format!(
+            "Skipping optional {}@{}, since {} is missing",
+            package.package.name, package.version, missing.name
+        ));
+    }
+}
 
-        let seen = Arc::new(DashSet::new());
+/// How the dependencies of the root package are resolved, from cotton.toml and package.json
+async fn resolve_options(root: &PackageMetadata) -> color_eyre::Result<ResolveOptions> {
+    let config = read_config().await?;
 
-        for req in remaining {
-            queue_resolve(send.clone(), req, relations.clone(), seen.clone(), download)?;
-        }
+    let mut options = ResolveOptions::new(root.iter_all().collect(), supported_platforms().clone());
+    options.direct_strategy = ARGS.resolution_mode.direct();
+    options.skip_foreign = config.skip_foreign_optional;
+    options.auto_install_peers = config.auto_install_peers;
+    options.overrides = collect_overrides(root);
+    options.rules = root.cotton.package_rules.clone();
+    // Groups are not always installed, so they cannot satisfy the peers of other packages
+    options.provided = root.iter_declared().map(|x| x.name).collect();
 
-        drop(send);
+    Ok(options)
+}
 
-        while let Ok(f) = recv.recv_async().await {
-            f.await??;
-        }
+/// Resolve the dependencies of `root` that are missing from the graph, optionally downloading
+/// them along the way
+pub async fn resolve_graph(
+    graph: &mut Graph,
+    root: &PackageMetadata,
+    download: bool,
+) -> color_eyre::Result<()> {
+    graph
+        .append(resolve_options(root).await?, Registries { download })
+        .await
+}
 
-        self.relations = relations
-            .iter()
-            .filter(|x| seen.contains(x.key()))
-            .map(|x| (x.key().clone(), x.value().clone()))
-            .collect();
+/// Requirements of `root` that are missing from the graph, and locked requirements that are no
+/// longer reachable
+pub async fn check_coverage(
+    graph: &Graph,
+    root: &PackageMetadata,
+) -> color_eyre::Result<(Vec<PackageSpecifier>, Vec<PackageSpecifier>)> {
+    Ok(graph.check_coverage(&resolve_options(root).await?))
+}
 
-        Ok(())
+/// Direct dependencies of the root and of each workspace, by directory, which are only
+/// recorded in monorepos
+pub fn importers(
+    graph: &Graph,
+    root: &PackageMetadata,
+    workspaces: &[Workspace],
+) -> BTreeMap<String, Importer> {
+    if workspaces.is_empty() {
+        return BTreeMap::new();
     }
 
-    pub fn resolve_req(
-        &self,
-        req: &PackageSpecifier,
-    ) -> color_eyre::Result<VersionedPackageInfo, Report> {
-        Ok(self
+    let import = |reqs: Vec<PackageSpecifier>| -> Importer {
+        reqs.into_iter()
+            .filter_map(|req| {
+                let locked = graph.relations.get(&req)?;
+                Some((
+                    req.name,
+                    ImportedDependency {
+                        specifier: req.version.to_compact_string(),
+                        version: locked.version.clone(),
+                    },
+                ))
+            })
+            .collect()
+    };
+
+    let mut importers = BTreeMap::new();
+    importers.insert(".".to_string(), import(root.iter_all().collect()));
+    for workspace in workspaces {
+        // Dependencies of workspaces are rewritten when resolved, so start from the resolved workspace
+        let resolved = graph
             .relations
-            .get(req)
-            .wrap_err("A dependency could not be found")
-            .with_note(|| format!("Attempted to find {req:?}"))
-            .with_suggestion(|| {
-                if ARGS.immutable {
-                    "Make sure that the lockfile is up-to-date. Passing --immutable prevents any changes to the lockfile."
-                } else {
-                    "Make sure that the lockfile is consistent. Automatic resolution of merge conflicts can lead to inconsistency."
-                }
-            })?
-            .clone())
+            .iter()
+            .find(|(req, _)| req.name == workspace.name && workspace_range(&req.version).is_some());
+        if let Some((_, package)) = resolved {
+            importers.insert(
+                workspace.dir.display().to_string(),
+                import(package.package.iter().collect()),
+            );
+        }
     }
 
-    fn build_tree(
-        &self,
-        package: &VersionedPackageInfo,
-        stack: &mut Vec<VersionedPackageInfo>,
-        exclude: &FxHashSet<(CompactString, Version)>,
-        optional: bool,
-    ) -> color_eyre::Result<Option<DependencyTree>> {
-        if stack.iter().any(|x| package == x) {
-            log_verbose(&format!(
-                "Detected cyclic dependencies: {} > {} {}",
-                stack
-                    .iter()
-                    .map(
-                        |package| format!("{}@{}", package.package.name, package.version)
-                            .bright_blue()
-                            .to_string()
-                    )
-                    .join(" > "),
-                package.package.name,
-                package.version
-            ));
+    importers
+}
 
-            return Ok(None);
-        }
+/// Forget the locked metadata of packages read from disk, so that the next resolution reads them
+/// again, and prunes the dependencies that were removed from them
+pub fn forget_local(graph: &mut Graph) {
+    graph.relations.retain(|req, _| {
+        local_dir(&req.version).is_none()
+            && linked_dir(&req.version).is_none()
+            && workspace_range(&req.version).is_none()
+    });
+}
 
-        let root = Dependency {
-            name: package.package.name.to_compact_string(),
-            version: package.version.clone(),
-            dist: package.package.dist.clone(),
-            bins: package.package.bins().into_iter().collect(),
-            scripts: package.package.scripts.clone(),
-        };
+/// Format of the lockfiles written by this version of cotton
+pub const LOCKFILE_VERSION: u64 = 1;
 
-        if !package.package.supported() {
-            if optional {
-                return Ok(None);
-            } else {
-                return Err(
-                    Report::msg("Required dependency is not supported").note(format!(
-                        "Package {}@{} is not supported on this platform.",
-                        package.package.name, package.version
-                    )),
-                );
-            }
-        }
+/// Direct dependencies of the root or a workspace, by name
+pub type Importer = BTreeMap<CompactString, ImportedDependency>;
 
-        let mut deps = vec![];
-        for dep in package.package.iter() {
-            let package2 = self.resolve_req(&dep)?;
-            stack.push(package.clone());
-            if !exclude.contains(&(package2.package.name.clone(), package2.version.clone())) {
-                if let Some(tree) = self.build_tree(&package2, stack, exclude, dep.optional)? {
-                    deps.push(tree);
-                }
-            }
-            stack.pop().unwrap();
-        }
+/// A direct dependency, as written in `package.json` and as locked
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ImportedDependency {
+    pub specifier: CompactString,
+    pub version: Version,
+}
 
-        let tree = DependencyTree {
-            children: deps
+#[derive(Serialize, Deserialize, Default)]
+pub struct Lockfile {
+    /// Format of the lockfile, which is 0 for lockfiles from before the format was versioned
+    #[serde(default)]
+    pub lockfile_version: u64,
+    /// Lockfiles of workspaces with the requirements that only they use, when sharded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<PathBuf>,
+    /// Direct dependencies of the root and each workspace, so that changes to a single member
+    /// are easy to review
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub importers: BTreeMap<String, Importer>,
+    #[serde(flatten)]
+    pub relations: BTreeMap<PackageSpecifier, (Version, PackageInfo)>,
+    /// Number of requirements dropped while merging git conflicts, which need to be resolved again
+    #[serde(skip)]
+    pub conflicts: usize,
+}
+
+impl Lockfile {
+    pub fn new(graph: Graph) -> Self {
+        Self {
+            lockfile_version: LOCKFILE_VERSION,
+            shards: vec![],
+            importers: BTreeMap::new(),
+            relations: graph
+                .relations
                 .into_iter()
-                .map(|x| (x.root.name.to_compact_string(), x))
+                .map(|(req, pkg)| (req, (pkg.version, (*pkg.package).clone())))
                 .collect(),
-            root,
-        };
-
-        Ok(Some(tree))
+            conflicts: 0,
+        }
     }
 
-    pub fn build_trees(
-        &self,
-        root_reqs: &[PackageSpecifier],
-    ) -> color_eyre::Result<Vec<DependencyTree>> {
-        let mut is_optional = FxHashMap::default();
+    /// Fail if a lockfile is in a newer format than this version of cotton understands, since
+    /// reading or replacing it could silently lose information
+    pub fn check_version(lockfile: &Value) -> color_eyre::Result<u64> {
+        let version = lockfile
+            .get("lockfile_version")
+            .map(|x| {
+                x.as_u64()
+                    .wrap_err("`lockfile_version` must be a non-negative integer")
+            })
+            .transpose()?
+            .unwrap_or(0);
+
+        if version > LOCKFILE_VERSION {
+            return Err(eyre!(
+                "Lockfile is in format {version}, but this version of cotton only supports up to format {LOCKFILE_VERSION}"
+            )
+            .suggestion("Update cotton with `cotton self-update`"));
+        }
 
-        let mut reqs = FxHashMap::default();
+        Ok(version)
+    }
 
-        for req in root_reqs {
-            let pkg = self.resolve_req(req)?;
-            reqs.insert(req.name.clone(), pkg.clone());
-            is_optional.insert(pkg, req.optional);
+    /// Parse a lockfile, upgrading it from older formats and merging both sides of git conflicts
+    pub fn parse(text: &str) -> color_eyre::Result<Self> {
+        let Some((ours, theirs)) = split_conflicts(text) else {
+            return Self::parse_resolved(text);
+        };
+        if ARGS.immutable {
+            return Err(eyre!("Lockfile has merge conflicts")
+                .suggestion("Run `cotton install` without --immutable to resolve them"));
         }
 
-        let mut flat_deps = FxHashSet::default();
-        let mut edge = VecDeque::new();
-        edge.extend(reqs.values().cloned());
-
-        while let Some(next) = edge.pop_front() {
-            if !flat_deps.contains(&next) {
-                for req in next.package.iter() {
-                    let pkg = self.resolve_req(&req)?;
-                    is_optional.insert(pkg.clone(), req.optional);
-                    edge.push_back(pkg);
+        let mut lockfile =
+            Self::parse_resolved(&ours).wrap_err("Failed to read our side of the conflict")?;
+        let theirs =
+            Self::parse_resolved(&theirs).wrap_err("Failed to read their side of the conflict")?;
+
+        // Requirements locked differently on each side are dropped, so that they are resolved again
+        for (req, locked) in theirs.relations {
+            match lockfile.relations.get(&req) {
+                Some(x) if *x != locked => {
+                    lockfile.relations.remove(&req);
+                    lockfile.conflicts += 1;
+                }
+                Some(_) => {}
+                None => {
+                    lockfile.relations.insert(req, locked);
                 }
-                flat_deps.insert(next);
             }
         }
-
-        let mut hoisted: FxHashMap<_, VersionedPackageInfo> = FxHashMap::default();
-        for dep in flat_deps {
-            if let Some(prev) = hoisted.get(&dep.package.name) {
-                if dep.version > prev.version {
-                    hoisted.insert(dep.package.name.clone(), dep.clone());
-                }
-            } else {
-                hoisted.insert(dep.package.name.clone(), dep.clone());
+        for shard in theirs.shards {
+            if !lockfile.shards.contains(&shard) {
+                lockfile.shards.push(shard);
             }
         }
 
-        for (name, pkg) in &reqs {
-            hoisted.insert(name.clone(), pkg.clone());
-        }
+        Ok(lockfile)
+    }
 
-        for (name, pkg) in hoisted.iter() {
-            reqs.insert(name.clone(), pkg.clone());
+    fn parse_resolved(text: &str) -> color_eyre::Result<Self> {
+        let mut lockfile: Value = serde_json::from_str(text).map_err(|e| {
+            eyre!("Lockfile is not valid JSON: {e}")
+                .suggestion("Fix the lockfile by hand, or recreate it with `cotton update`")
+        })?;
+        if !lockfile.is_object() {
+            return Err(eyre!("Lockfile must be a JSON object"));
         }
 
-        let exclude = hoisted
-            .into_iter()
-            .map(|(name, pkg)| (name, pkg.version))
-            .collect();
-
-        let mut v = vec![];
-        for pkg in reqs.values() {
-            v.push(self.build_tree(pkg, &mut vec![], &exclude, is_optional[pkg])?);
+        for version in Self::check_version(&lockfile)?..LOCKFILE_VERSION {
+            log_verbose(&format!(
+                "Migrating lockfile from format {version} to {}",
+                version + 1
+            ));
+            migrate(&mut lockfile, version);
         }
 
-        let v = v.into_iter().flatten().collect();
-        Ok(v)
-    }
-}
-
-#[derive(Serialize, Deserialize, Default)]
-pub struct Lockfile {
-    #[serde(flatten)]
-    pub relations: BTreeMap<PackageSpecifier, (Version, PackageInfo)>,
-}
-
-impl Lockfile {
-    pub fn new(graph: Graph) -> Self {
-        Self {
-            relations: graph
-                .relations
-                .into_iter()
-                .map(|(req, pkg)| (req, (pkg.version, (*pkg.package).clone())))
-                .collect(),
-        }
+        Ok(serde_json::from_value(lockfile)?)
     }
 
     pub fn into_graph(self) -> Graph {
@@ -303,3 +319,57 @@ impl Lockfile {
         }
     }
 }
+
+/// The two sides of a file with git conflict markers, or `None` if it has no conflicts
+fn split_conflicts(text: &str) -> Option<(String, String)> {
+    #[derive(PartialEq)]
+    enum Side {
+        Both,
+        Ours,
+        Base,
+        Theirs,
+    }
+
+    if !text.lines().any(|x| x.starts_with("<<<<<<<")) {
+        return None;
+    }
+
+    let mut side = Side::Both;
+    let mut ours = String::new();
+    let mut theirs = String::new();
+    for line in text.split_inclusive('\n') {
+        if line.starts_with("<<<<<<<") {
+            side = Side::Ours;
+        } else if line.starts_with("|||||||") && side == Side::Ours {
+            // The common ancestor, written with `merge.conflictStyle = diff3`
+            side = Side::Base;
+        } else if line.starts_with("=======") && side != Side::Both {
+            side = Side::Theirs;
+        } else if line.starts_with(">>>>>>>") {
+            side = Side::Both;
+        } else {
+            match side {
+                Side::Both => {
+                    ours.push_str(line);
+                    theirs.push_str(line);
+                }
+                Side::Ours => ours.push_str(line),
+                Side::Theirs => theirs.push_str(line),
+                Side::Base => {}
+            }
+        }
+    }
+
+    Some((ours, theirs))
+}
+
+/// Upgrade a lockfile from format `version` to the next one
+fn migrate(lockfile: &mut Value, version: u64) {
+    match version {
+        // Unversioned lockfiles have the same layout as format 1
+        0 => {}
+        _ => unreachable!("No migration from lockfile format {version}"),
+    }
+
+    lockfile["lockfile_version"] = (version + 1).into();
+}
@@ -0,0 +1,232 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use compact_str::CompactString;
+use owo_colors::OwoColorize;
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    npm::DependencyTree,
+    plan::{install_path, Plan},
+};
+
+enum Problem {
+    Unresolvable(String),
+    MissingEntry { dependency: String, entry: String },
+    MissingImport(String),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Unresolvable(name) => write!(f, "cannot resolve {name}"),
+            Problem::MissingEntry { dependency, entry } => {
+                write!(f, "entry point {entry} of {dependency} does not exist")
+            }
+            Problem::MissingImport(target) => write!(f, "import target {target} does not exist"),
+        }
+    }
+}
+
+fn read_manifest(dir: &Path) -> Value {
+    read_to_string(dir.join("package.json"))
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+/// Find a package the same way Node does, by searching `node_modules` in every parent directory
+fn find_dependency(from: &Path, name: &str) -> Option<PathBuf> {
+    from.ancestors()
+        .filter(|dir| dir.file_name() != Some("node_modules".as_ref()))
+        .map(|dir| dir.join("node_modules").join(name))
+        .find(|x| x.join("package.json").exists())
+}
+
+/// Whether `require` would find a file at `path`, trying extensions and index files
+pub fn resolve_file(path: &Path) -> bool {
+    let with_extension = |ext: &str| {
+        let mut path = OsString::from(path);
+        path.push(ext);
+        PathBuf::from(path).is_file()
+    };
+
+    path.is_file()
+        || [".js", ".json", ".node", ".cjs", ".mjs"]
+            .into_iter()
+            .any(with_extension)
+        || ["index.js", "index.json", "index.node"]
+            .into_iter()
+            .any(|x| path.join(x).is_file())
+}
+
+/// Collect every file target of an `exports` or `imports` map, across all conditions
+fn collect_targets<'a>(value: &'a Value, targets: &mut Vec<&'a str>) {
+    match value {
+        Value::String(x) => targets.push(x),
+        Value::Array(x) => x.iter().for_each(|x| collect_targets(x, targets)),
+        Value::Object(x) => x.values().for_each(|x| collect_targets(x, targets)),
+        _ => {}
+    }
+}
+
+fn missing_entries(dir: &Path) -> Vec<String> {
+    let manifest = read_manifest(dir);
+
+    if !manifest["exports"].is_null() {
+        let mut targets = vec![];
+        collect_targets(&manifest["exports"], &mut targets);
+
+        return targets
+            .into_iter()
+            .filter(|x| x.starts_with("./") && !x.contains('*'))
+            .filter(|x| !dir.join(x).exists())
+            .map(|x| x.to_string())
+            .collect();
+    }
+
+    match manifest["main"].as_str() {
+        Some(main) if !resolve_file(&dir.join(main)) => vec![main.to_string()],
+        _ => vec![],
+    }
+}
+
+/// The package name of a bare specifier such as `@scope/name/sub/path`
+fn package_name(specifier: &str) -> &str {
+    let end = if specifier.starts_with('@') { 2 } else { 1 };
+    match specifier.match_indices('/').nth(end - 1) {
+        Some((i, _)) => &specifier[..i],
+        None => specifier,
+    }
+}
+
+struct Checker {
+    entries: FxHashMap<PathBuf, Vec<String>>,
+}
+
+impl Checker {
+    fn check_package(&mut self, dir: &Path, include_dev: bool) -> Vec<Problem> {
+        let manifest = read_manifest(dir);
+        let mut problems = vec![];
+
+        let mut required = vec![];
+        let mut fields = vec!["dependencies", "peerDependencies", "optionalDependencies"];
+        if include_dev {
+            fields.push("devDependencies");
+        }
+        for field in fields {
+            for name in manifest[field]
+                .as_object()
+                .into_iter()
+                .flat_map(|x| x.keys())
+            {
+                let optional = field == "optionalDependencies"
+                    || manifest["peerDependenciesMeta"][name]["optional"].as_bool() == Some(true);
+                required.push((name.as_str(), optional));
+            }
+        }
+
+        for (name, optional) in required {
+            let Some(dep_dir) = find_dependency(dir, name) else {
+                if !optional {
+                    problems.push(Problem::Unresolvable(name.to_string()));
+                }
+                continue;
+            };
+
+            let entries = self
+                .entries
+                .entry(dep_dir.clone())
+                .or_insert_with(|| missing_entries(&dep_dir));
+            for entry in entries.iter() {
+                problems.push(Problem::MissingEntry {
+                    dependency: name.to_string(),
+                    entry: entry.clone(),
+                });
+            }
+        }
+
+        let mut imports = vec![];
+        collect_targets(&manifest["imports"], &mut imports);
+        for target in imports.into_iter().filter(|x| !x.contains('*')) {
+            if target.starts_with("./") {
+                if !dir.join(target).exists() {
+                    problems.push(Problem::MissingImport(target.to_string()));
+                }
+            } else if !target.starts_with('.') && !target.starts_with('/') && !target.contains(':')
+            {
+                let name = package_name(target);
+                if find_dependency(dir, name).is_none() {
+                    problems.push(Problem::Unresolvable(name.to_string()));
+                }
+            }
+        }
+
+        problems
+    }
+
+    fn check_tree(
+        &mut self,
+        tree: &DependencyTree,
+        prefix: &mut Vec<CompactString>,
+        found: &mut Vec<(String, Vec<Problem>)>,
+    ) -> Result<()> {
+        let dir = install_path(prefix, &tree.root)?;
+        let problems = self.check_package(&dir, false);
+        if !problems.is_empty() {
+            found.push((tree.root.id(), problems));
+        }
+
+        prefix.push(tree.root.name.clone());
+        for child in tree.children.values() {
+            self.check_tree(child, prefix, found)?;
+        }
+        prefix.pop();
+
+        Ok(())
+    }
+}
+
+/// Check that every installed package can resolve its dependencies and their entry points
+pub fn resolve_check(plan: &Plan) -> Result<()> {
+    let mut checker = Checker {
+        entries: FxHashMap::default(),
+    };
+
+    let mut found = vec![];
+
+    let problems = checker.check_package(Path::new(""), true);
+    if !problems.is_empty() {
+        found.push(("(root)".to_string(), problems));
+    }
+
+    for tree in plan.trees.values() {
+        checker.check_tree(tree, &mut vec![], &mut found)?;
+    }
+
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (id, problems) in &found {
+        for problem in problems {
+            println!("{} {}: {}", " BROKEN ".on_red(), id, problem);
+        }
+    }
+
+    if found.is_empty() {
+        println!("All dependencies resolve");
+        Ok(())
+    } else {
+        Err(
+            eyre!("{} packages have unresolvable dependencies", found.len())
+                .suggestion("Packages may rely on dependencies they do not declare"),
+        )
+    }
+}
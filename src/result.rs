@@ -0,0 +1,124 @@
+use color_eyre::{eyre::Result, Report};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    env, fs, io,
+    path::PathBuf,
+    process::exit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    metrics::{CACHE_HITS, DOWNLOADS},
+    progress::log_warning,
+    ARGS,
+};
+
+pub static START: Lazy<Instant> = Lazy::new(Instant::now);
+/// Taken out of the environment so that nested invocations do not overwrite it
+pub static RESULT_FILE: Lazy<Option<PathBuf>> = Lazy::new(|| {
+    let path = env::var_os("COTTON_RESULT_FILE")?;
+    env::remove_var("COTTON_RESULT_FILE");
+    // Resolved before `--working-dir` changes the current directory
+    Some(env::current_dir().map_or_else(|_| path.clone().into(), |x| x.join(&path)))
+});
+pub static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+/// Number of packages in the most recent install of this command
+pub static PACKAGES: Lazy<Mutex<Option<usize>>> = Lazy::new(|| Mutex::new(None));
+
+/// Result of a command, written to `COTTON_RESULT_FILE` for wrapper scripts
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CommandResult {
+    command: String,
+    success: bool,
+    exit_code: i32,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packages: Option<usize>,
+    downloads: usize,
+    cache_hits: usize,
+    warnings: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Name of the subcommand as typed, such as `install` or `run`
+fn command_name() -> String {
+    let debug = format!("{:?}", ARGS.cmd);
+    let variant = debug
+        .split(|x: char| !x.is_alphanumeric())
+        .next()
+        .unwrap_or_default();
+
+    let mut name = String::new();
+    for (i, c) in variant.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('-');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Broad cause of an error, for scripts that retry on some failures but not others
+pub fn error_class(error: &Report) -> &'static str {
+    // Shared errors from memoized downloads keep their cause inside
+    if let Some(e) = error.downcast_ref::<Arc<Report>>() {
+        return error_class(e);
+    }
+    for cause in error.chain() {
+        if cause.is::<reqwest::Error>() {
+            return "network";
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::PermissionDenied => "permission",
+                _ => "io",
+            };
+        }
+        if cause.is::<serde_json::Error>() || cause.is::<toml::de::Error>() {
+            return "parse";
+        }
+    }
+    "other"
+}
+
+/// Write the result of the command to `COTTON_RESULT_FILE`, if it is set
+pub fn write_result(exit_code: i32, error: Option<&Report>) -> Result<()> {
+    let Some(path) = &*RESULT_FILE else {
+        return Ok(());
+    };
+
+    let result = CommandResult {
+        command: command_name(),
+        success: exit_code == 0,
+        exit_code,
+        duration_ms: START.elapsed().as_millis(),
+        packages: *PACKAGES.lock().unwrap(),
+        downloads: DOWNLOADS.load(Ordering::Relaxed),
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+        warnings: WARNINGS.load(Ordering::Relaxed),
+        error_class: error.map(error_class),
+        error: error.map(|x| x.chain().join(": ")),
+    };
+
+    fs::write(path, serde_json::to_string_pretty(&result)? + "\n")?;
+
+    Ok(())
+}
+
+/// Exit with the status of a script, recording it as the result of the command
+pub fn exit_with(code: i32) -> ! {
+    if let Err(e) = write_result(code, None) {
+        log_warning(&format!("Failed to write result file: {e}"));
+    }
+    exit(code);
+}
@@ -0,0 +1,285 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use compact_str::CompactString;
+use itertools::Itertools;
+use node_semver::Version;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, time::SystemTime};
+
+use crate::{
+    package::{Dist, PackageMetadata},
+    resolve::Graph,
+    util::supported_platforms,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbomFormat {
+    Cyclonedx,
+    Spdx,
+}
+
+struct Component {
+    name: CompactString,
+    version: Version,
+    dist: Dist,
+    license: Option<CompactString>,
+    depends_on: Vec<String>,
+}
+
+impl Component {
+    fn purl(&self) -> String {
+        purl(&self.name, &self.version)
+    }
+
+    /// Hashes as `(algorithm, hex digest)` pairs, taken from the registry's `integrity` and `shasum`
+    fn hashes(&self) -> Vec<(&'static str, String)> {
+        let mut hashes = vec![];
+
+        for hash in self
+            .dist
+            .integrity
+            .iter()
+            .flat_map(|x| x.split_whitespace())
+        {
+            let Some((alg, digest)) = hash.split_once('-') else {
+                continue;
+            };
+            let alg = match alg {
+                "sha512" => "SHA-512",
+                "sha384" => "SHA-384",
+                "sha256" => "SHA-256",
+                "sha1" => "SHA-1",
+                _ => continue,
+            };
+            if let Ok(digest) = STANDARD.decode(digest) {
+                hashes.push((alg, digest.iter().map(|x| format!("{x:02x}")).collect()));
+            }
+        }
+
+        if let Some(shasum) = &self.dist.shasum {
+            if !hashes.iter().any(|(alg, _)| *alg == "SHA-1") {
+                hashes.push(("SHA-1", shasum.to_string()));
+            }
+        }
+
+        hashes
+    }
+}
+
+fn purl(name: &str, version: &Version) -> String {
+    format!("pkg:npm/{}@{version}", name.replace('@', "%40"))
+}
+
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|x| format!("{x:02x}")).join("");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn is_license_expression(license: &str) -> bool {
+    license.split_whitespace().all(|word| {
+        matches!(word, "AND" | "OR" | "WITH")
+            || word
+                .trim_matches(['(', ')'])
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.+".contains(c))
+    })
+}
+
+fn components(graph: &Graph) -> color_eyre::Result<Vec<Component>> {
+    let mut components = BTreeMap::new();
+
+    for pkg in graph.relations.values() {
+        if !pkg.package.supported(supported_platforms()) {
+            continue;
+        }
+
+        let depends_on = pkg
+            .package
+            .iter()
+            .map(|req| graph.resolve_req(&req))
+            .filter_ok(|x| x.package.supported(supported_platforms()))
+            .map_ok(|x| purl(&x.package.name, &x.version))
+            .collect::<Result<_, _>>()?;
+
+        components.insert(
+            (pkg.package.name.clone(), pkg.version.clone()),
+            Component {
+                name: pkg.package.name.clone(),
+                version: pkg.version.clone(),
+                dist: pkg.package.dist.clone(),
+                license: pkg.package.license.clone(),
+                depends_on,
+            },
+        );
+    }
+
+    Ok(components.into_values().collect())
+}
+
+fn root_depends_on(package: &PackageMetadata, graph: &Graph) -> color_eyre::Result<Vec<String>> {
+    package
+        .iter_all()
+        .map(|req| graph.resolve_req(&req))
+        .filter_ok(|x| x.package.supported(supported_platforms()))
+        .map_ok(|x| purl(&x.package.name, &x.version))
+        .collect()
+}
+
+fn cyclonedx(
+    package: &PackageMetadata,
+    root_depends_on: Vec<String>,
+    components: &[Component],
+) -> Value {
+    let root_ref = format!("{}@{}", package.name, version_or_default(package));
+
+    let mut dependencies = vec![json!({ "ref": root_ref, "dependsOn": root_depends_on })];
+    dependencies.extend(
+        components
+            .iter()
+            .map(|x| json!({ "ref": x.purl(), "dependsOn": x.depends_on })),
+    );
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", random_uuid()),
+        "version": 1,
+        "metadata": {
+            "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "tools": [{ "name": "cotton", "version": env!("CARGO_PKG_VERSION") }],
+            "component": {
+                "type": "application",
+                "bom-ref": root_ref,
+                "name": package.name,
+                "version": version_or_default(package),
+            },
+        },
+        "components": components.iter().map(|x| {
+            let mut component = json!({
+                "type": "library",
+                "bom-ref": x.purl(),
+                "name": x.name,
+                "version": x.version,
+                "purl": x.purl(),
+                "hashes": x.hashes().into_iter().map(|(alg, content)| json!({ "alg": alg, "content": content })).collect_vec(),
+                "externalReferences": [{ "type": "distribution", "url": x.dist.tarball }],
+            });
+            if let Some(license) = &x.license {
+                component["licenses"] = if is_license_expression(license) {
+                    json!([{ "expression": license }])
+                } else {
+                    json!([{ "license": { "name": license } }])
+                };
+            }
+            component
+        }).collect_vec(),
+        "dependencies": dependencies,
+    })
+}
+
+fn spdx(
+    package: &PackageMetadata,
+    root_depends_on: Vec<String>,
+    components: &[Component],
+) -> Value {
+    let ids: BTreeMap<String, String> = components
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.purl(), format!("SPDXRef-Package-{i}")))
+        .collect();
+
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": "SPDXRef-Root",
+    })];
+
+    let depends_on =
+        root_depends_on
+            .iter()
+            .map(|x| ("SPDXRef-Root", x))
+            .chain(components.iter().flat_map(|x| {
+                x.depends_on
+                    .iter()
+                    .map(|dep| (ids[&x.purl()].as_str(), dep))
+            }));
+    for (from, to) in depends_on {
+        relationships.push(json!({
+            "spdxElementId": from,
+            "relationshipType": "DEPENDS_ON",
+            "relatedSpdxElement": ids[to],
+        }));
+    }
+
+    let mut packages = vec![json!({
+        "SPDXID": "SPDXRef-Root",
+        "name": package.name,
+        "versionInfo": version_or_default(package),
+        "downloadLocation": "NOASSERTION",
+        "filesAnalyzed": false,
+    })];
+    packages.extend(components.iter().map(|x| {
+        json!({
+            "SPDXID": ids[&x.purl()],
+            "name": x.name,
+            "versionInfo": x.version,
+            "downloadLocation": x.dist.tarball,
+            "filesAnalyzed": false,
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": x.license.as_ref().filter(|x| is_license_expression(x)).map(|x| x.as_str()).unwrap_or("NOASSERTION"),
+            "checksums": x.hashes().into_iter().map(|(alg, value)| json!({ "algorithm": alg.replace('-', ""), "checksumValue": value })).collect_vec(),
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": x.purl(),
+            }],
+        })
+    }));
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": package.name,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/{}-{}", package.name.replace('/', "-"), random_uuid()),
+        "creationInfo": {
+            "created": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "creators": [format!("Tool: cotton-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+fn version_or_default(package: &PackageMetadata) -> String {
+    package
+        .version
+        .as_ref()
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "0.0.0".into())
+}
+
+pub fn sbom(
+    package: &PackageMetadata,
+    graph: &Graph,
+    format: SbomFormat,
+) -> color_eyre::Result<Value> {
+    let components = components(graph)?;
+    let root_depends_on = root_depends_on(package, graph)?;
+
+    Ok(match format {
+        SbomFormat::Cyclonedx => cyclonedx(package, root_depends_on, &components),
+        SbomFormat::Spdx => spdx(package, root_depends_on, &components),
+    })
+}
@@ -99,3 +99,42 @@ fn do_scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(
 pub fn scoped_join<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<PathBuf> {
     do_scoped_resolve(root, unsafe_path).map(|(root, path)| root.join(path))
 }
+
+/// What a path inside `node_modules` or the store is resolved for, to give context to errors
+#[derive(Debug, Clone, Copy)]
+pub enum PathOperation {
+    Install,
+    Extract,
+    Script,
+}
+
+impl std::fmt::Display for PathOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathOperation::Install => write!(f, "install"),
+            PathOperation::Extract => write!(f, "extract"),
+            PathOperation::Script => write!(f, "run scripts for"),
+        }
+    }
+}
+
+/// `scoped_join`, with errors naming the package and the operation that needed the path
+pub fn scoped_join_for<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    operation: PathOperation,
+    package: &str,
+) -> color_eyre::Result<PathBuf> {
+    use color_eyre::{eyre::WrapErr, Section};
+
+    scoped_join(&root, &unsafe_path)
+        .wrap_err_with(|| format!("Failed to resolve the path to {operation} {package}"))
+        .with_note(|| {
+            format!(
+                "Resolving {} inside {}",
+                unsafe_path.as_ref().display(),
+                root.as_ref().display()
+            )
+        })
+        .suggestion("Run `cotton doctor --paths` to find problematic symlinks")
+}
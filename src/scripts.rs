@@ -0,0 +1,362 @@
+use color_eyre::eyre::{eyre, Result};
+use compact_str::CompactString;
+use owo_colors::OwoColorize;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::{read_link, remove_file},
+    path::{absolute, Component, Path},
+    process::{ExitStatus, Stdio},
+    sync::Mutex,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use crate::{
+    approval::INSTALL_SCRIPTS,
+    bins::link_bins,
+    new_path,
+    npm::{Dependency, DependencyTree},
+    package::PackageMetadata,
+    plan::Plan,
+    progress::{log_verbose, PROGRESS_BAR},
+    scoped_path::{scoped_join_for, PathOperation},
+    shell,
+    util::{load_graph_from_lockfile, read_json, write_json},
+};
+
+const DEFERRED_PATH: &str = "node_modules/.cotton/deferred-scripts.json";
+
+/// A package with install scripts, and the packages it is nested under (including itself)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScriptTask {
+    pub stack: Vec<CompactString>,
+    pub dep: Dependency,
+}
+
+/// Packages with install scripts, with direct dependencies first and deeper ones after
+pub fn install_script_tasks(plan: &Plan) -> Vec<ScriptTask> {
+    let mut tasks = vec![];
+
+    let mut queue: VecDeque<(Vec<CompactString>, &DependencyTree)> = plan
+        .trees
+        .values()
+        .map(|tree| (vec![tree.root.name.clone()], tree))
+        .collect();
+    queue.make_contiguous().sort_by(|a, b| a.0.cmp(&b.0));
+
+    while let Some((stack, tree)) = queue.pop_front() {
+        if INSTALL_SCRIPTS
+            .iter()
+            .any(|x| tree.root.scripts.contains_key(*x))
+        {
+            tasks.push(ScriptTask {
+                stack: stack.clone(),
+                dep: tree.root.clone(),
+            });
+        }
+
+        let mut children: Vec<_> = tree.children.values().collect();
+        children.sort_by(|a, b| a.root.name.cmp(&b.root.name));
+        for child in children {
+            let mut stack = stack.clone();
+            stack.push(child.root.name.clone());
+            queue.push_back((stack, child));
+        }
+    }
+
+    tasks
+}
+
+/// Number of output lines kept for the report of a failed script
+const OUTPUT_LINES: usize = 20;
+
+/// An install script that exited unsuccessfully, with the end of its output
+pub struct ScriptFailure {
+    chain: Vec<CompactString>,
+    package: String,
+    script: &'static str,
+    status: ExitStatus,
+    output: Vec<String>,
+}
+
+impl Display for ScriptFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} script of {} failed ({})",
+            self.script, self.package, self.status
+        )?;
+        if self.chain.len() > 1 {
+            writeln!(
+                f,
+                "  required by {}",
+                self.chain[..self.chain.len() - 1].join(" > ")
+            )?;
+        }
+        for line in &self.output {
+            writeln!(f, "  {} {line}", "|".dimmed())?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe a script to itself through the variables that npm sets, which tools such as node-gyp
+/// and semantic-release read
+pub fn set_lifecycle_env(
+    command: &mut Command,
+    event: &str,
+    script: &str,
+    dir: &Path,
+    name: &str,
+    version: Option<&str>,
+) -> Result<()> {
+    command
+        .env("npm_lifecycle_event", event)
+        .env("npm_lifecycle_script", script)
+        .env("npm_package_name", name)
+        .env("npm_package_json", absolute(dir.join("package.json"))?);
+    if let Some(version) = version {
+        command.env("npm_package_version", version);
+    }
+
+    Ok(())
+}
+
+/// Run a script, printing its output prefixed with the package name and keeping the last lines
+async fn run_captured(
+    event: &str,
+    script: &str,
+    dir: &Path,
+    name: &str,
+    version: Option<&str>,
+) -> Result<(ExitStatus, VecDeque<String>)> {
+    let mut command = Command::new(shell().await?);
+    command
+        .arg("-c")
+        .arg(script)
+        .current_dir(dir)
+        .env("PATH", new_path()?);
+    set_lifecycle_env(&mut command, event, script, dir, name, version)?;
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let prefix = format!("{name} |").bright_blue().to_string();
+    let output = Mutex::new(VecDeque::new());
+    let keep = |line: String| {
+        let mut output = output.lock().unwrap();
+        if output.len() == OUTPUT_LINES {
+            output.pop_front();
+        }
+        output.push_back(line);
+    };
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let stdout = async {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            PROGRESS_BAR.suspend(|| println!("{prefix} {line}"));
+            keep(line);
+        }
+        Ok(())
+    };
+    let stderr = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            PROGRESS_BAR.suspend(|| eprintln!("{prefix} {line}"));
+            keep(line);
+        }
+        Ok(())
+    };
+
+    let (status, (), ()) = tokio::try_join!(child.wait(), stdout, stderr)?;
+
+    Ok((status, output.into_inner().unwrap()))
+}
+
+/// Run the install scripts of a package, stopping at the first one that fails
+async fn exec_install_script(task: &ScriptTask) -> Result<Option<ScriptFailure>> {
+    let dir = scoped_join_for(
+        "node_modules",
+        task.stack.join("/node_modules/"),
+        PathOperation::Script,
+        &task.dep.id(),
+    )?;
+
+    for script_name in INSTALL_SCRIPTS {
+        if let Some(script) = task.dep.scripts.get(script_name) {
+            PROGRESS_BAR.suspend(|| {
+                println!(
+                    "Executing {script_name} script for {}",
+                    task.stack.join(" > ")
+                );
+            });
+
+            let (status, output) = run_captured(
+                script_name,
+                script,
+                &dir,
+                &task.dep.name,
+                Some(&task.dep.version.to_string()),
+            )
+            .await?;
+
+            if !status.success() {
+                return Ok(Some(ScriptFailure {
+                    chain: task.stack.clone(),
+                    package: format!("{}@{}", task.dep.name, task.dep.version),
+                    script: script_name,
+                    status,
+                    output: output.into(),
+                }));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Run a lifecycle script of the project in `dir`, such as `prepare`, if it is defined
+pub async fn run_lifecycle_script(
+    dir: &Path,
+    package: &PackageMetadata,
+    script_name: &str,
+) -> Result<()> {
+    let Some(script) = package.scripts.get(script_name) else {
+        return Ok(());
+    };
+    let script = script
+        .as_str()
+        .ok_or_else(|| eyre!("Script `{script_name}` is not a string"))?;
+
+    let name = if package.name.is_empty() {
+        "package.json"
+    } else {
+        package.name.as_str()
+    };
+    PROGRESS_BAR.suspend(|| println!("Executing {script_name} script for {name}"));
+
+    let version = package.version.as_ref().map(|x| x.to_string());
+    let (status, _) = run_captured(script_name, script, dir, name, version.as_deref()).await?;
+    if !status.success() {
+        return Err(eyre!(
+            "Script `{script_name}` of {name} failed with {status}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Print the failed scripts, and fail if there are any
+fn report_failures(failures: &[ScriptFailure]) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    PROGRESS_BAR.suspend(|| {
+        for failure in failures {
+            print!("{} {failure}", " FAILED ".on_red());
+        }
+    });
+
+    Err(eyre!("{} install scripts failed", failures.len()))
+}
+
+/// Run install scripts now, discarding scripts deferred by a previous install
+pub async fn run_install_scripts(tasks: &[ScriptTask]) -> Result<()> {
+    let _ = remove_file(DEFERRED_PATH);
+
+    let mut failures = vec![];
+    for task in tasks {
+        failures.extend(exec_install_script(task).await?);
+    }
+
+    report_failures(&failures)
+}
+
+/// Save install scripts to be run when their package is first executed
+pub async fn defer_install_scripts(tasks: &[ScriptTask]) -> Result<()> {
+    if !tasks.is_empty() {
+        log_verbose(&format!("Deferring {} install scripts", tasks.len()));
+    }
+
+    write_json(DEFERRED_PATH, tasks).await
+}
+
+/// The package providing a bin in `node_modules/.bin`
+fn bin_package(cmd: &str) -> Option<CompactString> {
+    let target = read_link(Path::new("node_modules/.bin").join(cmd)).ok()?;
+    let mut names = target
+        .components()
+        .skip_while(|x| *x == Component::ParentDir)
+        .filter_map(|x| x.as_os_str().to_str());
+
+    let first = names.next()?;
+    if first.starts_with('@') {
+        Some(format!("{first}/{}", names.next()?).into())
+    } else {
+        Some(first.into())
+    }
+}
+
+/// Run deferred install scripts of the packages whose bins are used by a command, and of their dependencies
+pub async fn run_deferred_scripts(command: &str) -> Result<()> {
+    let Ok(tasks) = read_json::<Vec<ScriptTask>>(DEFERRED_PATH).await else {
+        return Ok(());
+    };
+    if tasks.is_empty() {
+        return Ok(());
+    }
+
+    let mut queue: VecDeque<CompactString> = command
+        .split(|c: char| c.is_whitespace() || ";&|()".contains(c))
+        .filter_map(bin_package)
+        .collect();
+
+    let mut dependencies: FxHashMap<CompactString, FxHashSet<CompactString>> = Default::default();
+    for package in load_graph_from_lockfile().await?.relations.into_values() {
+        dependencies
+            .entry(package.package.name.clone())
+            .or_default()
+            .extend(package.package.iter().map(|x| x.name));
+    }
+
+    let mut relevant = FxHashSet::default();
+    while let Some(name) = queue.pop_front() {
+        if relevant.insert(name.clone()) {
+            queue.extend(dependencies.get(&name).into_iter().flatten().cloned());
+        }
+    }
+
+    let (now, mut later): (Vec<_>, Vec<_>) = tasks
+        .into_iter()
+        .partition(|x| relevant.contains(&x.dep.name));
+    if now.is_empty() {
+        return Ok(());
+    }
+
+    // Scripts of dependencies are run before the packages that use them
+    let mut failures = vec![];
+    for task in now.iter().rev() {
+        // Failed scripts stay deferred, to be tried again next time
+        if let Some(failure) = exec_install_script(task).await? {
+            failures.push(failure);
+            later.push(task.clone());
+        } else if task.stack.len() == 1 {
+            link_bins(Path::new(""), &task.dep)?;
+        }
+    }
+
+    write_json(DEFERRED_PATH, &later).await?;
+
+    report_failures(&failures)
+}
@@ -0,0 +1,132 @@
+use color_eyre::{
+    eyre::{eyre, ContextCompat, Result},
+    Help,
+};
+use node_semver::Version;
+use serde::Deserialize;
+use std::{
+    env::{
+        consts::{ARCH, OS},
+        current_exe,
+    },
+    fs::{rename, set_permissions, write, Permissions},
+    os::unix::prelude::PermissionsExt,
+};
+
+use crate::{
+    progress::log_progress,
+    util::{hash_hex, CLIENT},
+};
+
+/// Where builds of cotton are published, which has no releases to update from
+pub const BUILDS_URL: &str = "https://api.cirrus-ci.com/v1/artifact/github/danielhuang/cotton/Build/binaries/target/x86_64-unknown-linux-gnu/release/cotton";
+
+/// The target triple that this executable was built for, which names its release assets
+fn target_triple() -> String {
+    let os = match OS {
+        "linux" if cfg!(target_env = "musl") => "unknown-linux-musl".to_string(),
+        "linux" => "unknown-linux-gnu".to_string(),
+        "macos" => "apple-darwin".to_string(),
+        os => format!("unknown-{os}"),
+    };
+    format!("{ARCH}-{os}")
+}
+
+/// A release, in the format of the GitHub releases API
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|x| x.name == name)
+            .wrap_err_with(|| eyre!("Release {} has no asset `{name}`", self.tag_name))
+    }
+}
+
+pub enum UpdateStatus {
+    UpToDate(Version),
+    Available(Version),
+    Updated(Version),
+}
+
+async fn download(url: &str) -> Result<Vec<u8>> {
+    Ok(CLIENT
+        .get(url)
+        .header("User-Agent", "cotton")
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec())
+}
+
+/// Check `url` for a newer release, and replace the running executable with it unless `check_only`
+pub async fn self_update(url: &str, check_only: bool) -> Result<UpdateStatus> {
+    let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+
+    let release: Release = CLIENT
+        .get(url)
+        .header("User-Agent", "cotton")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let latest = Version::parse(release.tag_name.trim_start_matches('v'))?;
+
+    if latest <= current {
+        return Ok(UpdateStatus::UpToDate(current));
+    }
+    if check_only {
+        return Ok(UpdateStatus::Available(latest));
+    }
+
+    let name = format!("cotton-{}", target_triple());
+    let binary = download(&release.asset(&name)?.browser_download_url).await?;
+    let checksum = download(
+        &release
+            .asset(&format!("{name}.sha256"))?
+            .browser_download_url,
+    )
+    .await?;
+
+    // The checksum comes from the same release, so it only catches incomplete or corrupted
+    // downloads, while trust in the binary rests on the endpoint
+    let expected = String::from_utf8_lossy(&checksum)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let actual = hash_hex(&binary);
+    if actual != expected {
+        return Err(eyre!(
+            "Download of {name} is incomplete or corrupted: expected checksum {expected}, got {actual}"
+        ));
+    }
+
+    log_progress(&format!("Downloaded cotton {latest}"));
+
+    // Write next to the executable and rename over it, so that the replacement is atomic
+    let exe = current_exe()?;
+    let temp = exe.with_extension("new");
+    write(&temp, binary)
+        .map_err(|e| eyre!("{e}"))
+        .with_suggestion(|| format!("Check that {} is writable", exe.display()))?;
+    set_permissions(&temp, Permissions::from_mode(0o755))?;
+    rename(&temp, &exe)?;
+
+    Ok(UpdateStatus::Updated(latest))
+}
@@ -0,0 +1,82 @@
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use futures::TryStreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use rustc_hash::FxHashMap;
+use std::{collections::BTreeMap, future::Future, path::Path, sync::Mutex};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+};
+use tokio_tar::{Archive, Builder, Header};
+
+use crate::ARGS;
+
+/// Registry responses captured during this run, keyed by request
+static RECORDED: Lazy<Mutex<BTreeMap<String, Vec<u8>>>> = Lazy::new(Default::default);
+
+/// Registry responses of a previous run, which are used instead of the network
+static REPLAY: OnceCell<FxHashMap<String, Vec<u8>>> = OnceCell::new();
+
+/// Return the response for a registry request, replaying it from a session if one was loaded,
+/// and capturing it when recording a session
+pub async fn registry_response<F, Fut>(key: String, fetch: F) -> Result<Vec<u8>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<u8>>>,
+{
+    if let Some(replay) = REPLAY.get() {
+        return replay.get(&key).cloned().ok_or_else(|| {
+            eyre!("Response for {key} was not recorded in the session")
+                .suggestion("Record the session again with the same command")
+        });
+    }
+
+    let bytes = fetch().await?;
+    if ARGS.record_session.is_some() {
+        RECORDED.lock().unwrap().insert(key, bytes.clone());
+    }
+
+    Ok(bytes)
+}
+
+/// Load registry responses to replay from a session archive
+pub async fn load_session(path: &Path) -> Result<()> {
+    let reader = BufReader::new(File::open(path).await?);
+    let mut archive = Archive::new(GzipDecoder::new(reader));
+    let mut entries = archive.entries()?;
+
+    let mut responses = FxHashMap::default();
+    while let Some(mut entry) = entries.try_next().await? {
+        let key = entry.path()?.to_string_lossy().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data).await?;
+        responses.insert(key, data);
+    }
+
+    REPLAY
+        .set(responses)
+        .map_err(|_| eyre!("A session is already loaded"))
+}
+
+/// Write the registry responses captured during this run into a session archive
+pub async fn save_session(path: &Path) -> Result<usize> {
+    let recorded = std::mem::take(&mut *RECORDED.lock().unwrap());
+
+    let mut builder = Builder::new(GzipEncoder::new(File::create(path).await?));
+    for (key, data) in &recorded {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, key, &data[..]).await?;
+    }
+
+    let mut encoder = builder.into_inner().await?;
+    encoder.shutdown().await?;
+
+    Ok(recorded.len())
+}
@@ -0,0 +1,141 @@
+use color_eyre::eyre::{eyre, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    collections::VecDeque,
+    fs::remove_file,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    package::PackageSpecifier,
+    progress::log_verbose,
+    resolve::{importers, Graph, Lockfile},
+    util::{read_lockfile, read_package, write_json},
+    workspace::{find_workspaces, workspace_range},
+};
+
+/// Name of the lockfile segment in each workspace directory
+const SHARD_NAME: &str = "cotton.shard.lock";
+
+/// The shards listed in a lockfile, each of which must be the shard of a workspace, so that a
+/// crafted lockfile cannot make cotton read or remove other files
+pub async fn checked_shards(shards: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    if shards.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let workspaces = find_workspaces(&read_package().await?).await?;
+    shards
+        .iter()
+        .map(|shard| {
+            if workspaces.iter().any(|x| x.dir.join(SHARD_NAME) == *shard) {
+                Ok(shard.clone())
+            } else {
+                Err(eyre!(
+                    "Lockfile refers to {} as a shard, which is not the {SHARD_NAME} of a workspace",
+                    shard.display()
+                ))
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    Core,
+    Workspace(usize),
+}
+
+/// Requirements reachable from `roots`, without descending into other workspaces
+fn reachable(
+    graph: &Graph,
+    roots: impl Iterator<Item = PackageSpecifier>,
+) -> FxHashSet<PackageSpecifier> {
+    let mut found = FxHashSet::default();
+
+    let mut queue: VecDeque<_> = roots.collect();
+    while let Some(req) = queue.pop_front() {
+        if found.contains(&req) {
+            continue;
+        }
+        let Some(package) = graph.relations.get(&req) else {
+            continue;
+        };
+        // The dependencies of a workspace belong to its own shard
+        if workspace_range(&req.version).is_none() {
+            queue.extend(package.package.iter());
+        }
+        found.insert(req);
+    }
+
+    found
+}
+
+/// Write the lockfile as a shared core at `path`, and a shard in each workspace with the
+/// requirements that only that workspace uses
+pub async fn save_sharded(graph: &Graph, path: &Path) -> Result<()> {
+    let root = read_package().await?;
+    let workspaces = find_workspaces(&root).await?;
+
+    let mut owners: FxHashMap<PackageSpecifier, Owner> = reachable(graph, root.iter_all())
+        .into_iter()
+        .map(|req| (req, Owner::Core))
+        .collect();
+    for (i, workspace) in workspaces.iter().enumerate() {
+        // Dependencies of workspaces are rewritten when resolved, so start from the resolved workspace
+        let roots = graph
+            .relations
+            .iter()
+            .filter(|(req, _)| {
+                req.name == workspace.name && workspace_range(&req.version).is_some()
+            })
+            .flat_map(|(_, x)| x.package.iter());
+        for req in reachable(graph, roots) {
+            owners
+                .entry(req)
+                .and_modify(|x| {
+                    if *x != Owner::Workspace(i) {
+                        *x = Owner::Core;
+                    }
+                })
+                .or_insert(Owner::Workspace(i));
+        }
+    }
+
+    let mut core = Lockfile::new(Graph::default());
+    core.importers = importers(graph, &root, &workspaces);
+    let mut shards: Vec<Lockfile> = workspaces
+        .iter()
+        .map(|_| Lockfile::new(Graph::default()))
+        .collect();
+    for (req, package) in Lockfile::new(graph.clone()).relations {
+        match owners.get(&req).copied().unwrap_or(Owner::Core) {
+            Owner::Core => core.relations.insert(req, package),
+            Owner::Workspace(i) => shards[i].relations.insert(req, package),
+        };
+    }
+
+    let previous =
+        checked_shards(&read_lockfile().await.map(|x| x.shards).unwrap_or_default()).await?;
+
+    for (workspace, shard) in workspaces.iter().zip(shards) {
+        let shard_path = workspace.dir.join(SHARD_NAME);
+        if shard.relations.is_empty() {
+            continue;
+        }
+        log_verbose(&format!(
+            "Writing {} requirements to {}",
+            shard.relations.len(),
+            shard_path.display()
+        ));
+        write_json(&shard_path, shard).await?;
+        core.shards.push(shard_path);
+    }
+
+    // Shards of workspaces that no longer have their own requirements would be stale
+    for stale in previous.iter().filter(|x| !core.shards.contains(x)) {
+        let _ = remove_file(stale);
+    }
+
+    write_json(path, core).await
+}
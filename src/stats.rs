@@ -0,0 +1,129 @@
+use color_eyre::eyre::Result;
+use compact_str::CompactString;
+use itertools::Itertools;
+use owo_colors::OwoColorize;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::VecDeque;
+
+use crate::{
+    approval::INSTALL_SCRIPTS,
+    package::{PackageMetadata, PackageSpecifier, VersionedPackageInfo},
+    resolve::{Graph, VerboseEvents},
+};
+
+/// Number of duplicated names listed by version count
+const LISTED_DUPLICATES: usize = 5;
+
+/// Number of packages reachable from `root`, including itself
+fn subtree_size(graph: &Graph, root: &PackageSpecifier) -> Result<usize> {
+    let mut found: FxHashSet<VersionedPackageInfo> = FxHashSet::default();
+
+    let mut queue = VecDeque::from([root.clone()]);
+    while let Some(req) = queue.pop_front() {
+        let package = graph.resolve_req(&req)?;
+        if !found.contains(&package) {
+            queue.extend(package.package.iter());
+            found.insert(package);
+        }
+    }
+
+    Ok(found.len())
+}
+
+/// Length of the longest chain of dependencies needed to reach every package from the root
+fn max_depth(graph: &Graph, roots: &[PackageSpecifier]) -> Result<usize> {
+    let mut seen: FxHashSet<VersionedPackageInfo> = FxHashSet::default();
+    let mut depth = 0;
+
+    let mut queue: VecDeque<_> = roots.iter().map(|x| (x.clone(), 1)).collect();
+    while let Some((req, level)) = queue.pop_front() {
+        let package = graph.resolve_req(&req)?;
+        if !seen.contains(&package) {
+            depth = depth.max(level);
+            queue.extend(package.package.iter().map(|x| (x, level + 1)));
+            seen.insert(package);
+        }
+    }
+
+    Ok(depth)
+}
+
+fn print_row(label: &str, value: impl std::fmt::Display, detail: &str) {
+    println!("{label:<18} {}{detail}", value.yellow());
+}
+
+/// Print an overview of the packages that the root depends on
+pub fn print_stats(package: &PackageMetadata, graph: &Graph) -> Result<()> {
+    let roots = package.iter_all().collect_vec();
+    let graph = graph.pruned(&roots, &VerboseEvents);
+
+    // Local packages share a version, so packages are told apart by their full metadata
+    let packages: FxHashSet<&VersionedPackageInfo> = graph.relations.values().collect();
+
+    let mut versions: FxHashMap<&CompactString, usize> = FxHashMap::default();
+    for x in &packages {
+        *versions.entry(&x.package.name).or_default() += 1;
+    }
+    let duplicates = versions
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .sorted_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)))
+        .collect_vec();
+
+    let heaviest = roots
+        .iter()
+        .map(|req| Ok((req, subtree_size(&graph, req)?)))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.name.cmp(&a.0.name)));
+
+    let with_scripts = packages
+        .iter()
+        .filter(|x| {
+            INSTALL_SCRIPTS
+                .iter()
+                .any(|s| x.package.scripts.contains_key(*s))
+        })
+        .count();
+    let deprecated = packages
+        .iter()
+        .filter(|x| x.package.deprecated.is_some())
+        .count();
+    let platform_specific = packages
+        .iter()
+        .filter(|x| {
+            !x.package.os.is_empty() || !x.package.cpu.is_empty() || !x.package.libc.is_empty()
+        })
+        .count();
+
+    let listed = if duplicates.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " ({})",
+            duplicates
+                .iter()
+                .take(LISTED_DUPLICATES)
+                .map(|(name, count)| format!("{} ×{count}", name.bright_blue()))
+                .join(", ")
+        )
+    };
+
+    print_row("Packages", packages.len(), "");
+    print_row("Unique names", versions.len(), "");
+    print_row("Duplicated names", duplicates.len(), &listed);
+    print_row("Max depth", max_depth(&graph, &roots)?, "");
+    match heaviest {
+        Some((req, size)) => print_row(
+            "Heaviest subtree",
+            size,
+            &format!(" packages ({})", req.name.bright_blue()),
+        ),
+        None => print_row("Heaviest subtree", 0, ""),
+    }
+    print_row("Install scripts", with_scripts, "");
+    print_row("Deprecated", deprecated, "");
+    print_row("Platform-specific", platform_specific, "");
+
+    Ok(())
+}
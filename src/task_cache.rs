@@ -0,0 +1,126 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{copy, create_dir_all, read, read_dir, remove_dir_all, remove_file, rename},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    hash::dependency_hash, package::PackageMetadata, plan::store_dir, resolve::Graph,
+    util::hash_hex,
+};
+
+/// Files that a script reads and writes, as globs relative to the project
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TaskConfig {
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+fn cache_dir(key: &str) -> PathBuf {
+    store_dir().join("tasks").join(key)
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        for entry in read_dir(path)? {
+            collect_files(&entry?.path(), files)?;
+        }
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Files matching the globs, where matched directories include everything inside them
+fn expand(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for pattern in patterns {
+        for path in glob::glob(pattern)? {
+            let path = path?;
+            // Outputs are restored to the same paths, so they must stay inside the project
+            if path
+                .components()
+                .any(|x| !matches!(x, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(
+                    eyre!("Task path {} is outside of the project", path.display())
+                        .suggestion("Use paths relative to package.json, without `..`"),
+                );
+            }
+            collect_files(&path, &mut files)?;
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Hash of everything that determines the outputs of a script
+pub fn task_key(
+    package: &PackageMetadata,
+    graph: &Graph,
+    script: &str,
+    task: &TaskConfig,
+) -> Result<String> {
+    let mut lines = vec![
+        format!("script {script}"),
+        format!("inputs {:?}", task.inputs),
+        format!("outputs {:?}", task.outputs),
+        format!("dependencies {}", dependency_hash(package, graph, None)?),
+    ];
+    for file in expand(&task.inputs)? {
+        lines.push(format!("{} {}", file.display(), hash_hex(read(&file)?)));
+    }
+
+    Ok(hash_hex(lines.join("\n")))
+}
+
+/// Replace the outputs of a script with the ones cached for `key`, returning whether they were cached
+pub fn restore_outputs(key: &str, task: &TaskConfig) -> Result<bool> {
+    let dir = cache_dir(key);
+    if !dir.exists() {
+        return Ok(false);
+    }
+
+    for file in expand(&task.outputs)? {
+        remove_file(file)?;
+    }
+
+    let mut cached = vec![];
+    collect_files(&dir, &mut cached)?;
+    for file in cached {
+        let target = file.strip_prefix(&dir)?;
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        copy(&file, target)?;
+    }
+
+    Ok(true)
+}
+
+/// Copy the outputs of a successful run into the store
+pub fn save_outputs(key: &str, task: &TaskConfig) -> Result<()> {
+    let dir = cache_dir(key);
+    let partial = dir.with_extension("partial");
+    let _ = remove_dir_all(&partial);
+
+    for file in expand(&task.outputs)? {
+        let target = partial.join(&file);
+        create_dir_all(target.parent().unwrap())?;
+        copy(&file, target)?;
+    }
+    create_dir_all(&partial)?;
+
+    // Other runs may have cached the same outputs in the meantime
+    let _ = remove_dir_all(&dir);
+    rename(&partial, &dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use futures::TryStreamExt;
+use serde_json::Value;
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::{Component, Path, PathBuf},
+};
+use tokio::fs::create_dir_all;
+use tokio_tar::Archive;
+use tokio_util::io::StreamReader;
+
+use crate::{
+    git::{git, resolve_github_commit, GitHubSource},
+    progress::log_progress,
+    util::{read_json, write_json, VersionSpecifier, CLIENT},
+};
+
+/// Values substituted for `{{name}}` and `{{author}}` in template files
+pub struct TemplateVars {
+    pub name: String,
+    pub author: String,
+}
+
+impl TemplateVars {
+    /// Substitute the values into `text`, escaping them as JSON strings when `json` is set
+    fn apply(&self, text: &str, json: bool) -> String {
+        let escape = |x: &str| {
+            if json {
+                let quoted = Value::from(x).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            } else {
+                x.to_string()
+            }
+        };
+        text.replace("{{name}}", &escape(&self.name))
+            .replace("{{author}}", &escape(&self.author))
+    }
+}
+
+/// The author configured in git, as `Name <email>`
+pub async fn git_author() -> String {
+    let name = git(&["config", "user.name"], None)
+        .await
+        .unwrap_or_default();
+    match git(&["config", "user.email"], None).await {
+        Ok(email) if !email.is_empty() => format!("{name} <{email}>"),
+        _ => name,
+    }
+}
+
+/// Download a `github:owner/repo` template into `dir`, filling in `vars`
+pub async fn init_from_template(source: &str, dir: &Path, vars: &TemplateVars) -> Result<()> {
+    let spec: VersionSpecifier = serde_json::from_value(Value::String(source.to_string()))?;
+    let source = GitHubSource::parse(&spec).ok_or_else(|| {
+        eyre!("Unsupported template `{source}`")
+            .suggestion("Templates are specified as `github:owner/repo` or `github:owner/repo#ref`")
+    })?;
+
+    let commit = resolve_github_commit(&source).await?;
+    let url = source.tarball_url(&commit);
+
+    log_progress(&format!(
+        "Downloading template {}/{}",
+        source.owner, source.repo
+    ));
+
+    let res = CLIENT
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes_stream()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+
+    let mut archive = Archive::new(GzipDecoder::new(StreamReader::new(res)));
+    let mut entries = archive.entries()?;
+    let mut files = vec![];
+
+    // Files are nested in a `repo-commit` directory, which is dropped
+    while let Some(mut entry) = entries.try_next().await? {
+        let path = entry.path()?;
+        let relative: PathBuf = path.components().skip(1).collect();
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if !relative
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+        {
+            return Err(eyre!("Template contains invalid path {}", path.display()));
+        }
+        // Later entries could be written through a link to outside of the project
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            return Err(eyre!("Template contains a link at {}", path.display())
+                .suggestion("Links are not supported in templates"));
+        }
+
+        let target = dir.join(relative);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).await?;
+        }
+        entry.unpack(&target).await?;
+        if entry_type.is_file() {
+            files.push(target);
+        }
+    }
+
+    for file in files {
+        if let Ok(text) = read_to_string(&file) {
+            let json = file.extension().is_some_and(|x| x == "json");
+            let replaced = vars.apply(&text, json);
+            if replaced != text {
+                write(&file, replaced)?;
+            }
+        }
+    }
+
+    let manifest = dir.join("package.json");
+    if manifest.exists() {
+        let mut package: Value = read_json(&manifest).await?;
+        package["name"] = vars.name.clone().into();
+        write_json(&manifest, &package).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, path::Path};
+use tokio::{
+    fs::{remove_dir_all, remove_file, symlink_metadata},
+    process::Command,
+};
+
+use crate::{npm::Dependency, util::hash_hex};
+
+/// Changes applied to every package in the store after it is extracted
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Glob patterns of files to remove, relative to the package, such as `**/*.md`
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Shell command to run in each package directory
+    pub command: Option<String>,
+}
+
+impl TransformConfig {
+    /// Identifies the transforms applied to a store entry, so that changing them invalidates it
+    pub fn hash(&self) -> Result<String> {
+        Ok(hash_hex(serde_json::to_vec(self)?))
+    }
+}
+
+async fn remove_path(path: &Path) -> Result<()> {
+    let result = match symlink_metadata(path).await {
+        Ok(meta) if meta.is_dir() => remove_dir_all(path).await,
+        Ok(_) => remove_file(path).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub async fn apply_transform(
+    dir: &Path,
+    dep: &Dependency,
+    transform: &TransformConfig,
+) -> Result<()> {
+    let mut matches = vec![];
+    for pattern in &transform.remove {
+        let pattern = dir.join(pattern);
+        for path in glob::glob(&pattern.to_string_lossy())? {
+            matches.push(path?);
+        }
+    }
+
+    // Parents come first, so their contents are already gone when reached
+    matches.sort();
+    for path in matches {
+        if path != dir.join("package.json") {
+            remove_path(&path).await?;
+        }
+    }
+
+    if let Some(command) = &transform.command {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(dir)
+            .env("COTTON_PACKAGE_NAME", &*dep.name)
+            .env("COTTON_PACKAGE_VERSION", dep.version.to_string())
+            .status()
+            .await?;
+
+        if !status.success() {
+            return Err(eyre!(
+                "Transform command failed for {} ({status})",
+                dep.id()
+            ));
+        }
+    }
+
+    Ok(())
+}
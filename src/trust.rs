@@ -0,0 +1,159 @@
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Section,
+};
+use compact_str::CompactString;
+use itertools::Itertools;
+use node_semver::Version;
+use once_cell::sync::OnceCell;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Display,
+    fs::{create_dir_all, read_to_string, write},
+    io::ErrorKind,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{global::cotton_home, package::Dist, progress::log_warning, resolve::Graph, ARGS};
+
+/// Identity of a package version when it was first seen
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TrustRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<CompactString>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub keyids: BTreeSet<CompactString>,
+}
+
+impl TrustRecord {
+    fn new(dist: &Dist) -> Self {
+        Self {
+            integrity: dist.integrity.clone().or_else(|| dist.shasum.clone()),
+            keyids: dist.signatures.iter().map(|x| x.keyid.clone()).collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.integrity.is_none() && self.keyids.is_empty()
+    }
+}
+
+impl Display for TrustRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "integrity {}, signed by [{}]",
+            self.integrity.as_deref().unwrap_or("none"),
+            self.keyids
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct TrustStore {
+    #[serde(default)]
+    pub trusted: BTreeMap<String, TrustRecord>,
+    /// Changed identities that have been seen, but not yet accepted
+    #[serde(default)]
+    pub pending: BTreeMap<String, TrustRecord>,
+}
+
+fn load() -> Result<TrustStore> {
+    let path = cotton_home()?.join("trust.json");
+    match read_to_string(&path) {
+        Ok(s) => serde_json::from_str::<TrustStore>(&s)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))
+            .suggestion("Fix the file, or remove it to forget every trusted package"),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(TrustStore::default()),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+static TRUST: OnceCell<Mutex<TrustStore>> = OnceCell::new();
+
+/// The trust store, which is loaded on first use. A store that cannot be read is an error rather
+/// than empty, since saving over it would lose every recorded identity.
+pub fn store() -> Result<MutexGuard<'static, TrustStore>> {
+    Ok(TRUST
+        .get_or_try_init(|| load().map(Mutex::new))?
+        .lock()
+        .unwrap())
+}
+
+pub fn save() -> Result<()> {
+    let home = cotton_home()?;
+    create_dir_all(&home)?;
+    let store = store()?;
+    write(
+        home.join("trust.json"),
+        serde_json::to_string_pretty(&*store)?,
+    )?;
+    Ok(())
+}
+
+/// Record the identity of a package on first use, and warn if it has changed since. Returns
+/// whether it has changed.
+fn observe(store: &mut TrustStore, name: &str, version: &Version, dist: &Dist) -> bool {
+    let record = TrustRecord::new(dist);
+    if record.is_empty() {
+        return false;
+    }
+
+    let key = format!("{name}@{version}");
+
+    match store.trusted.get(&key) {
+        None => {
+            store.trusted.insert(key, record);
+            false
+        }
+        Some(trusted) if trusted == &record => false,
+        Some(trusted) => {
+            log_warning(&format!(
+                "{} has changed since it was first installed!\n  trusted: {}\n  now:     {}\nThis may indicate a compromised registry. Run `cotton trust` to review.",
+                key.red().bold(),
+                trusted,
+                record
+            ));
+            store.pending.insert(key, record);
+            true
+        }
+    }
+}
+
+/// Record the identities of the packages in the graph. Changed identities only warn during
+/// interactive installs, but fail with `--immutable` or in CI until `cotton trust` accepts them.
+pub fn observe_graph(graph: &Graph) -> Result<()> {
+    let mut changed = BTreeSet::new();
+    {
+        let mut store = store()?;
+        for pkg in graph.relations.values() {
+            if observe(
+                &mut store,
+                &pkg.package.name,
+                &pkg.version,
+                &pkg.package.dist,
+            ) {
+                changed.insert(format!("{}@{}", pkg.package.name, pkg.version));
+            }
+        }
+    }
+
+    save()?;
+
+    if !changed.is_empty() && (ARGS.immutable || env::var_os("CI").is_some()) {
+        return Err(eyre!(
+            "Packages changed since they were first installed: {}",
+            changed.iter().join(", ")
+        )
+        .suggestion("Run `cotton trust` to review, and `cotton trust --accept` to trust them"));
+    }
+
+    Ok(())
+}
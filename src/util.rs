@@ -1,33 +1,76 @@
-use color_eyre::eyre::{Context, Result};
-use color_eyre::Report;
-use compact_str::{CompactString, ToCompactString};
-use node_semver::{Range, Version};
+use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::{Report, Section};
+use cotton_core::platform::{Platform, Platforms};
 use once_cell::sync::Lazy;
-use reqwest::{Client, ClientBuilder, Url};
+use reqwest::dns::{Name, Resolve, Resolving};
+use reqwest::{Client, ClientBuilder, Proxy};
+use rustc_hash::FxHashSet;
 use serde::de::DeserializeOwned;
-use serde::{de::Error, Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::Value;
-use std::future::Future;
-use std::io::ErrorKind;
-use std::path::Path;
-use std::sync::Arc;
-use std::{
-    env::consts::{ARCH, OS},
-    fmt::Display,
+use sha2::{Digest, Sha256};
+use std::env::{
+    self,
+    consts::{ARCH, OS},
 };
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::fs::{read_to_string, File};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{stdin, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::OnceCell;
 use tracing::instrument;
 
-use crate::package::PackageMetadata;
-use crate::progress::log_warning;
-use crate::resolve::{Graph, Lockfile};
+use crate::catalog::{uses_catalogs, Catalogs};
+use crate::config::{read_config, read_config_sync};
+use crate::diff::write_manifest;
+use crate::git::git;
+use crate::package::{Mode, PackageMetadata};
+use crate::package_manager::user_agent;
+use crate::progress::{log_verbose, log_warning};
+use crate::resolve::{importers, Graph, Lockfile};
+use crate::shard::{checked_shards, save_sharded};
+use crate::workspace::find_workspaces;
+use crate::ARGS;
+
+pub use cotton_core::spec::VersionSpecifier;
+
+pub const NO_NETWORK: &str = "Network access is disabled by `--no-network`";
+
+/// Fails every lookup, so that no request can leave the process
+struct NoNetwork;
+
+impl Resolve for NoNetwork {
+    fn resolve(&self, _: Name) -> Resolving {
+        Box::pin(async { Err(NO_NETWORK.into()) })
+    }
+}
 
-pub const CLIENT_LIMIT: usize = 100;
+fn client_builder() -> ClientBuilder {
+    let builder = ClientBuilder::new().user_agent(user_agent());
+    if !ARGS.no_network {
+        return builder;
+    }
+
+    // Requests to IP addresses skip the lookup, so all of them go through a proxy that needs one
+    builder
+        .dns_resolver(Arc::new(NoNetwork))
+        .proxy(Proxy::custom(|_| Some("http://no-network.invalid")))
+}
+
+/// Fail if `--no-network` is set, for network access outside of `CLIENT`
+pub fn check_network(target: &str) -> Result<()> {
+    if ARGS.no_network {
+        return Err(eyre!("{NO_NETWORK}, but {target} needs it"));
+    }
+    Ok(())
+}
 
-pub static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+pub static CLIENT: Lazy<Client> = Lazy::new(|| client_builder().build().unwrap());
 pub static CLIENT_Z: Lazy<Client> = Lazy::new(|| {
-    ClientBuilder::new()
+    client_builder()
         .brotli(true)
         .gzip(true)
         .deflate(true)
@@ -43,93 +86,137 @@ pub fn decode_json<T: DeserializeOwned>(
     serde_path_to_error::deserialize(jd)
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
-pub struct VersionSpecifierPrefixed {
-    pub prefix: CompactString,
-    pub rest: CompactString,
-}
-
-impl Display for VersionSpecifierPrefixed {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.prefix, self.rest)
+/// The os of this machine, as named by node
+pub fn host_node_os() -> &'static str {
+    match OS {
+        "linux" => "linux",
+        "macos" => "darwin",
+        "freebsd" => "freebsd",
+        "openbsd" => "openbsd",
+        "windows" => "win32",
+        _ => unreachable!(),
     }
 }
 
-impl Serialize for VersionSpecifierPrefixed {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        self.to_string().serialize(serializer)
+/// The cpu of this machine, as named by node
+pub fn host_node_cpu() -> &'static str {
+    match ARCH {
+        "x86_64" => "x64",
+        "x86" => "ia32",
+        "aarch64" => "arm64",
+        x => x,
     }
 }
 
-impl<'de> Deserialize<'de> for VersionSpecifierPrefixed {
-    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        let (prefix, rest) = s
-            .split_once(':')
-            .ok_or_else(|| D::Error::custom("missing :"))?;
-        if prefix.starts_with("http") {
-            return Err(D::Error::custom("http not allowed"));
-        }
-        Ok(Self {
-            prefix: prefix.to_compact_string(),
-            rest: rest.to_compact_string(),
+/// The platform packages are installed for, from `--target-os`/`--target-cpu` or cotton.toml
+static TARGET: Lazy<(Option<String>, Option<String>)> = Lazy::new(|| {
+    let config = read_config_sync().unwrap_or_default();
+    (
+        ARGS.target_os.clone().or(config.target_os),
+        ARGS.target_cpu.clone().or(config.target_cpu),
+    )
+});
+
+/// The mode selected with `--mode`, or otherwise by `NODE_ENV`
+pub fn install_mode() -> Mode {
+    ARGS.mode
+        .unwrap_or_else(|| match env::var("NODE_ENV").as_deref() {
+            Ok("production") => Mode::Production,
+            _ => Mode::Development,
         })
-    }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
-#[serde(untagged)]
-pub enum VersionSpecifier {
-    Range(Range),
-    Prefixed(VersionSpecifierPrefixed),
-    DirectUrl(Url),
-    Other(CompactString),
+/// Whether packages are installed for a platform other than this machine
+pub fn is_cross_platform() -> bool {
+    TARGET.0.as_deref().is_some_and(|x| x != host_node_os())
+        || TARGET.1.as_deref().is_some_and(|x| x != host_node_cpu())
 }
 
-impl VersionSpecifier {
-    pub fn satisfies(&self, v: &Version) -> bool {
-        match self {
-            VersionSpecifier::Range(r) => r.satisfies(v),
-            VersionSpecifier::Prefixed(_) => true,
-            VersionSpecifier::DirectUrl(_) => true,
-            VersionSpecifier::Other(_) => false,
-        }
-    }
+/// The os that packages are installed for
+pub fn get_node_os() -> &'static str {
+    TARGET.0.as_deref().unwrap_or_else(host_node_os)
 }
 
-impl Display for VersionSpecifier {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            VersionSpecifier::Range(a) => a.fmt(f),
-            VersionSpecifier::Prefixed(a) => a.fmt(f),
-            VersionSpecifier::DirectUrl(a) => a.fmt(f),
-            VersionSpecifier::Other(a) => a.fmt(f),
-        }
-    }
+/// The cpu that packages are installed for
+pub fn get_node_cpu() -> &'static str {
+    TARGET.1.as_deref().unwrap_or_else(host_node_cpu)
 }
 
-pub fn get_node_os() -> &'static str {
-    match OS {
-        "linux" => "linux",
-        "macos" => "darwin",
-        "freebsd" => "freebsd",
-        "openbsd" => "openbsd",
-        "windows" => "win32",
-        _ => unreachable!(),
-    }
+/// The C library of the system, as used by the `libc` field (only detected on Linux, when not cross-installing)
+pub fn get_node_libc() -> Option<&'static str> {
+    static LIBC: Lazy<Option<&'static str>> = Lazy::new(|| {
+        // The libc of another platform is unknown, so neither variant is excluded
+        if OS != "linux" || is_cross_platform() {
+            return None;
+        }
+
+        // Alpine's `ldd` is a script running the musl loader
+        let ldd = std::fs::read("/usr/bin/ldd").unwrap_or_default();
+        let has_musl_loader = std::fs::read_dir("/lib").is_ok_and(|x| {
+            x.flatten()
+                .any(|x| x.file_name().to_string_lossy().starts_with("ld-musl-"))
+        });
+        if has_musl_loader || ldd.windows(4).any(|x| x == b"musl") {
+            Some("musl")
+        } else {
+            Some("glibc")
+        }
+    });
+
+    *LIBC
 }
 
-pub fn get_node_cpu() -> &'static str {
-    match ARCH {
-        "x86_64" => "x64",
-        x => x,
-    }
+/// The target platform, along with the others in `supported_architectures`
+pub fn supported_platforms() -> &'static Platforms {
+    static PLATFORMS: Lazy<Platforms> = Lazy::new(|| {
+        let config = read_config_sync()
+            .unwrap_or_default()
+            .supported_architectures;
+
+        let resolve = |list: Vec<String>, current: &str| {
+            if list.is_empty() {
+                return vec![current.to_string()];
+            }
+            list.into_iter()
+                .map(|x| {
+                    if x == "current" {
+                        current.to_string()
+                    } else {
+                        x
+                    }
+                })
+                .collect()
+        };
+
+        let libc = if config.libc.is_empty() {
+            get_node_libc().map(|x| vec![x.to_string()])
+        } else {
+            config
+                .libc
+                .into_iter()
+                .map(|x| {
+                    if x == "current" {
+                        get_node_libc().map(|x| x.to_string())
+                    } else {
+                        Some(x)
+                    }
+                })
+                .collect()
+        };
+
+        Platforms {
+            os: resolve(config.os, get_node_os()),
+            cpu: resolve(config.cpu, get_node_cpu()),
+            libc,
+            target: Platform {
+                os: get_node_os().to_string(),
+                cpu: get_node_cpu().to_string(),
+                libc: get_node_libc().map(|x| x.to_string()),
+            },
+        }
+    });
+
+    &PLATFORMS
 }
 
 const RETRY_LIMIT: usize = 3;
@@ -139,6 +226,8 @@ pub async fn retry<T, Fut: Future<Output = Result<T>>>(mut f: impl FnMut() -> Fu
     for _ in 0..RETRY_LIMIT {
         match f().await {
             Ok(x) => return Ok(x),
+            // Nothing would change on another attempt
+            Err(e) if ARGS.no_network => return Err(e),
             Err(e) => {
                 log_warning(&format!("Retrying {e}"));
                 last = Some(e);
@@ -148,12 +237,58 @@ pub async fn retry<T, Fut: Future<Output = Result<T>>>(mut f: impl FnMut() -> Fu
     Err(last.unwrap()).wrap_err("Failed all retries")
 }
 
+pub fn manifest_path() -> PathBuf {
+    ARGS.manifest
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("package.json"))
+}
+
+pub fn lockfile_path() -> PathBuf {
+    ARGS.lockfile
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("cotton.lock"))
+}
+
+/// Read a file, where `-` refers to stdin (which is only consumed once)
+pub async fn read_input(path: &Path) -> io::Result<String> {
+    static STDIN: OnceCell<String> = OnceCell::const_new();
+
+    if path == Path::new("-") {
+        STDIN
+            .get_or_try_init(|| async {
+                let mut s = String::new();
+                stdin().read_to_string(&mut s).await?;
+                Ok(s)
+            })
+            .await
+            .cloned()
+    } else {
+        read_to_string(path).await
+    }
+}
+
 pub async fn read_package() -> Result<PackageMetadata> {
-    read_json("package.json").await
+    let path = manifest_path();
+    let s = read_input(&path)
+        .await
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let mut package: PackageMetadata = serde_json::from_str(&s)?;
+
+    if uses_catalogs(&package) {
+        Catalogs::enclosing(&package)
+            .await?
+            .apply(&mut package)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    }
+    package
+        .check_groups()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    Ok(package)
 }
 
 pub async fn read_package_or_default<T: DeserializeOwned>() -> Result<T> {
-    let s = match read_to_string("package.json").await {
+    let s = match read_input(&manifest_path()).await {
         Ok(s) => s,
         Err(e) if e.kind() == ErrorKind::NotFound => "{}".into(),
         r => r?,
@@ -162,7 +297,54 @@ pub async fn read_package_or_default<T: DeserializeOwned>() -> Result<T> {
 }
 
 pub async fn save_package(package: &Value) -> Result<()> {
-    write_json("package.json", package).await
+    let path = manifest_path();
+    if path == Path::new("-") {
+        return Err(eyre!("Cannot modify a manifest read from stdin"));
+    }
+    write_manifest(&path, package).await
+}
+
+pub async fn save_lockfile(graph: &Graph) -> Result<()> {
+    let path = lockfile_path();
+    if path == Path::new("-") {
+        return Err(eyre!("Cannot modify a lockfile read from stdin")
+            .suggestion("Pass --immutable to prevent changes to the lockfile"));
+    }
+
+    // Replacing a lockfile in a newer format would lose what this version does not understand
+    if let Ok(previous) = read_input(&path).await {
+        if let Ok(previous) = serde_json::from_str(&previous) {
+            Lockfile::check_version(&previous)
+                .wrap_err_with(|| format!("Refusing to replace {}", path.display()))?;
+        }
+    }
+
+    if let Ok(previous) = load_graph_from_lockfile().await {
+        let pruned = previous
+            .relations
+            .into_keys()
+            .filter(|req| !graph.relations.contains_key(req))
+            .count();
+        if pruned > 0 {
+            log_verbose(&format!(
+                "Pruned {pruned} requirements that are no longer used from the lockfile"
+            ));
+        }
+    }
+
+    if read_config().await?.shard_lockfile {
+        return save_sharded(graph, &path).await;
+    }
+    // Shards from when sharding was enabled are merged into the lockfile
+    let shards = read_lockfile().await.map(|x| x.shards).unwrap_or_default();
+    for shard in checked_shards(&shards).await? {
+        let _ = std::fs::remove_file(shard);
+    }
+
+    let root = read_package().await?;
+    let mut lockfile = Lockfile::new(graph.clone());
+    lockfile.importers = importers(graph, &root, &find_workspaces(&root).await?);
+    write_json(path, lockfile).await
 }
 
 #[instrument]
@@ -181,9 +363,97 @@ pub async fn write_json<T: Serialize>(path: impl AsRef<Path>, data: T) -> Result
     Ok(())
 }
 
-pub async fn load_graph_from_lockfile() -> Graph {
-    let lockfile: Lockfile = read_json("cotton.lock").await.unwrap_or_default();
+/// Read the lockfile, without the shards it refers to
+pub async fn read_lockfile() -> Result<Lockfile> {
+    let path = lockfile_path();
+    match read_input(&path).await {
+        Ok(s) => Lockfile::parse(&s).wrap_err_with(|| format!("Failed to read {}", path.display())),
+        Err(_) => Ok(Default::default()),
+    }
+}
+
+/// Warn about conflicts merged in a lockfile, once per file since it is read several times
+fn warn_conflicts(path: &Path, lockfile: &Lockfile) {
+    static WARNED: Lazy<Mutex<FxHashSet<PathBuf>>> = Lazy::new(Default::default);
+
+    if lockfile.conflicts > 0 && WARNED.lock().unwrap().insert(path.to_path_buf()) {
+        log_warning(&format!(
+            "Merged conflicts in {}, resolving {} conflicting requirements again",
+            path.display(),
+            lockfile.conflicts
+        ));
+    }
+}
+
+/// Paths of the lockfile and its shards
+pub async fn lockfile_paths() -> Vec<PathBuf> {
+    let mut paths = vec![lockfile_path()];
+    let shards = read_lockfile().await.map(|x| x.shards).unwrap_or_default();
+    paths.extend(checked_shards(&shards).await.unwrap_or_default());
+    paths
+}
+
+/// Contents of the lockfile and its shards, for detecting changes
+pub async fn lockfile_contents() -> String {
+    let mut contents = String::new();
+    for path in lockfile_paths().await {
+        contents += &read_input(&path).await.unwrap_or_default();
+    }
+    contents
+}
+
+/// Load the graph from the lockfile, merging in its shards
+pub async fn load_graph_from_lockfile() -> Result<Graph> {
+    let mut lockfile = read_lockfile().await?;
+    warn_conflicts(&lockfile_path(), &lockfile);
+    for shard in checked_shards(&take(&mut lockfile.shards)).await? {
+        let text = match read_to_string(&shard).await {
+            Ok(x) => x,
+            Err(e) => {
+                log_warning(&format!(
+                    "Failed to read lockfile shard {}: {e}",
+                    shard.display()
+                ));
+                continue;
+            }
+        };
+        let shard_lockfile = Lockfile::parse(&text)
+            .wrap_err_with(|| format!("Failed to read lockfile shard {}", shard.display()))?;
+        warn_conflicts(&shard, &shard_lockfile);
+        lockfile.relations.extend(shard_lockfile.relations);
+    }
+    Ok(lockfile.into_graph())
+}
+
+/// Read a file as committed to git, relative to the current directory
+async fn read_committed(path: &Path) -> Result<String> {
+    git(&["show", &format!("HEAD:./{}", path.display())], None).await
+}
+
+/// Load the graph from the lockfile as committed to git, merging in its committed shards. This is
+/// empty outside of a repository, or if the lockfile has not been committed.
+pub async fn load_committed_graph() -> Graph {
+    let path = lockfile_path();
+    if path == Path::new("-") {
+        return Graph::default();
+    }
+
+    let Ok(mut lockfile) = async { Lockfile::parse(&read_committed(&path).await?) }.await else {
+        return Graph::default();
+    };
+    for shard in checked_shards(&take(&mut lockfile.shards))
+        .await
+        .unwrap_or_default()
+    {
+        if let Ok(shard) = async { Lockfile::parse(&read_committed(&shard).await?) }.await {
+            lockfile.relations.extend(shard.relations);
+        }
+    }
     lockfile.into_graph()
 }
 
 pub type ArcResult<T, E = Report> = Result<T, Arc<E>>;
+
+pub fn hash_hex(data: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
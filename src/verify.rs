@@ -0,0 +1,230 @@
+use color_eyre::{
+    eyre::{eyre, Result},
+    Section,
+};
+use compact_str::CompactString;
+use node_semver::Version;
+use owo_colors::OwoColorize;
+use std::{
+    fmt::Display,
+    fs::{metadata, read_dir, read_to_string, remove_dir_all, remove_file, symlink_metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bins::{bin_link_path, normalize_bins},
+    local::{is_linked, is_local, linked_path, local_path},
+    npm::{Dependency, DependencyTree},
+    plan::{install_marker, install_package, install_path, store_src_path, Plan},
+    progress::log_warning,
+    resolve_check::resolve_file,
+};
+
+enum Problem {
+    Missing,
+    WrongVersion(String),
+    NotLinked(PathBuf),
+    BrokenBin(CompactString),
+    MissingFile(PathBuf),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "not installed"),
+            Problem::WrongVersion(found) => write!(f, "found version {found}"),
+            Problem::NotLinked(path) => {
+                write!(f, "{} is not linked to the store", path.display())
+            }
+            Problem::BrokenBin(cmd) => write!(f, "bin {cmd} is broken"),
+            Problem::MissingFile(path) => write!(f, "{} does not exist", path.display()),
+        }
+    }
+}
+
+/// Check that every file in the store is hardlinked to the installed copy
+fn check_links(src: &Path, dst: &Path, problems: &mut Vec<Problem>) -> Result<()> {
+    for entry in read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            check_links(&entry.path(), &target, problems)?;
+        } else {
+            let src_meta = entry.metadata()?;
+            match symlink_metadata(&target) {
+                Ok(dst_meta)
+                    if dst_meta.ino() == src_meta.ino() && dst_meta.dev() == src_meta.dev() => {}
+                _ => problems.push(Problem::NotLinked(target)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_package(prefix: &[CompactString], dep: &Dependency) -> Result<Vec<Problem>> {
+    let target_path = install_path(prefix, dep)?;
+
+    let installed = match linked_path(&dep.dist) {
+        Some(dir) => is_linked(&target_path, dir),
+        None => metadata(install_marker(&target_path, dep)).is_ok(),
+    };
+    if !installed {
+        return Ok(vec![Problem::Missing]);
+    }
+
+    let mut problems = vec![];
+
+    let version = read_to_string(target_path.join("package.json"))
+        .ok()
+        .and_then(|x| serde_json::from_str::<serde_json::Value>(&x).ok())
+        .and_then(|x| x["version"].as_str().map(|x| x.to_string()));
+    // Build metadata only distinguishes store entries, and is not part of the package's own version
+    let expected = Version {
+        build: vec![],
+        ..dep.version.clone()
+    };
+    if !is_local(&dep.dist) && version.as_deref() != Some(&*expected.to_string()) {
+        problems.push(Problem::WrongVersion(
+            version.unwrap_or_else(|| "none".into()),
+        ));
+    }
+
+    if let Ok(src) = store_src_path(dep) {
+        check_links(&src, &target_path, &mut problems)?;
+    }
+
+    if prefix.is_empty() {
+        // Bins whose files do not exist are never linked, and are reported during installation
+        for (cmd, path) in normalize_bins(dep) {
+            if metadata(target_path.join(path)).is_ok() && metadata(bin_link_path(&cmd)).is_err() {
+                problems.push(Problem::BrokenBin(cmd));
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Spot-check the files a package cannot work without: its manifest, entry point and bins. Only
+/// files that exist in the source are expected, so packages published without them are not broken.
+fn check_files(prefix: &[CompactString], dep: &Dependency) -> Result<Vec<Problem>> {
+    let target_path = install_path(prefix, dep)?;
+
+    // Linked packages are the user's own directories
+    if linked_path(&dep.dist).is_some() {
+        return Ok(vec![]);
+    }
+    if metadata(install_marker(&target_path, dep)).is_err() {
+        return Ok(vec![Problem::Missing]);
+    }
+
+    let src = match local_path(&dep.dist) {
+        Some(dir) => PathBuf::from(dir),
+        None => store_src_path(dep)?,
+    };
+    let manifest = read_to_string(src.join("package.json"))
+        .ok()
+        .and_then(|x| serde_json::from_str::<serde_json::Value>(&x).ok())
+        .unwrap_or_default();
+
+    let mut files = vec![PathBuf::from("package.json")];
+    // Node falls back to `index` when there is no `main`
+    files.push(manifest["main"].as_str().unwrap_or("index").into());
+    files.extend(normalize_bins(dep).into_iter().map(|(_, path)| path));
+
+    Ok(files
+        .into_iter()
+        .filter(|x| resolve_file(&src.join(x)) && !resolve_file(&target_path.join(x)))
+        .map(Problem::MissingFile)
+        .collect())
+}
+
+/// Run `check` on every package in a tree, collecting the packages that have problems
+fn check_tree(
+    tree: &DependencyTree,
+    check: fn(&[CompactString], &Dependency) -> Result<Vec<Problem>>,
+    prefix: &mut Vec<CompactString>,
+    found: &mut Vec<(Vec<CompactString>, Dependency, Vec<Problem>)>,
+) -> Result<()> {
+    let problems = check(prefix, &tree.root)?;
+    if !problems.is_empty() {
+        found.push((prefix.clone(), tree.root.clone(), problems));
+    }
+
+    prefix.push(tree.root.name.clone());
+    for child in tree.children.values() {
+        check_tree(child, check, prefix, found)?;
+    }
+    prefix.pop();
+
+    Ok(())
+}
+
+async fn repair(prefix: &[CompactString], dep: &Dependency, problems: &[Problem]) -> Result<()> {
+    for problem in problems {
+        if let Problem::BrokenBin(cmd) = problem {
+            let _ = remove_file(bin_link_path(cmd));
+        }
+    }
+
+    let target_path = install_path(prefix, dep)?;
+    let _ = remove_dir_all(&target_path);
+
+    install_package(Path::new(""), prefix, dep).await
+}
+
+/// Reinstall packages whose key files were deleted from `node_modules`, for `--check-files`
+pub async fn repair_missing_files(plan: &Plan) -> Result<()> {
+    let mut found = vec![];
+    for tree in plan.trees.values() {
+        check_tree(tree, check_files, &mut vec![], &mut found)?;
+    }
+
+    for (prefix, dep, problems) in &found {
+        for problem in problems {
+            log_warning(&format!(
+                "Reinstalling {}, since {problem}",
+                dep.id().bright_blue()
+            ));
+        }
+        repair(prefix, dep, problems).await?;
+    }
+
+    Ok(())
+}
+
+/// Verify that `node_modules` matches the plan, optionally reinstalling broken packages
+pub async fn verify(plan: &Plan, fix: bool) -> Result<()> {
+    let mut found = vec![];
+    for tree in plan.trees.values() {
+        check_tree(tree, check_package, &mut vec![], &mut found)?;
+    }
+
+    for (prefix, dep, problems) in &found {
+        let path = prefix
+            .iter()
+            .chain([&dep.name])
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>()
+            .join(" > ");
+        for problem in problems {
+            println!("{} {}: {}", " BROKEN ".on_red(), path, problem);
+        }
+
+        if fix {
+            repair(prefix, dep, problems).await?;
+            println!("{} {}", " REPAIRED ".on_green(), path);
+        }
+    }
+
+    if found.is_empty() {
+        println!("All packages are installed correctly");
+    } else if !fix {
+        return Err(eyre!("{} packages are broken", found.len())
+            .suggestion("Run `cotton verify --repair` to reinstall them"));
+    }
+
+    Ok(())
+}
@@ -2,34 +2,126 @@ use futures::{
     channel::mpsc::{channel, Receiver},
     SinkExt, StreamExt,
 };
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
+use notify::{
+    Config, ErrorKind, Event, EventHandler, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{task::spawn_blocking, time::timeout};
+
+use crate::{config::read_config, progress::log_warning};
+
+/// How long native watches may take to set up before polling instead, such as on network filesystems
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
 
-fn async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<Event>)> {
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn async_handler(accept: fn(&Event) -> bool) -> (impl EventHandler, Receiver<Event>) {
     let (mut tx, rx) = channel(1);
 
-    let watcher = RecommendedWatcher::new(
-        move |res: notify::Result<Event>| {
-            futures::executor::block_on(async {
-                if let Ok(res) = res {
-                    if res.kind.is_access() {
-                        let _ = tx.send(res).await;
-                    }
+    let handler = move |res: notify::Result<Event>| {
+        futures::executor::block_on(async {
+            if let Ok(res) = res {
+                if accept(&res) {
+                    let _ = tx.send(res).await;
                 }
-            })
-        },
-        Config::default(),
-    )?;
+            }
+        })
+    };
 
-    Ok((watcher, rx))
+    (handler, rx)
 }
 
-pub async fn async_watch(paths: impl IntoIterator<Item = &Path>) -> notify::Result<Event> {
-    let (mut watcher, mut rx) = async_watcher()?;
+fn native_watcher(paths: &[PathBuf]) -> notify::Result<(Box<dyn Watcher + Send>, Receiver<Event>)> {
+    let (handler, rx) = async_handler(|x| x.kind.is_access());
+    let mut watcher = RecommendedWatcher::new(handler, Config::default())?;
+
+    for path in paths {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+    }
+
+    Ok((Box::new(watcher), rx))
+}
+
+fn poll_watcher(
+    paths: &[PathBuf],
+    interval: Duration,
+) -> notify::Result<(Box<dyn Watcher + Send>, Receiver<Event>)> {
+    // Polling only sees changed metadata, not files being accessed
+    let (handler, rx) = async_handler(|x| !x.kind.is_access());
+    let mut watcher = PollWatcher::new(handler, Config::default().with_poll_interval(interval))?;
 
     for path in paths {
         watcher.watch(path, RecursiveMode::Recursive)?;
     }
 
-    Ok(rx.next().await.unwrap())
+    Ok((Box::new(watcher), rx))
+}
+
+/// Whether the system ran out of inotify watches or instances
+fn is_limit(e: &notify::Error) -> bool {
+    match &e.kind {
+        ErrorKind::MaxFilesWatch => true,
+        ErrorKind::Io(e) => matches!(
+            e.raw_os_error(),
+            Some(x) if x == nix::libc::ENOSPC || x == nix::libc::EMFILE
+        ),
+        _ => false,
+    }
+}
+
+/// Wait for a change to one of `paths`, polling them if they cannot be watched natively
+pub async fn async_watch(paths: impl IntoIterator<Item = &Path>) -> notify::Result<Event> {
+    let paths: Vec<PathBuf> = paths.into_iter().map(|x| x.to_path_buf()).collect();
+    let poll_interval = read_config().await.ok().and_then(|x| x.watch_poll_interval);
+
+    let (watcher, mut rx) = match poll_interval {
+        Some(interval) => poll_watcher(&paths, interval)?,
+        None => {
+            let native = {
+                let paths = paths.clone();
+                timeout(
+                    SETUP_TIMEOUT,
+                    spawn_blocking(move || native_watcher(&paths)),
+                )
+                .await
+            };
+            let interval = DEFAULT_POLL_INTERVAL;
+
+            match native {
+                Ok(Ok(Ok(x))) => x,
+                Ok(Ok(Err(e))) if matches!(e.kind, ErrorKind::PathNotFound) => return Err(e),
+                Ok(Ok(Err(e))) if is_limit(&e) => {
+                    log_warning(&format!(
+                        "The system limit of file watches is exhausted, so files are polled every {} instead. Raise fs.inotify.max_user_watches and fs.inotify.max_user_instances to watch them natively.",
+                        humantime::format_duration(interval)
+                    ));
+                    poll_watcher(&paths, interval)?
+                }
+                Ok(Ok(Err(e))) => {
+                    log_warning(&format!(
+                        "Failed to watch files ({e}), so they are polled every {} instead",
+                        humantime::format_duration(interval)
+                    ));
+                    poll_watcher(&paths, interval)?
+                }
+                Ok(Err(e)) => return Err(notify::Error::generic(&e.to_string())),
+                Err(_) => {
+                    log_warning(&format!(
+                        "Watching files took longer than {}, so they are polled every {} instead",
+                        humantime::format_duration(SETUP_TIMEOUT),
+                        humantime::format_duration(interval)
+                    ));
+                    poll_watcher(&paths, interval)?
+                }
+            }
+        }
+    };
+
+    let event = rx.next().await.unwrap();
+    drop(watcher);
+
+    Ok(event)
 }
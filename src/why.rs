@@ -0,0 +1,332 @@
+use color_eyre::eyre::{eyre, Result};
+use compact_str::CompactString;
+use itertools::Itertools;
+use multimap::MultiMap;
+use node_semver::Version;
+use owo_colors::OwoColorize;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use tokio::fs::create_dir_all;
+
+use crate::{
+    package::{PackageMetadata, PackageSpecifier},
+    progress::log_verbose,
+    resolve::Graph,
+    util::{hash_hex, lockfile_contents, manifest_path, read_input, read_json, write_json},
+};
+
+type Node = (CompactString, Version);
+
+/// Upper bound on the number of chains printed for a single package
+const MAX_CHAINS: usize = 1000;
+
+const INDEX_PATH: &str = ".cotton/reverse-index.json";
+
+/// Reverse dependency map, stored next to the lockfile and manifest it was built from
+#[derive(Serialize, Deserialize)]
+struct ReverseIndex {
+    lockfile: String,
+    entries: Vec<(Node, Vec<PackageSpecifier>)>,
+}
+
+/// Edges are resolved in parallel, and each thread's share merged at the end
+fn build_map(graph: &Graph) -> Result<MultiMap<Node, PackageSpecifier>> {
+    graph
+        .relations
+        .par_iter()
+        .flat_map_iter(|(from, to)| {
+            to.package
+                .iter()
+                .map(move |child_req| -> Result<_> { Ok((graph.resolve_req(&child_req)?, from)) })
+        })
+        .try_fold(MultiMap::new, |mut map, edge| -> Result<_> {
+            let (child_dep, from) = edge?;
+            map.insert(
+                (child_dep.package.name.clone(), child_dep.version),
+                from.clone(),
+            );
+            Ok(map)
+        })
+        .try_reduce(MultiMap::new, |mut map, other| {
+            for (node, required_by) in other {
+                map.insert_many(node, required_by);
+            }
+            Ok(map)
+        })
+}
+
+/// Load the reverse dependency map from the cache if the lockfile and manifest are unchanged,
+/// otherwise build it from the graph and update the cache
+pub async fn load_map(graph: &Graph) -> Result<MultiMap<Node, PackageSpecifier>> {
+    // The graph is pruned to the dependencies in the manifest, so both determine the map
+    let lockfile = hash_hex(
+        lockfile_contents().await + &read_input(&manifest_path()).await.unwrap_or_default(),
+    );
+
+    if let Ok(index) = read_json::<ReverseIndex>(INDEX_PATH).await {
+        if index.lockfile == lockfile {
+            log_verbose("Using cached reverse dependency index");
+
+            let mut map = MultiMap::new();
+            for (node, required_by) in index.entries {
+                map.insert_many(node, required_by);
+            }
+            return Ok(map);
+        }
+    }
+
+    let map = build_map(graph)?;
+
+    let index = ReverseIndex {
+        lockfile,
+        entries: map
+            .iter_all()
+            .map(|(node, required_by)| (node.clone(), required_by.clone()))
+            .collect(),
+    };
+    if let Err(e) = async {
+        create_dir_all(".cotton").await?;
+        write_json(INDEX_PATH, index).await
+    }
+    .await
+    {
+        log_verbose(&format!("Failed to save reverse dependency index: {e}"));
+    }
+
+    Ok(map)
+}
+
+fn targets(graph: &Graph, name: &CompactString, version: Option<&Version>) -> Vec<Node> {
+    if let Some(version) = version {
+        vec![(name.clone(), version.clone())]
+    } else {
+        graph
+            .relations
+            .iter()
+            .filter(|(req, _)| req.name == name)
+            .map(|(_, resolved)| (name.clone(), resolved.version.clone()))
+            .unique()
+            .sorted()
+            .collect()
+    }
+}
+
+struct Chains<'a> {
+    graph: &'a Graph,
+    map: &'a MultiMap<Node, PackageSpecifier>,
+    roots: FxHashSet<Node>,
+    stack: Vec<Node>,
+    found: Vec<Vec<Node>>,
+}
+
+impl Chains<'_> {
+    fn visit(&mut self, node: Node) -> Result<()> {
+        if self.found.len() >= MAX_CHAINS {
+            return Ok(());
+        }
+
+        self.stack.push(node.clone());
+
+        if self.roots.contains(&node) {
+            self.found.push(self.stack.clone());
+        }
+
+        if let Some(required_by) = self.map.get_vec(&node) {
+            let parents: FxHashSet<Node> = required_by
+                .iter()
+                .map(|x| {
+                    self.graph
+                        .resolve_req(x)
+                        .map(|x| (x.package.name.clone(), x.version))
+                })
+                .try_collect()?;
+
+            for parent in parents.into_iter().sorted() {
+                if !self.stack.contains(&parent) {
+                    self.visit(parent)?;
+                }
+            }
+        }
+
+        self.stack.pop();
+
+        Ok(())
+    }
+}
+
+/// All chains of dependents leading from `node` up to `package.json`
+fn chains(
+    package: &PackageMetadata,
+    graph: &Graph,
+    map: &MultiMap<Node, PackageSpecifier>,
+    node: Node,
+) -> Result<Vec<Vec<Node>>> {
+    let roots = package
+        .iter_all()
+        .map(|req| {
+            graph
+                .resolve_req(&req)
+                .map(|x| (x.package.name.clone(), x.version))
+        })
+        .try_collect()?;
+
+    let mut chains = Chains {
+        graph,
+        map,
+        roots,
+        stack: vec![],
+        found: vec![],
+    };
+    chains.visit(node)?;
+
+    Ok(chains.found)
+}
+
+#[derive(Default)]
+struct Trie(BTreeMap<String, Trie>);
+
+impl Trie {
+    fn insert(&mut self, path: impl IntoIterator<Item = String>) {
+        let mut trie = self;
+        for label in path {
+            trie = trie.0.entry(label).or_default();
+        }
+    }
+
+    fn print(&self, prefix: &str) {
+        for (i, (label, child)) in self.0.iter().enumerate() {
+            let last = i == self.0.len() - 1;
+            println!("{prefix}{}{label}", if last { "└─ " } else { "├─ " });
+            child.print(&format!("{prefix}{}", if last { "   " } else { "│  " }));
+        }
+    }
+}
+
+fn label((name, version): &Node) -> String {
+    format!("{name}@{version}")
+}
+
+#[derive(Serialize)]
+struct WhyJson {
+    package: String,
+    chains: Vec<Vec<String>>,
+}
+
+pub fn print_tree(
+    package: &PackageMetadata,
+    graph: &Graph,
+    map: &MultiMap<Node, PackageSpecifier>,
+    name: &CompactString,
+    version: Option<&Version>,
+    json: bool,
+) -> Result<()> {
+    let targets = targets(graph, name, version);
+    if targets.is_empty() {
+        return Err(eyre!("Package {} is not used", name));
+    }
+
+    let mut output = vec![];
+
+    for target in targets {
+        let chains = chains(package, graph, map, target.clone())?;
+        if chains.is_empty() {
+            return Err(eyre!("Package {} is not used", label(&target)));
+        }
+        if chains.len() >= MAX_CHAINS {
+            eprintln!(
+                "Note: only the first {MAX_CHAINS} chains for {} are shown",
+                label(&target)
+            );
+        }
+
+        if json {
+            output.push(WhyJson {
+                package: label(&target),
+                chains: chains
+                    .iter()
+                    .map(|chain| {
+                        chain
+                            .iter()
+                            .map(label)
+                            .chain(["package.json".to_string()])
+                            .collect()
+                    })
+                    .collect(),
+            });
+        } else {
+            let mut trie = Trie::default();
+            for chain in chains {
+                trie.insert(
+                    chain[1..]
+                        .iter()
+                        .map(label)
+                        .chain(["package.json".to_string()]),
+                );
+            }
+
+            println!("{}", label(&target).yellow().bold());
+            trie.print("");
+            println!();
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    }
+
+    Ok(())
+}
+
+pub fn print_flat(
+    package: &PackageMetadata,
+    graph: &Graph,
+    map: &MultiMap<Node, PackageSpecifier>,
+    name: &CompactString,
+    version: Option<&Version>,
+) -> Result<()> {
+    let mut seen = FxHashSet::default();
+    let mut queue: VecDeque<_> = targets(graph, name, version).into();
+
+    if queue.is_empty() {
+        return Err(eyre!("Package {} is not used", name));
+    }
+
+    while let Some((name, version)) = queue.pop_front() {
+        if seen.insert((name.clone(), version.clone())) {
+            if let Some(required_by) = map.get_vec(&(name.clone(), version.clone())) {
+                let required_by: FxHashSet<_> = required_by
+                    .iter()
+                    .map(|x| graph.resolve_req(x))
+                    .try_collect()?;
+                if !required_by.is_empty() {
+                    println!(
+                        "{}",
+                        format!("{}@{} is used by:", name.yellow(), version).bold()
+                    );
+                    for dep in required_by {
+                        queue.push_back((dep.package.name.clone(), dep.version.clone()));
+                        println!(" - {}@{}", dep.package.name, dep.version);
+                    }
+                    println!();
+                }
+            } else if package
+                .iter_all()
+                .any(|x| x.name == name && x.version.satisfies(&version))
+            {
+                println!(
+                    "{}",
+                    format!("{}@{} is used by package.json", name.yellow(), version).bold()
+                );
+                println!();
+            } else {
+                return Err(eyre!("Package {}@{} is not used", name, version));
+            }
+        }
+    }
+
+    println!("Analyzed {} packages", seen.len().yellow());
+
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use compact_str::{CompactString, ToCompactString};
+use futures::future::try_join_all;
+use itertools::Itertools;
+use node_semver::{Range, Version};
+use owo_colors::OwoColorize;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use crate::{
+    catalog::Catalogs,
+    local::rebase_local_dependencies,
+    package::{Dist, PackageInfo, PackageMetadata},
+    progress::{log_warning, PROGRESS_BAR},
+    scripts::set_lifecycle_env,
+    util::{read_json, VersionSpecifier},
+};
+
+/// The `workspaces` field of `package.json`, as either a list of globs or an object
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum Workspaces {
+    List(Vec<String>),
+    Object {
+        #[serde(default)]
+        packages: Vec<String>,
+    },
+}
+
+impl Workspaces {
+    pub fn patterns(&self) -> &[String] {
+        match self {
+            Workspaces::List(x) => x,
+            Workspaces::Object { packages } => packages,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub name: CompactString,
+    pub dir: PathBuf,
+    pub package: PackageMetadata,
+}
+
+/// Find all workspace members declared by the root package, relative to the current directory
+pub async fn find_workspaces(root: &PackageMetadata) -> Result<Vec<Workspace>> {
+    find_workspaces_in(Path::new(""), root).await
+}
+
+/// Find all workspace members declared by the root package in `root_dir`
+async fn find_workspaces_in(root_dir: &Path, root: &PackageMetadata) -> Result<Vec<Workspace>> {
+    let Some(workspaces) = &root.workspaces else {
+        return Ok(vec![]);
+    };
+
+    let mut found = vec![];
+    let mut seen = FxHashSet::default();
+    let catalogs = Catalogs::new(root).await?;
+
+    for pattern in workspaces.patterns() {
+        for dir in glob::glob(&root_dir.join(pattern).to_string_lossy())? {
+            let dir = dir?;
+            let manifest = dir.join("package.json");
+            if !manifest.exists() || !seen.insert(dir.clone()) {
+                continue;
+            }
+
+            let mut package: PackageMetadata = read_json(&manifest).await?;
+            catalogs
+                .apply(&mut package)
+                .wrap_err_with(|| format!("Failed to read {}", manifest.display()))?;
+            found.push(Workspace {
+                name: package.name.clone(),
+                dir,
+                package,
+            });
+        }
+    }
+
+    Ok(found)
+}
+
+/// Order workspaces so that each one comes after the workspaces it depends on
+pub fn sort_workspaces(workspaces: Vec<Workspace>) -> Vec<Workspace> {
+    let names: FxHashSet<_> = workspaces.iter().map(|x| x.name.clone()).collect();
+
+    let mut remaining: FxHashMap<_, FxHashSet<_>> = workspaces
+        .iter()
+        .map(|x| {
+            (
+                x.name.clone(),
+                x.package
+                    .iter_all()
+                    .map(|req| req.name)
+                    .filter(|name| names.contains(name) && *name != x.name)
+                    .collect(),
+            )
+        })
+        .collect();
+
+    let mut by_name: FxHashMap<_, _> = workspaces
+        .into_iter()
+        .map(|x| (x.name.clone(), x))
+        .collect();
+
+    let mut sorted = vec![];
+    while !remaining.is_empty() {
+        let mut ready = remaining
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| name.clone())
+            .sorted()
+            .collect_vec();
+
+        if ready.is_empty() {
+            log_warning(&format!(
+                "Cyclic dependencies between workspaces: {}",
+                remaining.keys().sorted().join(", ")
+            ));
+            ready = remaining.keys().cloned().sorted().collect();
+        }
+
+        for name in ready {
+            remaining.remove(&name);
+            for deps in remaining.values_mut() {
+                deps.remove(&name);
+            }
+            sorted.extend(by_name.remove(&name));
+        }
+    }
+
+    sorted
+}
+
+pub enum Task<'a> {
+    Script(&'a str),
+    Exec(&'a OsString, &'a [OsString]),
+}
+
+fn bin_path(dirs: &[PathBuf]) -> Result<OsString> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    let paths = dirs.iter().cloned().chain(env::split_paths(&path));
+    Ok(env::join_paths(paths)?)
+}
+
+async fn run_prefixed(name: &str, mut command: Command) -> Result<()> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let prefix = format!("{name} |").bright_blue().to_string();
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let stdout = async {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            PROGRESS_BAR.suspend(|| println!("{prefix} {line}"));
+        }
+        Ok(())
+    };
+    let stderr = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            PROGRESS_BAR.suspend(|| eprintln!("{prefix} {line}"));
+        }
+        Ok(())
+    };
+
+    let (status, (), ()) = tokio::try_join!(child.wait(), stdout, stderr)?;
+
+    if !status.success() {
+        return Err(eyre!("Command failed in workspace {name} ({status})"));
+    }
+
+    Ok(())
+}
+
+/// Run a script or command in every workspace, in dependency order unless `parallel` is set
+pub async fn run_in_workspaces(
+    workspaces: Vec<Workspace>,
+    shell: &str,
+    root: &Path,
+    task: Task<'_>,
+    parallel: bool,
+) -> Result<()> {
+    let mut commands = vec![];
+
+    for workspace in sort_workspaces(workspaces) {
+        let mut command = match task {
+            Task::Script(name) => {
+                let Some(script) = workspace.package.scripts.get(name).and_then(|x| x.as_str())
+                else {
+                    continue;
+                };
+                let mut command = Command::new(shell);
+                command.arg("-c").arg(script);
+                set_lifecycle_env(
+                    &mut command,
+                    name,
+                    script,
+                    &root.join(&workspace.dir),
+                    &workspace.name,
+                    workspace
+                        .package
+                        .version
+                        .as_ref()
+                        .map(|x| x.to_string())
+                        .as_deref(),
+                )?;
+                command
+            }
+            Task::Exec(exe, args) => {
+                let mut command = Command::new(exe);
+                command.args(args);
+                command
+            }
+        };
+
+        command.current_dir(&workspace.dir).env(
+            "PATH",
+            bin_path(&[
+                root.join(&workspace.dir).join("node_modules/.bin"),
+                root.join("node_modules/.bin"),
+            ])?,
+        );
+
+        commands.push((workspace.name, command));
+    }
+
+    if parallel {
+        try_join_all(
+            commands
+                .into_iter()
+                .map(|(name, command)| async move { run_prefixed(&name, command).await }),
+        )
+        .await?;
+    } else {
+        for (name, command) in commands {
+            run_prefixed(&name, command).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the workspaces of the nearest project that declares any, searching upwards from the current directory
+pub async fn enclosing_workspaces() -> Result<Vec<Workspace>> {
+    let mut dir = PathBuf::new();
+
+    for ancestor in env::current_dir()?.ancestors() {
+        if let Ok(root) = read_json::<PackageMetadata>(ancestor.join("package.json")).await {
+            if root.workspaces.is_some() {
+                return find_workspaces_in(&dir, &root).await;
+            }
+        }
+        dir.push("..");
+    }
+
+    Ok(vec![])
+}
+
+/// The range of a `workspace:` specifier
+pub fn workspace_range(spec: &VersionSpecifier) -> Option<&str> {
+    match spec {
+        VersionSpecifier::Prefixed(prefixed) if prefixed.prefix == "workspace" => {
+            Some(&prefixed.rest)
+        }
+        _ => None,
+    }
+}
+
+/// Resolve a `workspace:` specifier to the sibling workspace package, which is linked instead of downloaded
+pub async fn fetch_workspace_package(
+    name: &str,
+    range: &str,
+) -> Result<(Version, Arc<PackageInfo>)> {
+    let workspace = enclosing_workspaces()
+        .await?
+        .into_iter()
+        .find(|x| x.name == name)
+        .ok_or_else(|| eyre!("`{name}` is not a workspace package"))?;
+
+    let version = workspace
+        .package
+        .version
+        .clone()
+        .unwrap_or_else(|| Version::from((0, 0, 0)));
+
+    if !matches!(range, "" | "*" | "^" | "~") && !Range::parse(range)?.satisfies(&version) {
+        return Err(eyre!(
+            "Workspace package {name}@{version} does not satisfy workspace:{range}"
+        ));
+    }
+
+    let mut package = workspace.package;
+    rebase_local_dependencies(&mut package, &workspace.dir)?;
+    let mut package = package.info();
+    package.dist = Dist {
+        tarball: format!("link:{}", workspace.dir.display()).to_compact_string(),
+        ..Default::default()
+    };
+
+    Ok((version, Arc::new(package)))
+}
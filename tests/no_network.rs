@@ -0,0 +1,79 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all, write},
+    io::ErrorKind,
+    net::TcpListener,
+    path::PathBuf,
+    process::Command,
+};
+
+/// A project with one locked dependency, whose registry and tarball point at `registry`
+fn fixture(name: &str, registry: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cotton-{name}-{}", std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+
+    write(
+        dir.join("package.json"),
+        r#"{ "name": "fixture", "version": "1.0.0", "dependencies": { "left-pad": "^1.3.0" } }"#,
+    )
+    .unwrap();
+    write(
+        dir.join("cotton.toml"),
+        format!("[[registry]]\nurl = \"{registry}\"\n"),
+    )
+    .unwrap();
+    write(
+        dir.join("cotton.lock"),
+        format!(
+            r#"{{
+                "lockfile_version": 1,
+                "left-pad!^1.3.0": [
+                    "1.3.0",
+                    {{
+                        "name": "left-pad",
+                        "dist": {{ "tarball": "{registry}/left-pad/-/left-pad-1.3.0.tgz" }}
+                    }}
+                ]
+            }}"#
+        ),
+    )
+    .unwrap();
+
+    dir
+}
+
+#[test]
+fn lockfile_commands_make_no_requests() {
+    // Any request would reach this listener, either as the registry or as the proxy
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let registry = format!("http://{}", listener.local_addr().unwrap());
+    let dir = fixture("no-network", &registry);
+
+    for args in [&["list"][..], &["why", "left-pad"], &["stats"]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_cotton"))
+            .arg("--no-network")
+            .args(args)
+            .current_dir(&dir)
+            .env("HOME", &dir)
+            .env_remove("COTTON_HOME")
+            .env("HTTP_PROXY", &registry)
+            .env("HTTPS_PROXY", &registry)
+            .env("ALL_PROXY", &registry)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "`cotton {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    match listener.accept() {
+        Ok((_, addr)) => panic!("A request was made from {addr}"),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+    }
+
+    let _ = remove_dir_all(&dir);
+}
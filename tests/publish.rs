@@ -0,0 +1,96 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all, write},
+    io::{Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    process::Command,
+    thread,
+};
+
+/// A registry that has never heard of any package
+fn empty_registry() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+    });
+
+    url
+}
+
+fn fixture(name: &str, registry: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cotton-{name}-{}", std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(dir.join("lib")).unwrap();
+
+    write(
+        dir.join("package.json"),
+        r#"{ "name": "fixture", "version": "1.0.0" }"#,
+    )
+    .unwrap();
+    write(
+        dir.join("cotton.toml"),
+        format!("[[registry]]\nurl = \"{registry}\"\n"),
+    )
+    .unwrap();
+    write(dir.join(".npmignore"), "*.log\nREADME.md\n").unwrap();
+    write(
+        dir.join(".npmrc"),
+        "//registry.npmjs.org/:_authToken=secret\n",
+    )
+    .unwrap();
+    write(dir.join(".env"), "TOKEN=secret\n").unwrap();
+    write(dir.join("debug.log"), "").unwrap();
+    write(dir.join("README.md"), "# fixture\n").unwrap();
+    write(dir.join("lib/index.js"), "").unwrap();
+
+    dir
+}
+
+#[test]
+fn dry_run_leaves_out_secrets_and_ignored_files() {
+    let registry = empty_registry();
+    let dir = fixture("publish", &registry);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cotton"))
+        .args(["publish", "--dry-run"])
+        .current_dir(&dir)
+        .env("HOME", &dir)
+        .env_remove("COTTON_HOME")
+        .env("NO_PROXY", "127.0.0.1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "`cotton publish --dry-run` failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let files = stdout
+        .lines()
+        .filter_map(|x| x.strip_prefix("  "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        ["package.json", "README.md", "cotton.toml", "lib/index.js"]
+    );
+
+    let _ = remove_dir_all(&dir);
+}